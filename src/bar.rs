@@ -0,0 +1,229 @@
+use std::io;
+use std::io::Write;
+use std::time::Instant;
+
+use console;
+use messages;
+use progress::{Phase, Renderer};
+
+/// Below this many columns the bar itself is dropped for a compact line.
+const MIN_BAR_WIDTH: usize = 60;
+/// The narrowest bar worth drawing.
+const MIN_BAR_CELLS: usize = 10;
+
+/// Terminal width of stdout, polled on every redraw so resizes are
+/// picked up without a SIGWINCH handler.
+#[cfg(unix)]
+fn terminal_width() -> Option<usize> {
+    use libc;
+
+    let mut size: libc::winsize = unsafe { ::std::mem::zeroed() };
+    let ret = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) };
+    if ret == 0 && size.ws_col > 0 {
+        Some(size.ws_col as usize)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn terminal_width() -> Option<usize> {
+    None
+}
+
+/// Format a byte count with binary units, e.g. `512.00 MiB`.
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", value as u64)
+    } else {
+        format!("{:.2} {}", value, UNITS[unit])
+    }
+}
+
+pub fn format_eta(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    if secs < 60 {
+        format!("{}s", secs)
+    } else if secs < 3600 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h{:02}m", secs / 3600, (secs % 3600) / 60)
+    }
+}
+
+/// Where a bar is drawn.
+enum Out<W> {
+    Writer(W),
+    /// A line of its own among the console's bars, below printed lines.
+    Console(u64),
+}
+
+/// A progress bar that lays itself out for the current terminal width and
+/// never draws a line long enough to wrap.
+pub struct Bar<W: Write> {
+    out: Out<W>,
+    total: u64,
+    current: u64,
+    started: Instant,
+    phase: Phase,
+}
+
+impl Bar<io::Stdout> {
+    /// A bar on stdout for a transfer of `total` bytes (0 when unknown),
+    /// drawn through the console so other output stays above it.
+    pub fn stdout(total: u64) -> Bar<io::Stdout> {
+        Bar {
+            out: Out::Console(console::new_bar()),
+            ..Bar::new(io::stdout(), total)
+        }
+    }
+}
+
+impl<W: Write> Bar<W> {
+    pub fn new(out: W, total: u64) -> Bar<W> {
+        Bar {
+            out: Out::Writer(out),
+            total,
+            current: 0,
+            started: Instant::now(),
+            phase: Phase::Download,
+        }
+    }
+
+    /// Count bytes for `phase`; anything but a download is labelled.
+    pub fn phase(mut self, phase: Phase) -> Bar<W> {
+        self.phase = phase;
+        self
+    }
+
+    fn layout(&self, width: usize) -> String {
+        let elapsed = self.started.elapsed().as_secs_f64().max(1e-9);
+        let rate = self.current as f64 / elapsed;
+        let rate = format!("{}/s", format_bytes(rate));
+        let eta = if self.total > self.current && self.current > 0 {
+            Some(format_eta((self.total - self.current) as f64 * elapsed / self.current as f64))
+        } else {
+            None
+        };
+        let percent = if self.total > 0 {
+            Some(self.current as f64 * 100.0 / self.total as f64)
+        } else {
+            None
+        };
+
+        let mut candidates = Vec::new();
+        if width >= MIN_BAR_WIDTH {
+            if let Some(percent) = percent {
+                let counter = format!(
+                    "{} / {}",
+                    format_bytes(self.current as f64),
+                    format_bytes(self.total as f64)
+                );
+                let with_eta = eta.as_ref().map(|eta| format!(" {:.2} % {} {}", percent, rate, eta));
+                let without_eta = format!(" {:.2} % {}", percent, rate);
+                for suffix in with_eta.iter().chain(Some(&without_eta)) {
+                    let used = counter.len() + suffix.len() + 3;
+                    if used + MIN_BAR_CELLS <= width {
+                        let cells = width - used;
+                        let filled = ((percent / 100.0) * cells as f64).round() as usize;
+                        let filled = filled.min(cells);
+                        candidates.push(format!(
+                            "{} [{}{}]{}",
+                            counter,
+                            "=".repeat(filled),
+                            " ".repeat(cells - filled),
+                            suffix
+                        ));
+                    }
+                }
+            }
+        }
+
+        let done = format_bytes(self.current as f64);
+        let compact = match percent {
+            Some(percent) => format!("{:.0}% {} {}", percent, done, rate),
+            None => format!("{} {}", done, rate),
+        };
+        if let Some(ref eta) = eta {
+            candidates.push(format!("{} {}", compact, eta));
+        }
+        candidates.push(compact);
+
+        let mut line = candidates
+            .into_iter()
+            .find(|line| line.len() <= width)
+            .unwrap_or_else(|| match percent {
+                Some(percent) => format!("{:.0}%", percent),
+                None => done,
+            });
+        line.truncate(width);
+        line
+    }
+
+    fn width() -> usize {
+        // leave the last column free so the cursor never wraps
+        terminal_width().unwrap_or(80).saturating_sub(1).max(1)
+    }
+
+    fn draw(&mut self) {
+        let width = Bar::<W>::width();
+        let mut label = match self.phase {
+            Phase::Download => String::new(),
+            phase => format!("{} ", messages::render(&format!("progress.{}", phase.name()), &[])),
+        };
+        if let (Out::Console(_), Some(tag)) = (&self.out, console::bar_tag()) {
+            label = format!("[{}] {}", tag, label);
+        }
+        let label = if label.chars().count() * 2 < width { label } else { String::new() };
+        let line = format!("{}{}", label, self.layout(width - label.chars().count()));
+        match self.out {
+            Out::Writer(ref mut out) => {
+                let _ = write!(out, "\r{:<width$}", line, width = width);
+                let _ = out.flush();
+            }
+            Out::Console(id) => console::draw_bar(id, format!("{:<width$}", line, width = width)),
+        }
+    }
+}
+
+impl<W: Write + Send> Renderer for Bar<W> {
+    fn inc(&mut self, bytes: u64) {
+        self.current += bytes;
+        self.draw();
+    }
+
+    /// Clear the bar so anything printed afterwards starts on a clean line.
+    fn finish(&mut self) {
+        let done = messages::render("progress.done", &[]);
+        match self.out {
+            Out::Writer(ref mut out) => {
+                let width = Bar::<W>::width();
+                let _ = write!(out, "\r{:width$}\r", "", width = width);
+                let _ = writeln!(out, "{}", done);
+                let _ = out.flush();
+            }
+            Out::Console(id) => {
+                console::end_bar(id);
+                console::out(&done);
+            }
+        }
+    }
+
+    fn abandon(&mut self) {
+        match self.out {
+            Out::Writer(ref mut out) => {
+                let width = Bar::<W>::width();
+                let _ = write!(out, "\r{:width$}\r", "", width = width);
+                let _ = out.flush();
+            }
+            Out::Console(id) => console::end_bar(id),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use serde_json::Value;
+
+use checksum::Algorithm;
+use input;
+use messages;
+use motw;
+use progress;
+use sandbox;
+use tcpinfo;
+use xattr;
+
+/// Bumped only when a key below is removed or changes meaning; new keys
+/// may appear at any version. Wrappers can rely on every key across
+/// releases with the same schema.
+pub const SCHEMA: u64 = 1;
+
+/// URL schemes jobs can be fetched from.
+const SCHEMES: [&str; 2] = ["http", "https"];
+
+/// What this build supports, for `--capabilities`. Features that are not
+/// built in are listed as `false` rather than left out, so a missing key
+/// means a wrapper is newer than the binary.
+pub fn listing() -> Value {
+    let algorithms: Vec<String> = Algorithm::ALL.iter().map(|algorithm| algorithm.to_string()).collect();
+    json!({
+        "schema": SCHEMA,
+        "version": env!("CARGO_PKG_VERSION"),
+        "features": {
+            "blake3": true,
+            "concurrent_batch": true,
+            "control_socket": true,
+            "cookies": true,
+            "decompress_keep_both": true,
+            "dump_header": true,
+            "har": true,
+            "head": true,
+            "http2": true,
+            "http3": false,
+            "json_result": true,
+            "mark_of_the_web": motw::SUPPORTED,
+            "metalink": false,
+            "output_template": true,
+            "pinning": true,
+            "proxy": true,
+            "sandbox": sandbox::SUPPORTED,
+            "segments": true,
+            "socks": true,
+            "tcp_info": tcpinfo::SUPPORTED,
+            "tls_profiles": true,
+            "upload": true,
+            "verbose_trace": true,
+            "xattr": xattr::SUPPORTED,
+            "zip_member": true,
+        },
+        "url_schemes": SCHEMES,
+        "hash_algorithms": algorithms,
+        "input_formats": input::FORMATS,
+        "progress_modes": progress::MODES,
+        "languages": messages::LANGS,
+        "max_versions": {
+            "http": "2",
+            "tls": "1.3",
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::{listing, SCHEMA};
+
+    fn keys(value: &Value) -> Vec<&str> {
+        let mut keys: Vec<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn top_level_keys_are_stable() {
+        let listing = listing();
+        assert_eq!(
+            keys(&listing),
+            [
+                "features",
+                "hash_algorithms",
+                "input_formats",
+                "languages",
+                "max_versions",
+                "progress_modes",
+                "schema",
+                "url_schemes",
+                "version",
+            ]
+        );
+        assert_eq!(listing["schema"], SCHEMA);
+        assert_eq!(keys(&listing["max_versions"]), ["http", "tls"]);
+    }
+
+    #[test]
+    fn feature_keys_are_stable_and_boolean() {
+        let listing = listing();
+        assert_eq!(
+            keys(&listing["features"]),
+            [
+                "blake3",
+                "concurrent_batch",
+                "control_socket",
+                "cookies",
+                "decompress_keep_both",
+                "dump_header",
+                "har",
+                "head",
+                "http2",
+                "http3",
+                "json_result",
+                "mark_of_the_web",
+                "metalink",
+                "output_template",
+                "pinning",
+                "proxy",
+                "sandbox",
+                "segments",
+                "socks",
+                "tcp_info",
+                "tls_profiles",
+                "upload",
+                "verbose_trace",
+                "xattr",
+                "zip_member",
+            ]
+        );
+        assert!(listing["features"].as_object().unwrap().values().all(|value| value.is_boolean()));
+    }
+}
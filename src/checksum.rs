@@ -0,0 +1,249 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 5] = [
+        Algorithm::Md5,
+        Algorithm::Sha1,
+        Algorithm::Sha256,
+        Algorithm::Sha512,
+        Algorithm::Blake3,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        match name.to_lowercase().replace('-', "").as_str() {
+            "md5" => Some(Algorithm::Md5),
+            "sha1" => Some(Algorithm::Sha1),
+            "sha256" => Some(Algorithm::Sha256),
+            "sha512" => Some(Algorithm::Sha512),
+            "blake3" => Some(Algorithm::Blake3),
+            _ => None,
+        }
+    }
+
+    fn hex_len(self) -> usize {
+        match self {
+            Algorithm::Md5 => 32,
+            Algorithm::Sha1 => 40,
+            Algorithm::Sha256 | Algorithm::Blake3 => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Algorithm::Md5 => write!(f, "md5"),
+            Algorithm::Sha1 => write!(f, "sha1"),
+            Algorithm::Sha256 => write!(f, "sha256"),
+            Algorithm::Sha512 => write!(f, "sha512"),
+            Algorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// An expected digest, e.g. `sha256:<hex>`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub hex: String,
+}
+
+impl Checksum {
+    /// Parse `<algorithm>:<hex>`. A bare hex string is accepted when its
+    /// length identifies the algorithm; 64 digits are taken for sha256.
+    pub fn parse(value: &str) -> Result<Checksum, String> {
+        let value = value.trim();
+        let (algorithm, hex) = match value.find(':') {
+            Some(idx) => {
+                let name = &value[..idx];
+                let algorithm = Algorithm::from_name(name)
+                    .ok_or_else(|| format!("unsupported checksum algorithm: {}", name))?;
+                (algorithm, &value[idx + 1..])
+            }
+            None => match value.len() {
+                32 => (Algorithm::Md5, value),
+                40 => (Algorithm::Sha1, value),
+                64 => (Algorithm::Sha256, value),
+                128 => (Algorithm::Sha512, value),
+                _ => return Err(format!("cannot infer checksum algorithm: {}", value)),
+            },
+        };
+        Checksum::new(algorithm, hex)
+    }
+
+    pub fn new(algorithm: Algorithm, hex: &str) -> Result<Checksum, String> {
+        if hex.len() != algorithm.hex_len() || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("invalid {} digest: {}", algorithm, hex));
+        }
+        Ok(Checksum {
+            algorithm,
+            hex: hex.to_lowercase(),
+        })
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.hex)
+    }
+}
+
+/// Digests package indexes put in URL fragments that we cannot check.
+const UNCHECKED_FRAGMENT_DIGESTS: [&str; 2] = ["sha224", "sha384"];
+
+/// A digest named in a URL fragment.
+#[derive(Debug, PartialEq)]
+pub enum FragmentDigest {
+    Checked(Checksum),
+    /// The name of an algorithm we have no implementation of.
+    Unchecked(String),
+}
+
+/// The digest in a URL fragment such as `sha256=<hex>`, as package indexes
+/// publish them, possibly among other `&`-separated parameters. A fragment
+/// without one gives `Ok(None)`.
+pub fn from_fragment(fragment: &str) -> Result<Option<FragmentDigest>, String> {
+    for param in fragment.split('&') {
+        let (name, hex) = match param.find('=') {
+            Some(idx) => (param[..idx].to_ascii_lowercase(), &param[idx + 1..]),
+            None => continue,
+        };
+        if let Some(algorithm) = Algorithm::from_name(&name) {
+            return Checksum::new(algorithm, hex).map(|checksum| Some(FragmentDigest::Checked(checksum)));
+        }
+        if UNCHECKED_FRAGMENT_DIGESTS.contains(&name.as_str()) {
+            return Ok(Some(FragmentDigest::Unchecked(name)));
+        }
+    }
+    Ok(None)
+}
+
+/// The digests listed in a `sha256sum`-style file, one per line as
+/// `<hex>  <name>` (`*` before the name in binary mode) or in the BSD
+/// `SHA256 (<name>) = <hex>` form. Lines in neither form, such as a
+/// signature wrapped around the list, are passed over, as are digests of
+/// algorithms we cannot check.
+pub struct Sums {
+    entries: Vec<(String, Checksum)>,
+}
+
+impl Sums {
+    pub fn parse(text: &str) -> Sums {
+        let entries = text.lines().filter_map(|line| sums_line(line.trim_end_matches('\r'))).collect();
+        Sums { entries }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The digest listed for the file `name`, either as exactly that name or
+    /// as the last part of a listed path.
+    pub fn find(&self, name: &str) -> Option<&Checksum> {
+        let exact = self.entries.iter().find(|entry| entry.0 == name);
+        exact
+            .or_else(|| {
+                self.entries
+                    .iter()
+                    .find(|entry| entry.0.rsplit('/').next() == Some(name))
+            })
+            .map(|entry| &entry.1)
+    }
+}
+
+fn sums_line(line: &str) -> Option<(String, Checksum)> {
+    // BSD form: SHA256 (name) = hex
+    if let Some(open) = line.find(" (") {
+        let algorithm = Algorithm::from_name(&line[..open]);
+        if let (Some(algorithm), Some(close)) = (algorithm, line.rfind(") = ")) {
+            let checksum = Checksum::new(algorithm, line[close + 4..].trim()).ok()?;
+            return Some((line[open + 2..close].to_string(), checksum));
+        }
+    }
+    // GNU form; a leading backslash means the name has \\ and \n escapes
+    let (escaped, line) = match line.strip_prefix('\\') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let space = line.find(' ')?;
+    let checksum = Checksum::parse(&line[..space]).ok()?;
+    let name = &line[space + 1..];
+    let name = name.strip_prefix(|c| c == ' ' || c == '*')?;
+    if name.is_empty() {
+        return None;
+    }
+    let name = if escaped { unescape(name) } else { name.to_string() };
+    Some((name, checksum))
+}
+
+fn unescape(name: &str) -> String {
+    let mut out = String::with_capacity(name.len());
+    let mut chars = name.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some(other) => out.push(other),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{from_fragment, Algorithm, Checksum, FragmentDigest};
+
+    const SHA256: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn checked(algorithm: Algorithm, hex: &str) -> Result<Option<FragmentDigest>, String> {
+        Ok(Some(FragmentDigest::Checked(Checksum::new(algorithm, hex).unwrap())))
+    }
+
+    #[test]
+    fn fragment_digest_becomes_a_checksum() {
+        assert_eq!(from_fragment(&format!("sha256={}", SHA256)), checked(Algorithm::Sha256, SHA256));
+        assert_eq!(
+            from_fragment("MD5=D41D8CD98F00B204E9800998ECF8427E"),
+            checked(Algorithm::Md5, "d41d8cd98f00b204e9800998ecf8427e")
+        );
+    }
+
+    #[test]
+    fn fragment_digest_is_found_among_other_parameters() {
+        assert_eq!(
+            from_fragment(&format!("egg=tool&sha256={}&subdirectory=src", SHA256)),
+            checked(Algorithm::Sha256, SHA256)
+        );
+    }
+
+    #[test]
+    fn fragment_without_a_digest_gives_none() {
+        assert_eq!(from_fragment(""), Ok(None));
+        assert_eq!(from_fragment("section-2"), Ok(None));
+        assert_eq!(from_fragment("egg=tool&page=3"), Ok(None));
+    }
+
+    #[test]
+    fn fragment_digest_we_cannot_check_is_named() {
+        assert_eq!(from_fragment("sha384=abc"), Ok(Some(FragmentDigest::Unchecked("sha384".to_string()))));
+    }
+
+    #[test]
+    fn malformed_fragment_digest_is_an_error() {
+        assert_eq!(from_fragment("sha256=abc"), Err("invalid sha256 digest: abc".to_string()));
+    }
+}
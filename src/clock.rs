@@ -0,0 +1,82 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, DATE};
+use reqwest::redirect::Policy;
+use reqwest::{StatusCode, Url};
+
+use bar;
+use messages;
+use messages::Lang;
+
+/// Clock differences below this are ordinary drift and not worth a hint.
+const THRESHOLD_SECS: i64 = 5 * 60;
+
+/// Query parameters that make a URL expire: S3 and GCS presigned URLs,
+/// CloudFront signed URLs and Azure SAS tokens.
+const EXPIRY_PARAMS: [&str; 6] = [
+    "x-amz-date",
+    "x-amz-expires",
+    "x-goog-date",
+    "x-goog-expires",
+    "expires",
+    "se",
+];
+
+/// Seconds the server's `Date` header is ahead of the local clock.
+pub fn skew(headers: &HeaderMap) -> Option<i64> {
+    let date = headers.get(DATE)?.to_str().ok()?;
+    let server = DateTime::parse_from_rfc2822(date).ok()?;
+    Some(server.signed_duration_since(Utc::now()).num_seconds())
+}
+
+/// Whether `skew` seconds is enough to break certificate or signature
+/// validity checks.
+pub fn significant(skew: i64) -> bool {
+    skew.abs() > THRESHOLD_SECS
+}
+
+/// The advice to give, in `lang`, for a clock `skew` seconds off the
+/// server's.
+pub fn hint_in(lang: Lang, skew: i64) -> String {
+    let by = bar::format_eta(skew.unsigned_abs() as f64);
+    messages::render_in(lang, "hint.clock-skew", &[&by])
+}
+
+/// Whether `url` carries a signature with an expiry time.
+pub fn expiring(url: &Url) -> bool {
+    url.query_pairs()
+        .any(|(key, _)| EXPIRY_PARAMS.iter().any(|param| key.eq_ignore_ascii_case(param)))
+}
+
+/// Warn when a signed URL was refused and the server's clock disagrees
+/// with ours, which makes the signature look expired or not yet valid.
+pub fn check_response(resp: &Response) {
+    if resp.status() != StatusCode::FORBIDDEN || !expiring(resp.url()) {
+        return;
+    }
+    if let Some(skew) = skew(resp.headers()).filter(|skew| significant(*skew)) {
+        let hint = hint_in(messages::lang(), skew);
+        messages::warn("warning.signed-url-clock", &[&resp.url().path(), &hint]);
+    }
+}
+
+/// Ask the server behind `url` for its time after its certificate was
+/// rejected for its validity period. The certificate cannot be trusted, so
+/// only a HEAD of the bare origin is sent, never the path or query of
+/// `url`, and only the `Date` header is read.
+pub fn probe(url: &str) -> Option<i64> {
+    let mut origin = Url::parse(url).ok()?;
+    origin.set_path("/");
+    origin.set_query(None);
+    origin.set_fragment(None);
+    let client = Client::builder()
+        .danger_accept_invalid_certs(true)
+        .redirect(Policy::none())
+        .timeout(Duration::from_secs(5))
+        .build()
+        .ok()?;
+    let resp = client.head(origin).send().ok()?;
+    skew(resp.headers())
+}
@@ -0,0 +1,307 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+/// Which of the terminal's streams a line is for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+enum Record {
+    Line(Stream, String),
+    /// The freshly laid out line of a progress bar.
+    Bar(u64, String),
+    EndBar(u64),
+    Flush(Sender<()>),
+}
+
+/// Every human-facing line is handed to one printer thread, so lines from
+/// concurrent transfers never interleave and always appear above the
+/// progress bars rather than through them.
+static PRINTER: OnceLock<Mutex<Sender<Record>>> = OnceLock::new();
+static NEXT_BAR: AtomicU64 = AtomicU64::new(1);
+/// Transfers currently tagged; lines carry their tag once there is more
+/// than one.
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+/// Set by `screen_on_stderr`: lines for stdout and the bars go to stderr.
+static SCREEN_ON_STDERR: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static TAG: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+fn send(record: Record) {
+    let printer = PRINTER.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || print(receiver));
+        Mutex::new(sender)
+    });
+    let _ = printer.lock().unwrap().send(record);
+}
+
+/// The bars on screen, drawn in order below everything printed so far.
+#[derive(Default)]
+struct Screen {
+    bars: Vec<(u64, String)>,
+    /// How many bar lines are drawn; the cursor rests on the last one.
+    drawn: usize,
+}
+
+impl Screen {
+    fn hide(&mut self, out: &mut dyn Write) {
+        match self.drawn {
+            0 => {}
+            1 => {
+                let width = self.bars.iter().map(|bar| bar.1.chars().count()).max().unwrap_or(0);
+                let _ = write!(out, "\r{:width$}\r", "", width = width);
+            }
+            drawn => {
+                for line in 0..drawn {
+                    let _ = write!(out, "\r\x1b[2K");
+                    if line + 1 < drawn {
+                        let _ = write!(out, "\x1b[1A");
+                    }
+                }
+            }
+        }
+        self.drawn = 0;
+    }
+
+    fn show(&mut self, out: &mut dyn Write) {
+        for (idx, bar) in self.bars.iter().enumerate() {
+            if idx > 0 {
+                let _ = writeln!(out);
+            }
+            let _ = write!(out, "\r{}", bar.1);
+        }
+        self.drawn = self.bars.len();
+    }
+}
+
+/// Print what is meant for stdout, the bars included, on stderr instead,
+/// leaving stdout to a body or a document for programs.
+pub fn screen_on_stderr() {
+    SCREEN_ON_STDERR.store(true, Ordering::SeqCst);
+}
+
+/// Whether the bars would be drawn on a terminal rather than into a file
+/// or pipe, where their carriage returns only litter the log.
+pub fn screen_is_terminal() -> bool {
+    if SCREEN_ON_STDERR.load(Ordering::SeqCst) {
+        io::stderr().is_terminal()
+    } else {
+        io::stdout().is_terminal()
+    }
+}
+
+/// The stream the bars and the lines for stdout are drawn on, locked.
+fn screen_out() -> Box<dyn Write> {
+    if SCREEN_ON_STDERR.load(Ordering::SeqCst) {
+        Box::new(io::stderr().lock())
+    } else {
+        Box::new(io::stdout().lock())
+    }
+}
+
+fn print(receiver: Receiver<Record>) {
+    let mut screen = Screen::default();
+    let stderr = io::stderr();
+    while let Ok(record) = receiver.recv() {
+        match record {
+            // stdout is only locked when there is something to do on it, as
+            // a body may be streaming there under its lock
+            Record::Line(Stream::Stderr, ref text) if screen.drawn == 0 => {
+                let mut err = stderr.lock();
+                let _ = err.write_all(text.as_bytes()).and_then(|_| err.flush());
+            }
+            Record::Line(stream, text) => {
+                let mut out = screen_out();
+                screen.hide(&mut out);
+                let _ = out.flush();
+                match stream {
+                    Stream::Stdout => {
+                        let _ = out.write_all(text.as_bytes());
+                    }
+                    Stream::Stderr => {
+                        let mut err = stderr.lock();
+                        let _ = err.write_all(text.as_bytes()).and_then(|_| err.flush());
+                    }
+                }
+                screen.show(&mut out);
+                let _ = out.flush();
+            }
+            Record::Bar(id, line) => {
+                let mut out = screen_out();
+                match screen.bars.iter().position(|bar| bar.0 == id) {
+                    // a lone bar is redrawn in place
+                    Some(idx) if screen.drawn == 1 && screen.bars.len() == 1 => {
+                        screen.bars[idx].1 = line;
+                        let _ = write!(out, "\r{}", screen.bars[idx].1);
+                    }
+                    Some(idx) => {
+                        screen.hide(&mut out);
+                        screen.bars[idx].1 = line;
+                        screen.show(&mut out);
+                    }
+                    None => {
+                        screen.hide(&mut out);
+                        screen.bars.push((id, line));
+                        screen.show(&mut out);
+                    }
+                }
+                let _ = out.flush();
+            }
+            Record::EndBar(id) => {
+                let mut out = screen_out();
+                screen.hide(&mut out);
+                screen.bars.retain(|bar| bar.0 != id);
+                screen.show(&mut out);
+                let _ = out.flush();
+            }
+            // every record above was flushed as it was written
+            Record::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Print `text` on `stream`, one line per line of it, each tagged with the
+/// transfer it belongs to while several are under way.
+pub fn line(stream: Stream, text: &str) {
+    let tag = match ACTIVE.load(Ordering::SeqCst) {
+        0 | 1 => None,
+        _ => TAG.with(|tag| tag.borrow().clone()),
+    };
+    let mut record = String::with_capacity(text.len() + 1);
+    for line in text.lines() {
+        if let Some(ref tag) = tag {
+            record.push_str(&format!("[{}] ", tag));
+        }
+        record.push_str(line);
+        record.push('\n');
+    }
+    if record.is_empty() {
+        record.push('\n');
+    }
+    send(Record::Line(stream, record));
+}
+
+pub fn out(text: &str) {
+    line(Stream::Stdout, text);
+}
+
+pub fn err(text: &str) {
+    line(Stream::Stderr, text);
+}
+
+/// The tag to put before a bar of this thread's transfer, if lines are
+/// being tagged.
+pub fn bar_tag() -> Option<String> {
+    if ACTIVE.load(Ordering::SeqCst) < 2 {
+        return None;
+    }
+    TAG.with(|tag| tag.borrow().clone())
+}
+
+/// Print one line of output for programs on stderr, never tagged.
+pub fn event(text: &str) {
+    send(Record::Line(Stream::Stderr, format!("{}\n", text)));
+}
+
+/// A bar of its own below the printed lines.
+pub fn new_bar() -> u64 {
+    NEXT_BAR.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn draw_bar(id: u64, line: String) {
+    send(Record::Bar(id, line));
+}
+
+pub fn end_bar(id: u64) {
+    send(Record::EndBar(id));
+}
+
+/// Wait until everything handed to the printer is on the terminal.
+pub fn flush() {
+    if PRINTER.get().is_none() {
+        return;
+    }
+    let (done, wait) = mpsc::channel();
+    send(Record::Flush(done));
+    let _ = wait.recv();
+}
+
+/// Exit once all output is written.
+pub fn exit(code: i32) -> ! {
+    flush();
+    process::exit(code);
+}
+
+/// Marks this thread's output as belonging to the transfer `name` until
+/// dropped.
+pub struct Tagged(());
+
+pub fn tag(name: String) -> Tagged {
+    TAG.with(|tag| *tag.borrow_mut() = Some(name));
+    ACTIVE.fetch_add(1, Ordering::SeqCst);
+    Tagged(())
+}
+
+impl Drop for Tagged {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::SeqCst);
+        TAG.with(|tag| *tag.borrow_mut() = None);
+    }
+}
+
+/// A writer whose output reaches `stream` through the printer, a line at
+/// a time, for code that formats onto a `Write`.
+pub struct Lines {
+    stream: Stream,
+    pending: Vec<u8>,
+}
+
+impl Lines {
+    pub fn new(stream: Stream) -> Lines {
+        Lines {
+            stream,
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Write for Lines {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if let Some(end) = self.pending.iter().rposition(|&b| b == b'\n') {
+            let rest = self.pending.split_off(end + 1);
+            let text = String::from_utf8_lossy(&self.pending[..end]).into_owned();
+            line(self.stream, &text);
+            self.pending = rest;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            let text = String::from_utf8_lossy(&self.pending).into_owned();
+            self.pending.clear();
+            line(self.stream, &text);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Lines {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
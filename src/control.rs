@@ -0,0 +1,481 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc;
+use serde_json::Value;
+
+/// Set by `cancel()`; transfers stop at their next chunk.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+/// Bytes per second `advance` lets through across all transfers, zero for
+/// unlimited.
+static RATE_LIMIT: AtomicU64 = AtomicU64::new(0);
+/// The bucket `RATE_LIMIT` is drawn from, started by the first byte.
+static LIMITER: Mutex<Option<Bucket>> = Mutex::new(None);
+/// `--speed-limit` in bytes per second and `--speed-time`.
+static SPEED_LIMIT: Mutex<Option<(u64, Duration)>> = Mutex::new(None);
+
+/// Every transfer slot, one per thread that ran a job, in the order they
+/// were first used.
+static TRANSFERS: Mutex<Vec<Transfer>> = Mutex::new(Vec::new());
+static NEXT_SLOT: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<Handle>> = const { Cell::new(None) };
+}
+
+/// The transfer a thread works on, so that helper threads of a job can
+/// report to it with `adopt`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Handle(u64);
+
+/// What a job is doing, for `status` queries.
+struct Transfer {
+    slot: Handle,
+    state: &'static str,
+    url: Option<String>,
+    started: Option<Instant>,
+    bytes: u64,
+    total: Option<u64>,
+    /// The bucket `job_limit` is drawn from.
+    limiter: Option<Bucket>,
+    /// The current job's own limit in bytes per second, 0 for none.
+    job_limit: u64,
+    /// When the current job has to be done by, if it has a time limit.
+    deadline: Option<Instant>,
+    /// Set by `abort`; only this transfer stops.
+    aborted: bool,
+    /// When the current second of the body began, the bytes that arrived
+    /// since, and when the last of them did.
+    window: Option<(Instant, u64, Instant)>,
+    /// The most bytes per second over any whole second of the body.
+    peak: f64,
+}
+
+impl Transfer {
+    fn new(slot: Handle) -> Transfer {
+        Transfer {
+            slot,
+            state: "idle",
+            url: None,
+            started: None,
+            bytes: 0,
+            total: None,
+            limiter: None,
+            job_limit: 0,
+            deadline: None,
+            aborted: false,
+            window: None,
+            peak: 0.0,
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let elapsed = self.started.map(|started| started.elapsed().as_secs_f64());
+        let rate = elapsed.filter(|secs| *secs > 0.0).map(|secs| self.bytes as f64 / secs);
+        let eta = match (self.total, rate) {
+            (Some(total), Some(rate)) if rate > 0.0 => Some(total.saturating_sub(self.bytes) as f64 / rate),
+            _ => None,
+        };
+        json!({
+            "state": self.state,
+            "url": self.url,
+            "bytes": self.bytes,
+            "total": self.total,
+            "rate": rate,
+            "eta": eta,
+        })
+    }
+}
+
+/// Run `f` on this thread's transfer, if it has one.
+fn with_current<T>(f: impl FnOnce(&mut Transfer) -> T) -> Option<T> {
+    let slot = CURRENT.with(Cell::get)?;
+    let mut transfers = TRANSFERS.lock().unwrap();
+    transfers.iter_mut().find(|transfer| transfer.slot == slot).map(f)
+}
+
+/// The error a transfer fails with once cancellation was requested.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+}
+
+/// Cancel everything on SIGINT or SIGTERM, as the `cancel` command does,
+/// so that transfers stop at their next chunk and clean up. A second
+/// signal is not caught and ends the process at once.
+pub fn cancel_on_signals() {
+    extern "C" fn on_signal(_: libc::c_int) {
+        CANCELLED.store(true, Ordering::SeqCst);
+    }
+    let mut action: libc::sigaction = unsafe { ::std::mem::zeroed() };
+    action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    action.sa_flags = libc::SA_RESTART | libc::SA_RESETHAND;
+    for &signal in &[libc::SIGINT, libc::SIGTERM] {
+        unsafe { libc::sigaction(signal, &action, ::std::ptr::null_mut()) };
+    }
+}
+
+pub fn is_cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+/// Stop the transfer `slot` at its next chunk, leaving the others running.
+pub fn abort(slot: Handle) {
+    let mut transfers = TRANSFERS.lock().unwrap();
+    if let Some(transfer) = transfers.iter_mut().find(|transfer| transfer.slot == slot) {
+        transfer.aborted = true;
+    }
+}
+
+/// This thread's transfer, once it started a job.
+pub fn handle() -> Option<Handle> {
+    CURRENT.with(Cell::get)
+}
+
+/// Count this thread's work towards the transfer `slot`, as a helper
+/// thread of its job.
+pub fn adopt(slot: Option<Handle>) {
+    CURRENT.with(|current| current.set(slot));
+}
+
+/// Fail with `Cancelled` if cancellation was requested for everything or
+/// for this thread's transfer.
+pub fn check() -> io::Result<()> {
+    if is_cancelled() || with_current(|transfer| transfer.aborted).unwrap_or(false) {
+        Err(io::Error::other(Cancelled))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn set_rate_limit(bytes_per_sec: u64) {
+    RATE_LIMIT.store(bytes_per_sec, Ordering::SeqCst);
+    *LIMITER.lock().unwrap() = None;
+}
+
+pub fn set_speed_limit(bytes_per_sec: u64, time: Duration) {
+    *SPEED_LIMIT.lock().unwrap() = Some((bytes_per_sec, time));
+}
+
+/// A body that fails with `TimedOut` once it arrives slower than
+/// `--speed-limit` for `--speed-time`. The time is counted in windows of
+/// that length, each starting where the last one ended.
+pub struct SpeedCheck<R> {
+    inner: R,
+    limit: Option<(u64, Duration)>,
+    received: u64,
+    /// When the current window began and what was received by then.
+    window: (Instant, u64),
+}
+
+impl<R: Read> SpeedCheck<R> {
+    pub fn new(inner: R) -> SpeedCheck<R> {
+        SpeedCheck {
+            inner,
+            limit: *SPEED_LIMIT.lock().unwrap(),
+            received: 0,
+            window: (Instant::now(), 0),
+        }
+    }
+
+    /// Whether the window is over with too few bytes, starting the next
+    /// one if it is over but was fast enough.
+    fn too_slow(&mut self) -> bool {
+        let (limit, time) = match self.limit {
+            Some(limit) => limit,
+            None => return false,
+        };
+        let elapsed = self.window.0.elapsed();
+        if elapsed < time {
+            return false;
+        }
+        let rate = (self.received - self.window.1) as f64 / elapsed.as_secs_f64();
+        self.window = (Instant::now(), self.received);
+        rate < limit as f64
+    }
+
+    fn slow_error(&self) -> io::Error {
+        let (limit, time) = self.limit.unwrap_or_default();
+        let msg = format!(
+            "slower than {} bytes per second for {} seconds (--speed-limit)",
+            limit,
+            time.as_secs_f64()
+        );
+        io::Error::new(io::ErrorKind::TimedOut, msg)
+    }
+}
+
+impl<R: Read> Read for SpeedCheck<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(len) => {
+                self.received += len as u64;
+                if len > 0 && self.too_slow() {
+                    return Err(self.slow_error());
+                }
+                Ok(len)
+            }
+            // most likely the wait --speed-time caps the read timeout to
+            Err(_) if self.too_slow() => Err(self.slow_error()),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// This thread's transfer slot, taken now if it has none yet.
+pub fn slot() -> Handle {
+    handle().unwrap_or_else(|| {
+        let slot = Handle(NEXT_SLOT.fetch_add(1, Ordering::Relaxed));
+        TRANSFERS.lock().unwrap().push(Transfer::new(slot));
+        adopt(Some(slot));
+        slot
+    })
+}
+
+/// The URL of this thread's current job.
+pub fn current_url() -> Option<String> {
+    with_current(|transfer| transfer.url.clone()).and_then(|url| url)
+}
+
+/// A job for `url` is starting on this thread, which reuses the transfer
+/// slot of its previous job. An abort stays in force.
+pub fn start(url: &str) {
+    let slot = slot();
+    let mut transfers = TRANSFERS.lock().unwrap();
+    if let Some(transfer) = transfers.iter_mut().find(|transfer| transfer.slot == slot) {
+        *transfer = Transfer {
+            state: "requesting",
+            url: Some(url.to_string()),
+            aborted: transfer.aborted,
+            ..Transfer::new(slot)
+        };
+    }
+}
+
+/// Limit the current job to `bytes_per_sec`, on top of the global limit.
+pub fn limit_job(bytes_per_sec: u64) {
+    with_current(|transfer| {
+        transfer.job_limit = bytes_per_sec;
+        transfer.limiter = None;
+    });
+}
+
+/// Give the current job `max` from now to finish.
+pub fn limit_time(max: Duration) {
+    with_current(|transfer| transfer.deadline = Some(Instant::now() + max));
+}
+
+/// What remains of the current job's time, if it is limited.
+pub fn time_left() -> Option<Duration> {
+    let deadline = with_current(|transfer| transfer.deadline).flatten()?;
+    Some(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// Fail with `TimedOut` once the current job ran out of time.
+pub fn check_deadline() -> io::Result<()> {
+    match with_current(|transfer| transfer.deadline).flatten() {
+        Some(deadline) if Instant::now() >= deadline => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "--max-time or the entry's max_time ran out"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The response arrived and its body, of `total` bytes when known, is
+/// about to be copied.
+pub fn receiving(total: Option<u64>) {
+    with_current(|transfer| {
+        transfer.state = "downloading";
+        transfer.started = Some(Instant::now());
+        transfer.total = total;
+    });
+}
+
+/// The current job ended in `state`.
+pub fn finish(state: &'static str) {
+    with_current(|transfer| transfer.state = state);
+}
+
+/// A token bucket refilled at the limit and holding at most a second's
+/// worth, so that an idle spell between transfers does not turn into a
+/// burst. Bytes taken beyond what it holds are a debt that is slept off.
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+/// Take `len` bytes from `bucket` at `limit` bytes per second and say how
+/// long to wait before they may go on.
+fn due(bucket: &mut Option<Bucket>, len: u64, limit: u64, now: Instant) -> Duration {
+    let rate = limit as f64;
+    let bucket = bucket.get_or_insert(Bucket {
+        tokens: 0.0,
+        refilled: now,
+    });
+    let refill = now.saturating_duration_since(bucket.refilled).as_secs_f64() * rate;
+    bucket.tokens = (bucket.tokens + refill).min(rate) - len as f64;
+    bucket.refilled = now;
+    if bucket.tokens >= 0.0 {
+        Duration::ZERO
+    } else {
+        Duration::from_secs_f64(-bucket.tokens / rate)
+    }
+}
+
+/// The seconds over which the peak rate is averaged.
+const PEAK_WINDOW: f64 = 1.0;
+
+/// The peak rate of this thread's current job in bytes per second: that
+/// of its busiest whole second, or of what it got of one.
+pub fn peak_rate() -> Option<f64> {
+    with_current(|transfer| {
+        let (start, bytes, last) = transfer.window?;
+        let elapsed = last.saturating_duration_since(start).as_secs_f64();
+        let partial = if elapsed > 0.0 { bytes as f64 / elapsed } else { 0.0 };
+        Some(transfer.peak.max(partial)).filter(|peak| *peak > 0.0)
+    })
+    .flatten()
+}
+
+/// Count `len` more body bytes, sleeping as long as the job's own limit or
+/// the global one needs.
+pub fn advance(len: u64) {
+    let now = Instant::now();
+    let job_delay = with_current(|transfer| {
+        if transfer.state != "downloading" {
+            return None;
+        }
+        transfer.bytes += len;
+        // the first chunk only marks when the body began arriving
+        let (start, bytes, _) = *transfer.window.get_or_insert((now, 0, now));
+        let bytes = bytes + if transfer.bytes == len { 0 } else { len };
+        let elapsed = now.saturating_duration_since(start).as_secs_f64();
+        transfer.window = if elapsed >= PEAK_WINDOW {
+            transfer.peak = transfer.peak.max(bytes as f64 / elapsed);
+            Some((now, 0, now))
+        } else {
+            Some((start, bytes, now))
+        };
+        match transfer.job_limit {
+            0 => Some(Duration::ZERO),
+            limit => Some(due(&mut transfer.limiter, len, limit, now)),
+        }
+    });
+    let job_delay = match job_delay.flatten() {
+        Some(delay) => delay,
+        None => return,
+    };
+    let global_delay = match RATE_LIMIT.load(Ordering::SeqCst) {
+        0 => Duration::ZERO,
+        limit => due(&mut LIMITER.lock().unwrap(), len, limit, now),
+    };
+    thread::sleep(job_delay.max(global_delay));
+}
+
+/// The busiest transfer's state at the top level, as when jobs ran one at
+/// a time, and every transfer under `transfers` once there are several.
+fn status() -> Value {
+    let transfers = TRANSFERS.lock().unwrap();
+    let shown = transfers
+        .iter()
+        .find(|transfer| transfer.state == "downloading" || transfer.state == "requesting")
+        .or_else(|| transfers.last());
+    let mut doc = match shown {
+        Some(transfer) => transfer.to_json(),
+        None => Transfer::new(Handle(0)).to_json(),
+    };
+    doc["rate_limit"] = match RATE_LIMIT.load(Ordering::SeqCst) {
+        0 => Value::Null,
+        limit => json!(limit),
+    };
+    if transfers.len() > 1 {
+        doc["transfers"] = Value::Array(transfers.iter().map(Transfer::to_json).collect());
+    }
+    doc
+}
+
+/// Answer one query, given either as JSON (`{"command": "set-rate",
+/// "rate": 1000}`) or as words (`set-rate 1000`).
+fn answer(line: &str) -> Value {
+    let (command, arg) = match serde_json::from_str::<Value>(line) {
+        Ok(query) => (
+            query["command"].as_str().unwrap_or("").to_string(),
+            query["rate"].as_u64().map(|rate| rate.to_string()),
+        ),
+        Err(_) => {
+            let mut words = line.split_whitespace();
+            (
+                words.next().unwrap_or("").to_string(),
+                words.next().map(String::from),
+            )
+        }
+    };
+    match command.as_str() {
+        "status" => status(),
+        "cancel" => {
+            cancel();
+            json!({ "ok": true })
+        }
+        "set-rate" => match arg.as_ref().and_then(|rate| rate.parse::<u64>().ok()) {
+            Some(rate) => {
+                set_rate_limit(rate);
+                json!({ "ok": true, "rate_limit": if rate == 0 { None } else { Some(rate) } })
+            }
+            None => json!({ "error": "set-rate needs a rate in bytes per second (0 for unlimited)" }),
+        },
+        _ => json!({ "error": format!("unknown command: {}", command) }),
+    }
+}
+
+fn serve_client(stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(writer, "{}", answer(line.trim()))?;
+    }
+    Ok(())
+}
+
+/// Listen on a Unix socket at `path`, readable and writable only by this
+/// user, and answer queries from one client at a time on a helper thread.
+pub fn listen(path: &Path) -> io::Result<()> {
+    // no other thread is running yet to be surprised by the umask
+    let old = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(old) };
+    let listener = listener?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let _ = serve_client(stream);
+        }
+    });
+    Ok(())
+}
+
+pub fn remove(path: &Path) {
+    let _ = fs::remove_file(path);
+}
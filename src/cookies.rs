@@ -0,0 +1,241 @@
+use std::cmp::Reverse;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use reqwest::header::{HeaderMap, HeaderValue, SET_COOKIE};
+use reqwest::Url;
+
+use fsutil;
+
+/// Cookies read from and written to a file in the Netscape format curl and
+/// wget use, and those servers set during the run.
+#[derive(Default)]
+pub struct Jar {
+    cookies: Mutex<Vec<Cookie>>,
+}
+
+struct Cookie {
+    name: String,
+    value: String,
+    /// Lowercase, without a leading dot.
+    domain: String,
+    /// Sent to `domain` only, not to its subdomains.
+    host_only: bool,
+    path: String,
+    secure: bool,
+    http_only: bool,
+    /// Seconds since the epoch; `None` for a session cookie.
+    expires: Option<i64>,
+}
+
+/// Marks an HttpOnly cookie in the Netscape format.
+const HTTP_ONLY_PREFIX: &str = "#HttpOnly_";
+
+impl Cookie {
+    fn expired(&self, now: i64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    fn matches(&self, url: &Url, now: i64) -> bool {
+        let host = url.host_str().unwrap_or("").to_ascii_lowercase();
+        let domain = if self.host_only { host == self.domain } else { domain_match(&host, &self.domain) };
+        domain && path_match(url.path(), &self.path) && (!self.secure || url.scheme() == "https") && !self.expired(now)
+    }
+
+    fn same(&self, other: &Cookie) -> bool {
+        self.name == other.name && self.domain == other.domain && self.path == other.path
+    }
+}
+
+/// Whether `host` is `domain` or one of its subdomains. Addresses only
+/// match themselves.
+fn domain_match(host: &str, domain: &str) -> bool {
+    let subdomain = host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.');
+    host == domain || (subdomain && host.parse::<IpAddr>().is_err())
+}
+
+/// Whether a request for `path` falls under a cookie's `prefix`.
+fn path_match(path: &str, prefix: &str) -> bool {
+    path == prefix
+        || (path.starts_with(prefix) && (prefix.ends_with('/') || path[prefix.len()..].starts_with('/')))
+}
+
+/// The path a cookie without a `Path` attribute applies to: the request
+/// path up to its last `/`.
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(end) if end > 0 => url.path()[..end].to_string(),
+        _ => "/".to_string(),
+    }
+}
+
+/// An `Expires` date, in the format HTTP uses or the older one with
+/// dashes that servers still send.
+fn parse_expires(value: &str) -> Option<i64> {
+    if let Ok(date) = DateTime::parse_from_rfc2822(value) {
+        return Some(date.timestamp());
+    }
+    ["%a, %d-%b-%Y %H:%M:%S GMT", "%a, %d-%b-%y %H:%M:%S GMT", "%A, %d-%b-%y %H:%M:%S GMT"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+        .map(|date| date.and_utc().timestamp())
+}
+
+/// Parse a `Set-Cookie` value sent in answer to a request for `url`.
+/// Cookies for another site, and secure ones sent over plain HTTP, are
+/// refused.
+fn parse_set_cookie(url: &Url, value: &str, now: i64) -> Option<Cookie> {
+    let host = url.host_str()?.to_ascii_lowercase();
+    let mut parts = value.split(';');
+    let pair = parts.next()?;
+    let eq = pair.find('=')?;
+    let name = pair[..eq].trim();
+    if name.is_empty() {
+        return None;
+    }
+    let mut cookie = Cookie {
+        name: name.to_string(),
+        value: pair[eq + 1..].trim().to_string(),
+        domain: host.clone(),
+        host_only: true,
+        path: default_path(url),
+        secure: false,
+        http_only: false,
+        expires: None,
+    };
+    let mut max_age = None;
+    for attribute in parts {
+        let (key, value) = match attribute.find('=') {
+            Some(eq) => (attribute[..eq].trim(), attribute[eq + 1..].trim()),
+            None => (attribute.trim(), ""),
+        };
+        match key.to_ascii_lowercase().as_str() {
+            "domain" if !value.is_empty() => {
+                let domain = value.trim_start_matches('.').to_ascii_lowercase();
+                // a bare top-level domain would send the cookie everywhere
+                if !domain_match(&host, &domain) || !domain.contains('.') {
+                    return None;
+                }
+                cookie.host_only = false;
+                cookie.domain = domain;
+            }
+            "path" if value.starts_with('/') => cookie.path = value.to_string(),
+            "secure" => cookie.secure = true,
+            "httponly" => cookie.http_only = true,
+            "max-age" => max_age = value.parse::<i64>().ok(),
+            "expires" => cookie.expires = parse_expires(value),
+            _ => {}
+        }
+    }
+    if let Some(seconds) = max_age {
+        cookie.expires = Some(now.saturating_add(seconds.max(0)));
+    }
+    if cookie.secure && url.scheme() != "https" {
+        return None;
+    }
+    Some(cookie)
+}
+
+fn flag(value: bool) -> &'static str {
+    if value {
+        "TRUE"
+    } else {
+        "FALSE"
+    }
+}
+
+impl Jar {
+    /// Read a cookie file in the Netscape format.
+    pub fn load(path: &Path) -> Result<Jar, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut cookies = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let (line, http_only) = match line.strip_prefix(HTTP_ONLY_PREFIX) {
+                Some(rest) => (rest, true),
+                None => (line, false),
+            };
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 7 {
+                return Err(format!("line {}: expected 7 tab-separated fields", idx + 1));
+            }
+            let expires = fields[4]
+                .parse::<i64>()
+                .map_err(|_| format!("line {}: {} is not an expiry time", idx + 1, fields[4]))?;
+            cookies.push(Cookie {
+                name: fields[5].to_string(),
+                value: fields[6].to_string(),
+                domain: fields[0].trim_start_matches('.').to_ascii_lowercase(),
+                host_only: !fields[1].eq_ignore_ascii_case("TRUE"),
+                path: fields[2].to_string(),
+                secure: fields[3].eq_ignore_ascii_case("TRUE"),
+                http_only,
+                expires: if expires == 0 { None } else { Some(expires) },
+            });
+        }
+        Ok(Jar {
+            cookies: Mutex::new(cookies),
+        })
+    }
+
+    /// Write every cookie that has not expired, session cookies included,
+    /// so that a later run can carry on the session.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let now = Utc::now().timestamp();
+        let mut text = String::from("# Netscape HTTP Cookie File\n\n");
+        for cookie in self.cookies.lock().unwrap().iter().filter(|cookie| !cookie.expired(now)) {
+            text.push_str(&format!(
+                "{}{}{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+                if cookie.http_only { HTTP_ONLY_PREFIX } else { "" },
+                if cookie.host_only { "" } else { "." },
+                cookie.domain,
+                flag(!cookie.host_only),
+                cookie.path,
+                flag(cookie.secure),
+                cookie.expires.unwrap_or(0),
+                cookie.name,
+                cookie.value
+            ));
+        }
+        fsutil::write_atomic(path, text.as_bytes())
+    }
+
+    /// The `Cookie` value for a request to `url`, more specific paths
+    /// first.
+    pub fn header(&self, url: &Url) -> Option<HeaderValue> {
+        let now = Utc::now().timestamp();
+        let cookies = self.cookies.lock().unwrap();
+        let mut matching: Vec<&Cookie> = cookies.iter().filter(|cookie| cookie.matches(url, now)).collect();
+        if matching.is_empty() {
+            return None;
+        }
+        matching.sort_by_key(|cookie| Reverse(cookie.path.len()));
+        let pairs: Vec<String> = matching.iter().map(|cookie| format!("{}={}", cookie.name, cookie.value)).collect();
+        let mut value = HeaderValue::from_str(&pairs.join("; ")).ok()?;
+        value.set_sensitive(true);
+        Some(value)
+    }
+
+    /// Keep the cookies set by a response from `url`. A cookie that is
+    /// already expired removes the one it replaces.
+    pub fn store(&self, url: &Url, headers: &HeaderMap) {
+        let now = Utc::now().timestamp();
+        let mut cookies = self.cookies.lock().unwrap();
+        for value in headers.get_all(SET_COOKIE) {
+            let cookie = match value.to_str().ok().and_then(|value| parse_set_cookie(url, value, now)) {
+                Some(cookie) => cookie,
+                None => continue,
+            };
+            cookies.retain(|known| !known.same(&cookie));
+            if !cookie.expired(now) {
+                cookies.push(cookie);
+            }
+        }
+    }
+}
@@ -0,0 +1,59 @@
+/// The CRC-32 used by zip and gzip (reflected polynomial `0xEDB88320`).
+pub struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 == 1 { 0xedb8_8320 ^ (c >> 1) } else { c >> 1 };
+            }
+            *entry = c;
+        }
+        Crc32 {
+            table,
+            value: 0xffff_ffff,
+        }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        let mut c = self.value;
+        for &byte in buf {
+            c = self.table[((c ^ u32::from(byte)) & 0xff) as usize] ^ (c >> 8);
+        }
+        self.value = c;
+    }
+
+    pub fn finish(&self) -> u32 {
+        self.value ^ 0xffff_ffff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Crc32;
+
+    fn crc(parts: &[&[u8]]) -> u32 {
+        let mut crc = Crc32::new();
+        for part in parts {
+            crc.update(part);
+        }
+        crc.finish()
+    }
+
+    #[test]
+    fn check_values() {
+        assert_eq!(crc(&[]), 0);
+        assert_eq!(crc(&[b"123456789"]), 0xcbf4_3926);
+        assert_eq!(crc(&[b"The quick brown fox jumps over the lazy dog"]), 0x414f_a339);
+    }
+
+    #[test]
+    fn updates_add_up() {
+        assert_eq!(crc(&[b"1234", b"", b"56789"]), 0xcbf4_3926);
+    }
+}
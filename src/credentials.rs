@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use data_encoding::BASE64;
+use percent_encoding::percent_decode_str;
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+
+use messages;
+
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// A username and password taken from a URL's `user:password@` userinfo.
+pub struct Credentials {
+    pub user: String,
+    pub password: Option<String>,
+}
+
+impl Credentials {
+    /// The `Authorization` value for HTTP Basic auth with these credentials.
+    pub fn basic_auth(&self) -> HeaderValue {
+        let pair = format!("{}:{}", self.user, self.password.as_deref().unwrap_or(""));
+        let mut value = HeaderValue::from_str(&format!("Basic {}", BASE64.encode(pair.as_bytes())))
+            .expect("base64 is a valid header value");
+        value.set_sensitive(true);
+        value
+    }
+}
+
+/// Credentials given as `user:password`, as `--user` takes them. The
+/// password runs to the end, so it may contain colons; without any the
+/// password is empty.
+pub fn parse(pair: &str) -> Credentials {
+    let mut parts = pair.splitn(2, ':');
+    Credentials {
+        user: parts.next().unwrap_or("").to_string(),
+        password: parts.next().map(str::to_string),
+    }
+}
+
+/// The `Authorization` value for a bearer token.
+pub fn bearer(token: &str) -> Result<HeaderValue, String> {
+    let mut value = HeaderValue::from_str(&format!("Bearer {}", token.trim()))
+        .map_err(|_| "not a valid token".to_string())?;
+    value.set_sensitive(true);
+    Ok(value)
+}
+
+/// Remove the userinfo from `url`, returning the decoded credentials if
+/// there were any.
+pub fn take(url: &mut Url) -> Option<Credentials> {
+    if url.username().is_empty() && url.password().is_none() {
+        return None;
+    }
+    let decode = |value: &str| percent_decode_str(value).decode_utf8_lossy().into_owned();
+    let credentials = Credentials {
+        user: decode(url.username()),
+        password: url.password().map(decode),
+    };
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    Some(credentials)
+}
+
+/// Whether `url` carries a username or password.
+pub fn present(url: &Url) -> bool {
+    !url.username().is_empty() || url.password().is_some()
+}
+
+/// `url` with any userinfo removed, for display. Strings that do not parse
+/// as a URL are returned as they are.
+pub fn redact(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) if present(&parsed) => {
+            take(&mut parsed);
+            parsed.into()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Print the advice against credentials in URLs, once per run.
+pub fn warn_once(url: &Url) {
+    if WARNED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    messages::warn("warning.url-credentials", &[url]);
+}
@@ -0,0 +1,779 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use libc;
+use reqwest;
+use rustls;
+
+use bar;
+use checksum::Checksum;
+use clock;
+use control;
+use messages;
+use messages::{Lang, Message};
+use pinning::PinMismatch;
+use tcpinfo;
+
+pub const EXIT_URL_FAILURE: i32 = 1;
+pub const EXIT_OUTPUT_FAILURE: i32 = 2;
+pub const EXIT_CONNECT_FAILURE: i32 = 3;
+pub const EXIT_CHECKSUM_FAILURE: i32 = 4;
+pub const EXIT_UNSAFE_OUTPUT: i32 = 5;
+/// Batch runs that went on past failures: some entries failed, or all did.
+pub const EXIT_SOME_FAILED: i32 = 6;
+pub const EXIT_ALL_FAILED: i32 = 7;
+/// A batch that `--fail-fast` stopped before every entry was attempted.
+pub const EXIT_STOPPED_EARLY: i32 = 8;
+/// A server's key matched none of the `--pin-sha256` pins.
+pub const EXIT_PIN_MISMATCH: i32 = 9;
+/// A 4xx or 5xx answer that failed the transfer, as with `--fail`.
+pub const EXIT_HTTP_CLIENT_ERROR: i32 = 10;
+pub const EXIT_HTTP_SERVER_ERROR: i32 = 11;
+pub const EXIT_CANCELLED: i32 = 130;
+
+/// Whether requests may go through a proxy, from `--proxy`, `--socks5` or
+/// the environment. A connect failure is then one to the proxy, which the
+/// HTTP client's error does not name.
+static PROXIED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_proxied() {
+    PROXIED.store(true, Ordering::Relaxed);
+}
+
+/// The failure classes reported as `error.kind` in machine-readable output.
+/// These names are part of the documented interface: new kinds may be added
+/// but existing ones are never renamed or repurposed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Dns,
+    Connect,
+    Tls,
+    HttpStatus,
+    Timeout,
+    Io,
+    Checksum,
+    Input,
+    Security,
+    Cancelled,
+    ReadOnlyFs,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Dns => "dns",
+            Kind::Connect => "connect",
+            Kind::Tls => "tls",
+            Kind::HttpStatus => "http_status",
+            Kind::Timeout => "timeout",
+            Kind::Io => "io",
+            Kind::Checksum => "checksum",
+            Kind::Input => "input",
+            Kind::Security => "security",
+            Kind::Cancelled => "cancelled",
+            Kind::ReadOnlyFs => "read_only_fs",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DownloadError {
+    Dns { host: String, source: reqwest::Error },
+    NetworkUnreachable { peer: String, source: reqwest::Error },
+    HostUnreachable { peer: String, source: reqwest::Error },
+    ConnectionRefused { peer: String, source: reqwest::Error },
+    ConnectTimeout { peer: String, source: reqwest::Error },
+    Request(reqwest::Error),
+    /// The server certificate was expired or not yet valid; `skew` is how
+    /// far the server's clock is ahead of ours, when it could be asked.
+    CertificateTime { skew: Option<i64>, source: reqwest::Error },
+    /// The server's key matched none of the `--pin-sha256` pins.
+    PinMismatch { found: String, source: reqwest::Error },
+    /// A 429 or 503 whose `Retry-After` said when to come back.
+    Throttled { retry_after: Duration, source: reqwest::Error },
+    /// `tcp` holds the statistics of the connection the body came over.
+    ChecksumMismatch {
+        expected: Checksum,
+        actual: String,
+        tcp: Option<tcpinfo::Stats>,
+    },
+    Input(Message),
+    Output(io::Error),
+    /// Reading the body failed part way through.
+    BodyRead(Box<BodyRead>),
+    /// The output's filesystem turned read-only (`EROFS`), after `written`
+    /// bytes when that is known.
+    ReadOnly { written: Option<u64>, source: io::Error },
+    /// The output path would be written through a symlink or outside
+    /// `--restrict-to`.
+    UnsafeOutput(Message),
+    /// Cancellation was requested before the job finished.
+    Cancelled,
+}
+
+/// Where in the body a read failed, so that a failure deep into a large
+/// transfer reads differently from one at the first byte.
+#[derive(Debug)]
+pub struct BodyRead {
+    /// Bytes delivered before the failure.
+    pub received: u64,
+    /// Size of the last successful read.
+    pub last_read: usize,
+    pub elapsed: Duration,
+    pub source: io::Error,
+}
+
+impl BodyRead {
+    /// Wrap a read error with where it happened, keeping its kind.
+    pub fn wrap(source: io::Error, received: u64, last_read: usize, elapsed: Duration) -> io::Error {
+        if source.get_ref().is_some_and(|inner| inner.is::<control::Cancelled>()) {
+            return source;
+        }
+        let kind = source.kind();
+        let context = BodyRead {
+            received,
+            last_read,
+            elapsed,
+            source,
+        };
+        io::Error::new(kind, context)
+    }
+}
+
+impl fmt::Display for BodyRead {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", DownloadError::body_read_message(self, Lang::En))
+    }
+}
+
+impl Error for BodyRead {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.source()
+    }
+}
+
+/// A write that failed once `written` bytes of the body were already out,
+/// carried inside an `io::Error` until it becomes a `DownloadError`.
+#[derive(Debug)]
+pub struct WriteFailed {
+    pub written: u64,
+    pub source: io::Error,
+}
+
+impl WriteFailed {
+    /// Wrap a write error with how far the output got, when that is what
+    /// the error class reports: only for a read-only filesystem today.
+    pub fn wrap(source: io::Error, written: u64) -> io::Error {
+        if source.raw_os_error() != Some(libc::EROFS) {
+            return source;
+        }
+        let kind = source.kind();
+        io::Error::new(kind, WriteFailed { written, source })
+    }
+}
+
+impl fmt::Display for WriteFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} after {} bytes", self.source, self.written)
+    }
+}
+
+impl Error for WriteFailed {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+impl DownloadError {
+    /// An input error reading the catalog message `id`.
+    pub fn input(id: &'static str, args: &[&dyn fmt::Display]) -> DownloadError {
+        DownloadError::Input(Message::new(id, args))
+    }
+
+    /// Classify a reqwest error by walking its source chain for the
+    /// underlying connect errno. `url` is used to name the peer when the
+    /// connection was made to its host.
+    pub fn from_reqwest(err: reqwest::Error, url: &str) -> DownloadError {
+        if let Some(found) = pin_mismatch(&err).map(|mismatch| mismatch.found.clone()) {
+            return DownloadError::PinMismatch { found, source: err };
+        }
+        if certificate_time_error(&err) {
+            let skew = clock::probe(err.url().map(|u| u.as_str()).unwrap_or(url));
+            return DownloadError::CertificateTime { skew, source: err };
+        }
+        if !err.is_connect() {
+            return DownloadError::Request(err);
+        }
+
+        let url = err.url().map(|u| u.as_str()).unwrap_or(url);
+        let target = dialed(url, PROXIED.load(Ordering::Relaxed));
+        if dns_failure(&err) {
+            let host = target.map_or("unknown".to_string(), |(host, _)| host.trim_matches(['[', ']']).to_string());
+            return DownloadError::Dns { host, source: err };
+        }
+        let peer = target.map_or("unknown".to_string(), |(host, port)| format!("{}:{}", host, port));
+        let kind = io_error_kind(&err);
+        match kind {
+            Some(io::ErrorKind::NetworkUnreachable) => {
+                DownloadError::NetworkUnreachable { peer, source: err }
+            }
+            Some(io::ErrorKind::HostUnreachable) => {
+                DownloadError::HostUnreachable { peer, source: err }
+            }
+            Some(io::ErrorKind::ConnectionRefused) => {
+                DownloadError::ConnectionRefused { peer, source: err }
+            }
+            Some(io::ErrorKind::TimedOut) => DownloadError::ConnectTimeout { peer, source: err },
+            _ if err.is_timeout() => DownloadError::ConnectTimeout { peer, source: err },
+            _ => DownloadError::Request(err),
+        }
+    }
+
+    /// The class of failure, used in machine-readable output.
+    pub fn kind(&self) -> Kind {
+        match *self {
+            DownloadError::Dns { .. } => Kind::Dns,
+            DownloadError::NetworkUnreachable { .. }
+            | DownloadError::HostUnreachable { .. }
+            | DownloadError::ConnectionRefused { .. } => Kind::Connect,
+            DownloadError::ConnectTimeout { .. } => Kind::Timeout,
+            DownloadError::CertificateTime { .. } | DownloadError::PinMismatch { .. } => Kind::Tls,
+            DownloadError::Request(ref e) => {
+                if tls_error(e).is_some() {
+                    Kind::Tls
+                } else if e.is_timeout() {
+                    Kind::Timeout
+                } else if e.is_status() || e.is_redirect() {
+                    Kind::HttpStatus
+                } else if e.is_connect() {
+                    Kind::Connect
+                } else if e.is_builder() {
+                    Kind::Input
+                } else {
+                    Kind::Io
+                }
+            }
+            DownloadError::Throttled { .. } => Kind::HttpStatus,
+            DownloadError::ChecksumMismatch { .. } => Kind::Checksum,
+            DownloadError::Input(_) => Kind::Input,
+            DownloadError::Output(_) | DownloadError::BodyRead(_) => Kind::Io,
+            DownloadError::ReadOnly { .. } => Kind::ReadOnlyFs,
+            DownloadError::UnsafeOutput(_) => Kind::Security,
+            DownloadError::Cancelled => Kind::Cancelled,
+        }
+    }
+
+    /// The HTTP status behind an `http_status` failure.
+    pub fn status_code(&self) -> Option<u16> {
+        match *self {
+            DownloadError::Request(ref e) | DownloadError::Throttled { source: ref e, .. } => {
+                e.status().map(|status| status.as_u16())
+            }
+            _ => None,
+        }
+    }
+
+    /// Body bytes received before a read failed part way through; a
+    /// transfer that got this far can be resumed from here.
+    pub fn received_bytes(&self) -> Option<u64> {
+        match *self {
+            DownloadError::BodyRead(ref body) => Some(body.received),
+            DownloadError::ReadOnly { written, .. } => written,
+            _ => None,
+        }
+    }
+
+    /// The TCP statistics of a body that failed verification, to tell a
+    /// lossy network path from a bad file.
+    pub fn tcp_stats(&self) -> Option<tcpinfo::Stats> {
+        match *self {
+            DownloadError::ChecksumMismatch { tcp, .. } => tcp,
+            _ => None,
+        }
+    }
+
+    /// Whether trying again may succeed: the network or the server failed,
+    /// not the request itself, the output or verification.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            DownloadError::Dns { .. }
+            | DownloadError::NetworkUnreachable { .. }
+            | DownloadError::HostUnreachable { .. }
+            | DownloadError::ConnectionRefused { .. }
+            | DownloadError::ConnectTimeout { .. }
+            | DownloadError::Throttled { .. }
+            | DownloadError::BodyRead(_) => true,
+            DownloadError::Request(ref e) => match e.status() {
+                Some(status) => matches!(status.as_u16(), 408 | 429 | 500 | 502 | 503 | 504),
+                None => tls_error(e).is_none() && !e.is_builder() && !e.is_redirect(),
+            },
+            _ => false,
+        }
+    }
+
+    /// How long the server asked to wait before trying again.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match *self {
+            DownloadError::Throttled { retry_after, .. } => Some(retry_after),
+            _ => None,
+        }
+    }
+
+    /// Seconds the server's clock is ahead of ours, when that is far enough
+    /// off to explain the failure.
+    pub fn clock_skew(&self) -> Option<i64> {
+        match *self {
+            DownloadError::CertificateTime { skew, .. } => skew.filter(|skew| clock::significant(*skew)),
+            _ => None,
+        }
+    }
+
+    /// The symbolic errno, e.g. `ECONNREFUSED`, when the failure carries one.
+    pub fn errno_name(&self) -> Option<&'static str> {
+        let code = match *self {
+            DownloadError::Output(ref e) | DownloadError::ReadOnly { source: ref e, .. } => e.raw_os_error(),
+            DownloadError::BodyRead(ref body) => body
+                .source
+                .raw_os_error()
+                .or_else(|| body.source.source().and_then(find_io_error).and_then(io::Error::raw_os_error)),
+            DownloadError::Input(_)
+            | DownloadError::ChecksumMismatch { .. }
+            | DownloadError::UnsafeOutput(_)
+            | DownloadError::Cancelled => None,
+            _ => self.source().and_then(find_io_error).and_then(io::Error::raw_os_error),
+        };
+        code.and_then(errno_name)
+    }
+
+    /// The alert received from the peer when a TLS handshake was rejected.
+    pub fn tls_alert(&self) -> Option<String> {
+        let err = match *self {
+            DownloadError::Request(ref e) => tls_error(e)?,
+            _ => return None,
+        };
+        match *err {
+            rustls::Error::AlertReceived(alert) => Some(format!("{:?}", alert)),
+            _ => None,
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            DownloadError::NetworkUnreachable { .. }
+            | DownloadError::HostUnreachable { .. }
+            | DownloadError::ConnectionRefused { .. }
+            | DownloadError::ConnectTimeout { .. }
+            | DownloadError::Dns { .. } => EXIT_CONNECT_FAILURE,
+            DownloadError::Request(ref e) | DownloadError::Throttled { source: ref e, .. } => match e.status() {
+                Some(status) if status.is_client_error() => EXIT_HTTP_CLIENT_ERROR,
+                Some(status) if status.is_server_error() => EXIT_HTTP_SERVER_ERROR,
+                _ => EXIT_URL_FAILURE,
+            },
+            DownloadError::CertificateTime { .. } | DownloadError::Input(_) => EXIT_URL_FAILURE,
+            DownloadError::ChecksumMismatch { .. } => EXIT_CHECKSUM_FAILURE,
+            DownloadError::Output(_) | DownloadError::BodyRead(_) | DownloadError::ReadOnly { .. } => {
+                EXIT_OUTPUT_FAILURE
+            }
+            DownloadError::UnsafeOutput(_) => EXIT_UNSAFE_OUTPUT,
+            DownloadError::PinMismatch { .. } => EXIT_PIN_MISMATCH,
+            DownloadError::Cancelled => EXIT_CANCELLED,
+        }
+    }
+
+    /// Write the localized message followed by every error in the source
+    /// chain.
+    pub fn write_chain(&self, writer: &mut dyn io::Write) {
+        let _ = writeln!(writer, "{}", self.localized());
+        let caused_by = messages::render("caused-by", &[]);
+        let mut source = self.source();
+        while let Some(err) = source {
+            let _ = writeln!(writer, "  {}: {}", caused_by, err);
+            source = err.source();
+        }
+    }
+
+    /// The message in the language selected with `--lang` or the locale.
+    /// `Display` always gives the English one, which is what the result file
+    /// records.
+    pub fn localized(&self) -> String {
+        self.message(messages::lang())
+    }
+
+    fn body_read_message(body: &BodyRead, lang: Lang) -> String {
+        let received = bar::format_bytes(body.received as f64);
+        let elapsed = body.elapsed.as_secs();
+        let context = messages::render_in(lang, "error.body-read", &[&received, &body.last_read, &elapsed]);
+        format!("{}: {}", context, body.source)
+    }
+
+    fn message(&self, lang: Lang) -> String {
+        match *self {
+            DownloadError::Dns { ref host, .. } => messages::render_in(lang, "error.dns", &[host]),
+            DownloadError::NetworkUnreachable { ref peer, .. } => {
+                messages::render_in(lang, "error.network-unreachable", &[peer])
+            }
+            DownloadError::HostUnreachable { ref peer, .. } => {
+                messages::render_in(lang, "error.host-unreachable", &[peer])
+            }
+            DownloadError::ConnectionRefused { ref peer, .. } => {
+                messages::render_in(lang, "error.connection-refused", &[peer])
+            }
+            DownloadError::ConnectTimeout { ref peer, .. } => {
+                messages::render_in(lang, "error.connect-timeout", &[peer])
+            }
+            DownloadError::Request(ref e) => e.to_string(),
+            DownloadError::CertificateTime { ref source, .. } => match self.clock_skew() {
+                Some(skew) => format!("{}; {}", source, clock::hint_in(lang, skew)),
+                None => source.to_string(),
+            },
+            DownloadError::Throttled { retry_after, ref source } => {
+                messages::render_in(lang, "error.retry-after", &[source, &retry_after.as_secs()])
+            }
+            DownloadError::PinMismatch { ref found, ref source } => {
+                let host = source.url().and_then(|url| url.host_str()).unwrap_or("");
+                messages::render_in(lang, "error.pin-mismatch", &[&host, found])
+            }
+            DownloadError::ChecksumMismatch { ref expected, ref actual, .. } => messages::render_in(
+                lang,
+                "error.checksum-mismatch",
+                &[expected, &expected.algorithm, actual],
+            ),
+            DownloadError::Input(ref msg) => msg.render_in(lang),
+            DownloadError::Output(ref e) => e.to_string(),
+            DownloadError::BodyRead(ref body) => DownloadError::body_read_message(body, lang),
+            DownloadError::ReadOnly { written: Some(written), .. } => messages::render_in(
+                lang,
+                "error.read-only-after",
+                &[&bar::format_bytes(written as f64)],
+            ),
+            DownloadError::ReadOnly { written: None, .. } => messages::render_in(lang, "error.read-only", &[]),
+            DownloadError::UnsafeOutput(ref msg) => {
+                messages::render_in(lang, "error.unsafe-output", &[&msg.render_in(lang)])
+            }
+            DownloadError::Cancelled => messages::render_in(lang, "error.cancelled", &[]),
+        }
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message(Lang::En))
+    }
+}
+
+impl Error for DownloadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            DownloadError::Dns { ref source, .. }
+            | DownloadError::NetworkUnreachable { ref source, .. }
+            | DownloadError::HostUnreachable { ref source, .. }
+            | DownloadError::ConnectionRefused { ref source, .. }
+            | DownloadError::ConnectTimeout { ref source, .. } => Some(source),
+            DownloadError::Request(ref e)
+            | DownloadError::CertificateTime { source: ref e, .. }
+            | DownloadError::PinMismatch { source: ref e, .. }
+            | DownloadError::Throttled { source: ref e, .. } => e.source(),
+            DownloadError::ChecksumMismatch { .. }
+            | DownloadError::Input(_)
+            | DownloadError::UnsafeOutput(_)
+            | DownloadError::Cancelled => None,
+            DownloadError::Output(ref e) => e.source(),
+            DownloadError::BodyRead(ref body) => body.source.source(),
+            DownloadError::ReadOnly { ref source, .. } => Some(source),
+        }
+    }
+}
+
+impl From<io::Error> for DownloadError {
+    fn from(err: io::Error) -> DownloadError {
+        match err.get_ref() {
+            Some(inner) if inner.is::<control::Cancelled>() => DownloadError::Cancelled,
+            Some(inner) if inner.is::<BodyRead>() => {
+                DownloadError::BodyRead(err.into_inner().unwrap().downcast::<BodyRead>().unwrap())
+            }
+            Some(inner) if inner.is::<WriteFailed>() => {
+                let failed = err.into_inner().unwrap().downcast::<WriteFailed>().unwrap();
+                DownloadError::ReadOnly {
+                    written: Some(failed.written),
+                    source: failed.source,
+                }
+            }
+            _ if err.raw_os_error() == Some(libc::EROFS) => DownloadError::ReadOnly {
+                written: None,
+                source: err,
+            },
+            _ => DownloadError::Output(err),
+        }
+    }
+}
+
+fn io_error_kind(err: &reqwest::Error) -> Option<io::ErrorKind> {
+    err.source().and_then(find_io_error).map(io::Error::kind)
+}
+
+fn find_io_error<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a io::Error> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(io_err) = err.downcast_ref::<io::Error>() {
+            return Some(io_err);
+        }
+        source = err.source();
+    }
+    None
+}
+
+/// The rustls error behind a failed handshake. rustls errors reach us
+/// wrapped in (possibly nested) `io::Error`s, whose `source()` skips the
+/// wrapped value, so step inside each `io::Error` along the way.
+fn tls_error(err: &reqwest::Error) -> Option<&rustls::Error> {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if let Some(tls_err) = err.downcast_ref::<rustls::Error>() {
+            return Some(tls_err);
+        }
+        source = match err.downcast_ref::<io::Error>().and_then(io::Error::get_ref) {
+            Some(inner) => Some(inner),
+            None => err.source(),
+        };
+    }
+    None
+}
+
+/// The pin check that failed the handshake, if that is what did.
+fn pin_mismatch(err: &reqwest::Error) -> Option<&PinMismatch> {
+    match tls_error(err) {
+        Some(rustls::Error::InvalidCertificate(rustls::CertificateError::Other(ref other))) => {
+            other.downcast_ref::<PinMismatch>()
+        }
+        _ => None,
+    }
+}
+
+/// Whether the handshake failed because the certificate was outside its
+/// validity period, which a wrong local clock also produces.
+fn certificate_time_error(err: &reqwest::Error) -> bool {
+    matches!(
+        tls_error(err),
+        Some(rustls::Error::InvalidCertificate(
+            rustls::CertificateError::Expired | rustls::CertificateError::NotValidYet
+        ))
+    )
+}
+
+fn errno_name(code: i32) -> Option<&'static str> {
+    Some(match code {
+        libc::EPERM => "EPERM",
+        libc::ENOENT => "ENOENT",
+        libc::EIO => "EIO",
+        libc::EACCES => "EACCES",
+        libc::EEXIST => "EEXIST",
+        libc::ENOTDIR => "ENOTDIR",
+        libc::EISDIR => "EISDIR",
+        libc::EFBIG => "EFBIG",
+        libc::ENOSPC => "ENOSPC",
+        libc::EROFS => "EROFS",
+        libc::EPIPE => "EPIPE",
+        libc::EDQUOT => "EDQUOT",
+        libc::ECONNRESET => "ECONNRESET",
+        libc::ECONNREFUSED => "ECONNREFUSED",
+        libc::ECONNABORTED => "ECONNABORTED",
+        libc::ENETUNREACH => "ENETUNREACH",
+        libc::EHOSTUNREACH => "EHOSTUNREACH",
+        libc::ETIMEDOUT => "ETIMEDOUT",
+        libc::EADDRNOTAVAIL => "EADDRNOTAVAIL",
+        _ => return None,
+    })
+}
+
+/// Whether the connector failed to resolve the host it was to connect to,
+/// as opposed to reaching it.
+fn dns_failure(err: &reqwest::Error) -> bool {
+    let mut source = err.source();
+    while let Some(err) = source {
+        if err.to_string().starts_with("dns error") {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+/// The host and port the connector dialed for `url`, as the URL names
+/// them: the client does not say which of a name's addresses it tried, so
+/// none is guessed. Behind a proxy the connection was to the proxy
+/// instead, and the peer is not known.
+fn dialed(url: &str, proxied: bool) -> Option<(String, u16)> {
+    if proxied {
+        return None;
+    }
+    let parsed = reqwest::Url::parse(url).ok()?;
+    Some((parsed.host_str()?.to_string(), parsed.port_or_known_default()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    use libc;
+    use reqwest;
+
+    use super::{dialed, DownloadError, Kind};
+    use checksum::{Algorithm, Checksum};
+    use control;
+    use messages::{Lang, Message};
+
+    /// Answer one connection on a local port with `response` once the
+    /// request has arrived, or keep it waiting with no answer when `None`.
+    fn serve(response: Option<&'static [u8]>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request);
+            match response {
+                Some(response) => {
+                    let _ = stream.write_all(response);
+                }
+                None => thread::sleep(Duration::from_secs(5)),
+            }
+        });
+        port
+    }
+
+    /// A port nothing listens on.
+    fn closed_port() -> u16 {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+    }
+
+    fn fetch(url: &str) -> DownloadError {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_millis(300))
+            .build()
+            .unwrap();
+        let err = client
+            .get(url)
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .unwrap_err();
+        DownloadError::from_reqwest(err, url)
+    }
+
+    #[test]
+    fn input_errors_are_translated_but_display_in_english() {
+        let err = DownloadError::input("error.query-pair", &[&"a"]);
+        assert_eq!(err.to_string(), "--query a: expected key=value");
+        assert_eq!(err.message(Lang::De), "--query a: key=value erwartet");
+
+        let entry = Message::new("unit.line", &[&3]);
+        let err = DownloadError::Input(Message::Catalog("error.entry-header", vec![entry]));
+        assert_eq!(err.to_string(), "line 3: invalid header");
+        assert_eq!(err.message(Lang::De), "Zeile 3: ungültiger Header");
+
+        let err = DownloadError::Input("x: not found".to_string().into());
+        assert_eq!(err.message(Lang::De), "x: not found");
+    }
+
+    #[test]
+    fn kind_names_are_stable() {
+        let kinds = [
+            (Kind::Dns, "dns"),
+            (Kind::Connect, "connect"),
+            (Kind::Tls, "tls"),
+            (Kind::HttpStatus, "http_status"),
+            (Kind::Timeout, "timeout"),
+            (Kind::Io, "io"),
+            (Kind::Checksum, "checksum"),
+            (Kind::Input, "input"),
+            (Kind::Security, "security"),
+            (Kind::Cancelled, "cancelled"),
+            (Kind::ReadOnlyFs, "read_only_fs"),
+        ];
+        for &(kind, name) in &kinds {
+            assert_eq!(kind.name(), name);
+        }
+    }
+
+    #[test]
+    fn unresolvable_host_is_dns() {
+        let err = fetch("http://no-such-host.invalid/");
+        assert_eq!(err.kind(), Kind::Dns);
+        assert_eq!(err.to_string(), "could not resolve host no-such-host.invalid");
+    }
+
+    #[test]
+    fn refused_connection_is_connect_and_names_the_dialed_peer() {
+        let port = closed_port();
+        let err = fetch(&format!("http://127.0.0.1:{}/", port));
+        assert_eq!(err.kind(), Kind::Connect);
+        assert_eq!(err.errno_name(), Some("ECONNREFUSED"));
+        assert_eq!(err.to_string(), format!("connection refused by 127.0.0.1:{}", port));
+    }
+
+    #[test]
+    fn peer_behind_a_proxy_is_unknown() {
+        assert_eq!(dialed("http://localhost/x", false), Some(("localhost".to_string(), 80)));
+        assert_eq!(dialed("https://[::1]:8443/", false), Some(("[::1]".to_string(), 8443)));
+        assert_eq!(dialed("http://localhost/x", true), None);
+    }
+
+    #[test]
+    fn unanswered_request_is_timeout() {
+        let port = serve(None);
+        let err = fetch(&format!("http://127.0.0.1:{}/", port));
+        assert_eq!(err.kind(), Kind::Timeout);
+        assert!(err.is_transient());
+    }
+
+    #[test]
+    fn plain_http_answer_to_a_handshake_is_tls() {
+        let port = serve(Some(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n"));
+        let err = fetch(&format!("https://127.0.0.1:{}/", port));
+        assert_eq!(err.kind(), Kind::Tls);
+        assert!(!err.is_transient());
+    }
+
+    #[test]
+    fn error_status_is_http_status_with_its_code() {
+        let port = serve(Some(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n"));
+        let err = fetch(&format!("http://127.0.0.1:{}/", port));
+        assert_eq!(err.kind(), Kind::HttpStatus);
+        assert_eq!(err.status_code(), Some(404));
+        assert_eq!(err.exit_code(), super::EXIT_HTTP_CLIENT_ERROR);
+    }
+
+    #[test]
+    fn output_failures_are_io_or_read_only_fs() {
+        let err = DownloadError::from(io::Error::from_raw_os_error(libc::ENOSPC));
+        assert_eq!(err.kind(), Kind::Io);
+        assert_eq!(err.errno_name(), Some("ENOSPC"));
+        let err = DownloadError::from(io::Error::from_raw_os_error(libc::EROFS));
+        assert_eq!(err.kind(), Kind::ReadOnlyFs);
+        assert_eq!(err.errno_name(), Some("EROFS"));
+    }
+
+    #[test]
+    fn mismatch_is_checksum() {
+        let err = DownloadError::ChecksumMismatch {
+            expected: Checksum::new(Algorithm::Md5, "d41d8cd98f00b204e9800998ecf8427e").unwrap(),
+            actual: "0cc175b9c0f1b6a831c399e269772661".to_string(),
+            tcp: None,
+        };
+        assert_eq!(err.kind(), Kind::Checksum);
+        assert_eq!(err.exit_code(), super::EXIT_CHECKSUM_FAILURE);
+    }
+
+    #[test]
+    fn cancelled_read_is_cancelled() {
+        let err = DownloadError::from(io::Error::other(control::Cancelled));
+        assert_eq!(err.kind(), Kind::Cancelled);
+        assert_eq!(err.exit_code(), super::EXIT_CANCELLED);
+    }
+}
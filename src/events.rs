@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use console;
+use control;
+use progress::{Phase, Renderer};
+
+/// Progress as newline-delimited JSON on stderr, for programs driving the
+/// download: a `progress` event at most once per interval, then `done` or
+/// `abandoned`.
+pub struct Events {
+    url: Option<String>,
+    phase: Phase,
+    total: Option<u64>,
+    current: u64,
+    started: Instant,
+    interval: Duration,
+    last_event: Option<Instant>,
+}
+
+impl Events {
+    /// Events for a transfer of `total` bytes (0 when unknown) of the
+    /// current job's URL.
+    pub fn new(total: u64, phase: Phase, interval: Duration) -> Events {
+        Events {
+            url: control::current_url(),
+            phase,
+            total: if total > 0 { Some(total) } else { None },
+            current: 0,
+            started: Instant::now(),
+            interval,
+            last_event: None,
+        }
+    }
+
+    fn emit(&mut self, event: &str) {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 { Some(self.current as f64 / elapsed) } else { None };
+        let eta = match (self.total, rate) {
+            (Some(total), Some(rate)) if rate > 0.0 => Some(total.saturating_sub(self.current) as f64 / rate),
+            _ => None,
+        };
+        let doc = json!({
+            "event": event,
+            "url": self.url,
+            "phase": self.phase.name(),
+            "bytes": self.current,
+            "total": self.total,
+            "rate": rate.map(|rate| rate.round() as u64),
+            "eta": eta.map(|eta| (eta * 10.0).round() / 10.0),
+        });
+        console::event(&doc.to_string());
+        self.last_event = Some(Instant::now());
+    }
+}
+
+impl Renderer for Events {
+    fn inc(&mut self, bytes: u64) {
+        self.current += bytes;
+        if self.last_event.is_none_or(|last| last.elapsed() >= self.interval) {
+            self.emit("progress");
+        }
+    }
+
+    fn finish(&mut self) {
+        self.emit("done");
+    }
+
+    fn abandon(&mut self) {
+        self.emit("abandoned");
+    }
+}
@@ -0,0 +1,273 @@
+/// Percent-decode a URL path segment for use as a filename. Escapes that
+/// would decode to a path separator or NUL (`%2F`, `%5C`, `%00`) are kept
+/// as they are, and the segment is returned untouched when the decoded
+/// bytes are not valid UTF-8. Only one level of encoding is removed, so
+/// `%2520` becomes `%20`.
+pub fn decode(segment: &str) -> String {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escape = if bytes[i] == b'%' && i + 2 < bytes.len() {
+            hex_pair(bytes[i + 1], bytes[i + 2])
+        } else {
+            None
+        };
+        match escape {
+            Some(b'/') | Some(b'\\') | Some(0) | None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap_or_else(|_| segment.to_string())
+}
+
+/// The filename a `Content-Disposition` value suggests, `filename*` over
+/// `filename`, reduced to its last path component. Names that are empty,
+/// hidden or hold control characters are refused.
+pub fn from_disposition(value: &str) -> Option<String> {
+    let mut plain = None;
+    let mut extended = None;
+    for (name, value) in disposition_params(value) {
+        match name.to_ascii_lowercase().as_str() {
+            "filename" => plain = Some(value),
+            "filename*" => extended = decode_ext_value(&value),
+            _ => {}
+        }
+    }
+    let name = extended.or(plain)?;
+    let name = name.rsplit(['/', '\\']).next()?.trim();
+    if name.is_empty() || name.starts_with('.') || name.chars().any(char::is_control) {
+        return None;
+    }
+    sanitize(name)
+}
+
+/// The `name=value` parameters after the disposition type, with quoted
+/// values unescaped.
+fn disposition_params(value: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    let mut rest = value.split_once(';').map_or("", |(_, params)| params);
+    loop {
+        rest = rest.trim_start_matches(|c: char| c == ';' || c.is_whitespace());
+        let eq = match rest.find(['=', ';']) {
+            Some(idx) if rest[idx..].starts_with('=') => idx,
+            // a parameter without a value
+            Some(idx) => {
+                rest = &rest[idx..];
+                continue;
+            }
+            None => break,
+        };
+        let name = rest[..eq].trim().to_string();
+        rest = rest[eq + 1..].trim_start();
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((idx, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = idx + 1;
+                        break;
+                    }
+                    '\\' => value.extend(chars.next().map(|(_, escaped)| escaped)),
+                    c => value.push(c),
+                }
+            }
+            rest = &quoted[end..];
+            value
+        } else {
+            let end = rest.find(';').unwrap_or(rest.len());
+            let value = rest[..end].trim().to_string();
+            rest = &rest[end..];
+            value
+        };
+        params.push((name, value));
+    }
+    params
+}
+
+/// Decode an RFC 8187 `charset'language'value`, in UTF-8 or ISO-8859-1.
+fn decode_ext_value(value: &str) -> Option<String> {
+    let mut parts = value.splitn(3, '\'');
+    let charset = parts.next()?.to_ascii_lowercase();
+    let encoded = parts.nth(1)?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' && i + 2 < encoded.len() {
+            bytes.push(hex_pair(encoded[i + 1], encoded[i + 2])?);
+            i += 3;
+        } else {
+            bytes.push(encoded[i]);
+            i += 1;
+        }
+    }
+    match charset.as_str() {
+        "utf-8" => String::from_utf8(bytes).ok(),
+        "iso-8859-1" => Some(bytes.into_iter().map(char::from).collect()),
+        _ => None,
+    }
+}
+
+/// The names Windows keeps for devices, whatever the extension.
+const RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Make a name the remote chose safe to create in the output directory.
+/// Path separators and control characters become `_`. On Windows so do
+/// the characters it refuses in names, trailing dots and spaces are
+/// dropped and a device name such as `CON` or `nul.txt` gets a `_` in
+/// front. A name of nothing but dots and white space, such as `..`, is
+/// refused.
+pub fn sanitize(name: &str) -> Option<String> {
+    sanitize_for(name, cfg!(windows))
+}
+
+/// `sanitize`, with the Windows rules applied whatever the platform when
+/// `windows` is set.
+fn sanitize_for(name: &str, windows: bool) -> Option<String> {
+    let refused = |c: char| c == '/' || c == '\\' || c.is_control() || (windows && "<>:\"|?*".contains(c));
+    let mut clean: String = name.chars().map(|c| if refused(c) { '_' } else { c }).collect();
+    if windows {
+        let kept = clean.trim_end_matches(['.', ' ']).len();
+        clean.truncate(kept);
+    }
+    if clean.chars().all(|c| c == '.' || c.is_whitespace()) {
+        return None;
+    }
+    let stem = clean.split('.').next().unwrap_or("").trim_end();
+    if windows && RESERVED.iter().any(|reserved| reserved.eq_ignore_ascii_case(stem)) {
+        clean.insert(0, '_');
+    }
+    Some(clean)
+}
+
+/// A URL path segment without `;`-delimited matrix parameters, such as a
+/// `;jsessionid=...` suffix.
+pub fn strip_matrix(segment: &str) -> &str {
+    segment.split(';').next().unwrap_or(segment)
+}
+
+fn hex_pair(hi: u8, lo: u8) -> Option<u8> {
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    Some(digit(hi)? << 4 | digit(lo)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, from_disposition, sanitize_for, strip_matrix};
+
+    #[test]
+    fn decodes_escaped_characters() {
+        assert_eq!(decode("my%20file.pdf"), "my file.pdf");
+        assert_eq!(decode("caf%C3%A9.txt"), "café.txt");
+        assert_eq!(decode("plain.tar.gz"), "plain.tar.gz");
+    }
+
+    #[test]
+    fn keeps_separator_and_nul_escapes_encoded() {
+        assert_eq!(decode("a%2Fb.txt"), "a%2Fb.txt");
+        assert_eq!(decode("a%2fb.txt"), "a%2fb.txt");
+        assert_eq!(decode("a%5Cb.txt"), "a%5Cb.txt");
+        assert_eq!(decode("a%00b.txt"), "a%00b.txt");
+        assert_eq!(decode("my%20dir%2Ffile"), "my dir%2Ffile");
+    }
+
+    #[test]
+    fn removes_one_level_of_encoding() {
+        assert_eq!(decode("my%2520file"), "my%20file");
+        assert_eq!(decode("a%252Fb"), "a%2Fb");
+    }
+
+    #[test]
+    fn keeps_the_raw_form_when_decoding_gives_invalid_utf8() {
+        assert_eq!(decode("bad%FF%FE.bin"), "bad%FF%FE.bin");
+        assert_eq!(decode("half%C3.bin"), "half%C3.bin");
+    }
+
+    #[test]
+    fn leaves_incomplete_escapes_alone() {
+        assert_eq!(decode("100%"), "100%");
+        assert_eq!(decode("a%2"), "a%2");
+        assert_eq!(decode("a%zz"), "a%zz");
+    }
+
+    #[test]
+    fn matrix_parameters_are_cut_from_a_segment() {
+        assert_eq!(strip_matrix("file.tar.gz;jsessionid=ABC123"), "file.tar.gz");
+        assert_eq!(strip_matrix("file.tar.gz;a=1;b=2"), "file.tar.gz");
+        assert_eq!(strip_matrix("file.tar.gz"), "file.tar.gz");
+        assert_eq!(strip_matrix(";jsessionid=ABC123"), "");
+    }
+
+    fn both(name: &str) -> (Option<String>, Option<String>) {
+        (sanitize_for(name, false), sanitize_for(name, true))
+    }
+
+    fn same(name: &str) -> (Option<String>, Option<String>) {
+        (Some(name.to_string()), Some(name.to_string()))
+    }
+
+    #[test]
+    fn path_separators_become_underscores() {
+        assert_eq!(both("a/b.txt"), same("a_b.txt"));
+        assert_eq!(both("a\\b.txt"), same("a_b.txt"));
+        assert_eq!(both("../../etc/passwd"), same(".._.._etc_passwd"));
+        assert_eq!(both("/etc/passwd"), same("_etc_passwd"));
+        assert_eq!(both("..\\..\\boot.ini"), same(".._.._boot.ini"));
+    }
+
+    #[test]
+    fn names_of_only_dots_are_refused() {
+        for name in &["", ".", "..", "...", " .. ", "   "] {
+            assert_eq!(both(name), (None, None), "{:?}", name);
+        }
+        assert_eq!(both("..a"), same("..a"));
+    }
+
+    #[test]
+    fn control_characters_become_underscores() {
+        assert_eq!(both("a\u{0}b"), same("a_b"));
+        assert_eq!(both("report\r\n.txt"), same("report__.txt"));
+        assert_eq!(both("bell\u{7}\u{1b}[0m.txt"), same("bell__[0m.txt"));
+        assert_eq!(both("del\u{7f}.txt"), same("del_.txt"));
+    }
+
+    #[test]
+    fn reserved_windows_names_are_prefixed_there() {
+        for name in &["CON", "con", "nul.txt", "Com1.tar.gz", "LPT9", "aux .txt"] {
+            assert_eq!(sanitize_for(name, true), Some(format!("_{}", name)), "{}", name);
+            assert_eq!(sanitize_for(name, false), Some(name.to_string()), "{}", name);
+        }
+        for name in &["CONSOLE.txt", "com10", "nully", "lpt"] {
+            assert_eq!(both(name), same(name));
+        }
+    }
+
+    #[test]
+    fn characters_and_endings_windows_refuses_are_changed_there() {
+        let name = "a<b>c:d\"e|f?g*h";
+        assert_eq!(both(name), (Some(name.to_string()), Some("a_b_c_d_e_f_g_h".to_string())));
+        assert_eq!(both("name. . "), (Some("name. . ".to_string()), Some("name".to_string())));
+        assert_eq!(both("CON."), (Some("CON.".to_string()), Some("_CON".to_string())));
+    }
+
+    #[test]
+    fn disposition_names_keep_only_their_last_component() {
+        assert_eq!(from_disposition("attachment; filename=\"../../etc/passwd\""), Some("passwd".to_string()));
+        assert_eq!(from_disposition("attachment; filename=\"..\\\\..\\\\boot.ini\""), Some("boot.ini".to_string()));
+        assert_eq!(from_disposition("attachment; filename*=UTF-8''..%2F..%2Fevil.sh"), Some("evil.sh".to_string()));
+        assert_eq!(from_disposition("attachment; filename=\"..\""), None);
+        assert_eq!(from_disposition("attachment; filename=\".bashrc\""), None);
+        assert_eq!(from_disposition("attachment; filename*=UTF-8''a%0Ab.txt"), None);
+    }
+}
@@ -0,0 +1,262 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+use messages;
+
+/// Write `contents` to a temporary file beside `path` and rename it into
+/// place, so readers never observe a partially written file.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    let tmp_path = Path::new(&tmp_name);
+
+    let result = File::create(tmp_path)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(tmp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(tmp_path);
+    }
+    result
+}
+
+/// Create or truncate `path` for writing, failing with `ELOOP` instead of
+/// following a symlink in its last component.
+#[cfg(unix)]
+pub fn create_nofollow(path: &Path) -> io::Result<File> {
+    use libc;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+pub fn create_nofollow(path: &Path) -> io::Result<File> {
+    if is_symlink(path) {
+        return Err(io::Error::new(io::ErrorKind::Other, "path is a symbolic link"));
+    }
+    File::create(path)
+}
+
+/// Open `path` for writing without truncating it, creating it if needed,
+/// refusing a symlink as `create_nofollow` does.
+#[cfg(unix)]
+pub fn open_nofollow(path: &Path) -> io::Result<File> {
+    use libc;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+}
+
+#[cfg(not(unix))]
+pub fn open_nofollow(path: &Path) -> io::Result<File> {
+    if is_symlink(path) {
+        return Err(io::Error::new(io::ErrorKind::Other, "path is a symbolic link"));
+    }
+    fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)
+}
+
+pub fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// Whether `path` stays inside `root`, which must already be canonical,
+/// once symlinks are resolved. A path that does not exist yet is judged by
+/// its nearest existing ancestor; `..` is not allowed in the missing part.
+pub fn resolves_within(path: &Path, root: &Path) -> io::Result<bool> {
+    let mut existing = path;
+    while !existing.as_os_str().is_empty() && fs::symlink_metadata(existing).is_err() {
+        // `file_name` is None for a path ending in `..`
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(_)) => existing = parent,
+            _ => return Ok(false),
+        }
+    }
+    if existing.as_os_str().is_empty() {
+        existing = Path::new(".");
+    }
+    Ok(fs::canonicalize(existing)?.starts_with(root))
+}
+
+/// Take over an inherited file descriptor for writing, failing if it is not
+/// open or was opened read-only.
+#[cfg(unix)]
+pub fn writable_fd(fd: i32) -> io::Result<File> {
+    use libc;
+    use std::os::unix::io::FromRawFd;
+
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "descriptor is read-only"));
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(unix))]
+pub fn writable_fd(_fd: i32) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "file descriptors are not supported on this platform",
+    ))
+}
+
+/// Reserve `len` bytes of disk for `file` without changing its length.
+/// Only a lack of space is an error: filesystems and platforms that
+/// cannot reserve space are left to allocate as the file grows.
+#[cfg(target_os = "linux")]
+pub fn preallocate(file: &File, len: u64) -> io::Result<()> {
+    use libc;
+    use std::os::unix::io::AsRawFd;
+
+    let len = len.min(libc::off_t::MAX as u64) as libc::off_t;
+    if unsafe { libc::fallocate(file.as_raw_fd(), libc::FALLOC_FL_KEEP_SIZE, 0, len) } == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) | Some(libc::EFBIG) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn preallocate(_file: &File, _len: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Whether `path` is a FIFO or character device, which are written to as
+/// streams rather than treated as files.
+#[cfg(unix)]
+pub fn is_stream(path: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+
+    fs::metadata(path)
+        .map(|meta| meta.file_type().is_fifo() || meta.file_type().is_char_device())
+        .unwrap_or(false)
+}
+
+#[cfg(not(unix))]
+pub fn is_stream(_path: &Path) -> bool {
+    false
+}
+
+/// Open a FIFO or device for writing without truncating it. When a FIFO has
+/// no reader yet, say so before blocking until one attaches.
+#[cfg(unix)]
+pub fn open_stream(path: &Path) -> io::Result<File> {
+    use libc;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    match fs::OpenOptions::new()
+        .write(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(path)
+    {
+        Ok(file) => {
+            // a reader is attached; go back to ordinary blocking writes
+            let fd = file.as_raw_fd();
+            let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+            if flags == -1 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags & !libc::O_NONBLOCK) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(file)
+        }
+        Err(ref e) if e.raw_os_error() == Some(libc::ENXIO) => {
+            messages::note("note.waiting-reader", &[&path.display()]);
+            fs::OpenOptions::new().write(true).open(path)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn open_stream(path: &Path) -> io::Result<File> {
+    fs::OpenOptions::new().write(true).open(path)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::fs::symlink;
+    use std::path::PathBuf;
+    use std::process;
+
+    use libc;
+
+    use super::{create_nofollow, is_symlink, open_nofollow, resolves_within};
+
+    /// An empty directory of the test's own under the temporary directory.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("download-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    #[test]
+    fn symlinked_file_is_refused_unless_followed() {
+        let dir = scratch("symlinked-file");
+        fs::write(dir.join("target"), b"kept").unwrap();
+        symlink(dir.join("target"), dir.join("link")).unwrap();
+        assert!(is_symlink(&dir.join("link")));
+        assert!(!is_symlink(&dir.join("target")));
+
+        for open in &[create_nofollow, open_nofollow] {
+            let err = open(&dir.join("link")).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ELOOP));
+        }
+        assert_eq!(fs::read(dir.join("target")).unwrap(), b"kept");
+
+        // what --follow-symlinks opens instead
+        File::create(dir.join("link")).unwrap().write_all(b"through").unwrap();
+        assert_eq!(fs::read(dir.join("target")).unwrap(), b"through");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn plain_file_is_created_and_reopened() {
+        let dir = scratch("plain-file");
+        create_nofollow(&dir.join("out")).unwrap().write_all(b"first").unwrap();
+        open_nofollow(&dir.join("out")).unwrap().write_all(b"F").unwrap();
+        assert_eq!(fs::read(dir.join("out")).unwrap(), b"First");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn symlinked_parent_directory_leaves_the_root() {
+        let dir = scratch("symlinked-parent");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("inside")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        symlink(dir.join("outside"), root.join("escape")).unwrap();
+        symlink(root.join("inside"), root.join("alias")).unwrap();
+
+        assert!(resolves_within(&root.join("inside/new.bin"), &root).unwrap());
+        assert!(resolves_within(&root.join("alias/new.bin"), &root).unwrap());
+        assert!(!resolves_within(&root.join("escape/new.bin"), &root).unwrap());
+        assert!(!resolves_within(&root.join("escape/deeper/new.bin"), &root).unwrap());
+        assert!(!resolves_within(&root.join("missing/../../new.bin"), &root).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
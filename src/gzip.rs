@@ -0,0 +1,239 @@
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use crc32::Crc32;
+use hasher::{HashWriter, Hasher};
+use inflate::Inflate;
+
+const ID: [u8; 2] = [0x1f, 0x8b];
+const DEFLATE: u8 = 8;
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+/// Chunks queued for the decompressing thread before the download waits.
+const QUEUED: usize = 8;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("gzip: {}", msg))
+}
+
+/// Where the decompressed copy of `path` goes: the name without `.gz`, or
+/// with `.tgz` turned into `.tar`. Other names have none.
+pub fn decompressed_path(path: &Path) -> Option<PathBuf> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    let stem = path.file_stem().filter(|stem| !stem.is_empty())?;
+    match ext.as_str() {
+        "gz" => Some(path.with_file_name(stem)),
+        "tgz" => Some(path.with_extension("tar")),
+        _ => None,
+    }
+}
+
+enum State {
+    Header,
+    Body,
+    Done,
+}
+
+/// Decompresses a gzip stream (RFC 1952), checking each member's CRC-32
+/// and length. Members written one after another decompress as one file,
+/// as with `gzip -d`.
+pub struct Gunzip<R> {
+    inflate: Inflate<R>,
+    state: State,
+    crc: Crc32,
+    size: u32,
+}
+
+impl<R: Read> Gunzip<R> {
+    pub fn new(inner: R) -> Gunzip<R> {
+        Gunzip {
+            inflate: Inflate::new(inner),
+            state: State::Header,
+            crc: Crc32::new(),
+            size: 0,
+        }
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        self.inflate.next_byte()?.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "gzip: stream ended early")
+        })
+    }
+
+    fn u32_le(&mut self) -> io::Result<u32> {
+        let mut value = 0;
+        for shift in 0..4 {
+            value |= u32::from(self.byte()?) << (8 * shift);
+        }
+        Ok(value)
+    }
+
+    fn skip_zero_terminated(&mut self) -> io::Result<()> {
+        while self.byte()? != 0 {}
+        Ok(())
+    }
+
+    /// Read a member header, given its first byte.
+    fn header(&mut self, first: u8) -> io::Result<()> {
+        if [first, self.byte()?] != ID {
+            return Err(invalid("not a gzip stream"));
+        }
+        if self.byte()? != DEFLATE {
+            return Err(invalid("unsupported compression method"));
+        }
+        let flags = self.byte()?;
+        // modification time, extra flags and operating system
+        for _ in 0..6 {
+            self.byte()?;
+        }
+        if flags & FEXTRA != 0 {
+            let len = u16::from(self.byte()?) | u16::from(self.byte()?) << 8;
+            for _ in 0..len {
+                self.byte()?;
+            }
+        }
+        if flags & FNAME != 0 {
+            self.skip_zero_terminated()?;
+        }
+        if flags & FCOMMENT != 0 {
+            self.skip_zero_terminated()?;
+        }
+        if flags & FHCRC != 0 {
+            self.byte()?;
+            self.byte()?;
+        }
+        self.crc = Crc32::new();
+        self.size = 0;
+        Ok(())
+    }
+
+    fn trailer(&mut self) -> io::Result<()> {
+        if self.u32_le()? != self.crc.finish() {
+            return Err(invalid("CRC-32 mismatch"));
+        }
+        if self.u32_le()? != self.size {
+            return Err(invalid("length mismatch"));
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Gunzip<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            match self.state {
+                State::Header => {
+                    let first = self.byte()?;
+                    self.header(first)?;
+                    self.state = State::Body;
+                }
+                State::Body => {
+                    let len = self.inflate.read(out)?;
+                    if len > 0 {
+                        self.crc.update(&out[..len]);
+                        self.size = self.size.wrapping_add(len as u32);
+                        return Ok(len);
+                    }
+                    self.trailer()?;
+                    match self.inflate.next_byte()? {
+                        Some(first) => {
+                            self.inflate.restart();
+                            self.header(first)?;
+                        }
+                        None => self.state = State::Done,
+                    }
+                }
+                State::Done => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Reads the chunks a `Branch` was handed, in order.
+struct Chunks {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.receiver.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// A writer that decompresses the gzip stream written to it into another
+/// output on a helper thread, so a download can be kept both compressed
+/// and decompressed from one pass over the network. A corrupt stream never
+/// fails the writes; it is reported by `finish`.
+pub struct Branch {
+    sender: Option<SyncSender<Vec<u8>>>,
+    worker: JoinHandle<io::Result<(Hasher, u64)>>,
+}
+
+impl Branch {
+    pub fn spawn<W: Write + Send + 'static>(output: W) -> Branch {
+        let (sender, receiver) = mpsc::sync_channel(QUEUED);
+        let worker = thread::spawn(move || {
+            let mut input = Gunzip::new(Chunks {
+                receiver,
+                chunk: Vec::new(),
+                pos: 0,
+            });
+            let mut writer = HashWriter::with_state(output, Hasher::new(), 0);
+            io::copy(&mut input, &mut writer)?;
+            writer.flush()?;
+            Ok(writer.into_state())
+        });
+        Branch {
+            sender: Some(sender),
+            worker,
+        }
+    }
+
+    /// Wait for the decompressed copy to be written, returning its digests
+    /// and length.
+    pub fn finish(mut self) -> io::Result<(Hasher, u64)> {
+        self.sender = None;
+        self.worker
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("gzip: decompressing thread panicked")))
+    }
+}
+
+impl Write for Branch {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // once the thread has stopped on an error, the rest goes nowhere
+        if let Some(ref sender) = self.sender {
+            if sender.send(buf.to_vec()).is_err() {
+                self.sender = None;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
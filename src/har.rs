@@ -0,0 +1,148 @@
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{SecondsFormat, Utc};
+use reqwest::blocking::{Request, Response};
+use reqwest::header::{HeaderMap, CONTENT_TYPE, LOCATION};
+use serde_json;
+use serde_json::Value;
+
+use fsutil;
+use timing;
+
+/// Every exchange of a run, for `--har` to write as an HTTP Archive 1.2
+/// that browser devtools can load. Entries are JSON from the start, filled
+/// in as the response and then its body arrive; what never does stays at
+/// the `-1` the format uses for unknown.
+#[derive(Default)]
+pub struct Archive {
+    entries: Mutex<Vec<Value>>,
+}
+
+/// `headers` as HAR lists them, with the values of those named in
+/// `redact` left out.
+fn header_list(headers: &HeaderMap, redact: &[&str]) -> Vec<Value> {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if redact.contains(&name.as_str()) {
+                "[redacted]".to_string()
+            } else {
+                String::from_utf8_lossy(value.as_bytes()).into_owned()
+            };
+            json!({ "name": name.as_str(), "value": value })
+        })
+        .collect()
+}
+
+/// The sum of the timings HAR counts into an entry's `time`, the unknown
+/// ones left out.
+fn total_time(timings: &Value) -> f64 {
+    ["blocked", "dns", "connect", "send", "wait", "receive"]
+        .iter()
+        .filter_map(|phase| timings[*phase].as_f64())
+        .filter(|ms| *ms > 0.0)
+        .sum()
+}
+
+impl Archive {
+    /// Record `req` as it is about to be sent, and return the index its
+    /// response and body are recorded under.
+    pub fn request(&self, req: &Request, redact: &[&str]) -> usize {
+        let query: Vec<Value> = req
+            .url()
+            .query_pairs()
+            .map(|(name, value)| json!({ "name": name, "value": value }))
+            .collect();
+        let entry = json!({
+            "startedDateTime": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "time": 0,
+            "request": {
+                "method": req.method().as_str(),
+                "url": req.url().as_str(),
+                "httpVersion": format!("{:?}", req.version()),
+                "cookies": [],
+                "headers": header_list(req.headers(), redact),
+                "queryString": query,
+                "headersSize": -1,
+                "bodySize": if req.body().is_some() { -1 } else { 0 },
+            },
+            "response": {
+                "status": 0,
+                "statusText": "",
+                "httpVersion": "",
+                "cookies": [],
+                "headers": [],
+                "content": { "size": -1, "mimeType": "" },
+                "redirectURL": "",
+                "headersSize": -1,
+                "bodySize": -1,
+            },
+            "cache": {},
+            "timings": { "blocked": -1, "dns": -1, "connect": -1, "ssl": -1, "send": 0, "wait": 0, "receive": 0 },
+        });
+        let mut entries = self.entries.lock().unwrap();
+        entries.push(entry);
+        entries.len() - 1
+    }
+
+    /// The response to entry `idx` arrived `wait` after the request went.
+    pub fn response(&self, idx: usize, resp: &Response, wait: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = &mut entries[idx];
+        let version = format!("{:?}", resp.version());
+        let header = |name| resp.headers().get(name).and_then(|value| value.to_str().ok()).unwrap_or("");
+        entry["request"]["httpVersion"] = json!(version);
+        entry["response"] = json!({
+            "status": resp.status().as_u16(),
+            "statusText": resp.status().canonical_reason().unwrap_or(""),
+            "httpVersion": version,
+            "cookies": [],
+            "headers": header_list(resp.headers(), &[]),
+            "content": { "size": -1, "mimeType": header(CONTENT_TYPE) },
+            "redirectURL": header(LOCATION),
+            "headersSize": -1,
+            "bodySize": -1,
+        });
+        if let Some(addr) = resp.remote_addr() {
+            entry["serverIPAddress"] = json!(addr.ip().to_string());
+        }
+        entry["timings"]["wait"] = json!(timing::millis(wait));
+        entry["time"] = json!(total_time(&entry["timings"]));
+    }
+
+    /// Entry `idx` got no response, failing with `error` after `time`.
+    pub fn failed(&self, idx: usize, error: &str, time: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = &mut entries[idx];
+        entry["response"]["_error"] = json!(error);
+        entry["timings"]["wait"] = json!(timing::millis(time));
+        entry["time"] = json!(total_time(&entry["timings"]));
+    }
+
+    /// The body of entry `idx` came to `size` bytes, read over `receive_ms`
+    /// where that was timed.
+    pub fn body(&self, idx: usize, size: u64, receive_ms: Option<u64>) {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = &mut entries[idx];
+        entry["response"]["content"]["size"] = json!(size);
+        entry["response"]["bodySize"] = json!(size);
+        if let Some(ms) = receive_ms {
+            entry["timings"]["receive"] = json!(ms);
+            entry["time"] = json!(total_time(&entry["timings"]));
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let doc = json!({
+            "log": {
+                "version": "1.2",
+                "creator": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "entries": *self.entries.lock().unwrap(),
+            }
+        });
+        fsutil::write_atomic(path, &serde_json::to_vec_pretty(&doc).unwrap())
+    }
+}
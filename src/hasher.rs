@@ -0,0 +1,167 @@
+use std::io;
+use std::io::Write;
+use std::sync::OnceLock;
+
+use data_encoding::HEXLOWER;
+use digest::Digest;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use blake3::Blake3;
+use checksum::Algorithm;
+use md5::Md5;
+
+/// What is computed unless `--digest` says otherwise.
+pub const DEFAULT: [Algorithm; 2] = [Algorithm::Sha1, Algorithm::Sha256];
+
+static SELECTED: OnceLock<Vec<Algorithm>> = OnceLock::new();
+
+/// Compute `algorithms`, in that order, wherever a hasher is created from
+/// now on. Only the first call counts.
+pub fn select(algorithms: Vec<Algorithm>) {
+    let _ = SELECTED.set(algorithms);
+}
+
+pub fn selected() -> &'static [Algorithm] {
+    SELECTED.get().map_or(&DEFAULT, Vec::as_slice)
+}
+
+/// Parse a `--digest` list such as `sha256,blake3`. Names given twice are
+/// computed once.
+pub fn parse_list(list: &str) -> Result<Vec<Algorithm>, String> {
+    let mut algorithms = Vec::new();
+    for name in list.split(',').map(str::trim) {
+        let algorithm = Algorithm::from_name(name).ok_or_else(|| format!("--digest: unknown algorithm '{}'", name))?;
+        if !algorithms.contains(&algorithm) {
+            algorithms.push(algorithm);
+        }
+    }
+    Ok(algorithms)
+}
+
+enum State {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<Blake3>),
+}
+
+impl State {
+    fn new(algorithm: Algorithm) -> State {
+        match algorithm {
+            Algorithm::Md5 => State::Md5(Md5::new()),
+            Algorithm::Sha1 => State::Sha1(Sha1::new()),
+            Algorithm::Sha256 => State::Sha256(Sha256::new()),
+            Algorithm::Sha512 => State::Sha512(Sha512::new()),
+            Algorithm::Blake3 => State::Blake3(Box::new(Blake3::new())),
+        }
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        match *self {
+            State::Md5(ref mut state) => state.update(buf),
+            State::Sha1(ref mut state) => state.update(buf),
+            State::Sha256(ref mut state) => state.update(buf),
+            State::Sha512(ref mut state) => state.update(buf),
+            State::Blake3(ref mut state) => state.update(buf),
+        }
+    }
+
+    fn finish(self) -> (Algorithm, String) {
+        match self {
+            State::Md5(state) => (Algorithm::Md5, HEXLOWER.encode(&state.finalize())),
+            State::Sha1(state) => (Algorithm::Sha1, HEXLOWER.encode(&state.finalize())),
+            State::Sha256(state) => (Algorithm::Sha256, HEXLOWER.encode(&state.finalize())),
+            State::Sha512(state) => (Algorithm::Sha512, HEXLOWER.encode(&state.finalize())),
+            State::Blake3(state) => (Algorithm::Blake3, HEXLOWER.encode(&state.finalize())),
+        }
+    }
+}
+
+/// Hex digests of one stream, in the order they were selected.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Digests(Vec<(Algorithm, String)>);
+
+impl Digests {
+    pub fn get(&self, algorithm: Algorithm) -> Option<&str> {
+        self.0
+            .iter()
+            .find(|digest| digest.0 == algorithm)
+            .map(|digest| digest.1.as_str())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(Algorithm, String)> {
+        self.0.iter()
+    }
+}
+
+/// Computes every selected digest over a stream of bytes.
+pub struct Hasher {
+    states: Vec<State>,
+}
+
+impl Default for Hasher {
+    fn default() -> Hasher {
+        Hasher::new()
+    }
+}
+
+impl Hasher {
+    /// A hasher for the selected algorithms.
+    pub fn new() -> Hasher {
+        Hasher::with(selected())
+    }
+
+    pub fn with(algorithms: &[Algorithm]) -> Hasher {
+        Hasher {
+            states: algorithms.iter().map(|&algorithm| State::new(algorithm)).collect(),
+        }
+    }
+
+    pub fn update(&mut self, buf: &[u8]) {
+        for state in &mut self.states {
+            state.update(buf);
+        }
+    }
+
+    pub fn finish(self) -> Digests {
+        Digests(self.states.into_iter().map(State::finish).collect())
+    }
+}
+
+/// A writer that hashes everything passing through it.
+pub struct HashWriter<W> {
+    inner: W,
+    hasher: Hasher,
+    written: u64,
+}
+
+impl<W: Write> HashWriter<W> {
+    /// Continue hashing from an earlier writer's state so that several
+    /// outputs can share one digest.
+    pub fn with_state(inner: W, hasher: Hasher, written: u64) -> HashWriter<W> {
+        HashWriter {
+            inner,
+            hasher,
+            written,
+        }
+    }
+
+    pub fn into_state(self) -> (Hasher, u64) {
+        (self.hasher, self.written)
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.written += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,424 @@
+use std::io;
+use std::io::{BufReader, Read};
+
+const WINDOW: usize = 32 * 1024;
+const MAX_BITS: usize = 15;
+
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115,
+    131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+/// The order code length code lengths are sent in.
+const CL_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("deflate: {}", msg))
+}
+
+/// Reads the stream least significant bit first, as DEFLATE packs it.
+struct Bits<R> {
+    inner: BufReader<R>,
+    buf: u64,
+    count: u32,
+    /// Set once the underlying reader is exhausted.
+    eof: bool,
+}
+
+impl<R: Read> Bits<R> {
+    /// Make at least `n` bits available when the input has them.
+    fn fill(&mut self, n: u32) -> io::Result<()> {
+        while self.count < n && !self.eof {
+            let mut byte = [0; 1];
+            match self.inner.read(&mut byte) {
+                Ok(0) => self.eof = true,
+                Ok(_) => {
+                    self.buf |= u64::from(byte[0]) << self.count;
+                    self.count += 8;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// The next `n` bits without consuming them, zero-padded at the end of
+    /// the input.
+    fn peek(&mut self, n: u32) -> io::Result<u32> {
+        self.fill(n)?;
+        Ok((self.buf & ((1 << n) - 1)) as u32)
+    }
+
+    fn consume(&mut self, n: u32) -> io::Result<()> {
+        if self.count < n {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "deflate: stream ended early",
+            ));
+        }
+        self.buf >>= n;
+        self.count -= n;
+        Ok(())
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        let value = self.peek(n)?;
+        self.consume(n)?;
+        Ok(value)
+    }
+
+    /// Drop the bits left in the current byte.
+    fn align(&mut self) {
+        let drop = self.count % 8;
+        self.buf >>= drop;
+        self.count -= drop;
+    }
+}
+
+/// A canonical Huffman code as a lookup table indexed by the next `bits`
+/// input bits. Each entry holds `symbol << 4 | length`; a zero length marks
+/// a bit pattern the code does not use.
+struct Table {
+    entries: Vec<u16>,
+    bits: u32,
+}
+
+impl Table {
+    fn new(lengths: &[u8]) -> io::Result<Table> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        let bits = (1..=MAX_BITS).rev().find(|&len| counts[len] > 0).unwrap_or(1);
+
+        let mut next = [0u32; MAX_BITS + 2];
+        let mut code = 0u32;
+        for len in 1..=MAX_BITS {
+            code = (code + u32::from(counts[len - 1])) << 1;
+            next[len] = code;
+        }
+        let mut entries = vec![0u16; 1 << bits];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len == 0 {
+                continue;
+            }
+            let len = len as usize;
+            let code = next[len];
+            next[len] += 1;
+            if code >= 1 << len {
+                return Err(invalid("oversubscribed Huffman code"));
+            }
+            // codes are sent most significant bit first
+            let reversed = code.reverse_bits() >> (32 - len);
+            let entry = (symbol as u16) << 4 | len as u16;
+            let mut index = reversed as usize;
+            while index < entries.len() {
+                entries[index] = entry;
+                index += 1 << len;
+            }
+        }
+        Ok(Table {
+            entries,
+            bits: bits as u32,
+        })
+    }
+
+    fn fixed_literals() -> Table {
+        let mut lengths = [8u8; 288];
+        for len in &mut lengths[144..256] {
+            *len = 9;
+        }
+        for len in &mut lengths[256..280] {
+            *len = 7;
+        }
+        Table::new(&lengths).unwrap()
+    }
+
+    fn fixed_distances() -> Table {
+        Table::new(&[5u8; 30]).unwrap()
+    }
+
+    fn decode<R: Read>(&self, bits: &mut Bits<R>) -> io::Result<u16> {
+        let entry = self.entries[bits.peek(self.bits)? as usize];
+        let len = u32::from(entry & 0xf);
+        if len == 0 {
+            return Err(invalid("invalid Huffman code"));
+        }
+        bits.consume(len)?;
+        Ok(entry >> 4)
+    }
+}
+
+enum State {
+    /// Expecting a block header.
+    Header,
+    /// Inside a stored block with this many bytes left.
+    Stored(u16),
+    /// Inside a compressed block.
+    Huffman(Box<(Table, Table)>),
+    Done,
+}
+
+/// Decompresses a raw DEFLATE stream (RFC 1951), as stored in zip members.
+pub struct Inflate<R> {
+    bits: Bits<R>,
+    state: State,
+    last_block: bool,
+    window: Vec<u8>,
+    /// Total bytes produced so far; the window position is this modulo its
+    /// size.
+    produced: u64,
+    /// A back-reference still being copied: `(length left, distance)`.
+    copy: Option<(u16, u16)>,
+}
+
+impl<R: Read> Inflate<R> {
+    pub fn new(inner: R) -> Inflate<R> {
+        Inflate {
+            bits: Bits {
+                inner: BufReader::new(inner),
+                buf: 0,
+                count: 0,
+                eof: false,
+            },
+            state: State::Header,
+            last_block: false,
+            window: vec![0; WINDOW],
+            produced: 0,
+            copy: None,
+        }
+    }
+
+    /// The next whole byte of the input past the bits already decoded, for
+    /// formats such as gzip that wrap DEFLATE in a header and trailer;
+    /// `None` at the end of the input.
+    pub fn next_byte(&mut self) -> io::Result<Option<u8>> {
+        self.bits.align();
+        self.bits.fill(8)?;
+        if self.bits.count < 8 {
+            return Ok(None);
+        }
+        self.bits.bits(8).map(|byte| Some(byte as u8))
+    }
+
+    /// Decode a new DEFLATE stream that follows in the same input.
+    pub fn restart(&mut self) {
+        self.state = State::Header;
+        self.last_block = false;
+        self.produced = 0;
+        self.copy = None;
+    }
+
+    fn push(&mut self, byte: u8, out: &mut [u8], n: &mut usize) {
+        self.window[self.produced as usize % WINDOW] = byte;
+        self.produced += 1;
+        out[*n] = byte;
+        *n += 1;
+    }
+
+    fn header(&mut self) -> io::Result<State> {
+        if self.last_block {
+            return Ok(State::Done);
+        }
+        self.last_block = self.bits.bits(1)? == 1;
+        match self.bits.bits(2)? {
+            0 => {
+                self.bits.align();
+                let len = self.bits.bits(16)? as u16;
+                let nlen = self.bits.bits(16)? as u16;
+                if len != !nlen {
+                    return Err(invalid("stored block length check failed"));
+                }
+                Ok(State::Stored(len))
+            }
+            1 => Ok(State::Huffman(Box::new((
+                Table::fixed_literals(),
+                Table::fixed_distances(),
+            )))),
+            2 => self.dynamic_tables().map(|tables| State::Huffman(Box::new(tables))),
+            _ => Err(invalid("reserved block type")),
+        }
+    }
+
+    fn dynamic_tables(&mut self) -> io::Result<(Table, Table)> {
+        let literals = self.bits.bits(5)? as usize + 257;
+        let distances = self.bits.bits(5)? as usize + 1;
+        let code_lengths = self.bits.bits(4)? as usize + 4;
+        let mut cl_lengths = [0u8; 19];
+        for &index in CL_ORDER.iter().take(code_lengths) {
+            cl_lengths[index] = self.bits.bits(3)? as u8;
+        }
+        let cl = Table::new(&cl_lengths)?;
+
+        let mut lengths = vec![0u8; literals + distances];
+        let mut i = 0;
+        while i < lengths.len() {
+            let (value, repeat) = match cl.decode(&mut self.bits)? {
+                symbol @ 0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.bits.bits(2)? as usize),
+                16 => return Err(invalid("repeat with no previous length")),
+                17 => (0, 3 + self.bits.bits(3)? as usize),
+                _ => (0, 11 + self.bits.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(invalid("too many code lengths"));
+            }
+            for len in &mut lengths[i..i + repeat] {
+                *len = value;
+            }
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(invalid("no end-of-block code"));
+        }
+        Ok((
+            Table::new(&lengths[..literals])?,
+            Table::new(&lengths[literals..])?,
+        ))
+    }
+
+    /// Decode one literal/length symbol, leaving either a byte in `out` or a
+    /// pending copy. Returns false at the end of the block.
+    fn symbol(&mut self, tables: &(Table, Table), out: &mut [u8], n: &mut usize) -> io::Result<bool> {
+        let symbol = tables.0.decode(&mut self.bits)? as usize;
+        if symbol < 256 {
+            self.push(symbol as u8, out, n);
+            return Ok(true);
+        }
+        if symbol == 256 {
+            return Ok(false);
+        }
+        let index = symbol - 257;
+        if index >= LEN_BASE.len() {
+            return Err(invalid("invalid length code"));
+        }
+        let len = LEN_BASE[index] + self.bits.bits(u32::from(LEN_EXTRA[index]))? as u16;
+        let index = tables.1.decode(&mut self.bits)? as usize;
+        if index >= DIST_BASE.len() {
+            return Err(invalid("invalid distance code"));
+        }
+        let dist = DIST_BASE[index] + self.bits.bits(u32::from(DIST_EXTRA[index]))? as u16;
+        if u64::from(dist) > self.produced {
+            return Err(invalid("distance reaches before the start of the stream"));
+        }
+        self.copy = Some((len, dist));
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for Inflate<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < out.len() {
+            if let Some((len, dist)) = self.copy.take() {
+                let mut left = len;
+                while left > 0 && n < out.len() {
+                    let from = (self.produced - u64::from(dist)) as usize % WINDOW;
+                    let byte = self.window[from];
+                    self.push(byte, out, &mut n);
+                    left -= 1;
+                }
+                if left > 0 {
+                    self.copy = Some((left, dist));
+                }
+                continue;
+            }
+            match self.state {
+                State::Done => break,
+                State::Header => self.state = self.header()?,
+                State::Stored(0) => self.state = State::Header,
+                State::Stored(left) => {
+                    self.state = State::Stored(left - 1);
+                    let byte = self.bits.bits(8)? as u8;
+                    self.push(byte, out, &mut n);
+                }
+                State::Huffman(_) => {
+                    let tables = match ::std::mem::replace(&mut self.state, State::Header) {
+                        State::Huffman(tables) => tables,
+                        _ => unreachable!(),
+                    };
+                    let mut more = true;
+                    while more && n < out.len() && self.copy.is_none() {
+                        more = self.symbol(&tables, out, &mut n)?;
+                    }
+                    if more {
+                        self.state = State::Huffman(tables);
+                    }
+                }
+            }
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io;
+    use std::io::Read;
+
+    use super::Inflate;
+
+    /// Inflate `input` a few bytes at a time, so that back-references are
+    /// split across reads.
+    fn inflate(input: &[u8]) -> io::Result<Vec<u8>> {
+        let mut inflate = Inflate::new(input);
+        let mut out = Vec::new();
+        let mut buf = [0; 7];
+        loop {
+            match inflate.read(&mut buf)? {
+                0 => return Ok(out),
+                n => out.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn stored_block_is_copied() {
+        let input = [1, 12, 0, 243, 255, 115, 116, 111, 114, 101, 100, 32, 98, 121, 116, 101, 115];
+        assert_eq!(inflate(&input).unwrap(), b"stored bytes");
+    }
+
+    #[test]
+    fn fixed_huffman_block_with_back_references() {
+        let input = [75, 76, 74, 78, 68, 69, 58, 10, 37, 25, 169, 121, 10, 16, 142, 66, 98, 122, 98, 102, 30, 23, 0];
+        assert_eq!(inflate(&input).unwrap(), b"abcabcabcabcabcabc, then abcabc again\n");
+    }
+
+    #[test]
+    fn empty_stream_gives_nothing() {
+        assert_eq!(inflate(&[3, 0]).unwrap(), b"");
+    }
+
+    #[test]
+    fn dynamic_huffman_blocks_past_the_window() {
+        let text = fs::read("tests/data/zip/text.txt").unwrap();
+        let out = inflate(&fs::read("tests/data/zip/text5.deflate").unwrap()).unwrap();
+        assert_eq!(out.len(), text.len() * 5);
+        assert!(out.chunks(text.len()).all(|chunk| chunk == &text[..]));
+    }
+
+    #[test]
+    fn truncated_stream_is_an_error() {
+        let input = fs::read("tests/data/zip/text5.deflate").unwrap();
+        assert!(inflate(&input[..input.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn reserved_block_type_is_refused() {
+        let err = inflate(&[0b111]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
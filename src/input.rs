@@ -0,0 +1,364 @@
+use std::convert::TryFrom;
+use std::io::BufRead;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use checksum::{Algorithm, Checksum};
+use ioutil;
+use messages;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// One URL per line, optionally followed by a tab and an output path,
+    /// then by another tab and the expected checksum.
+    Text,
+    /// One JSON object per line: `{"url": ..., "output": ..., "sha256": ...}`.
+    JsonLines,
+    /// aria2's input file: URL lines each followed by indented `key=value`
+    /// options.
+    Aria2,
+}
+
+/// The names `--input-format` accepts, one per format.
+pub const FORMATS: [&str; 3] = ["text", "jsonl", "aria2"];
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "jsonl" | "json-lines" => Some(Format::JsonLines),
+            "aria2" => Some(Format::Aria2),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub line: usize,
+    pub url: String,
+    pub output: Option<PathBuf>,
+    /// Directory for the output; joined with `output` or the remote name.
+    pub dir: Option<PathBuf>,
+    pub checksum: Option<Checksum>,
+    pub headers: Vec<(String, String)>,
+    pub overrides: Overrides,
+}
+
+/// Settings a JSON-lines entry gives for itself, over the global ones.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Overrides {
+    /// The longest a single attempt may take, body included.
+    pub max_time: Option<Duration>,
+    pub connect_timeout: Option<Duration>,
+    /// How often a transient failure is tried again.
+    pub retries: Option<u32>,
+    /// Bytes per second.
+    pub limit_rate: Option<u64>,
+}
+
+impl Overrides {
+    pub fn is_empty(&self) -> bool {
+        *self == Overrides::default()
+    }
+
+    /// Whether the entry needs a client of its own for its timeouts.
+    pub fn has_timeouts(&self) -> bool {
+        self.max_time.is_some() || self.connect_timeout.is_some()
+    }
+
+    /// The overrides as JSON-lines keys, with durations in seconds.
+    pub fn to_json(&self) -> Value {
+        let mut doc = json!({});
+        if let Some(max_time) = self.max_time {
+            doc["max_time"] = json!(max_time.as_secs_f64());
+        }
+        if let Some(connect_timeout) = self.connect_timeout {
+            doc["connect_timeout"] = json!(connect_timeout.as_secs_f64());
+        }
+        if let Some(retries) = self.retries {
+            doc["retries"] = json!(retries);
+        }
+        if let Some(limit_rate) = self.limit_rate {
+            doc["limit_rate"] = json!(limit_rate);
+        }
+        doc
+    }
+}
+
+impl Entry {
+    pub fn new(line: usize, url: String) -> Entry {
+        Entry {
+            line,
+            url,
+            output: None,
+            dir: None,
+            checksum: None,
+            headers: Vec::new(),
+            overrides: Overrides::default(),
+        }
+    }
+
+    /// The entry as a line of the native JSON-lines format.
+    pub fn to_json(&self) -> Value {
+        let mut doc = json!({ "url": self.url });
+        if let Some(ref output) = self.output {
+            doc["output"] = json!(output.to_string_lossy());
+        }
+        if let Some(ref dir) = self.dir {
+            doc["dir"] = json!(dir.to_string_lossy());
+        }
+        if let Some(ref checksum) = self.checksum {
+            doc[checksum.algorithm.to_string()] = json!(checksum.hex);
+        }
+        if !self.headers.is_empty() {
+            let headers: Vec<Value> = self
+                .headers
+                .iter()
+                .map(|(name, value)| json!(format!("{}: {}", name, value)))
+                .collect();
+            doc["headers"] = json!(headers);
+        }
+        if let Value::Object(overrides) = self.overrides.to_json() {
+            for (key, value) in overrides {
+                doc[key] = value;
+            }
+        }
+        doc
+    }
+}
+
+/// Read every entry from an input list. Blank lines and lines starting with
+/// `#` are ignored. Every invalid entry is reported, one per line, before
+/// anything is returned.
+pub fn read_entries<R: BufRead>(reader: R, format: Format) -> Result<Vec<Entry>, String> {
+    if format == Format::Aria2 {
+        return read_aria2(reader);
+    }
+    let mut entries = Vec::new();
+    let mut errors = Vec::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = line.map_err(|e| format!("line {}: {}", line_no, e))?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let entry = match format {
+            Format::Text => parse_text(line_no, trimmed),
+            Format::JsonLines => parse_json(line_no, trimmed),
+            Format::Aria2 => unreachable!(),
+        };
+        match entry {
+            Ok(entry) => entries.push(entry),
+            Err(e) => errors.push(format!("line {}: {}", line_no, e)),
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(entries)
+}
+
+fn parse_text(line: usize, value: &str) -> Result<Entry, String> {
+    let mut fields = value.splitn(3, '\t');
+    let url = fields.next().unwrap_or("").trim();
+    // an empty path keeps the remote name, so a checksum can follow alone
+    let output = fields
+        .next()
+        .map(|path| path.trim())
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from);
+    let checksum = match fields.next().map(str::trim).filter(|hex| !hex.is_empty()) {
+        Some(value) => Some(Checksum::parse(value)?),
+        None => None,
+    };
+    let mut entry = Entry::new(line, url.to_string());
+    entry.output = output;
+    entry.checksum = checksum;
+    Ok(entry)
+}
+
+fn parse_json(line: usize, value: &str) -> Result<Entry, String> {
+    let value: Value = serde_json::from_str(value).map_err(|e| e.to_string())?;
+    let url = match value.get("url").and_then(Value::as_str) {
+        Some(url) => url.to_string(),
+        None => return Err("missing \"url\" string".to_string()),
+    };
+    let mut entry = Entry::new(line, url);
+    entry.output = optional_str(&value, "output")?.map(PathBuf::from);
+    entry.dir = optional_str(&value, "dir")?.map(PathBuf::from);
+    entry.checksum = match (optional_str(&value, "sha256")?, optional_str(&value, "sha1")?) {
+        (Some(hex), _) => Some(Checksum::new(Algorithm::Sha256, hex)?),
+        (None, Some(hex)) => Some(Checksum::new(Algorithm::Sha1, hex)?),
+        (None, None) => None,
+    };
+    match value.get("headers") {
+        None | Some(Value::Null) => {}
+        Some(Value::Array(headers)) => {
+            for header in headers {
+                let header = header.as_str().ok_or("\"headers\" must be strings")?;
+                entry.headers.push(parse_header(header)?);
+            }
+        }
+        Some(_) => return Err("\"headers\" must be an array of strings".to_string()),
+    }
+    entry.overrides = Overrides {
+        max_time: optional_secs(&value, "max_time")?,
+        connect_timeout: optional_secs(&value, "connect_timeout")?,
+        retries: match value.get("retries") {
+            None | Some(Value::Null) => None,
+            Some(retries) => Some(
+                retries
+                    .as_u64()
+                    .and_then(|retries| u32::try_from(retries).ok())
+                    .ok_or("\"retries\" must be a non-negative integer")?,
+            ),
+        },
+        limit_rate: match value.get("limit_rate") {
+            None | Some(Value::Null) => None,
+            Some(Value::Number(rate)) => Some(rate.as_u64().filter(|&rate| rate > 0).ok_or(
+                "\"limit_rate\" must be a positive number of bytes per second",
+            )?),
+            Some(Value::String(rate)) => Some(ioutil::parse_size(rate).ok_or_else(|| {
+                format!("\"limit_rate\" {}: expected bytes per second, e.g. 500K", rate)
+            })?),
+            Some(_) => return Err("\"limit_rate\" must be a number or a string".to_string()),
+        },
+    };
+    Ok(entry)
+}
+
+/// A positive number of seconds.
+fn optional_secs(value: &Value, key: &str) -> Result<Option<Duration>, String> {
+    match value.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(secs) => secs
+            .as_f64()
+            .filter(|secs| secs.is_finite() && *secs > 0.0)
+            .map(|secs| Some(Duration::from_secs_f64(secs)))
+            .ok_or_else(|| format!("\"{}\" must be a positive number of seconds", key)),
+    }
+}
+
+/// Split a `Name: value` header line.
+pub fn parse_header(header: &str) -> Result<(String, String), String> {
+    let mut parts = header.splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim();
+    match parts.next() {
+        Some(value) if !name.is_empty() => Ok((name.to_string(), value.trim().to_string())),
+        _ => Err(format!("{}: expected \"Name: value\"", header)),
+    }
+}
+
+fn read_aria2<R: BufRead>(reader: R) -> Result<Vec<Entry>, String> {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut errors = Vec::new();
+    for (idx, line) in reader.lines().enumerate() {
+        let line_no = idx + 1;
+        let line = line.map_err(|e| format!("line {}: {}", line_no, e))?;
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            // mirrors of the same file are tab separated; only the first is used
+            let url = trimmed.split('\t').next().unwrap_or(trimmed);
+            entries.push(Entry::new(line_no, url.to_string()));
+            continue;
+        }
+        let result = match entries.last_mut() {
+            Some(entry) => aria2_option(entry, line_no, trimmed),
+            None => Err("option before any URL".to_string()),
+        };
+        if let Err(e) = result {
+            errors.push(format!("line {}: {}", line_no, e));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+    Ok(entries)
+}
+
+fn aria2_option(entry: &mut Entry, line: usize, option: &str) -> Result<(), String> {
+    let mut kv = option.splitn(2, '=');
+    let key = kv.next().unwrap_or("").trim();
+    let value = kv.next().map(str::trim).ok_or_else(|| format!("{}: expected key=value", option))?;
+    match key {
+        "out" => entry.output = Some(PathBuf::from(value)),
+        "dir" => entry.dir = Some(PathBuf::from(value)),
+        "checksum" => {
+            // aria2 spells these sha-1=HEX, sha-256=HEX and so on
+            let mut parts = value.splitn(2, '=');
+            let algorithm = parts
+                .next()
+                .and_then(Algorithm::from_name)
+                .ok_or_else(|| format!("{}: unsupported checksum", value))?;
+            let hex = parts.next().ok_or_else(|| format!("{}: expected TYPE=DIGEST", value))?;
+            entry.checksum = Some(Checksum::new(algorithm, hex)?);
+        }
+        "header" => entry.headers.push(parse_header(value)?),
+        "max-connection-per-server" => {
+            // accepted for compatibility; transfers use one connection
+            value
+                .parse::<u32>()
+                .map_err(|_| format!("{}: expected a number", option))?;
+        }
+        _ => {
+            messages::warn("warning.aria2-option", &[&line, &key]);
+        }
+    }
+    Ok(())
+}
+
+fn optional_str<'a>(value: &'a Value, key: &str) -> Result<Option<&'a str>, String> {
+    match value.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(format!("\"{}\" must be a string", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::{read_entries, Format};
+
+    const SHA1: &str = "da39a3ee5e6b4b0d3255bfef95601890afd80709";
+
+    #[test]
+    fn aria2_entries_take_their_options() {
+        let list = format!("https://host/a\n  out=one.bin\n  checksum=sha-1={}\n\nhttps://host/b\tmirror\n", SHA1);
+        let entries = read_entries(list.as_bytes(), Format::Aria2).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].output, Some(PathBuf::from("one.bin")));
+        assert_eq!(entries[0].checksum.as_ref().map(|sum| sum.hex.as_str()), Some(SHA1));
+        assert_eq!((entries[1].line, entries[1].url.as_str()), (5, "https://host/b"));
+    }
+
+    #[test]
+    fn aria2_reports_every_invalid_option() {
+        let list = "  out=early.bin\nhttps://host/a\n  out\n  checksum=crc=00\n  out=fine.bin\n";
+        assert_eq!(
+            read_entries(list.as_bytes(), Format::Aria2).map(|_| ()),
+            Err("line 1: option before any URL\nline 3: out: expected key=value\nline 4: crc=00: unsupported checksum"
+                .to_string())
+        );
+    }
+
+    #[test]
+    fn text_and_json_lines_report_every_invalid_entry() {
+        let text = "https://host/a\tout\tmd5:00\nhttps://host/b\nhttps://host/c\t\tsha1:xyz\n";
+        let err = read_entries(text.as_bytes(), Format::Text).map(|_| ()).unwrap_err();
+        assert_eq!(err.lines().map(|line| &line[..7]).collect::<Vec<_>>(), ["line 1:", "line 3:"]);
+        let jsonl = "{\"url\": \"https://host/a\"}\n{\"output\": \"b\"}\n# note\n{\"url\": 3}\n";
+        assert_eq!(
+            read_entries(jsonl.as_bytes(), Format::JsonLines).map(|_| ()),
+            Err("line 2: missing \"url\" string\nline 4: missing \"url\" string".to_string())
+        );
+    }
+}
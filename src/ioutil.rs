@@ -0,0 +1,288 @@
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const MAX_BACKOFF: Duration = Duration::from_millis(50);
+
+pub const INITIAL_BUFFER: usize = 64 * 1024;
+pub const MIN_BUFFER: usize = 16 * 1024;
+pub const DEFAULT_MAX_BUFFER: usize = 4 * 1024 * 1024;
+/// Fills quicker than this mean the link can keep a bigger buffer busy.
+const GROW_BELOW: Duration = Duration::from_millis(10);
+/// Fills slower than this hold back progress updates, so shrink.
+const SHRINK_ABOVE: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BufferSize {
+    /// Always read into a buffer of this many bytes.
+    Fixed(usize),
+    /// Start at `INITIAL_BUFFER` and adapt to throughput, up to `max`.
+    Adaptive { max: usize },
+}
+
+/// Parse a byte count with an optional binary `K`, `M` or `G` suffix.
+pub fn parse_size(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let (digits, scale) = match value.chars().last()?.to_ascii_uppercase() {
+        'K' => (&value[..value.len() - 1], 1 << 10),
+        'M' => (&value[..value.len() - 1], 1 << 20),
+        'G' => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(scale).filter(|&size| size > 0)
+}
+
+/// Parse a number of seconds, which may have a fraction.
+pub fn parse_secs(value: &str) -> Option<Duration> {
+    value
+        .trim()
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+}
+
+/// The buffer the body is read through. Each fill reads until the buffer is
+/// full; in adaptive mode the buffer doubles while fills complete quickly
+/// and halves when they take long enough to delay progress updates.
+pub struct ReadBuffer {
+    buf: Vec<u8>,
+    size: BufferSize,
+    /// How long the last fill took, when it filled the whole buffer.
+    last_fill: Option<Duration>,
+}
+
+impl ReadBuffer {
+    pub fn new(size: BufferSize) -> ReadBuffer {
+        let len = match size {
+            BufferSize::Fixed(len) => len,
+            BufferSize::Adaptive { max } => cmp::min(INITIAL_BUFFER, max),
+        };
+        ReadBuffer {
+            buf: vec![0; len],
+            size,
+            last_fill: None,
+        }
+    }
+
+    /// Read from `reader` until the buffer is full or the stream ends,
+    /// returning the bytes read. An empty slice means end of stream.
+    pub fn fill<R: Read + ?Sized>(&mut self, reader: &mut R) -> io::Result<&[u8]> {
+        if let Some(took) = self.last_fill.take() {
+            self.adapt(took);
+        }
+        let started = Instant::now();
+        let mut len = 0;
+        while len < self.buf.len() {
+            match reader.read(&mut self.buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if len == self.buf.len() {
+            self.last_fill = Some(started.elapsed());
+        }
+        Ok(&self.buf[..len])
+    }
+
+    fn adapt(&mut self, took: Duration) {
+        let max = match self.size {
+            BufferSize::Fixed(_) => return,
+            BufferSize::Adaptive { max } => max,
+        };
+        let len = self.buf.len();
+        let next = if took < GROW_BELOW {
+            cmp::min(len * 2, max)
+        } else if took > SHRINK_ABOVE {
+            cmp::max(len / 2, cmp::min(MIN_BUFFER, max))
+        } else {
+            len
+        };
+        if next != len {
+            self.buf.resize(next, 0);
+        }
+    }
+}
+
+/// A writer that retries writes interrupted by signals and waits out
+/// `WouldBlock`, which a parent process can cause by handing us a
+/// non-blocking pipe as stdout.
+pub struct RetryWriter<W> {
+    inner: W,
+}
+
+impl<W: Write> RetryWriter<W> {
+    pub fn new(inner: W) -> RetryWriter<W> {
+        RetryWriter { inner }
+    }
+}
+
+fn retry<T, F>(mut op: F) -> io::Result<T>
+where
+    F: FnMut() -> io::Result<T>,
+{
+    let mut backoff = Duration::from_millis(1);
+    loop {
+        match op() {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                thread::sleep(backoff);
+                backoff = cmp::min(backoff * 2, MAX_BACKOFF);
+            }
+            result => return result,
+        }
+    }
+}
+
+impl<W: Write> Write for RetryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        retry(|| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        retry(|| inner.flush())
+    }
+}
+
+/// A writer that writes everything to both `first` and `second`.
+pub struct Tee<A, B> {
+    first: A,
+    second: B,
+}
+
+impl<A: Write, B: Write> Tee<A, B> {
+    pub fn new(first: A, second: B) -> Tee<A, B> {
+        Tee { first, second }
+    }
+}
+
+impl<A: Write, B: Write> Write for Tee<A, B> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.first.write(buf)?;
+        self.second.write_all(&buf[..len])?;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{Cursor, Read, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{parse_size, BufferSize, ReadBuffer, RetryWriter, INITIAL_BUFFER, MIN_BUFFER};
+
+    /// Fails every write with `Interrupted`, then `WouldBlock`, before
+    /// taking a short write, as a signal-heavy non-blocking pipe would.
+    struct Flaky {
+        written: Vec<u8>,
+        calls: usize,
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            match self.calls % 3 {
+                1 => Err(io::Error::from(io::ErrorKind::Interrupted)),
+                2 => Err(io::Error::from(io::ErrorKind::WouldBlock)),
+                _ => {
+                    let len = buf.len().min(1000);
+                    self.written.extend_from_slice(&buf[..len]);
+                    Ok(len)
+                }
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.calls += 1;
+            if self.calls.is_multiple_of(2) {
+                Err(io::Error::from(io::ErrorKind::WouldBlock))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn retry_writer_rides_out_interrupted_and_would_block() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let mut writer = RetryWriter::new(Flaky { written: Vec::new(), calls: 0 });
+        writer.write_all(&data).unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.inner.written, data);
+    }
+
+    #[test]
+    fn retry_writer_passes_other_errors_on() {
+        struct Full;
+        impl Write for Full {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::Error::from(io::ErrorKind::WriteZero))
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+        let err = RetryWriter::new(Full).write_all(b"x").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+    }
+
+    /// Fills `buf` from `reader` `times` times and returns the length of
+    /// each fill.
+    fn fills<R: Read>(buf: &mut ReadBuffer, reader: &mut R, times: usize) -> Vec<usize> {
+        (0..times).map(|_| buf.fill(reader).unwrap().len()).collect()
+    }
+
+    #[test]
+    fn adaptive_buffer_grows_while_fills_are_quick() {
+        let mut reader = Cursor::new(vec![0; 16 << 20]);
+        let mut buf = ReadBuffer::new(BufferSize::Adaptive { max: 256 * 1024 });
+        let kib: Vec<usize> = fills(&mut buf, &mut reader, 4).into_iter().map(|len| len >> 10).collect();
+        assert_eq!(kib, vec![64, 128, 256, 256]);
+    }
+
+    #[test]
+    fn adaptive_buffer_shrinks_when_fills_are_slow() {
+        /// Takes longer than a progress interval over every read.
+        struct Slow;
+        impl Read for Slow {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                thread::sleep(Duration::from_millis(110));
+                Ok(buf.len())
+            }
+        }
+        let mut buf = ReadBuffer::new(BufferSize::Adaptive { max: 4 << 20 });
+        assert_eq!(fills(&mut buf, &mut Slow, 4), vec![INITIAL_BUFFER, INITIAL_BUFFER / 2, MIN_BUFFER, MIN_BUFFER]);
+    }
+
+    #[test]
+    fn fixed_buffer_keeps_its_size() {
+        let mut reader = Cursor::new(vec![0; 100_000]);
+        let mut buf = ReadBuffer::new(BufferSize::Fixed(8192));
+        let lens = fills(&mut buf, &mut reader, 14);
+        assert!(lens[..12].iter().all(|&len| len == 8192));
+        assert_eq!(lens[12..], [100_000 - 12 * 8192, 0]);
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("64k"), Some(64 << 10));
+        assert_eq!(parse_size("4M"), Some(4 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("0"), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("1.5M"), None);
+    }
+}
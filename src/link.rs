@@ -0,0 +1,50 @@
+use reqwest::header::{HeaderMap, LINK};
+use reqwest::Url;
+
+/// Find the `rel="next"` target among the `Link` headers, resolved against
+/// the URL of the response that carried them.
+pub fn next_link(headers: &HeaderMap, base: &Url) -> Option<Url> {
+    headers
+        .get_all(LINK)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(parse_links)
+        .find(|(_, rels)| rels.iter().any(|rel| rel.eq_ignore_ascii_case("next")))
+        .and_then(|(target, _)| base.join(&target).ok())
+}
+
+/// Split a `Link` header value into `(target, rel values)` pairs. Targets are
+/// enclosed in `<>` and may themselves contain commas or semicolons.
+fn parse_links(value: &str) -> Vec<(String, Vec<String>)> {
+    let mut links = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let target = rest[start + 1..end].trim().to_string();
+        rest = &rest[end + 1..];
+
+        // parameters run until the next link begins
+        let params = &rest[..rest.find('<').unwrap_or(rest.len())];
+        let rels = params
+            .split(';')
+            .filter_map(|param| {
+                let mut kv = param.splitn(2, '=');
+                let key = kv.next()?.trim();
+                let value = kv
+                    .next()?
+                    .trim_matches(|c: char| c == '"' || c == ',' || c.is_whitespace());
+                if key.eq_ignore_ascii_case("rel") {
+                    Some(value.split_whitespace().map(String::from).collect::<Vec<_>>())
+                } else {
+                    None
+                }
+            })
+            .flatten()
+            .collect();
+        links.push((target, rels));
+    }
+    links
+}
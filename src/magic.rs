@@ -0,0 +1,188 @@
+use std::ffi::OsString;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// How much of the start of a body is kept for detection; tar's magic
+/// sits at offset 257.
+pub const SNIFF_LEN: usize = 4096;
+
+/// A file type recognisable from its first bytes.
+#[derive(Debug, PartialEq)]
+pub struct Kind {
+    pub name: &'static str,
+    /// File extensions for the type, the usual one first.
+    extensions: &'static [&'static str],
+    /// Content types servers send for it.
+    mime: &'static [&'static str],
+    /// `(offset, bytes)` that must all match.
+    magic: &'static [(usize, &'static [u8])],
+}
+
+/// Every type `detect` knows, in the order they are tried.
+static KINDS: &[Kind] = &[
+    Kind {
+        name: "gzip",
+        extensions: &["gz", "tgz"],
+        mime: &["application/gzip", "application/x-gzip"],
+        magic: &[(0, b"\x1f\x8b")],
+    },
+    Kind {
+        name: "zstd",
+        extensions: &["zst", "tzst"],
+        mime: &["application/zstd"],
+        magic: &[(0, b"\x28\xb5\x2f\xfd")],
+    },
+    Kind {
+        name: "xz",
+        extensions: &["xz", "txz"],
+        mime: &["application/x-xz"],
+        magic: &[(0, b"\xfd7zXZ\x00")],
+    },
+    Kind {
+        name: "bzip2",
+        extensions: &["bz2", "tbz2", "tbz"],
+        mime: &["application/x-bzip2"],
+        magic: &[(0, b"BZh")],
+    },
+    Kind {
+        name: "zip",
+        extensions: &["zip", "jar", "whl", "apk"],
+        mime: &["application/zip", "application/java-archive"],
+        magic: &[(0, b"PK\x03\x04")],
+    },
+    Kind {
+        name: "7z",
+        extensions: &["7z"],
+        mime: &["application/x-7z-compressed"],
+        magic: &[(0, b"7z\xbc\xaf\x27\x1c")],
+    },
+    Kind {
+        name: "elf",
+        extensions: &["so"],
+        mime: &["application/x-elf", "application/x-executable", "application/x-sharedlib"],
+        magic: &[(0, b"\x7fELF")],
+    },
+    Kind {
+        name: "pe",
+        extensions: &["exe", "dll"],
+        mime: &["application/vnd.microsoft.portable-executable", "application/x-msdownload"],
+        magic: &[(0, b"MZ")],
+    },
+    Kind {
+        name: "pdf",
+        extensions: &["pdf"],
+        mime: &["application/pdf"],
+        magic: &[(0, b"%PDF-")],
+    },
+    Kind {
+        name: "png",
+        extensions: &["png"],
+        mime: &["image/png"],
+        magic: &[(0, b"\x89PNG\r\n\x1a\n")],
+    },
+    Kind {
+        name: "tar",
+        extensions: &["tar"],
+        mime: &["application/x-tar"],
+        magic: &[(257, b"ustar")],
+    },
+];
+
+/// Extensions that stand for a compressed tar, such as `tgz`.
+const TAR_SHORTHANDS: [&str; 5] = ["tgz", "tzst", "txz", "tbz2", "tbz"];
+
+/// The type of a body starting with `prefix`.
+pub fn detect(prefix: &[u8]) -> Option<&'static Kind> {
+    KINDS.iter().find(|kind| {
+        kind.magic
+            .iter()
+            .all(|&(offset, magic)| prefix.get(offset..offset + magic.len()) == Some(magic))
+    })
+}
+
+/// The type `path`'s extension claims.
+pub fn by_extension(path: &Path) -> Option<&'static Kind> {
+    let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+    KINDS.iter().find(|kind| kind.extensions.contains(&ext.as_str()))
+}
+
+/// The type a `Content-Type` value claims. Generic types such as
+/// `application/octet-stream` claim nothing.
+pub fn by_content_type(content_type: &str) -> Option<&'static Kind> {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    KINDS.iter().find(|kind| kind.mime.contains(&essence.as_str()))
+}
+
+/// `path` with its extension replaced by the usual one for `kind`. A tar
+/// stays a tar when only its compression was mislabelled, and a last
+/// extension that names no known type is kept with the new one added.
+pub fn fixed_path(path: &Path, kind: &Kind) -> PathBuf {
+    let strip = |path: &Path| path.with_extension("").into_os_string();
+    let ext = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_default();
+    let (mut name, tar): (OsString, bool) = if ext == "tar" || TAR_SHORTHANDS.contains(&ext.as_str()) {
+        (strip(path), true)
+    } else if by_extension(path).is_some() {
+        let stem = strip(path);
+        match Path::new(&stem).extension() {
+            Some(inner) if inner.eq_ignore_ascii_case("tar") => (strip(Path::new(&stem)), true),
+            _ => (stem, false),
+        }
+    } else {
+        (path.as_os_str().to_owned(), false)
+    };
+    if tar && ["gzip", "zstd", "xz", "bzip2"].contains(&kind.name) {
+        name.push(".tar");
+    }
+    name.push(".");
+    name.push(kind.extensions[0]);
+    PathBuf::from(name)
+}
+
+/// Whether the type the body was `detected` as disagrees with what its
+/// `Content-Type` or the extension of `path` claims.
+pub fn mismatch(detected: Option<&Kind>, content_type: Option<&str>, path: Option<&Path>) -> bool {
+    let claims = [content_type.and_then(by_content_type), path.and_then(by_extension)];
+    claims.iter().flatten().any(|claimed| Some(*claimed) != detected)
+}
+
+/// A reader that keeps the first `SNIFF_LEN` bytes read through it, when
+/// `enabled`, and changes nothing.
+pub struct Sniffer<R> {
+    inner: R,
+    prefix: Vec<u8>,
+    limit: usize,
+}
+
+impl<R: Read> Sniffer<R> {
+    pub fn new(inner: R, enabled: bool) -> Sniffer<R> {
+        let limit = if enabled { SNIFF_LEN } else { 0 };
+        Sniffer {
+            inner,
+            prefix: Vec::with_capacity(limit),
+            limit,
+        }
+    }
+
+    /// The type of what was read so far, if detection is enabled.
+    pub fn detected(&self) -> Option<&'static Kind> {
+        if self.limit == 0 {
+            None
+        } else {
+            detect(&self.prefix)
+        }
+    }
+}
+
+impl<R: Read> Read for Sniffer<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        let keep = len.min(self.limit - self.prefix.len());
+        self.prefix.extend_from_slice(&buf[..keep]);
+        Ok(len)
+    }
+}
@@ -1,44 +1,469 @@
+extern crate chrono;
 #[macro_use]
 extern crate clap;
 extern crate data_encoding;
 extern crate hyper;
-extern crate pbr;
+extern crate libc;
+extern crate percent_encoding;
 extern crate reqwest;
 extern crate digest;
+extern crate rustls;
+extern crate rustls_pemfile;
+#[macro_use]
+extern crate serde_json;
 extern crate sha2;
 extern crate sha1;
+extern crate webpki_roots;
+
+mod bar;
+mod blake3;
+mod capabilities;
+mod checksum;
+mod clock;
+mod console;
+mod control;
+mod cookies;
+mod crc32;
+mod credentials;
+mod error;
+mod events;
+mod filename;
+mod fsutil;
+mod gzip;
+mod har;
+mod hasher;
+mod inflate;
+mod input;
+mod ioutil;
+mod link;
+mod magic;
+mod manifest;
+mod md5;
+mod messages;
+mod meta;
+mod motw;
+mod netrc;
+mod pinning;
+mod progress;
+mod ranges;
+mod ratelimit;
+mod refresh;
+mod resume;
+mod sandbox;
+mod segments;
+mod selftest;
+mod socks;
+mod tcpinfo;
+mod template;
+mod timing;
+mod tls_config;
+mod upload;
+mod verify;
+mod xattr;
+mod zip;
 
-use clap::{App, Arg};
-use data_encoding::HEXLOWER;
-use pbr::{ProgressBar, Units};
+use chrono::{DateTime, SecondsFormat, Utc};
+use clap::{App, AppSettings, Arg, SubCommand};
+use progress::{Phase, Progress, Renderer};
+use serde_json::Value;
 
 use reqwest::header;
 use reqwest::tls;
 use hyper::Uri;
-use digest::Digest;
-use sha1::Sha1;
-use sha2::Sha256;
+use checksum::{Algorithm, Checksum, FragmentDigest};
+use console::Stream;
+use error::{BodyRead, DownloadError, WriteFailed};
+use hasher::{Digests, HashWriter, Hasher};
+use ioutil::{BufferSize, ReadBuffer, RetryWriter};
+use messages::{Lang, Message};
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Display;
+use std::fs;
 use std::fs::File;
+use std::hash::BuildHasher;
 use std::io;
+use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::prelude::*;
-use std::path::Path;
-use std::process;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 static DEFAULT_USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
-const EXIT_URL_FAILURE: i32 = 1;
-const EXIT_OUTPUT_FAILURE: i32 = 2;
 
+#[derive(Default)]
 struct DownloadResult {
     bytes_written: u64,
-    sha1: String,
-    sha256: String,
+    digests: Digests,
+    url_effective: String,
+    /// The status and reported headers of the response the body came in.
+    response: Option<Value>,
+    /// The request behind the body, with `--record-request`.
+    request: Option<Value>,
+    timing: timing::Phases,
+    /// The address that served good content after others failed
+    /// verification.
+    served_by: Option<IpAddr>,
+    /// What `--detect-type` recognised the body as.
+    detected_type: Option<&'static str>,
+    /// Where the output went instead of the job's path: the name
+    /// `--content-disposition` took from the server, or where
+    /// `--fix-extension` moved it.
+    renamed_to: Option<PathBuf>,
+    /// The copy `--decompress-keep-both` unpacked beside the output.
+    decompressed: Option<Decompressed>,
+    /// How the connection the body arrived on fared, where the kernel says.
+    tcp: Option<tcpinfo::Stats>,
+    /// Where the fetched bytes, and so the digests, begin, with
+    /// `--start-offset`.
+    start_offset: Option<u64>,
+    /// The length of the partial output `--continue` carried on from.
+    resumed_from: Option<u64>,
+    /// How many segments `--segments` fetched the body in.
+    segments: Option<u64>,
+    /// How long the job took, retries included.
+    duration: Option<Duration>,
+    /// The retries it took.
+    retries: u32,
+    /// The body's busiest second, in bytes per second.
+    peak_rate: Option<f64>,
+    /// `--timestamping` or `--etag` found the server's copy no different
+    /// from the output, which was left alone.
+    not_modified: bool,
+}
+
+/// A decompressed copy of a `.gz` output and its digests.
+struct Decompressed {
+    path: PathBuf,
+    bytes_written: u64,
+    digests: Digests,
+}
+
+/// What happened to a job that did not fail.
+enum Transfer {
+    Downloaded(Box<DownloadResult>),
+    /// The output already existed and satisfied the skip policy.
+    Skipped,
+    /// `--fail-fast` stopped the batch before the job was started.
+    NotAttempted,
+}
+
+/// What `--fail-fast` does once an entry of a batch has failed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FailFast {
+    /// Start no further downloads; let those under way finish.
+    Finish,
+    /// Also cancel the downloads under way.
+    Abort,
+}
+
+/// How transient failures are retried: `--retry`, `--retry-delay` and
+/// `--retry-max-time`. An entry's own `retries` replaces `count`.
+#[derive(Clone, Copy, Debug)]
+struct Retry {
+    count: u32,
+    /// The wait before the first retry, doubled for each one after it.
+    delay: Duration,
+    /// How long after the first attempt no further retry is started.
+    max_time: Option<Duration>,
+}
+
+impl Retry {
+    /// The wait before retry `attempt`, counting from 1: the delay doubled
+    /// up to 32 times, then scaled by a random factor between 1/2 and 1 so
+    /// that clients which failed together do not all retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let doubled = self.delay * (1 << cmp::min(attempt - 1, 5));
+        let random = RandomState::new().hash_one(attempt);
+        doubled.mul_f64(0.5 + random as f64 / u64::MAX as f64 / 2.0)
+    }
+}
+
+/// How much evidence is needed before an existing output is left alone.
+/// Each level is stronger, and costlier, than the one before.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SkipPolicy {
+    /// The file exists and is not empty; no network access at all.
+    Missing,
+    /// Size matches the remote Content-Length and the file is at least as
+    /// new as Last-Modified.
+    Existing,
+    /// The file hashes to the job's expected checksum.
+    Verified,
+}
+
+/// What `--no-clobber` and `--auto-rename` do with an output that is
+/// already there.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Clobber {
+    /// Fail the job.
+    Refuse,
+    /// Write to the first of `<output>.1`, `<output>.2`, ... that is free.
+    Rename,
+}
+
+/// A single URL to fetch along with where it should land.
+#[derive(Clone)]
+struct Job {
+    url: String,
+    output: Option<PathBuf>,
+    /// The output was derived from the remote name rather than given.
+    remote_named: bool,
+    checksum: Option<Checksum>,
+    /// Headers sent only for this job, from its input-file entry.
+    headers: header::HeaderMap,
+    /// Timeouts, retries and rate limit from its input-file entry.
+    overrides: input::Overrides,
+    /// The `--output-template` expansion that named the output, which
+    /// waits under a pending name when it needs the body's SHA-256.
+    named: Option<template::Expanded>,
+}
+
+/// A body streamed from stdin with `--stdin-body`, sent before the job.
+struct Upload {
+    url: String,
+    method: reqwest::Method,
+    headers: header::HeaderMap,
+    content_length: Option<u64>,
+}
+
+/// Settings used to build the HTTP client.
+#[derive(Clone)]
+struct ClientOptions {
+    max_redirects: usize,
+    reject_url_credentials: bool,
+    insecure: bool,
+    insecure_hostname: bool,
+    tcp_keepalive: Option<Duration>,
+    tcp_nodelay: bool,
+    verbose: bool,
+    /// Connect to this address for this host instead of resolving it.
+    pin: Option<(String, SocketAddr)>,
+    /// The per-host TLS profiles, and the one this client uses in place
+    /// of the global TLS settings.
+    profiles: Arc<tls_config::Profiles>,
+    profile: Option<usize>,
+    /// Limits for the whole of each request and for connecting: an input
+    /// entry's own, or `--max-time` and `--connect-timeout`.
+    timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    /// `--read-timeout`, or `--speed-time` when shorter: the longest wait
+    /// for the response or more of the body; reqwest's 30 seconds otherwise.
+    read_timeout: Option<Duration>,
+    /// `--proxy` or the SOCKS relay, used in place of the proxy environment
+    /// variables, and how `-v` shows it.
+    proxy: Option<(String, String)>,
+    /// Every redirect is left to `send_request`, which keeps the cookie
+    /// jar up to date and `--dump-header`, `--har` and `-v` complete on the
+    /// way.
+    manual_redirects: bool,
+    /// `--cacert`, `--capath` and `--cert`, for hosts whose TLS profile
+    /// does not say otherwise.
+    tls: Arc<tls_config::Defaults>,
+    /// `--http1.1` or `--http2-prior-knowledge`; negotiated otherwise.
+    http_version: Option<reqwest::Version>,
+    /// Built from options whose settings were already reported.
+    derived: bool,
+}
+
+/// The client for each per-host TLS profile, and the one for every other
+/// host, so that each request, redirects included, goes out with its
+/// host's TLS settings.
+#[derive(Default)]
+struct ClientPool {
+    profiles: Arc<tls_config::Profiles>,
+    default: Option<reqwest::blocking::Client>,
+    by_profile: Vec<reqwest::blocking::Client>,
+    /// What the clients were built from, for jobs that need their own.
+    options: Option<ClientOptions>,
+}
+
+impl ClientPool {
+    fn build(opts: &ClientOptions) -> reqwest::Result<ClientPool> {
+        let mut by_profile = Vec::with_capacity(opts.profiles.iter().count());
+        for idx in 0..opts.profiles.iter().count() {
+            let profiled = ClientOptions {
+                profile: Some(idx),
+                derived: true,
+                ..opts.clone()
+            };
+            by_profile.push(build_client(&profiled)?);
+        }
+        Ok(ClientPool {
+            profiles: opts.profiles.clone(),
+            default: Some(build_client(opts)?),
+            by_profile,
+            options: Some(opts.clone()),
+        })
+    }
+
+    /// A client of its own for a job whose input entry sets timeouts.
+    fn for_entry(
+        &self,
+        url: &str,
+        overrides: &input::Overrides,
+    ) -> Option<reqwest::Result<reqwest::blocking::Client>> {
+        let opts = self.options.as_ref().filter(|_| overrides.has_timeouts())?;
+        let own = ClientOptions {
+            timeout: overrides.max_time.or(opts.timeout),
+            connect_timeout: overrides.connect_timeout.or(opts.connect_timeout),
+            profile: reqwest::Url::parse(url).ok().and_then(|url| self.profiles.for_url(&url)),
+            derived: true,
+            ..opts.clone()
+        };
+        Some(build_client(&own))
+    }
+
+    /// The client for requests to `url`; none before the pool is built.
+    fn for_url(&self, url: &str) -> Option<&reqwest::blocking::Client> {
+        let profile = reqwest::Url::parse(url).ok().and_then(|url| self.profiles.for_url(&url));
+        match profile {
+            Some(idx) => self.by_profile.get(idx),
+            None => self.default.as_ref(),
+        }
+    }
+
+    /// The client to use instead when a request moves from `from` to a
+    /// host with another TLS profile.
+    fn switch(&self, from: &str, to: &reqwest::Url) -> Option<&reqwest::blocking::Client> {
+        let before = reqwest::Url::parse(from).ok().and_then(|from| self.profiles.for_url(&from));
+        if self.profiles.for_url(to) == before {
+            return None;
+        }
+        self.for_url(to.as_str())
+    }
+
+    /// The name of the TLS profile requests to `url` use.
+    fn label(&self, url: &reqwest::Url) -> &str {
+        match self.profiles.for_url(url) {
+            Some(idx) => &self.profiles.get(idx).label,
+            None => "default",
+        }
+    }
+}
+
+/// Settings shared by every job in an invocation.
+struct Options<'a> {
+    user_agent: &'a str,
+    /// `-H` headers, sent with every request after the user agent.
+    headers: header::HeaderMap,
+    /// `--cookie` and `--cookie-jar`: sent with requests and kept up to
+    /// date with the cookies servers set.
+    cookies: Option<Arc<cookies::Jar>>,
+    verbose: bool,
+    create_dirs: bool,
+    /// `-P`, made when an output under it is first written.
+    output_dir: Option<&'a Path>,
+    /// `--no-follow-symlinks`, or `--follow-symlinks` for `-O` names.
+    follow_symlinks: Option<bool>,
+    /// Canonical `--restrict-to` directory.
+    restrict_to: Option<PathBuf>,
+    query: Vec<(String, String)>,
+    query_replace: bool,
+    progress: progress::Mode,
+    progress_interval: Duration,
+    follow_link_next: bool,
+    max_parts: usize,
+    save_validators: Option<meta::Storage>,
+    skip: Option<SkipPolicy>,
+    clobber: Option<Clobber>,
+    retry: Retry,
+    /// `--max-time`, for jobs whose entry sets no `max_time` of its own.
+    max_time: Option<Duration>,
+    /// Chunk size for `--pipelined-ranges`.
+    pipelined_ranges: Option<u64>,
+    /// `--start-offset`: fetch only the bytes from here on.
+    start_offset: Option<u64>,
+    /// `--continue`: finish a partial output instead of starting over.
+    continue_partial: bool,
+    /// `--keep-partial`: a cancelled download leaves what it wrote.
+    keep_partial: bool,
+    /// `--fail`: a 4xx or 5xx answer fails the job instead of being saved.
+    fail_on_status: bool,
+    /// Date outputs from `Last-Modified`, unless `--no-remote-time`.
+    remote_time: bool,
+    /// Record where outputs came from in extended attributes, unless
+    /// `--no-provenance`.
+    provenance: bool,
+    /// `--mark-of-the-web`, where Windows reads it.
+    mark_of_the_web: bool,
+    /// `--timestamping`: fetch only what is newer than the output.
+    timestamping: bool,
+    /// `--etag`: fetch only what differs from the output's saved ETag.
+    etag: bool,
+    /// `--content-disposition`: name outputs after the server's suggestion
+    /// rather than the URL.
+    content_disposition: bool,
+    /// `--segments`: fetch a file output over up to this many connections.
+    segments: Option<u64>,
+    max_redirects: usize,
+    follow_meta_refresh: bool,
+    /// Hosts besides the page's own origin that meta refreshes may lead to.
+    meta_refresh_hosts: Vec<String>,
+    /// Rate limits reported by servers, unless `--ignore-ratelimit-headers`.
+    rate_limits: Option<ratelimit::Limits>,
+    buffer: BufferSize,
+    record_request: bool,
+    /// Where `--digest-fd` writes a JSON line per completed transfer.
+    digest_fd: Option<Mutex<File>>,
+    /// Where `--dump-header` writes the headers of every response.
+    dump_header: Option<Mutex<File>>,
+    /// The exchanges `--har` records.
+    har: Option<Arc<har::Archive>>,
+    zip_member: Option<String>,
+    /// `--detect-type`, or `--fix-extension` which needs it.
+    detect_type: bool,
+    fix_extension: bool,
+    /// `--decompress-keep-both`: also unpack `.gz` outputs beside them.
+    decompress_keep_both: bool,
+    connections: timing::Connections,
+    /// Clients by TLS profile, filled in once the sandbox is set up.
+    clients: ClientPool,
+    fail_fast: Option<FailFast>,
+    /// How to build clients pinned to other addresses of a host whose
+    /// content failed verification, unless `--no-alt-address-retry`.
+    alt_address_retry: Option<ClientOptions>,
 }
 
 fn get_filename(url: &str) -> Option<&str> {
     url.rsplit('/').next()
 }
 
+/// Derive an output path from the last segment of the URL path, placed
+/// under `output_dir` when given. The segment is percent-decoded unless
+/// `decode` is false, then sanitized.
+fn remote_name_path(
+    url: &str,
+    output_dir: Option<&Path>,
+    decode: bool,
+) -> Result<PathBuf, DownloadError> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|e| DownloadError::Input(format!("{}: {}", url, e).into()))?;
+    let raw = get_filename(uri.path())
+        .map(filename::strip_matrix)
+        .filter(|filename| !filename.is_empty())
+        .ok_or_else(|| DownloadError::input("error.no-filename", &[&url]))?;
+    let decoded = if decode {
+        filename::decode(raw)
+    } else {
+        raw.to_string()
+    };
+    let filename = filename::sanitize(&decoded)
+        .ok_or_else(|| DownloadError::input("error.no-filename", &[&url]))?;
+    Ok(match output_dir {
+        Some(dir) => dir.join(filename),
+        None => PathBuf::from(filename),
+    })
+}
+
 fn write_status(writer: &mut dyn Write, resp: &reqwest::blocking::Response) {
     let _ = writeln!(writer, "{:?} {}", resp.version(), resp.status());
 }
@@ -49,188 +474,4993 @@ fn write_headers(writer: &mut dyn Write, resp: &reqwest::blocking::Response) {
     }
 }
 
-fn http_download(
+/// Trace `req` on stderr for `-v`, as curl does: `>` before the request
+/// line and each header, with the values of sensitive ones left out.
+fn trace_request(req: &reqwest::blocking::Request) {
+    let mut trace = console::Lines::new(Stream::Stderr);
+    let _ = writeln!(trace, "> {} {} {:?}", req.method(), credentials::redact(req.url().as_str()), req.version());
+    for (name, value) in req.headers() {
+        let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+            "[redacted]".to_string()
+        } else {
+            String::from_utf8_lossy(value.as_bytes()).into_owned()
+        };
+        let _ = writeln!(trace, "> {}: {}", name, value);
+    }
+}
+
+/// Trace the connection and the headers of `resp`, the latter after `<`.
+fn trace_response(resp: &reqwest::blocking::Response, phases: Option<&timing::Phases>) {
+    let mut trace = console::Lines::new(Stream::Stderr);
+    if let Some(addr) = resp.remote_addr() {
+        let reused = match phases.and_then(|phases| phases.connection_reused) {
+            Some(true) => "reused connection",
+            Some(false) => "new connection",
+            None => "connection",
+        };
+        // rustls' version and cipher stay inside the HTTP client
+        let _ = writeln!(trace, "* {} to {}, {:?}", reused, addr, resp.version());
+    }
+    let _ = writeln!(trace, "< {:?} {}", resp.version(), resp.status());
+    for (name, value) in resp.headers() {
+        let _ = writeln!(trace, "< {}: {}", name, String::from_utf8_lossy(value.as_bytes()));
+    }
+}
+
+/// The proxy variables reqwest reads when no `--proxy` is given; which one
+/// applies depends on the scheme of the URL.
+const PROXY_VARS: [&str; 6] = ["HTTPS_PROXY", "https_proxy", "HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"];
+
+/// The proxies requests may go through, for `-v`, with any credentials
+/// left out.
+fn proxies_in_use(opts: &ClientOptions) -> Vec<String> {
+    if let Some((_, ref shown)) = opts.proxy {
+        return vec![shown.clone()];
+    }
+    PROXY_VARS
+        .iter()
+        .filter_map(|name| match env::var(name) {
+            Ok(ref url) if !url.is_empty() => Some(format!("{} (from {})", credentials::redact(url), name)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn build_client(opts: &ClientOptions) -> reqwest::Result<reqwest::blocking::Client> {
+    // redirects to URLs with credentials are stopped here and followed by
+    // http_request, which moves the credentials into a header; with a
+    // cookie jar, --dump-header, --har or -v every redirect is, so that
+    // each hop passes through send_request
+    let limited = reqwest::redirect::Policy::limited(opts.max_redirects);
+    let (max, reject) = (opts.max_redirects, opts.reject_url_credentials);
+    let (profiles, own, manual) = (opts.profiles.clone(), opts.profile, opts.manual_redirects);
+    let policy = reqwest::redirect::Policy::custom(move |attempt| {
+        let with_credentials = credentials::present(attempt.url());
+        if attempt.previous().len() >= max {
+            limited.redirect(attempt)
+        } else if with_credentials && reject {
+            let msg = format!(
+                "redirect to {} has credentials in the URL (--reject-url-credentials)",
+                credentials::redact(attempt.url().as_str())
+            );
+            attempt.error(msg)
+        } else if with_credentials || manual || profiles.for_url(attempt.url()) != own {
+            // a host with another TLS profile needs another client
+            attempt.stop()
+        } else {
+            timing::follow(attempt.previous(), attempt.url());
+            limited.redirect(attempt)
+        }
+    });
+    let mut builder = reqwest::blocking::Client::builder()
+        .redirect(policy)
+        .min_tls_version(tls::Version::TLS_1_2)
+        .tcp_keepalive(opts.tcp_keepalive)
+        .tcp_nodelay(opts.tcp_nodelay);
+    if let Some((ref host, addr)) = opts.pin {
+        builder = builder.resolve(host, addr);
+    }
+    // reqwest's blocking timeout bounds each wait rather than the whole
+    // request, which the job's deadline covers
+    let wait = match (opts.read_timeout, opts.timeout) {
+        (Some(read), Some(total)) => Some(read.min(total)),
+        (read, total) => read.or(total),
+    };
+    if let Some(timeout) = wait {
+        builder = builder.timeout(timeout);
+    }
+    if let Some(timeout) = opts.connect_timeout {
+        builder = builder.connect_timeout(timeout);
+    }
+    match opts.http_version {
+        Some(reqwest::Version::HTTP_11) => builder = builder.http1_only(),
+        Some(reqwest::Version::HTTP_2) => builder = builder.http2_prior_knowledge(),
+        _ => {}
+    }
+    if let Some((ref url, _)) = opts.proxy {
+        // hosts NO_PROXY names still bypass an explicit proxy
+        builder = builder.proxy(reqwest::Proxy::all(url.as_str())?.no_proxy(reqwest::NoProxy::from_env()));
+    }
+
+    // a pinned or per-profile client repeats a configuration that was
+    // already reported
+    let report = !opts.derived;
+    if opts.verbose && report {
+        let keepalive = opts
+            .tcp_keepalive
+            .map(|d| format!("{}s", d.as_secs()))
+            .unwrap_or_else(|| "off".to_string());
+        console::err(&format!(
+            "socket options: tcp_nodelay={} tcp_keepalive={}",
+            if opts.tcp_nodelay { "on" } else { "off" },
+            keepalive
+        ));
+        for proxy in proxies_in_use(opts) {
+            console::err(&format!("proxy: {}", proxy));
+        }
+    }
+
+    if let Some(idx) = opts.profile {
+        // the more specific per-host profile wins over global TLS options
+        builder = builder.use_preconfigured_tls(with_alpn(opts.profiles.get(idx).config.clone(), opts));
+    } else if opts.insecure {
+        if report {
+            messages::warn("warning.insecure", &[]);
+        }
+        if opts.tls.pins.is_empty() {
+            builder = builder.danger_accept_invalid_certs(true);
+        } else {
+            builder = builder.use_preconfigured_tls(with_alpn(tls_config::pinned_config(&opts.tls, true), opts));
+        }
+    } else if opts.insecure_hostname {
+        if report {
+            messages::warn("warning.insecure-hostname", &[]);
+        }
+        let config = tls_config::insecure_hostname_config(&opts.tls);
+        builder = builder.use_preconfigured_tls(with_alpn(config, opts));
+    } else if !opts.tls.pins.is_empty() {
+        builder = builder.use_preconfigured_tls(with_alpn(tls_config::pinned_config(&opts.tls, false), opts));
+    } else {
+        for cert in &opts.tls.roots {
+            builder = builder.add_root_certificate(reqwest::Certificate::from_der(&cert.0)?);
+        }
+        if let Some(ref cert) = opts.tls.client_cert {
+            builder = builder.identity(reqwest::Identity::from_pem(&cert.pem)?);
+        }
+    }
+
+    builder.build()
+}
+
+/// Offer only the protocol `--http1.1` or `--http2-prior-knowledge` is
+/// to speak over a TLS config of our own.
+fn with_alpn(mut config: rustls::ClientConfig, opts: &ClientOptions) -> rustls::ClientConfig {
+    match opts.http_version {
+        Some(reqwest::Version::HTTP_11) => config.alpn_protocols = vec![b"http/1.1".to_vec()],
+        Some(reqwest::Version::HTTP_2) => config.alpn_protocols = vec![b"h2".to_vec()],
+        _ => {}
+    }
+    config
+}
+
+/// Append `--query` parameters to `url`. With `replace`, existing
+/// parameters sharing a key with a new one are dropped first; the others
+/// are kept byte for byte, so that signed URLs stay valid.
+fn apply_query(url: &mut reqwest::Url, query: &[(String, String)], replace: bool) {
+    if query.is_empty() {
+        return;
+    }
+    if replace {
+        let kept: Vec<&str> = url
+            .query()
+            .unwrap_or("")
+            .split('&')
+            .filter(|segment| !segment.is_empty())
+            .filter(|segment| !query.iter().any(|(key, _)| *key == query_key(segment)))
+            .collect();
+        let kept = kept.join("&");
+        url.set_query(Some(&kept).filter(|kept| !kept.is_empty()).map(String::as_str));
+    }
+    url.query_pairs_mut().extend_pairs(query);
+}
+
+/// The decoded key of a raw `key=value` query parameter.
+fn query_key(segment: &str) -> String {
+    let key = segment.split('=').next().unwrap_or("").replace('+', " ");
+    percent_encoding::percent_decode_str(&key).decode_utf8_lossy().into_owned()
+}
+
+/// Headers whose values are never written to a request record.
+const SENSITIVE_HEADERS: [&str; 3] = ["authorization", "proxy-authorization", "cookie"];
+
+/// The request a response answers, as `--record-request` writes it.
+#[derive(Clone)]
+struct SentRequest(Value);
+
+/// Describe a request as it went out: its method, its URL after the
+/// client's own redirects, and every header sent with it. Sensitive headers
+/// are listed with a `redacted` reason in place of their value, including
+/// those the client drops when a redirect leaves the original origin.
+fn recorded_request(
+    method: &reqwest::Method,
+    sent: &header::HeaderMap,
+    requested: &reqwest::Url,
+    url: &reqwest::Url,
+) -> Value {
+    let mut headers = header::HeaderMap::new();
+    if let Some(host) = url.host_str() {
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+        headers.insert(header::HOST, header::HeaderValue::from_str(&host).unwrap());
+    }
+    headers.extend(sent.clone());
+    // reqwest's default, for requests without one of their own
+    if !headers.contains_key(header::ACCEPT) {
+        headers.insert(header::ACCEPT, header::HeaderValue::from_static("*/*"));
+    }
+
+    let same_origin = requested.origin() == url.origin();
+    let headers: Vec<Value> = headers
+        .iter()
+        .map(|(name, value)| {
+            if !SENSITIVE_HEADERS.contains(&name.as_str()) {
+                json!({ "name": name.as_str(), "value": value.to_str().unwrap_or("") })
+            } else if same_origin {
+                json!({ "name": name.as_str(), "redacted": "sensitive" })
+            } else {
+                json!({ "name": name.as_str(), "redacted": "stripped_on_redirect" })
+            }
+        })
+        .collect();
+    json!({
+        "method": method.as_str(),
+        "url": url.as_str(),
+        "headers": headers,
+    })
+}
+
+/// Headers sent with every request.
+fn request_headers(opts: &Options) -> header::HeaderMap {
+    let mut headers = header::HeaderMap::new();
+    let ua_header = header::HeaderValue::from_str(opts.user_agent).unwrap();
+    headers.insert(header::USER_AGENT, ua_header);
+    headers.extend(opts.headers.clone());
+    headers
+}
+
+fn http_request(
+    client: &reqwest::blocking::Client,
+    method: reqwest::Method,
     url: &str,
-    user_agent: &str,
-    max_redirects: usize,
+    headers: header::HeaderMap,
+    opts: &Options,
 ) -> reqwest::Result<reqwest::blocking::Response> {
-    let client = reqwest::blocking::Client::builder()
-        .redirect(reqwest::redirect::Policy::limited(max_redirects))
-        .min_tls_version(tls::Version::TLS_1_2)
+    let req = client
+        .request(method, url)
+        .headers(request_headers(opts))
+        .headers(headers)
         .build()?;
+    send_request(client, req, opts)
+}
+
+/// Send `req` with `--query` applied, waiting out rate limits and
+/// following redirects to URLs with credentials.
+fn send_request<'c>(
+    mut client: &'c reqwest::blocking::Client,
+    mut req: reqwest::blocking::Request,
+    opts: &'c Options,
+) -> reqwest::Result<reqwest::blocking::Response> {
+    apply_query(req.url_mut(), &opts.query, opts.query_replace);
+
+    if opts.verbose && !opts.query.is_empty() {
+        console::err(&format!("url: {}", req.url()));
+    }
+
+    // a Cookie header of the request's own stays with its origin
+    let own_cookie = req.headers().get(header::COOKIE).cloned();
+    let origin = req.url().origin();
+    let mut hops = 0;
+    let mut redirects = 0;
+    loop {
+        if let Some(ref limits) = opts.rate_limits {
+            if let Some(host) = req.url().host_str() {
+                limits.wait(host);
+            }
+        }
+        if let Some(ref jar) = opts.cookies {
+            let own = own_cookie.clone().filter(|_| req.url().origin() == origin);
+            match join_cookies(own, jar.header(req.url())) {
+                Some(value) => req.headers_mut().insert(header::COOKIE, value),
+                None => req.headers_mut().remove(header::COOKIE),
+            };
+        }
+
+        if opts.verbose && !opts.clients.profiles.is_empty() && req.url().scheme() == "https" {
+            let host = req.url().host_str().unwrap_or("");
+            messages::note("note.tls-profile", &[&host, &opts.clients.label(req.url())]);
+        }
+        // the body of a job with a deadline must arrive before it too
+        if let Some(left) = control::time_left() {
+            *req.timeout_mut() = Some(left.max(Duration::from_millis(1)));
+        }
+        let retry = req.try_clone();
+        let first = req.url().clone();
+        if opts.verbose {
+            trace_request(&req);
+        }
+        let entry = opts.har.as_ref().map(|har| har.request(&req, &SENSITIVE_HEADERS));
+        let record = opts.record_request.then(|| (req.method().clone(), req.headers().clone()));
+        let sent = Instant::now();
+        let mut resp = client.execute(req).inspect_err(|e| {
+            if let (Some(har), Some(idx)) = (opts.har.as_ref(), entry) {
+                har.failed(idx, &e.to_string(), sent.elapsed());
+            }
+        })?;
+        opts.connections.exchange(&mut resp, sent);
+        if let Some((method, headers)) = record {
+            let record = recorded_request(&method, &headers, &first, resp.url());
+            resp.extensions_mut().insert(SentRequest(record));
+        }
+        if let (Some(har), Some(idx)) = (opts.har.as_ref(), entry) {
+            har.response(idx, &resp, sent.elapsed());
+        }
+        if opts.verbose {
+            trace_response(&resp, resp.extensions().get::<timing::Phases>());
+        }
+        if let Some(ref file) = opts.dump_header {
+            dump_headers(file, &resp);
+        }
+        // the client's own hops are reported, but only ours are held to the limit
+        redirects += timing::followed(&first, resp.url());
+        if let Some(phases) = resp.extensions_mut().get_mut::<timing::Phases>() {
+            phases.redirects = Some(redirects);
+            phases.har_entry = entry;
+        }
+        clock::check_response(&resp);
+        if let Some(ref jar) = opts.cookies {
+            jar.store(resp.url(), resp.headers());
+        }
+
+        if let Some(ref limits) = opts.rate_limits {
+            limits.observe(&resp);
+        }
+
+        // the redirect policy stops at targets carrying credentials and at
+        // hosts with another TLS profile
+        let target = resp
+            .headers()
+            .get(header::LOCATION)
+            .filter(|_| resp.status().is_redirection())
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| resp.url().join(location).ok());
+        let switched = target.as_ref().and_then(|target| opts.clients.switch(resp.url().as_str(), target));
+        let manual = opts.cookies.is_some() || opts.dump_header.is_some() || opts.har.is_some() || opts.verbose;
+        let target = target.filter(|target| credentials::present(target) || switched.is_some() || manual);
+        let (mut target, mut next) = match (target, retry) {
+            (Some(target), Some(next)) if hops < opts.max_redirects => (target, next),
+            _ => return Ok(resp),
+        };
+        if target.origin() != resp.url().origin() {
+            for name in &[header::AUTHORIZATION, header::COOKIE, header::PROXY_AUTHORIZATION] {
+                next.headers_mut().remove(name);
+            }
+        }
+        if let Some(found) = credentials::take(&mut target) {
+            credentials::warn_once(&target);
+            next.headers_mut().insert(header::AUTHORIZATION, found.basic_auth());
+        }
+        if opts.verbose {
+            console::err(&format!("* redirect {} to {}", hops + 1, credentials::redact(target.as_str())));
+        }
+        *next.url_mut() = target;
+        if let Some(other) = switched {
+            client = other;
+        }
+        req = next;
+        hops += 1;
+        redirects += 1;
+    }
+}
+
+/// Append the status line and headers of `resp` to the `--dump-header`
+/// file, ended by an empty line, as curl's `-D` writes them.
+fn dump_headers(file: &Mutex<File>, resp: &reqwest::blocking::Response) {
+    let mut text = format!("{:?} {}\r\n", resp.version(), resp.status());
+    for (key, value) in resp.headers() {
+        text.push_str(&format!("{}: {}\r\n", key, String::from_utf8_lossy(value.as_bytes())));
+    }
+    text.push_str("\r\n");
+    // one write per response, so concurrent transfers never interleave
+    let mut file = file.lock().unwrap();
+    if let Err(e) = file.write_all(text.as_bytes()).and_then(|_| file.flush()) {
+        messages::warn("warning.dump-header", &[&e]);
+    }
+}
+
+/// A request's own `Cookie` value followed by the jar's.
+fn join_cookies(own: Option<header::HeaderValue>, jar: Option<header::HeaderValue>) -> Option<header::HeaderValue> {
+    match (own, jar) {
+        (Some(own), Some(jar)) => {
+            let joined = [own.as_bytes(), b"; ", jar.as_bytes()].concat();
+            let mut value = header::HeaderValue::from_bytes(&joined).ok()?;
+            value.set_sensitive(true);
+            Some(value)
+        }
+        (own, jar) => own.or(jar),
+    }
+}
+
+/// The headers for further requests that fetch more of the body at `url`
+/// on connections of their own: those of every request and the job's,
+/// with the jar's cookies for `url`.
+fn follow_up_headers(job_headers: &header::HeaderMap, url: &reqwest::Url, opts: &Options) -> header::HeaderMap {
+    let mut headers = request_headers(opts);
+    headers.extend(job_headers.clone());
+    if let Some(ref jar) = opts.cookies {
+        let own = headers.remove(header::COOKIE);
+        if let Some(value) = join_cookies(own, jar.header(url)) {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+    headers
+}
+
+/// The upload's credentials, for a `--then-get` URL on the same origin.
+fn same_origin_auth(upload: &Upload, url: &str) -> Option<header::HeaderValue> {
+    let from = reqwest::Url::parse(&upload.url).ok()?;
+    let to = reqwest::Url::parse(url).ok()?;
+    if from.origin() != to.origin() {
+        return None;
+    }
+    upload.headers.get(header::AUTHORIZATION).cloned()
+}
 
-    let ua_header = header::HeaderValue::from_str(user_agent).unwrap();
-    let resp = client
-        .get(url)
-        .header(header::USER_AGENT, ua_header)
-        .send()?;
+/// Send stdin to the upload URL, streaming it rather than holding it in
+/// memory, and report the response. Its body is copied to stdout when
+/// `body_to_stdout`, and otherwise dropped.
+fn run_upload(
+    client: &reqwest::blocking::Client,
+    upload: &Upload,
+    opts: &Options,
+    body_to_stdout: bool,
+) -> Result<Value, DownloadError> {
+    // a response on stdout has moved the screen, and so the bar, to stderr
+    let progress = display(upload.content_length.unwrap_or(0), Phase::Upload, opts);
+    let (body, sent) = upload::stdin_body(upload.content_length, progress);
+    let req = client
+        .request(upload.method.clone(), &upload.url)
+        .headers(request_headers(opts))
+        .headers(upload.headers.clone())
+        .body(body)
+        .build()
+        .map_err(|e| DownloadError::from_reqwest(e, &upload.url))?;
+    control::start(&upload.url);
+    let mut resp = send_request(client, req, opts).map_err(|e| DownloadError::from_reqwest(e, &upload.url))?;
+    messages::note("note.upload", &[&upload.method, &upload.url, &resp.status()]);
+    let mut doc = json!({
+        "method": upload.method.as_str(),
+        "url": upload.url,
+        "status": resp.status().as_u16(),
+        "bytes_sent": sent.load(Ordering::Relaxed),
+    });
+    if let Some(sent) = resp.extensions().get::<SentRequest>() {
+        doc["request"] = sent.0.clone();
+    }
+    if let Err(e) = resp.error_for_status_ref() {
+        return Err(DownloadError::from_reqwest(e, &upload.url));
+    }
+    if body_to_stdout {
+        let stdout = io::stdout();
+        let mut writer = BufWriter::new(RetryWriter::new(stdout.lock()));
+        download_with_progress(&mut resp, &mut writer, &mut Progress::hidden(), opts.buffer)?;
+        writer.flush()?;
+    }
+    Ok(doc)
+}
+
+/// Statuses that a server refusing HEAD answers with; a URL signed for GET
+/// alone gets a 403.
+const HEAD_REFUSED: [u16; 3] = [403, 405, 501];
+
+/// Ask for the headers of `job` alone with `-I`: a HEAD request, or a GET
+/// whose body is never read when HEAD is refused. The status and headers
+/// are printed with the name the output would get and its length.
+fn run_head(client: &reqwest::blocking::Client, job: &Job, opts: &Options) -> Result<Value, DownloadError> {
+    control::start(&job.url);
+    let request = |method| {
+        http_request(client, method, &job.url, job.headers.clone(), opts)
+            .map_err(|e| DownloadError::from_reqwest(e, &job.url))
+    };
+    let mut method = reqwest::Method::HEAD;
+    let mut resp = request(method.clone())?;
+    if HEAD_REFUSED.contains(&resp.status().as_u16()) {
+        method = reqwest::Method::GET;
+        resp = request(method.clone())?;
+    }
+    control::finish("finished");
+
+    let mut out = console::Lines::new(Stream::Stdout);
+    write_status(&mut out, &resp);
+    write_headers(&mut out, &resp);
+    drop(out);
+    let length = resp
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    let disposition = resp
+        .headers()
+        .get(header::CONTENT_DISPOSITION)
+        .and_then(|value| str::from_utf8(value.as_bytes()).ok())
+        .and_then(filename::from_disposition);
+    let filename = match (&job.output, disposition) {
+        (Some(path), Some(name)) if opts.content_disposition && job.remote_named => Some(path.with_file_name(name)),
+        (Some(path), _) => Some(path.clone()),
+        (None, Some(name)) => Some(PathBuf::from(name)),
+        (None, None) => remote_name_path(resp.url().as_str(), opts.output_dir, true).ok(),
+    };
+    let shown = filename.as_ref().map_or_else(|| "-".to_string(), |path| path.display().to_string());
+    let shown_length = length.map_or_else(|| "-".to_string(), |len| len.to_string());
+    console::out(&messages::render("summary.head", &[&shown, &shown_length]));
+
+    let mut headers = serde_json::Map::new();
+    for name in resp.headers().keys() {
+        let values: Vec<&str> = resp.headers().get_all(name).iter().filter_map(|v| v.to_str().ok()).collect();
+        headers.insert(name.to_string(), json!(values.join(", ")));
+    }
+    resp.error_for_status_ref()
+        .map_err(|e| DownloadError::from_reqwest(e, &job.url))?;
+    let mut doc = json!({
+        "url": job.url,
+        "url_effective": resp.url().as_str(),
+        "method": method.as_str(),
+        "status": resp.status().as_u16(),
+        "http_version": format!("{:?}", resp.version()),
+        "headers": headers,
+        "filename": filename,
+        "content_length": length,
+    });
+    if let Some(sent) = resp.extensions().get::<SentRequest>() {
+        doc["request"] = sent.0.clone();
+    }
+    Ok(doc)
+}
+
+/// Move credentials embedded in `url` into an `Authorization` header,
+/// leaving a URL that is safe to display and record. With `reject` such
+/// URLs are refused instead.
+fn url_credentials(url: &mut String, reject: bool) -> Result<Option<header::HeaderValue>, DownloadError> {
+    let mut parsed = match reqwest::Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return Ok(None),
+    };
+    let found = match credentials::take(&mut parsed) {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+    if reject {
+        return Err(DownloadError::input("error.url-credentials-refused", &[&parsed]));
+    }
+    credentials::warn_once(&parsed);
+    *url = parsed.into();
+    Ok(Some(found.basic_auth()))
+}
+
+fn http_download(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    headers: &header::HeaderMap,
+    opts: &Options,
+) -> reqwest::Result<reqwest::blocking::Response> {
+    http_request(client, reqwest::Method::GET, url, headers.clone(), opts)
+}
 
-    Ok(resp)
+fn download_with_progress<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    progress: &mut Progress,
+    buffer: BufferSize,
+) -> io::Result<DownloadResult>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+{
+    download_continued(reader, writer, progress, buffer, Hasher::new())
 }
 
-fn download_with_progress<R: ?Sized, W: ?Sized>(
+/// Copy as `download_with_progress` does, carrying on from a `hasher` that
+/// has seen what the output already held.
+fn download_continued<R, W>(
     reader: &mut R,
     writer: &mut W,
-    progress: &mut ProgressBar<io::Stdout>,
+    progress: &mut Progress,
+    buffer: BufferSize,
+    mut hasher: Hasher,
 ) -> io::Result<DownloadResult>
 where
-    R: Read,
-    W: Write,
+    R: Read + ?Sized,
+    W: Write + ?Sized,
 {
-    let mut buf = [0; 8192];
+    let mut reader = control::SpeedCheck::new(reader);
+    let mut buf = ReadBuffer::new(buffer);
     let mut written = 0;
-    let mut sha1_hasher = Sha1::new();
-    let mut sha256_hasher = Sha256::new();
+    let started = Instant::now();
+    let mut last_read = 0;
     loop {
-        let len = match reader.read(&mut buf) {
-            Ok(0) => {
-                return Ok(DownloadResult {
-                    bytes_written: written,
-                    sha1: HEXLOWER.encode(sha1_hasher.finalize().as_slice()),
-                    sha256: HEXLOWER.encode(sha256_hasher.finalize().as_slice()),
-                })
-            }
-            Ok(len) => len,
-            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-            Err(e) => return Err(e),
-        };
+        control::check()?;
+        let chunk = control::check_deadline()
+            .and_then(|_| buf.fill(&mut reader))
+            .map_err(|e| BodyRead::wrap(e, written, last_read, started.elapsed()))?;
+        if chunk.is_empty() {
+            progress.flush();
+            return Ok(DownloadResult {
+                bytes_written: written,
+                digests: hasher.finish(),
+                timing: timing::Phases {
+                    transfer_ms: Some(timing::millis(started.elapsed())),
+                    ..timing::Phases::default()
+                },
+                ..DownloadResult::default()
+            });
+        }
         // write buf to writer
-        writer.write_all(&buf[..len])?;
+        writer.write_all(chunk).map_err(|e| WriteFailed::wrap(e, written))?;
 
         // add buf to hash digests
-        sha1_hasher.update(&buf[..len]);
-        sha256_hasher.update(&buf[..len]);
+        hasher.update(chunk);
 
         // increment progress and bytes written
-        progress.add(len as u64);
-        written += len as u64;
+        progress.add(chunk.len() as u64);
+        written += chunk.len() as u64;
+        last_read = chunk.len();
+        control::advance(chunk.len() as u64);
     }
 }
 
-fn main() {
-    let args = App::new("download")
-        .version(crate_version!())
-        .about("remote file downloader command-line interface")
-        .arg(
-            Arg::with_name("output")
-                .short("o")
-                .long("output")
-                .value_name("OUTPUT")
-                .help("output filename")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("remote-name")
-                .short("O")
-                .long("remote-name")
-                .help("output to a file using the same name as the remote"),
-        )
-        .arg(
-            Arg::with_name("user-agent")
-                .short("A")
-                .long("user-agent")
-                .takes_value(true)
-                .help("use value as user-agent header"),
-        )
-        .arg(
-            Arg::with_name("max-redirects")
-                .long("max-redirects")
-                .takes_value(true)
-                .default_value("0")
-                .help("maximum number of redirects to follow"),
-        )
-        .arg(
-            Arg::with_name("verbose")
-                .short("v")
-                .long("verbose")
-                .help("enable verbose logging (useful for debugging)"),
-        )
-        .arg(Arg::with_name("url").required(true))
-        .get_matches();
+/// Print a `<algorithm>(<label>) = <hex>` line for every digest.
+fn print_digests(label: &str, digests: &Digests) {
+    for &(algorithm, ref hex) in digests.iter() {
+        console::out(&format!("{}({}) = {}", algorithm, label, hex));
+    }
+}
 
-    let url = args.value_of("url").unwrap();
-    let user_agent = args.value_of("user-agent").unwrap_or(DEFAULT_USER_AGENT);
-    let max_redirects = args.value_of("max-redirects")
-        .unwrap_or_default()
-        .parse::<usize>()
-        .unwrap();
-    let verbose = args.is_present("verbose");
+fn verify_checksum(
+    checksum: &Checksum,
+    result: &DownloadResult,
+    path: Option<&Path>,
+) -> Result<(), DownloadError> {
+    // every expected digest's algorithm is among those computed
+    let actual = result.digests.get(checksum.algorithm).unwrap_or_default();
+    if actual == checksum.hex {
+        return Ok(());
+    }
 
-    // determine an output filename; if none are set then send to stdout
-    let uri = url.parse::<Uri>().unwrap();
-    let output_path = {
-        if args.is_present("remote-name") {
-            get_filename(uri.path())
-                .map(|filename| Path::new(filename))
-        } else {
-            args.value_of("output")
-                .map(|path| Path::new(path))
-        }
-    };
+    // never leave a file that failed verification behind
+    if let Some(path) = path {
+        remove_output(path);
+    }
+    Err(DownloadError::ChecksumMismatch {
+        expected: checksum.clone(),
+        actual: actual.to_string(),
+        tcp: result.tcp,
+    })
+}
 
-    // setup client for downloading and send request
-    let mut resp = http_download(url, user_agent, max_redirects).unwrap_or_else(|e| {
-        let _ = writeln!(&mut io::stderr(), "{}", e);
-        process::exit(EXIT_URL_FAILURE);
-    });
+/// The average rate of a job's body in bytes per second: over its
+/// transfer where that was timed, and over the whole job otherwise.
+fn average_rate(result: &DownloadResult) -> Option<f64> {
+    let duration = result.duration?;
+    let secs = result.timing.transfer_ms.map_or(duration.as_secs_f64(), |ms| ms as f64 / 1000.0);
+    Some(result.bytes_written as f64 / secs).filter(|_| secs > 0.0)
+}
 
-    // process response
-    if let Some(file_path) = output_path {
-        let _ = File::create(file_path)
-            .and_then(|output_file| {
-                let mut writer = BufWriter::new(output_file);
-
-                if verbose {
-                    write_status(&mut io::stdout(), &resp);
-                    write_headers(&mut io::stdout(), &resp);
-                }
-
-                // setup progress bar based on content-length
-                let n_bytes: u64 = resp.headers()
-                    .get(header::CONTENT_LENGTH)
-                    .and_then(|content_len| content_len.to_str().ok())
-                    .and_then(|content_len| content_len.parse().ok())
-                    .unwrap_or(0);
-                let mut pb = ProgressBar::new(n_bytes);
-                pb.set_units(Units::Bytes);
-
-                // copy file with progress updates
-                let result = download_with_progress(&mut resp, &mut writer, &mut pb)?;
-                writer.flush()?;
-
-                // print hash digests
-                println!(
-                    "sha1({}) = {}",
-                    file_path.display(),
-                    result.sha1
-                );
-                println!(
-                    "sha256({}) = {}",
-                    file_path.display(),
-                    result.sha256,
+/// Print how long a job took, how fast its body came, and what it took to
+/// get there.
+fn report_stats(result: &DownloadResult) {
+    let duration = match result.duration {
+        Some(duration) => duration,
+        None => return,
+    };
+    let rate = |rate: Option<f64>| match rate {
+        Some(rate) => format!("{}/s", bar::format_bytes(rate)),
+        None => "-".to_string(),
+    };
+    messages::note(
+        "note.stats",
+        &[
+            &bar::format_bytes(result.bytes_written as f64),
+            &format!("{:.2}", duration.as_secs_f64()),
+            &rate(average_rate(result)),
+            &rate(result.peak_rate),
+            &result.retries,
+            &result.timing.redirects.unwrap_or(0),
+            &result.timing.http_version.as_deref().unwrap_or("-"),
+        ],
+    );
+}
+
+/// Print how the connection a body came over fared, if the kernel said.
+fn report_tcp(url: &str, stats: Option<tcpinfo::Stats>) {
+    let stats = match stats {
+        Some(stats) => stats,
+        None => return,
+    };
+    let rtt = format!("{:.2}", stats.rtt_ms);
+    match stats.delivery_rate {
+        Some(rate) => {
+            let rate = bar::format_bytes(rate as f64);
+            messages::note("note.tcp-info", &[&url, &stats.retransmits, &rtt, &rate]);
+        }
+        None => messages::note("note.tcp-info-no-rate", &[&url, &stats.retransmits, &rtt]),
+    }
+}
+
+/// Save `state`, with how far the segments of `plan` got, alongside
+/// `part`. A download goes on without it.
+fn save_state(state: &resume::State, plan: Option<&segments::Plan>, part: &Path) {
+    let state = resume::State {
+        segments: plan.map(segments::Plan::progress).unwrap_or_default(),
+        ..state.clone()
+    };
+    if let Err(e) = state.save(part) {
+        messages::warn("warning.resume-state", &[&part.display(), &e]);
+    }
+}
+
+fn remove_output(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        messages::warn("warning.remove-failed", &[&path.display(), &e]);
+    }
+}
+
+/// Close the display of a copy into `written` that failed. A cancelled
+/// copy's output is removed, unless `--keep-partial` asked for it or the
+/// file held bytes from before the run (`fresh` is false), which are left
+/// for `--continue`.
+fn abandon_copy(e: io::Error, written: &Path, fresh: bool, progress: &mut Progress, opts: &Options) -> DownloadError {
+    progress.abandon();
+    let e = DownloadError::from(e);
+    if let DownloadError::Cancelled = e {
+        if fresh && !opts.keep_partial {
+            remove_output(written);
+        } else {
+            messages::note("note.partial-kept", &[&written.display()]);
+        }
+    }
+    e
+}
+
+/// Report the copy a `--decompress-keep-both` branch wrote to `path`. A
+/// corrupt stream removes the copy; the caller still moves the compressed
+/// output into place before failing the job.
+fn decompressed_copy(
+    unpacked: io::Result<(Hasher, u64)>,
+    path: PathBuf,
+) -> Result<Decompressed, DownloadError> {
+    let (hasher, written) = match unpacked {
+        Ok(state) => state,
+        Err(e) => {
+            remove_output(&path);
+            let msg = format!("{}: {}", path.display(), e);
+            return Err(DownloadError::Output(io::Error::new(e.kind(), msg)));
+        }
+    };
+    let digests = hasher.finish();
+    print_digests(&path.display().to_string(), &digests);
+    Ok(Decompressed {
+        path,
+        bytes_written: written,
+        digests,
+    })
+}
+
+/// Whether the output of `job` may be written through a symlink. Names
+/// derived from the URL never are unless `--follow-symlinks` is given.
+fn follows_symlinks(job: &Job, opts: &Options) -> bool {
+    opts.follow_symlinks.unwrap_or(!job.remote_named)
+}
+
+fn symlink_refused(path: &Path, opts: &Options) -> DownloadError {
+    let hint = match opts.follow_symlinks {
+        Some(false) => "error.symlink-refused",
+        _ => "error.symlink-follow",
+    };
+    let path = Message::Text(path.display().to_string());
+    DownloadError::UnsafeOutput(Message::Catalog("error.symlink", vec![path, Message::new(hint, &[])]))
+}
+
+/// Refuse an output path that is a symlink we may not follow or that
+/// resolves outside `--restrict-to`.
+fn check_output_path(path: &Path, job: &Job, opts: &Options) -> Result<(), DownloadError> {
+    if !follows_symlinks(job, opts) && fsutil::is_symlink(path) {
+        return Err(symlink_refused(path, opts));
+    }
+    // refused before a .part is filled, not when it is renamed over it
+    if path.is_dir() {
+        return Err(DownloadError::Output(io::Error::from_raw_os_error(libc::EISDIR)));
+    }
+    if let Some(ref root) = opts.restrict_to {
+        if !fsutil::resolves_within(path, root)? {
+            let msg = Message::new("error.outside-root", &[&path.display(), &root.display()]);
+            return Err(DownloadError::UnsafeOutput(msg));
+        }
+    }
+    Ok(())
+}
+
+/// Open an existing output to write the rest of it from `offset` on, for
+/// `--start-offset`. What precedes the offset is kept as it is.
+fn resume_output(path: &Path, offset: u64, job: &Job, opts: &Options) -> Result<File, DownloadError> {
+    check_output_path(path, job, opts)?;
+    let mut file = if follows_symlinks(job, opts) {
+        fs::OpenOptions::new().write(true).create(true).truncate(false).open(path)?
+    } else {
+        fsutil::open_nofollow(path).map_err(|e| match e.raw_os_error() {
+            Some(libc::ELOOP) => symlink_refused(path, opts),
+            _ => DownloadError::Output(e),
+        })?
+    };
+    check_resumable(path, file.metadata()?.len(), offset)?;
+    file.seek(io::SeekFrom::Start(offset))?;
+    Ok(file)
+}
+
+fn check_resumable(path: &Path, len: u64, offset: u64) -> Result<(), DownloadError> {
+    if len < offset {
+        return Err(DownloadError::input("error.start-offset-short", &[&offset, &path.display(), &len]));
+    }
+    Ok(())
+}
+
+/// How digests of `path` are labelled: by the file, or by the range they
+/// cover when only the end of it was fetched.
+fn digest_label(path: &Path, opts: &Options) -> String {
+    match opts.start_offset {
+        Some(offset) => format!("range {}-", offset),
+        None => path.display().to_string(),
+    }
+}
+
+/// Where a download to `path` is written until it is complete and
+/// verified: `<path>.part` beside it. Streams, `--start-offset`, which
+/// writes into the output, and a symlink that is followed are written in
+/// place.
+fn staging_path(path: &Path, job: &Job, opts: &Options) -> Option<PathBuf> {
+    let followed = follows_symlinks(job, opts) && fsutil::is_symlink(path);
+    if fsutil::is_stream(path) || opts.start_offset.is_some() || followed {
+        return None;
+    }
+    let mut name = path.as_os_str().to_owned();
+    name.push(".part");
+    Some(PathBuf::from(name))
+}
+
+/// Reserve disk for a body of `len` bytes, so that one the disk cannot
+/// hold fails before the transfer rather than midway through it.
+fn preallocate(file: &File, path: &Path, len: u64) -> Result<(), DownloadError> {
+    fsutil::preallocate(file, len).map_err(|e| {
+        let msg = format!("{}: no room for {}: {}", path.display(), bar::format_bytes(len as f64), e);
+        DownloadError::Output(io::Error::new(e.kind(), msg))
+    })
+}
+
+/// Give an output the modification time the server's `Last-Modified`
+/// gives its copy, as curl's `-R` does.
+fn set_remote_time(path: &Path, resp: &reqwest::blocking::Response) {
+    let modified = resp
+        .headers()
+        .get(header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+    if let Some(modified) = modified {
+        let set = File::options().write(true).open(path).and_then(|file| file.set_modified(modified.into()));
+        if let Err(e) = set {
+            messages::warn("warning.remote-time", &[&path.display(), &e]);
+        }
+    }
+}
+
+/// Move a finished `.part` into place as `path`.
+fn commit_part(part: &Path, path: &Path, job: &Job, opts: &Options) -> Result<(), DownloadError> {
+    check_output_path(path, job, opts)?;
+    fs::rename(part, path)?;
+    resume::State::remove(part);
+    Ok(())
+}
+
+fn create_output(path: &Path, job: &Job, opts: &Options) -> Result<File, DownloadError> {
+    check_output_path(path, job, opts)?;
+    // -P and the directories of an --output-template are made as needed,
+    // others only with --create-dirs
+    let dir = if opts.create_dirs || job.named.is_some() {
+        path.parent().filter(|p| !p.as_os_str().is_empty())
+    } else {
+        opts.output_dir.filter(|dir| path.starts_with(dir))
+    };
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir)?;
+        // the new directories may have been created through a symlink
+        check_output_path(path, job, opts)?;
+    }
+    if follows_symlinks(job, opts) {
+        return Ok(File::create(path)?);
+    }
+    fsutil::create_nofollow(path).map_err(|e| match e.raw_os_error() {
+        Some(libc::ELOOP) => symlink_refused(path, opts),
+        _ => DownloadError::Output(e),
+    })
+}
+
+/// Progress of `total` bytes (0 when unknown) shown the way `--progress`
+/// asks.
+fn display(total: u64, phase: Phase, opts: &Options) -> Progress {
+    let renderer: Box<dyn Renderer> = match opts.progress {
+        progress::Mode::Bar if console::screen_is_terminal() => Box::new(bar::Bar::stdout(total).phase(phase)),
+        progress::Mode::Json => Box::new(events::Events::new(total, phase, opts.progress_interval)),
+        _ => return Progress::hidden(),
+    };
+    Progress::new(renderer, opts.progress_interval)
+}
+
+/// A progress display sized from the response's content-length.
+fn response_progress(resp: &reqwest::blocking::Response, opts: &Options) -> Progress {
+    let n_bytes: u64 = resp.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|content_len| content_len.to_str().ok())
+        .and_then(|content_len| content_len.parse().ok())
+        .unwrap_or(0);
+    display(n_bytes, Phase::Download, opts)
+}
+
+/// Send the request for a job's body, split into pipelined ranges when
+/// `--pipelined-ranges` is given, or only the bytes `from` on. With
+/// `--segments` the whole body is asked for as a range, so that the answer
+/// tells whether it can be split up.
+fn open_body(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    headers: &header::HeaderMap,
+    from: Option<u64>,
+    opts: &Options,
+) -> reqwest::Result<ranges::Body> {
+    if let Some(offset) = from {
+        let mut headers = headers.clone();
+        headers.insert(header::RANGE, ranges::from_header(offset));
+        return http_download(client, url, &headers, opts).map(ranges::Body::Plain);
+    }
+    if opts.segments.is_some() {
+        let mut range_headers = headers.clone();
+        range_headers.insert(header::RANGE, ranges::from_header(0));
+        let first = http_download(client, url, &range_headers, opts)?;
+        if first.status() != reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+            return Ok(ranges::Body::Plain(first));
+        }
+        // an empty resource has no first byte to ask for
+        return http_download(client, url, headers, opts).map(ranges::Body::Plain);
+    }
+    let chunk = match opts.pipelined_ranges {
+        Some(chunk) => chunk,
+        None => return http_download(client, url, headers, opts).map(ranges::Body::Plain),
+    };
+    let mut first_headers = headers.clone();
+    first_headers.insert(header::RANGE, ranges::range_header(0, chunk));
+    let first = http_request(client, reqwest::Method::GET, url, first_headers, opts)?;
+    if first.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // an empty resource has no first byte to ask for
+        return http_download(client, url, headers, opts).map(ranges::Body::Plain);
+    }
+    let range_headers = follow_up_headers(headers, first.url(), opts);
+    let client = opts.clients.switch(url, first.url()).unwrap_or(client);
+    Ok(ranges::Body::open(client, first, chunk, range_headers))
+}
+
+/// Whether a meta refresh from `from` may be followed to `to`.
+fn refresh_allowed(from: &reqwest::Url, to: &reqwest::Url, opts: &Options) -> bool {
+    if from.origin() == to.origin() {
+        return true;
+    }
+    to.host_str()
+        .map(|host| opts.meta_refresh_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)))
+        .unwrap_or(false)
+}
+
+/// Open the body for a job, following HTML meta-refresh pages when
+/// `--follow-meta-refresh` is given. Returns the body along with any bytes
+/// already read from it while sniffing. The server is asked for the body
+/// only if it differs from the `existing` output.
+fn open_page(
+    client: &reqwest::blocking::Client,
+    job: &Job,
+    from: Option<u64>,
+    if_range: Option<&header::HeaderValue>,
+    existing: &Existing,
+    opts: &Options,
+) -> Result<(ranges::Body, Vec<u8>), DownloadError> {
+    let mut headers = job.headers.clone();
+    if let (Some(_), Some(validator)) = (from, if_range) {
+        headers.insert(header::IF_RANGE, validator.clone());
+    }
+    if let Some(modified) = existing.modified {
+        let date = DateTime::<Utc>::from(modified).format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        if let Ok(date) = header::HeaderValue::from_str(&date) {
+            headers.insert(header::IF_MODIFIED_SINCE, date);
+        }
+    }
+    if let Some(etag) = existing.etag.as_ref().and_then(|etag| header::HeaderValue::from_str(etag).ok()) {
+        headers.insert(header::IF_NONE_MATCH, etag);
+    }
+    let mut url = job.url.clone();
+    let mut visited = HashSet::new();
+    loop {
+        let mut body =
+            open_body(client, &url, &headers, from, opts).map_err(|e| DownloadError::from_reqwest(e, &url))?;
+        if !refresh::is_candidate(body.response()) {
+            return Ok((body, Vec::new()));
+        }
+        let mut prefix = Vec::new();
+        (&mut body).take(refresh::SNIFF_LIMIT + 1).read_to_end(&mut prefix)?;
+        if prefix.len() as u64 > refresh::SNIFF_LIMIT {
+            return Ok((body, prefix));
+        }
+        let page = body.response().url().clone();
+        let target = match refresh::target(&prefix, &page) {
+            Some(target) => target,
+            None => return Ok((body, prefix)),
+        };
+
+        if !opts.follow_meta_refresh {
+            messages::warn("warning.meta-refresh", &[&page, &target]);
+            return Ok((body, prefix));
+        }
+        if !refresh_allowed(&page, &target, opts) {
+            let host = target.host_str().unwrap_or("");
+            messages::warn("warning.meta-refresh-host", &[&page, &target, &host]);
+            return Ok((body, prefix));
+        }
+        visited.insert(page.to_string());
+        if visited.contains(target.as_str()) {
+            return Err(DownloadError::input("error.meta-refresh-loop", &[&target]));
+        }
+        if visited.len() > opts.max_redirects {
+            return Err(DownloadError::input("error.meta-refresh-redirects", &[&target, &opts.max_redirects]));
+        }
+
+        messages::note("note.meta-refresh", &[&page, &target]);
+        url = String::from(target);
+    }
+}
+
+fn numbered_path(path: &Path, part: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", part));
+    PathBuf::from(name)
+}
+
+/// Where an output goes with `--no-clobber` or `--auto-rename`: `path`
+/// while nothing is there, else the first free numbered path, or an error.
+fn unclobbered(path: &Path, opts: &Options) -> Result<PathBuf, DownloadError> {
+    let taken = |path: &Path| fs::symlink_metadata(path).is_ok();
+    match opts.clobber {
+        Some(_) if fsutil::is_stream(path) || !taken(path) => Ok(path.to_path_buf()),
+        Some(Clobber::Refuse) => {
+            let msg = format!("{} already exists and --no-clobber leaves it alone", path.display());
+            Err(DownloadError::Output(io::Error::new(io::ErrorKind::AlreadyExists, msg)))
+        }
+        Some(Clobber::Rename) => {
+            let free = (1..).map(|n| numbered_path(path, n)).find(|numbered| !taken(numbered)).unwrap();
+            messages::note("note.auto-rename", &[&path.display(), &free.display()]);
+            Ok(free)
+        }
+        None => Ok(path.to_path_buf()),
+    }
+}
+
+/// Download a chain of parts linked by `Link: <...>; rel="next"`. Parts are
+/// written to numbered files when the output is named from the remote and
+/// are otherwise concatenated into the single output.
+fn run_parts(
+    client: &reqwest::blocking::Client,
+    job: &Job,
+    opts: &Options,
+) -> Result<DownloadResult, DownloadError> {
+    let numbered = job.remote_named;
+    if numbered && job.checksum.is_some() {
+        return Err(DownloadError::input("error.link-checksum", &[]));
+    }
+
+    let staged = job.output.as_ref().filter(|_| !numbered).and_then(|path| staging_path(path, job, opts));
+    let mut concat: Option<Box<dyn Write>> = match job.output {
+        Some(_) if numbered => None,
+        Some(ref path) => {
+            check_output_path(path, job, opts)?;
+            let file = create_output(staged.as_deref().unwrap_or(path), job, opts)?;
+            Some(Box::new(BufWriter::new(RetryWriter::new(file))))
+        }
+        None => Some(Box::new(BufWriter::new(RetryWriter::new(io::stdout())))),
+    };
+    // the summary goes to stderr whenever the body itself goes to stdout
+    let mut summary = console::Lines::new(match job.output {
+        Some(_) => Stream::Stdout,
+        None => Stream::Stderr,
+    });
+
+    let mut seen = HashSet::new();
+    let mut parts = Vec::new();
+    let mut combined = (Hasher::new(), 0);
+    let mut url = job.url.clone();
+    let mut url_effective;
+    let mut response;
+    loop {
+        seen.insert(url.clone());
+        let mut resp = http_download(client, &url, &job.headers, opts)
+            .map_err(|e| DownloadError::from_reqwest(e, &url))?;
+        let next = link::next_link(resp.headers(), resp.url());
+
+        let part_path = match concat {
+            Some(_) => None,
+            None => Some(unclobbered(&numbered_path(job.output.as_ref().unwrap(), parts.len() + 1), opts)?),
+        };
+        let dest = part_path.clone().or_else(|| job.output.clone());
+        let part_staged = part_path.as_ref().and_then(|path| staging_path(path, job, opts));
+        // where this part lands: its own file, or the one the parts are joined in
+        let written = part_staged.as_ref().or(part_path.as_ref()).or(staged.as_ref()).or(job.output.as_ref());
+        let result;
+        {
+            let mut part_file;
+            let target: &mut dyn Write = match (concat.as_mut(), written.filter(|_| part_path.is_some())) {
+                (Some(writer), _) => writer,
+                (None, Some(path)) => {
+                    part_file = BufWriter::new(RetryWriter::new(create_output(path, job, opts)?));
+                    &mut part_file
+                }
+                (None, None) => unreachable!(),
+            };
+
+            control::receiving(resp.content_length());
+            let mut progress = response_progress(&resp, opts);
+            let mut writer = HashWriter::with_state(target, combined.0, combined.1);
+            let copied = download_with_progress(&mut resp, &mut writer, &mut progress, opts.buffer)
+                .and_then(|copied| writer.flush().map(|_| copied));
+            result = match (copied, written) {
+                (Ok(copied), _) => copied,
+                (Err(e), Some(path)) if !fsutil::is_stream(path) => {
+                    drop(writer);
+                    drop(concat);
+                    return Err(abandon_copy(e, path, true, &mut progress, opts));
+                }
+                (Err(e), _) => {
+                    progress.abandon();
+                    return Err(e.into());
+                }
+            };
+            combined = writer.into_state();
+            url_effective = resp.url().to_string();
+            response = response_json(&resp);
+            progress.finish();
+        }
+        if let (Some(part), Some(path)) = (part_staged, part_path) {
+            commit_part(&part, &path, job, opts)?;
+        }
+        parts.push((url.clone(), dest, result));
+
+        let next = match next {
+            Some(next) => String::from(next),
+            None => break,
+        };
+        if seen.contains(&next) {
+            messages::warn("warning.link-loop", &[&next]);
+            break;
+        }
+        if parts.len() >= opts.max_parts {
+            messages::warn("warning.max-parts", &[&parts.len(), &next]);
+            break;
+        }
+        url = next;
+    }
+
+    for (idx, (part_url, dest, result)) in parts.iter().enumerate() {
+        let name = dest
+            .as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|| part_url.clone());
+        let _ = writeln!(
+            summary,
+            "part {}: {} ({} bytes) from {}",
+            idx + 1,
+            name,
+            result.bytes_written,
+            part_url
+        );
+        for &(algorithm, ref hex) in result.digests.iter() {
+            let _ = writeln!(summary, "  {} = {}", algorithm, hex);
+        }
+    }
+
+    let (hasher, bytes_written) = combined;
+    let result = DownloadResult {
+        bytes_written,
+        digests: hasher.finish(),
+        url_effective,
+        response: Some(response),
+        ..DownloadResult::default()
+    };
+    if !numbered {
+        let name = job
+            .output
+            .as_ref()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|| "-".to_string());
+        for &(algorithm, ref hex) in result.digests.iter() {
+            let _ = writeln!(summary, "{}({}) = {}", algorithm, name, hex);
+        }
+        if let Some(ref checksum) = job.checksum {
+            verify_checksum(checksum, &result, staged.as_deref().or(job.output.as_deref()))?;
+        }
+    }
+    if let (Some(part), Some(path)) = (staged, job.output.as_ref()) {
+        drop(concat);
+        commit_part(&part, path, job, opts)?;
+    }
+    Ok(result)
+}
+
+/// Compare an existing output with a HEAD of the remote, using saved
+/// validators to avoid trusting a local mtime that was never set from the
+/// server.
+fn is_up_to_date(
+    client: &reqwest::blocking::Client,
+    job: &Job,
+    path: &Path,
+    opts: &Options,
+) -> Result<bool, DownloadError> {
+    let local = match fs::metadata(path) {
+        Ok(local) => local,
+        Err(_) => return Ok(false),
+    };
+    let resp = http_request(client, reqwest::Method::HEAD, &job.url, job.headers.clone(), opts)
+        .map_err(|e| DownloadError::from_reqwest(e, &job.url))?;
+    if !resp.status().is_success() {
+        return Ok(false);
+    }
+    let remote = meta::Validators::from_response(&resp);
+    let saved = meta::Validators::load(path);
+
+    match remote.content_length {
+        Some(len) if len != local.len() => return Ok(false),
+        Some(_) => {}
+        None => {
+            // fall back to the size recorded when the file was written
+            let saved_len = saved.as_ref().and_then(|saved| saved.content_length);
+            if saved_len != Some(local.len()) {
+                return Ok(false);
+            }
+        }
+    }
+
+    let remote_modified = match remote.last_modified {
+        Some(ref modified) => modified,
+        None => return Ok(true),
+    };
+    if saved.as_ref().and_then(|saved| saved.last_modified.as_ref()) == Some(remote_modified) {
+        return Ok(true);
+    }
+    let remote_time = match DateTime::parse_from_rfc2822(remote_modified) {
+        Ok(time) => time.with_timezone(&Utc),
+        Err(_) => return Ok(false),
+    };
+    Ok(local
+        .modified()
+        .map(|mtime| DateTime::<Utc>::from(mtime) >= remote_time)
+        .unwrap_or(false))
+}
+
+/// What is known of an output a conditional request may leave alone: its
+/// mtime with `--timestamping`, and with `--etag` the ETag saved with it.
+#[derive(Default)]
+struct Existing {
+    modified: Option<SystemTime>,
+    etag: Option<String>,
+}
+
+impl Existing {
+    fn of(path: &Path, opts: &Options) -> Existing {
+        let meta = match fs::metadata(path) {
+            Ok(meta) => meta,
+            Err(_) => return Existing::default(),
+        };
+        Existing {
+            modified: meta.modified().ok().filter(|_| opts.timestamping),
+            etag: meta::Validators::load(path).and_then(|saved| saved.etag).filter(|_| opts.etag),
+        }
+    }
+
+    fn is_known(&self) -> bool {
+        self.modified.is_some() || self.etag.is_some()
+    }
+
+    /// Whether `resp` brings a copy other than the output: neither a 304
+    /// nor, from a server that ignored the conditions, the same ETag or a
+    /// `Last-Modified` no later than the output.
+    fn is_replaced_by(&self, resp: &reqwest::blocking::Response) -> bool {
+        if resp.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return false;
+        }
+        if !resp.status().is_success() {
+            return true;
+        }
+        let etag = resp.headers().get(header::ETAG).and_then(|value| value.to_str().ok());
+        if etag.is_some() && etag == self.etag.as_deref() {
+            return false;
+        }
+        let modified = resp
+            .headers()
+            .get(header::LAST_MODIFIED)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| DateTime::parse_from_rfc2822(value).ok());
+        match (modified, self.modified) {
+            (Some(modified), Some(local)) => modified.with_timezone(&Utc) > DateTime::<Utc>::from(local),
+            _ => true,
+        }
+    }
+}
+
+/// Hash the bytes from `reader` to check a local file, counting them on
+/// `progress`, which is labelled as verification. Nothing is written, so a
+/// cancel leaves no trace.
+fn hash_with_progress<R: Read + ?Sized>(reader: &mut R, mut progress: Progress) -> io::Result<DownloadResult> {
+    let buffer = BufferSize::Adaptive {
+        max: ioutil::DEFAULT_MAX_BUFFER,
+    };
+    let outcome = download_with_progress(reader, &mut io::sink(), &mut progress, buffer);
+    progress.finish();
+    outcome
+}
+
+fn hash_file(path: &Path, opts: &Options) -> io::Result<DownloadResult> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    hash_with_progress(&mut file, display(len, Phase::Verify, opts))
+}
+
+/// Fill a preallocated output with the segments of `plan`, then read it
+/// back for its digests, which need the bytes in order.
+fn fetch_segments(
+    plan: &segments::Plan,
+    body: &mut ranges::Body,
+    file: &File,
+    path: &Path,
+    progress: &mut Progress,
+    checkpoint: &mut dyn FnMut(&segments::Plan),
+    opts: &Options,
+) -> io::Result<DownloadResult> {
+    let started = Instant::now();
+    file.set_len(plan.total())?;
+    plan.fetch(body, file, progress, checkpoint)?;
+    let transfer_ms = timing::millis(started.elapsed());
+    progress.finish();
+    // reading the file back is not held to the rate limit
+    control::finish("verifying");
+    let mut reader = File::open(path)?;
+    let mut result = hash_with_progress(&mut reader, display(plan.total(), Phase::Verify, opts))?;
+    result.timing.transfer_ms = Some(transfer_ms);
+    result.segments = Some(plan.count());
+    Ok(result)
+}
+
+/// Hash the first `len` bytes of a partial output that `--continue` will
+/// append to, so the final digests cover the whole file.
+fn hash_prefix(path: &Path, len: u64, opts: &Options) -> io::Result<Hasher> {
+    let mut reader = File::open(path)?.take(len);
+    let mut progress = display(len, Phase::Verify, opts);
+    let mut buf = ReadBuffer::new(BufferSize::Adaptive {
+        max: ioutil::DEFAULT_MAX_BUFFER,
+    });
+    let mut hasher = Hasher::new();
+    let mut hashed = 0;
+    loop {
+        control::check()?;
+        let chunk = buf.fill(&mut reader)?;
+        if chunk.is_empty() {
+            break;
+        }
+        hasher.update(chunk);
+        progress.add(chunk.len() as u64);
+        hashed += chunk.len() as u64;
+    }
+    progress.finish();
+    if hashed < len {
+        let msg = format!("{} shrank while it was being hashed", path.display());
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+    }
+    Ok(hasher)
+}
+
+/// Whether an existing output satisfies the skip policy.
+fn should_skip(
+    client: &reqwest::blocking::Client,
+    job: &Job,
+    opts: &Options,
+) -> Result<bool, DownloadError> {
+    let path = match (opts.skip, job.output.as_ref()) {
+        (Some(_), Some(path)) if !fsutil::is_stream(path) => path,
+        _ => return Ok(false),
+    };
+    match opts.skip.unwrap() {
+        SkipPolicy::Missing => Ok(fs::metadata(path).map(|m| m.len() > 0).unwrap_or(false)),
+        SkipPolicy::Existing => is_up_to_date(client, job, path, opts),
+        SkipPolicy::Verified => {
+            let checksum = match job.checksum {
+                Some(ref checksum) => checksum,
+                None => return Ok(false),
+            };
+            let existing = match hash_file(path, opts).map_err(DownloadError::from) {
+                Ok(existing) => existing,
+                Err(DownloadError::Cancelled) => return Err(DownloadError::Cancelled),
+                Err(_) => return Ok(false),
+            };
+            Ok(existing.digests.get(checksum.algorithm) == Some(checksum.hex.as_str()))
+        }
+    }
+}
+
+/// What a job's lines are tagged with when several run at once: the name
+/// of its output, or the last segment of its URL.
+fn short_name(job: &Job) -> String {
+    if let Some(name) = job.output.as_ref().and_then(|path| path.file_name()) {
+        return name.to_string_lossy().into_owned();
+    }
+    reqwest::Url::parse(&job.url)
+        .ok()
+        .and_then(|url| {
+            let segment = url.path_segments()?.rev().find(|segment| !segment.is_empty())?.to_string();
+            Some(segment)
+        })
+        .unwrap_or_else(|| job.url.clone())
+}
+
+/// How often a job is retried after a transient failure.
+fn job_retries(job: &Job, opts: &Options) -> u32 {
+    job.overrides.retries.unwrap_or(opts.retry.count)
+}
+
+/// Retries for a server's `Retry-After` when no more were asked for.
+const RETRY_AFTER_RETRIES: u32 = 3;
+
+/// Run `jobs` in list order on up to `workers` threads at once; the
+/// outcomes come back in the same order. Failed entries are reported and
+/// the batch goes on, unless --fail-fast.
+fn run_queue(
+    client: &reqwest::blocking::Client,
+    jobs: &[Job],
+    opts: &Options,
+    workers: usize,
+) -> Vec<Result<Transfer, DownloadError>> {
+    let next = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let slots = Mutex::new(Vec::new());
+    let outcomes: Mutex<Vec<Option<Result<Transfer, DownloadError>>>> =
+        Mutex::new(jobs.iter().map(|_| None).collect());
+    thread::scope(|scope| {
+        for _ in 0..cmp::min(workers, jobs.len()) {
+            scope.spawn(|| {
+                let slot = control::slot();
+                slots.lock().unwrap().push(slot);
+                loop {
+                    let idx = next.fetch_add(1, Ordering::SeqCst);
+                    if idx >= jobs.len() {
+                        break;
+                    }
+                    let outcome = if stopped.load(Ordering::SeqCst) {
+                        Ok(Transfer::NotAttempted)
+                    } else {
+                        process_job(client, &jobs[idx], opts)
+                    };
+                    if let Err(ref e) = outcome {
+                        // the entries this one aborted are not reported again
+                        if !stopped.load(Ordering::SeqCst) || !matches!(*e, DownloadError::Cancelled) {
+                            report_error(e, opts.verbose);
+                        }
+                        if let Some(fail_fast) = opts.fail_fast {
+                            if !stopped.swap(true, Ordering::SeqCst) && fail_fast == FailFast::Abort {
+                                for &other in slots.lock().unwrap().iter().filter(|&&other| other != slot) {
+                                    control::abort(other);
+                                }
+                            }
+                        }
+                    }
+                    outcomes.lock().unwrap()[idx] = Some(outcome);
+                }
+            });
+        }
+    });
+    outcomes.into_inner().unwrap().into_iter().map(Option::unwrap).collect()
+}
+
+fn process_job(
+    client: &reqwest::blocking::Client,
+    job: &Job,
+    opts: &Options,
+) -> Result<Transfer, DownloadError> {
+    let _tag = console::tag(short_name(job));
+    control::check()?;
+    let own;
+    let client = match opts.clients.for_entry(&job.url, &job.overrides) {
+        Some(built) => {
+            own = built.map_err(|e| DownloadError::from_reqwest(e, &job.url))?;
+            &own
+        }
+        None => opts.clients.for_url(&job.url).unwrap_or(client),
+    };
+    if opts.verbose && !job.overrides.is_empty() {
+        messages::note("note.entry-overrides", &[&job.url, &job.overrides.to_json()]);
+    }
+    if should_skip(client, job, opts)? {
+        if opts.verbose {
+            if let Some(ref path) = job.output {
+                messages::note("note.skipping", &[&path.display()]);
+            }
+        }
+        return Ok(Transfer::Skipped);
+    }
+    // the output is claimed once, so that retries write over their own copy
+    let claimed = match job.output {
+        Some(ref path) if opts.clobber.is_some() => Some(unclobbered(path, opts)?)
+            .filter(|free| free != path)
+            .map(|free| Job { output: Some(free), ..job.clone() }),
+        _ => None,
+    };
+    let job = claimed.as_ref().unwrap_or(job);
+    let begin = || {
+        control::start(&job.url);
+        if let Some(rate) = job.overrides.limit_rate {
+            control::limit_job(rate);
+        }
+        if let Some(max_time) = job.overrides.max_time.or(opts.max_time) {
+            control::limit_time(max_time);
+        }
+    };
+    begin();
+    let started = Instant::now();
+    let mut outcome = run_job(client, job, opts);
+    let mut attempt = 0;
+    loop {
+        let retry_after = match outcome {
+            Err(ref e) if e.is_transient() => e.retry_after(),
+            _ => break,
+        };
+        let retries = match retry_after {
+            Some(_) => cmp::max(job_retries(job, opts), RETRY_AFTER_RETRIES),
+            None => job_retries(job, opts),
+        };
+        if attempt >= retries {
+            break;
+        }
+        attempt += 1;
+        let delay = retry_after.unwrap_or_else(|| opts.retry.backoff(attempt));
+        if let Some(max_time) = opts.retry.max_time {
+            if started.elapsed() + delay > max_time {
+                messages::warn("warning.retry-max-time", &[&job.url, &max_time.as_secs_f64()]);
+                break;
+            }
+        }
+        if let Err(ref e) = outcome {
+            let secs = format!("{:.1}", delay.as_secs_f64());
+            messages::warn("warning.retrying", &[&job.url, &e.localized(), &secs, &attempt, &retries]);
+        }
+        thread::sleep(delay);
+        if control::is_cancelled() {
+            outcome = Err(DownloadError::Cancelled);
+            break;
+        }
+        begin();
+        outcome = run_job(client, job, opts);
+    }
+    if let (Err(DownloadError::ChecksumMismatch { .. }), Some(client_opts)) =
+        (&outcome, opts.alt_address_retry.as_ref())
+    {
+        outcome = retry_other_addresses(job, client_opts, opts, outcome);
+    }
+    if let (Err(DownloadError::ReadOnly { .. }), Some(path)) = (&outcome, job.output.as_ref()) {
+        if !fsutil::is_stream(path) && fs::symlink_metadata(path).is_ok() {
+            messages::warn("warning.read-only-partial", &[&path.display()]);
+        }
+    }
+    control::finish(match outcome {
+        Ok(_) => "finished",
+        Err(DownloadError::Cancelled) => "cancelled",
+        Err(_) => "failed",
+    });
+    let mut result = outcome?;
+    if result.not_modified {
+        return Ok(Transfer::Skipped);
+    }
+    if result.renamed_to.is_none() && claimed.is_some() {
+        result.renamed_to = job.output.clone();
+    }
+    result.start_offset = opts.start_offset;
+    result.duration = Some(started.elapsed());
+    result.retries = attempt;
+    // a second's sample cannot be slower than the average over them all
+    result.peak_rate = match (control::peak_rate(), average_rate(&result)) {
+        (Some(peak), Some(average)) => Some(peak.max(average)),
+        (peak, average) => peak.or(average),
+    };
+    if let (Some(har), Some(idx)) = (opts.har.as_ref(), result.timing.har_entry) {
+        har.body(idx, result.bytes_written, result.timing.transfer_ms);
+    }
+    report_stats(&result);
+    if let Some(ref digest_fd) = opts.digest_fd {
+        let mut line = result_json(&result);
+        line["url"] = json!(job.url);
+        // one write per record, so concurrent readers never see half a line
+        let record = format!("{}\n", line);
+        let mut file = digest_fd.lock().unwrap();
+        file.write_all(record.as_bytes()).and_then(|_| file.flush())?;
+    }
+    Ok(Transfer::Downloaded(Box::new(result)))
+}
+
+/// Fetch `job` again from each other address its host resolves to, after
+/// the content served from one of them failed verification, and keep the
+/// first copy that verifies. Anycast and round-robin hosts can have a
+/// single bad node.
+fn retry_other_addresses(
+    job: &Job,
+    client_opts: &ClientOptions,
+    opts: &Options,
+    failed: Result<DownloadResult, DownloadError>,
+) -> Result<DownloadResult, DownloadError> {
+    let url = match reqwest::Url::parse(&job.url) {
+        Ok(url) => url,
+        Err(_) => return failed,
+    };
+    // an address literal has no other addresses to try
+    let (host, port) = match (url.host_str(), url.port_or_known_default()) {
+        (Some(host), Some(port)) if host.trim_matches(['[', ']']).parse::<IpAddr>().is_err() => {
+            (host.to_string(), port)
+        }
+        _ => return failed,
+    };
+    let mut tried: Vec<IpAddr> = timing::last_peer().map(|peer| peer.ip()).into_iter().collect();
+    let addrs: Vec<SocketAddr> = match (host.as_str(), port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(_) => return failed,
+    };
+
+    let mut outcome = failed;
+    for addr in addrs {
+        if tried.contains(&addr.ip()) {
+            continue;
+        }
+        tried.push(addr.ip());
+        messages::warn("warning.alt-address", &[&job.url, &addr.ip()]);
+        let pinned = ClientOptions {
+            pin: Some((host.clone(), addr)),
+            derived: true,
+            profile: client_opts.profiles.for_host(&host),
+            ..client_opts.clone()
+        };
+        let client = match build_client(&pinned) {
+            Ok(client) => client,
+            Err(e) => return Err(DownloadError::from_reqwest(e, &job.url)),
+        };
+        outcome = run_job(&client, job, opts);
+        match outcome {
+            Ok(ref mut result) => {
+                messages::note("note.alt-address-verified", &[&addr.ip()]);
+                result.served_by = Some(addr.ip());
+                break;
+            }
+            Err(DownloadError::ChecksumMismatch { .. }) => continue,
+            Err(_) => break,
+        }
+    }
+    outcome
+}
+
+/// Write a body into a FIFO or device. There is nothing to stage, clobber
+/// or timestamp, and a reader that goes away early ends the transfer with
+/// the digests of what it did receive rather than failing it.
+fn stream_output<R: Read + ?Sized>(
+    reader: &mut R,
+    path: &Path,
+    job: &Job,
+    total: Option<u64>,
+    opts: &Options,
+) -> Result<DownloadResult, DownloadError> {
+    let file = fsutil::open_stream(path)?;
+    let inner = HashWriter::with_state(RetryWriter::new(file), Hasher::new(), 0);
+    let mut writer = BufWriter::new(inner);
+    let mut progress = display(total.unwrap_or(0), Phase::Download, opts);
+
+    let outcome = download_with_progress(reader, &mut writer, &mut progress, opts.buffer)
+        .and_then(|result| writer.flush().map(|_| result));
+    progress.finish();
+    let (result, complete) = match outcome {
+        Ok(result) => (result, true),
+        Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            // digest what the reader actually accepted, not what was buffered
+            let (hasher, written) = writer.into_parts().0.into_state();
+            messages::warn("warning.reader-closed", &[&path.display(), &written]);
+            let result = DownloadResult {
+                bytes_written: written,
+                digests: hasher.finish(),
+                ..DownloadResult::default()
+            };
+            (result, false)
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    print_digests(&digest_label(path, opts), &result.digests);
+    if let (Some(checksum), true) = (job.checksum.as_ref(), complete) {
+        verify_checksum(checksum, &result, None)?;
+    }
+    Ok(result)
+}
+
+/// The name of the type a body was `detected` as, with `--detect-type`,
+/// warning when the response's `Content-Type` or the extension of `path`
+/// claims another.
+fn check_type(
+    detected: Option<&'static magic::Kind>,
+    resp: &reqwest::blocking::Response,
+    path: Option<&Path>,
+    opts: &Options,
+) -> Option<&'static str> {
+    if !opts.detect_type {
+        return None;
+    }
+    let content_type = resp.headers().get(header::CONTENT_TYPE).and_then(|value| value.to_str().ok());
+    if magic::mismatch(detected, content_type, path) {
+        let extension = path
+            .and_then(|path| path.extension())
+            .map(|ext| format!(".{}", ext.to_string_lossy()));
+        messages::warn(
+            "warning.type-mismatch",
+            &[
+                &resp.url(),
+                &detected.map(|kind| kind.name).unwrap_or("-"),
+                &content_type.unwrap_or("-"),
+                &extension.as_deref().unwrap_or("-"),
+            ],
+        );
+    }
+    detected.map(|kind| kind.name)
+}
+
+/// Rename an `-O` output whose extension claims another type than the
+/// `kind` its body was detected as, returning the new path. An existing
+/// file at the corrected name is left alone.
+fn fix_extension(
+    path: &Path,
+    kind: &magic::Kind,
+    job: &Job,
+    opts: &Options,
+) -> Result<Option<PathBuf>, DownloadError> {
+    if !magic::by_extension(path).is_some_and(|claimed| claimed != kind) {
+        return Ok(None);
+    }
+    let fixed = magic::fixed_path(path, kind);
+    if fs::symlink_metadata(&fixed).is_ok() {
+        messages::warn("warning.fix-extension-exists", &[&path.display(), &fixed.display()]);
+        return Ok(None);
+    }
+    check_output_path(&fixed, job, opts)?;
+    fs::rename(path, &fixed)?;
+    messages::note("note.fix-extension", &[&path.display(), &fixed.display()]);
+    Ok(Some(fixed))
+}
+
+/// Move an output from its pending name to the one its `--output-template`
+/// gives it after the body's SHA-256.
+fn move_named(path: &Path, named: &Path, job: &Job, opts: &Options) -> Result<(), DownloadError> {
+    check_output_path(named, job, opts)?;
+    if let Some(dir) = named.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+        check_output_path(named, job, opts)?;
+    }
+    fs::rename(path, named)?;
+    Ok(())
+}
+
+/// The output `--content-disposition` names after the server's
+/// suggestion, beside the one named after the URL.
+fn disposition_path(path: &Path, resp: &reqwest::blocking::Response) -> Option<PathBuf> {
+    let value = resp.headers().get(header::CONTENT_DISPOSITION)?;
+    let name = filename::from_disposition(str::from_utf8(value.as_bytes()).ok()?)?;
+    Some(path.with_file_name(name))
+}
+
+/// The result for a partial output that turned out to hold the whole
+/// resource already, as a server refusing a range from its end says. A
+/// `.part` it was `staged` in is moved into place once verified.
+fn finish_complete(
+    job: &Job,
+    path: &Path,
+    staged: Option<&Path>,
+    len: u64,
+    opts: &Options,
+) -> Result<DownloadResult, DownloadError> {
+    messages::note("note.continue-complete", &[&path.display(), &len]);
+    let written = staged.unwrap_or(path);
+    let mut result = hash_file(written, opts)?;
+    print_digests(&path.display().to_string(), &result.digests);
+    if let Some(ref checksum) = job.checksum {
+        verify_checksum(checksum, &result, Some(written))?;
+    }
+    if let Some(part) = staged {
+        commit_part(part, path, job, opts)?;
+    }
+    result.bytes_written = 0;
+    result.url_effective = job.url.clone();
+    result.resumed_from = Some(len);
+    Ok(result)
+}
+
+fn run_job(
+    client: &reqwest::blocking::Client,
+    job: &Job,
+    opts: &Options,
+) -> Result<DownloadResult, DownloadError> {
+    if let Some(ref path) = job.output {
+        check_output_path(path, job, opts)?;
+    }
+    if opts.follow_link_next {
+        return run_parts(client, job, opts);
+    }
+    if let Some(ref name) = opts.zip_member {
+        return run_zip_member(client, job, name, opts);
+    }
+
+    if let (Some(offset), true) = (opts.start_offset, job.checksum.is_some()) {
+        return Err(DownloadError::input("error.start-offset-checksum", &[&offset]));
+    }
+    if let (Some(offset), Some(path)) = (opts.start_offset, job.output.as_ref()) {
+        if !fsutil::is_stream(path) {
+            // before anything is sent, and before a missing output is created
+            let len = fs::metadata(path).map(|meta| meta.len()).unwrap_or(0);
+            check_resumable(path, len, offset)?;
+        }
+    }
+
+    // with --continue, ask only for what a partial output is missing: the
+    // .part an earlier run left, or else the output itself, carried on in
+    // place
+    let partial_len = |path: &Path| fs::metadata(path).map(|meta| meta.len()).ok().filter(|&len| len > 0);
+    let (mut partial, in_place) = match job.output {
+        Some(ref path) if opts.continue_partial && !fsutil::is_stream(path) => {
+            match staging_path(path, job, opts).as_deref().and_then(partial_len) {
+                Some(len) => (Some(len), false),
+                None => (partial_len(path), true),
+            }
+        }
+        _ => (None, false),
+    };
+    // the state saved with a .part says which resource it is a copy of and,
+    // for a segmented download, which of its bytes have arrived
+    let part = job.output.as_ref().and_then(|path| staging_path(path, job, opts));
+    let mut saved = part.as_deref().filter(|_| partial.is_some() && !in_place).and_then(resume::State::load);
+    if let (Some(state), Some(part)) = (saved.as_ref(), part.as_ref()) {
+        if state.url != job.url {
+            messages::warn("warning.continue-other-url", &[&job.url, &part.display(), &state.url]);
+            partial = None;
+            saved = None;
+        }
+    }
+    if let Some(missing) = saved.as_ref().and_then(resume::State::first_missing) {
+        partial = Some(missing);
+    }
+    let mut from = opts.start_offset.or(partial);
+    // ask for the rest of the version the partial output holds, so that a
+    // changed resource comes back whole
+    let if_range = match (saved.as_ref(), job.output.as_ref()) {
+        (Some(state), _) => ranges::if_range(state.etag.as_deref(), state.last_modified.as_deref()),
+        (None, Some(path)) if in_place && partial.is_some() => meta::Validators::load(path)
+            .and_then(|saved| ranges::if_range(saved.etag.as_deref(), saved.last_modified.as_deref())),
+        _ => None,
+    };
+    let existing = match job.output {
+        Some(ref path) if !fsutil::is_stream(path) => Existing::of(path, opts),
+        _ => Existing::default(),
+    };
+
+    // setup client for downloading and send request
+    let (mut body, mut sniffed) = open_page(client, job, from, if_range.as_ref(), &existing, opts)?;
+    // rather than splice two versions together, a changed resource is
+    // fetched whole
+    let changed = body.response().status() == reqwest::StatusCode::PARTIAL_CONTENT
+        && saved.as_ref().is_some_and(|state| !state.describes(body.response()));
+    if changed {
+        messages::warn("warning.continue-changed", &[&job.url]);
+        partial = None;
+        saved = None;
+        from = opts.start_offset;
+        (body, sniffed) = open_page(client, job, from, None, &existing, opts)?;
+    }
+    if let Some(path) = job.output.as_ref().filter(|_| existing.is_known()) {
+        if !existing.is_replaced_by(body.response()) {
+            messages::note("note.not-modified", &[&path.display(), &job.url]);
+            return Ok(DownloadResult {
+                not_modified: true,
+                ..DownloadResult::default()
+            });
+        }
+    }
+    if let Some(offset) = opts.start_offset {
+        ranges::check_from(body.response(), offset)
+            .map_err(|e| DownloadError::Input(format!("{}: {}", job.url, e).into()))?;
+    }
+    // a server that says when to come back is asked again then, not saved
+    if matches!(body.response().status().as_u16(), 429 | 503) {
+        if let (Some(retry_after), Err(source)) = (
+            ratelimit::retry_after(body.response().headers()),
+            body.response().error_for_status_ref(),
+        ) {
+            return Err(DownloadError::Throttled { retry_after, source });
+        }
+    }
+    // a complete partial output is answered with a 416, which is no failure
+    if opts.fail_on_status && !partial.is_some_and(|len| ranges::ends_at(body.response(), len)) {
+        if let Err(e) = body.response().error_for_status_ref() {
+            return Err(DownloadError::from_reqwest(e, &job.url));
+        }
+    }
+    if job_retries(job, opts) > 0 {
+        // a server error is worth another attempt, not saving as the body
+        if let Err(e) = body.response().error_for_status_ref() {
+            let err = DownloadError::from_reqwest(e, &job.url);
+            if err.is_transient() {
+                return Err(err);
+            }
+        }
+    }
+    let resumed = match partial {
+        Some(len) if ranges::ends_at(body.response(), len) => {
+            let path = job.output.as_ref().unwrap();
+            let staged = staging_path(path, job, opts).filter(|_| !in_place);
+            return finish_complete(job, path, staged.as_deref(), len, opts);
+        }
+        Some(len) => match ranges::check_from(body.response(), len) {
+            Ok(()) => Some(len),
+            Err(_) if body.response().status() == reqwest::StatusCode::OK => {
+                match if_range {
+                    Some(_) => messages::warn("warning.continue-changed", &[&job.url]),
+                    None => messages::warn("warning.continue-restart", &[&job.url]),
+                }
+                None
+            }
+            Err(e) => return Err(DownloadError::Input(format!("{}: {}", job.url, e).into())),
+        },
+        None => None,
+    };
+    control::receiving(body.total());
+    let socket = tcpinfo::Socket::of(body.response());
+    let url_effective = body.response().url().to_string();
+    let request = body.response().extensions().get::<SentRequest>().map(|sent| sent.0.clone());
+
+    // process response
+    if let Some(path) = job.output.as_ref().filter(|path| fsutil::is_stream(path)) {
+        let total = body.total();
+        let mut reader = magic::Sniffer::new(
+            io::Cursor::new(sniffed).chain(tcpinfo::Watch::new(&mut body, socket.as_ref())),
+            opts.detect_type,
+        );
+        let mut result = stream_output(&mut reader, path, job, total, opts)?;
+        result.tcp = socket.as_ref().and_then(tcpinfo::Socket::last);
+        if opts.verbose {
+            report_tcp(&job.url, result.tcp);
+        }
+        result.detected_type = check_type(reader.detected(), body.response(), Some(path), opts);
+        if let Some(name) = result.detected_type {
+            console::out(&format!("type({}) = {}", path.display(), name));
+        }
+        result.url_effective = url_effective;
+        result.response = Some(response_json(body.response()));
+        result.request = request;
+        result.timing.record_response(body.response());
+        return Ok(result);
+    }
+    // --content-disposition puts a URL-named output under the server's name
+    let disposed = match job.output {
+        Some(ref path) if opts.content_disposition && job.remote_named => disposition_path(path, body.response()),
+        _ => None,
+    };
+    let disposed = match disposed {
+        Some(path) => Some(unclobbered(&path, opts)?),
+        None => None,
+    };
+    if let Some(ref path) = disposed {
+        check_output_path(path, job, opts)?;
+        messages::note("note.content-disposition", &[&job.url, &path.display()]);
+    }
+    if let Some(file_path) = disposed.as_ref().or(job.output.as_ref()) {
+        // the output only appears once it is complete and verified
+        let staged = staging_path(file_path, job, opts).filter(|_| !(in_place && resumed.is_some()));
+        let written = staged.as_deref().unwrap_or(file_path);
+        // a segmented .part carries on with the segments it was split into
+        let saved_segments = saved.map(|state| state.segments).filter(|segments| !segments.is_empty());
+        let splittable = match resumed {
+            _ if !sniffed.is_empty() => None,
+            Some(len) => saved_segments.as_ref().and_then(|_| segments::splittable(body.response(), len)),
+            None => opts.segments.and_then(|_| segments::splittable(body.response(), 0)),
+        };
+        let plan = splittable.and_then(|total| {
+            let client = opts.clients.switch(&job.url, body.response().url()).unwrap_or(client);
+            let mut headers = follow_up_headers(&job.headers, body.response().url(), opts);
+            // refuse to put together segments of two different versions
+            if let Some(validator) = ranges::validator(body.response()) {
+                headers.insert(header::IF_RANGE, validator);
+            }
+            let url = body.response().url().clone();
+            match (saved_segments, opts.segments) {
+                (Some(segments), _) if segments.last().map(|segment| segment.end) == Some(total) => {
+                    Some(segments::Plan::resume(client, url, headers, total, segments))
+                }
+                (None, Some(wanted)) => Some(segments::Plan::new(client, url, headers, total, wanted)),
+                _ => None,
+            }
+        });
+        // the digests of a segmented download are taken from the file once
+        // it is complete
+        let hasher = match resumed {
+            Some(len) if plan.is_none() => hash_prefix(written, len, opts)?,
+            _ => Hasher::new(),
+        };
+        let write_from = opts.start_offset.or(resumed);
+        if staged.is_some() {
+            check_output_path(file_path, job, opts)?;
+        }
+        let output_file = match write_from {
+            Some(offset) => resume_output(written, offset, job, opts)?,
+            None => create_output(written, job, opts)?,
+        };
+        if let Some(total) = body.total() {
+            let reserved = preallocate(&output_file, file_path, total);
+            if reserved.is_err() && write_from.is_none() {
+                remove_output(written);
+            }
+            reserved?;
+        }
+        let state = staged.as_ref().map(|_| resume::State::of(&job.url, body.response()));
+        let segment_file = match plan {
+            Some(ref plan) => {
+                if opts.verbose {
+                    messages::note("note.segments", &[&plan.count(), &plan.total()]);
+                }
+                Some(output_file.try_clone()?)
+            }
+            None => None,
+        };
+        // what --continue needs to pick the .part up again; the bytes of a
+        // segment count once they are on disk
+        let checkpoint = |plan: Option<&segments::Plan>| {
+            if let (Some(state), Some(part)) = (state.as_ref(), staged.as_ref()) {
+                if let Some(ref file) = segment_file {
+                    let _ = file.sync_data();
+                }
+                save_state(state, plan, part);
+            }
+        };
+        checkpoint(plan.as_ref());
+        let kept = match write_from {
+            Some(offset) if plan.is_none() => Some((output_file.try_clone()?, offset)),
+            _ => None,
+        };
+        let mut writer = BufWriter::new(RetryWriter::new(output_file));
+        let unpack_path = match gzip::decompressed_path(file_path).filter(|_| opts.decompress_keep_both) {
+            Some(path) => Some(unclobbered(&path, opts)?),
+            None => None,
+        };
+        let mut unpack = match unpack_path {
+            Some(ref path) => {
+                let file = create_output(path, job, opts)?;
+                Some(gzip::Branch::spawn(BufWriter::new(RetryWriter::new(file))))
+            }
+            None => None,
+        };
+        let mut validators = meta::Validators::from_response(body.response());
+        validators.content_length = body.total();
+
+        // setup progress bar based on the size of the whole resource
+        let to_fetch = plan.as_ref().map_or(body.total(), |plan| Some(plan.remaining()));
+        let mut progress = display(to_fetch.unwrap_or(0), Phase::Download, opts);
+
+        // copy file with progress updates
+        let copied = match (plan.as_ref(), segment_file.as_ref()) {
+            (Some(plan), Some(file)) => {
+                let mut checkpoint = |plan: &segments::Plan| checkpoint(Some(plan));
+                fetch_segments(plan, &mut body, file, written, &mut progress, &mut checkpoint, opts)
+                    .map(|result| (result, None))
+            }
+            _ => {
+                let mut reader = magic::Sniffer::new(
+                    io::Cursor::new(sniffed).chain(tcpinfo::Watch::new(&mut body, socket.as_ref())),
+                    opts.detect_type,
                 );
+                let result = match unpack {
+                    Some(ref mut unpack) => {
+                        let mut both = ioutil::Tee::new(&mut writer, unpack);
+                        download_with_progress(&mut reader, &mut both, &mut progress, opts.buffer)
+                    }
+                    None => download_continued(&mut reader, &mut writer, &mut progress, opts.buffer, hasher),
+                };
+                result.map(|result| (result, reader.detected()))
+            }
+        };
+        let copied = copied.and_then(|copied| writer.flush().map(|_| copied));
+        drop(writer);
+        let (mut result, detected) = match copied {
+            Ok(copied) => copied,
+            Err(e) => {
+                drop(unpack);
+                let e = abandon_copy(e, written, write_from.is_none(), &mut progress, opts);
+                if let (DownloadError::Cancelled, Some(path)) = (&e, unpack_path.as_ref()) {
+                    remove_output(path);
+                }
+                match staged {
+                    Some(ref part) if fs::symlink_metadata(part).is_err() => resume::State::remove(part),
+                    _ => checkpoint(plan.as_ref()),
+                }
+                return Err(e);
+            }
+        };
+        result.resumed_from = resumed;
+        result.tcp = socket.as_ref().and_then(tcpinfo::Socket::last);
+        result.url_effective = url_effective;
+        result.response = Some(response_json(body.response()));
+        result.timing.record_response(body.response());
+        if let Some((file, offset)) = kept {
+            // drop whatever an earlier, longer copy left past the new end
+            file.set_len(offset + result.bytes_written)?;
+        }
+        let unpacked = unpack.map(|unpack| unpack.finish());
+        if opts.record_request {
+            validators.sha1 = result.digests.get(Algorithm::Sha1).map(String::from);
+            validators.sha256 = result.digests.get(Algorithm::Sha256).map(String::from);
+            validators.request = request.clone();
+        }
+        result.request = request;
+
+        // clear the bar before anything else is printed
+        progress.finish();
+
+        let named = job
+            .named
+            .as_ref()
+            .filter(|named| named.needs_sha256())
+            .map(|named| unclobbered(&named.path(result.digests.get(Algorithm::Sha256)), opts))
+            .transpose()?;
+
+        // print hash digests
+        print_digests(&digest_label(named.as_deref().unwrap_or(file_path), opts), &result.digests);
+        if opts.verbose {
+            report_tcp(&job.url, result.tcp);
+        }
+
+        if let Some(ref checksum) = job.checksum {
+            let verified = verify_checksum(checksum, &result, Some(written));
+            if let (Err(_), Some(path)) = (&verified, unpack_path.as_ref()) {
+                remove_output(path);
+            }
+            if let (Err(_), Some(part)) = (&verified, staged.as_ref()) {
+                resume::State::remove(part);
+            }
+            verified?;
+        }
+        // a corrupt stream fails the job once the compressed output is in place
+        let unpack_failed = match (unpacked, unpack_path) {
+            (Some(unpacked), Some(path)) => match decompressed_copy(unpacked, path) {
+                Ok(copy) => {
+                    result.decompressed = Some(copy);
+                    None
+                }
+                Err(e) => Some(e),
+            },
+            _ => None,
+        };
+        if opts.remote_time {
+            set_remote_time(written, body.response());
+            if let Some(ref copy) = result.decompressed {
+                set_remote_time(&copy.path, body.response());
+            }
+        }
+        if opts.provenance {
+            let url = credentials::redact(&job.url);
+            let url_effective = credentials::redact(&result.url_effective);
+            let provenance = meta::Provenance {
+                url: &url,
+                url_effective: &url_effective,
+                etag: validators.etag.as_deref(),
+                sha256: result.digests.get(Algorithm::Sha256),
+            };
+            if let Err(e) = provenance.record(written) {
+                messages::warn("warning.provenance", &[&file_path.display(), &e]);
+            }
+        }
+        if opts.mark_of_the_web {
+            if let Err(e) = motw::mark(written, &credentials::redact(&result.url_effective)) {
+                messages::warn("warning.mark-of-the-web", &[&file_path.display(), &e]);
+            }
+        }
+        if let Some(ref part) = staged {
+            commit_part(part, file_path, job, opts)?;
+        }
+        if let Some(e) = unpack_failed {
+            return Err(e);
+        }
+
+        result.detected_type = check_type(detected, body.response(), Some(file_path), opts);
+        if let Some(name) = result.detected_type {
+            console::out(&format!("type({}) = {}", file_path.display(), name));
+        }
+        result.renamed_to = disposed.clone();
+        if let Some(named) = named {
+            move_named(file_path, &named, job, opts)?;
+            result.renamed_to = Some(named);
+        }
+        let moved = result.renamed_to.clone();
+        let file_path = moved.as_deref().unwrap_or(file_path);
+        if let (Some(kind), true) = (detected, opts.fix_extension && job.remote_named) {
+            if let Some(fixed) = fix_extension(file_path, kind, job, opts)? {
+                result.renamed_to = Some(fixed);
+            }
+        }
+        let file_path = result.renamed_to.as_deref().unwrap_or(file_path);
+
+        if let Some(storage) = opts.save_validators {
+            if let Err(e) = validators.save(file_path, storage) {
+                messages::warn("warning.save-validators", &[&e]);
+            }
+        }
+
+        Ok(result)
+    } else {
+        let stdout = io::stdout();
+        let lock = stdout.lock();
+        let mut writer = BufWriter::new(RetryWriter::new(lock));
+
+        let mut progress = display(body.total().unwrap_or(0), Phase::Download, opts);
+        let mut reader = magic::Sniffer::new(
+            io::Cursor::new(sniffed).chain(tcpinfo::Watch::new(&mut body, socket.as_ref())),
+            opts.detect_type,
+        );
+        let copied = download_with_progress(&mut reader, &mut writer, &mut progress, opts.buffer)
+            .and_then(|copied| writer.flush().map(|_| copied));
+        let mut result = copied.inspect_err(|_| progress.abandon())?;
+        progress.finish();
+        print_digests(&digest_label(Path::new("-"), opts), &result.digests);
+        result.tcp = socket.as_ref().and_then(tcpinfo::Socket::last);
+        if opts.verbose {
+            report_tcp(&job.url, result.tcp);
+        }
+        result.detected_type = check_type(reader.detected(), body.response(), None, opts);
+        result.url_effective = url_effective;
+        result.response = Some(response_json(body.response()));
+        result.request = request;
+        result.timing.record_response(body.response());
+
+        if let Some(ref checksum) = job.checksum {
+            verify_checksum(checksum, &result, None)?;
+        }
+
+        Ok(result)
+    }
+}
+
+/// Extract one member of the zip archive at the job's URL. Only the end of
+/// the archive, its central directory and the member's own bytes are
+/// fetched, unless the server ignores range requests and the archive has
+/// to be downloaded whole.
+fn run_zip_member(
+    client: &reqwest::blocking::Client,
+    job: &Job,
+    name: &str,
+    opts: &Options,
+) -> Result<DownloadResult, DownloadError> {
+    let mut headers = job.headers.clone();
+    headers.insert(header::RANGE, zip::tail_range());
+    let mut resp = http_download(client, &job.url, &headers, opts)
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|e| DownloadError::from_reqwest(e, &job.url))?;
+    let url_effective = resp.url().to_string();
+    let response = response_json(&resp);
+    let mut exchange = timing::Phases::default();
+    exchange.record_response(&resp);
+
+    let mut archive = if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        let range_headers = follow_up_headers(&job.headers, resp.url(), opts);
+        let client = opts.clients.switch(&job.url, resp.url()).unwrap_or(client);
+        zip::Archive::remote(client, resp, range_headers)?
+    } else {
+        messages::warn("warning.zip-no-range", &[&url_effective, &name]);
+        let total = resp.content_length().unwrap_or(0);
+        control::receiving(resp.content_length());
+        zip::Archive::spool(|file| {
+            let mut progress = display(total, Phase::Download, opts);
+            let copied = download_with_progress(&mut resp, file, &mut progress, opts.buffer);
+            progress.finish();
+            copied.map(|_| ())
+        })?
+    };
+    let member = archive.member(name).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => DownloadError::Input(format!("{}: {}", url_effective, e).into()),
+        _ => DownloadError::Output(e),
+    })?;
+    let mut reader = archive.open(&member)?;
+    control::receiving(Some(member.size));
+
+    let mut result = match job.output {
+        Some(ref path) if fsutil::is_stream(path) => {
+            stream_output(&mut reader, path, job, Some(member.size), opts)?
+        }
+        Some(ref path) => {
+            let staged = staging_path(path, job, opts);
+            check_output_path(path, job, opts)?;
+            let written = staged.as_deref().unwrap_or(path);
+            let output_file = create_output(written, job, opts)?;
+            if let Err(e) = preallocate(&output_file, path, member.size) {
+                remove_output(written);
+                return Err(e);
+            }
+            let mut writer = BufWriter::new(RetryWriter::new(output_file));
+            let mut progress = display(member.size, Phase::Download, opts);
+            let copied = download_with_progress(&mut reader, &mut writer, &mut progress, opts.buffer)
+                .and_then(|copied| writer.flush().map(|_| copied));
+            drop(writer);
+            let result = copied.map_err(|e| abandon_copy(e, written, true, &mut progress, opts))?;
+            progress.finish();
+            print_digests(&path.display().to_string(), &result.digests);
+            if let Some(ref checksum) = job.checksum {
+                verify_checksum(checksum, &result, Some(written))?;
+            }
+            if let Some(part) = staged {
+                commit_part(&part, path, job, opts)?;
+            }
+            result
+        }
+        None => {
+            let stdout = io::stdout();
+            let mut writer = BufWriter::new(RetryWriter::new(stdout.lock()));
+            let mut progress = display(member.size, Phase::Download, opts);
+            let copied = download_with_progress(&mut reader, &mut writer, &mut progress, opts.buffer)
+                .and_then(|copied| writer.flush().map(|_| copied));
+            let result = copied.inspect_err(|_| progress.abandon())?;
+            progress.finish();
+            print_digests(&digest_label(Path::new("-"), opts), &result.digests);
+            if let Some(ref checksum) = job.checksum {
+                verify_checksum(checksum, &result, None)?;
+            }
+            result
+        }
+    };
+    result.url_effective = url_effective;
+    result.response = Some(response);
+    exchange.transfer_ms = result.timing.transfer_ms;
+    result.timing = exchange;
+    Ok(result)
+}
+
+/// The output path `-O` gives a zip member: its name without the
+/// directories leading to it.
+fn member_name_path(member: &str, output_dir: Option<&Path>) -> Result<PathBuf, DownloadError> {
+    let base = member
+        .rsplit('/')
+        .next()
+        .filter(|base| !base.is_empty())
+        .and_then(filename::sanitize)
+        .ok_or_else(|| DownloadError::input("error.no-filename", &[&member]))?;
+    Ok(match output_dir {
+        Some(dir) => dir.join(base),
+        None => PathBuf::from(base),
+    })
+}
+
+fn report_error(err: &DownloadError, verbose: bool) {
+    if verbose {
+        err.write_chain(&mut console::Lines::new(Stream::Stderr));
+    } else {
+        console::err(&err.localized());
+    }
+}
+
+/// Add one key per digest, named after its algorithm.
+fn add_digests(digests: &Digests, doc: &mut Value) {
+    for &(algorithm, ref hex) in digests.iter() {
+        doc[algorithm.to_string()] = json!(hex);
+    }
+}
+
+/// The headers of a response that the result document reports.
+const REPORTED_HEADERS: [header::HeaderName; 6] = [
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_ENCODING,
+    header::CONTENT_DISPOSITION,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+fn response_json(resp: &reqwest::blocking::Response) -> Value {
+    let mut headers = serde_json::Map::new();
+    for name in &REPORTED_HEADERS {
+        if let Some(value) = resp.headers().get(name).and_then(|value| value.to_str().ok()) {
+            headers.insert(name.to_string(), json!(value));
+        }
+    }
+    json!({
+        "status": resp.status().as_u16(),
+        "headers": headers,
+    })
+}
+
+fn result_json(result: &DownloadResult) -> Value {
+    let mut doc = json!({
+        "bytes_written": result.bytes_written,
+    });
+    add_digests(&result.digests, &mut doc);
+    if let Some(ref response) = result.response {
+        doc["response"] = response.clone();
+    }
+    if let Some(ref request) = result.request {
+        doc["request"] = request.clone();
+    }
+    result.timing.add_to(&mut doc);
+    if let Some(duration) = result.duration {
+        doc["duration_ms"] = json!(timing::millis(duration));
+        doc["bytes_per_sec"] = json!(average_rate(result));
+        doc["peak_bytes_per_sec"] = json!(result.peak_rate);
+        doc["retries"] = json!(result.retries);
+    }
+    tcpinfo::Stats::add_to(result.tcp, &mut doc);
+    if let Some(addr) = result.served_by {
+        doc["served_by"] = json!(addr.to_string());
+    }
+    if let Some(name) = result.detected_type {
+        doc["detected_type"] = json!(name);
+    }
+    if let Some(offset) = result.start_offset {
+        doc["start_offset"] = json!(offset);
+    }
+    if let Some(len) = result.resumed_from {
+        doc["resumed_from"] = json!(len);
+    }
+    if let Some(count) = result.segments {
+        doc["segments"] = json!(count);
+    }
+    if let Some(ref copy) = result.decompressed {
+        let mut decompressed = json!({
+            "output": copy.path,
+            "bytes_written": copy.bytes_written,
+        });
+        add_digests(&copy.digests, &mut decompressed);
+        doc["decompressed"] = decompressed;
+    }
+    doc
+}
+
+fn error_json(err: &DownloadError) -> Value {
+    let mut doc = json!({
+        "kind": err.kind().name(),
+        "message": err.to_string(),
+    });
+    if let Some(status) = err.status_code() {
+        doc["status"] = json!(status);
+    }
+    if let Some(errno) = err.errno_name() {
+        doc["errno"] = json!(errno);
+    }
+    if let Some(alert) = err.tls_alert() {
+        doc["tls_alert"] = json!(alert);
+    }
+    if let Some(received) = err.received_bytes() {
+        doc["received_bytes"] = json!(received);
+    }
+    if let Some(skew) = err.clock_skew() {
+        doc["clock_skew_secs"] = json!(skew);
+    }
+    if let Some(stats) = err.tcp_stats() {
+        tcpinfo::Stats::add_to(Some(stats), &mut doc);
+    }
+    doc
+}
+
+fn outcome_json(job: &Job, outcome: &Result<Transfer, DownloadError>) -> Value {
+    let result = match *outcome {
+        Ok(Transfer::Downloaded(ref result)) => Some(&**result),
+        _ => None,
+    };
+    json!({
+        "status": match *outcome {
+            Ok(Transfer::Downloaded(_)) => "downloaded",
+            Ok(Transfer::Skipped) => "skipped_existing",
+            Ok(Transfer::NotAttempted) => "not_attempted",
+            Err(_) => "failed",
+        },
+        "url": job.url,
+        "url_effective": result.map(|r| r.url_effective.clone()).unwrap_or_else(|| job.url.clone()),
+        "output": result
+            .and_then(|r| r.renamed_to.as_ref())
+            .or(job.output.as_ref())
+            .map(|p| p.display().to_string()),
+        "result": result.map(result_json),
+        "error": outcome.as_ref().err().map(error_json),
+        "overrides": Some(job.overrides.to_json()).filter(|_| !job.overrides.is_empty()),
+    })
+}
+
+/// The exit code of a batch with these `outcomes`: one stopped early tells
+/// apart from one where all or only some entries failed.
+fn batch_exit_code(outcomes: &[Result<Transfer, DownloadError>], cancelled: bool) -> i32 {
+    let failed = outcomes.iter().filter(|o| o.is_err()).count();
+    let not_attempted = outcomes.iter().filter(|o| matches!(**o, Ok(Transfer::NotAttempted))).count();
+    // entries cancelled without a cancel for everything were aborted by
+    // --fail-fast=abort
+    let aborted = outcomes.iter().filter(|o| matches!(**o, Err(DownloadError::Cancelled))).count();
+    if cancelled {
+        error::EXIT_CANCELLED
+    } else if not_attempted > 0 || aborted > 0 {
+        error::EXIT_STOPPED_EARLY
+    } else if failed == outcomes.len() && failed > 0 {
+        error::EXIT_ALL_FAILED
+    } else if failed > 0 {
+        error::EXIT_SOME_FAILED
+    } else {
+        0
+    }
+}
+
+/// Every way out of the process goes through here, so that `--result-file`
+/// is written however the run ended.
+struct Shutdown {
+    result_file: Option<PathBuf>,
+    /// The `--control-socket` to remove, once it was created.
+    control_socket: Option<PathBuf>,
+    /// Where `--cookie-jar` writes the cookies at the end of the run.
+    cookie_jar: Option<(PathBuf, Arc<cookies::Jar>)>,
+    /// `--har`, written however the run ended.
+    har: Option<(PathBuf, Arc<har::Archive>)>,
+    started_at: DateTime<Utc>,
+    timestamp: Option<template::Timestamp>,
+    verbose: bool,
+    /// `--json`: print the document on stdout too.
+    json: bool,
+}
+
+impl Shutdown {
+    /// Report `err` and exit with its exit code.
+    fn fail(&self, err: &DownloadError) -> ! {
+        report_error(err, self.verbose);
+        self.exit(err.exit_code(), json!({ "error": error_json(err) }))
+    }
+
+    fn exit(&self, code: i32, mut doc: Value) -> ! {
+        if let Some(ref path) = self.control_socket {
+            control::remove(path);
+        }
+        if let Some((ref path, ref jar)) = self.cookie_jar {
+            if let Err(e) = jar.save(path) {
+                console::err(&messages::render("error.not-saved", &[&"--cookie-jar", &path.display(), &e]));
+            }
+        }
+        if let Some((ref path, ref har)) = self.har {
+            if let Err(e) = har.save(path) {
+                console::err(&messages::render("error.not-saved", &[&"--har", &path.display(), &e]));
+            }
+        }
+        if !doc["error"].is_object() {
+            doc["error"] = Value::Null;
+        }
+        doc["exit_code"] = json!(code);
+        doc["started_at"] = json!(self.started_at.to_rfc3339_opts(SecondsFormat::Millis, true));
+        doc["finished_at"] = json!(Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true));
+        if let Some(ref timestamp) = self.timestamp {
+            doc["timestamp_name"] = json!({
+                "instant": timestamp.instant.to_rfc3339_opts(SecondsFormat::Secs, true),
+                "timezone": if timestamp.local { "local" } else { "utc" },
+            });
+        }
+        if let Some(ref path) = self.result_file {
+            let contents = serde_json::to_vec_pretty(&doc).unwrap();
+            if let Err(e) = fsutil::write_atomic(path, &contents) {
+                console::err(&format!("{}: {}", path.display(), e));
+            }
+        }
+        if self.json {
+            // after every line for the screen, which went to stderr
+            console::flush();
+            let stdout = io::stdout();
+            let _ = writeln!(stdout.lock(), "{}", doc);
+        }
+        console::exit(code);
+    }
+}
+
+/// Use a digest in the fragment of the job's URL, such as `#sha256=<hex>`,
+/// as its checksum when none was given.
+fn fragment_checksum(job: &mut Job, verbose: bool) -> Result<(), DownloadError> {
+    let fragment = match reqwest::Url::parse(&job.url).ok().and_then(|url| url.fragment().map(String::from)) {
+        Some(fragment) => fragment,
+        None => return Ok(()),
+    };
+    let found = checksum::from_fragment(&fragment)
+        .map_err(|e| DownloadError::input("error.url-fragment", &[&job.url, &e]))?;
+    match (found, job.checksum.is_some()) {
+        (Some(FragmentDigest::Checked(found)), true) => {
+            if verbose {
+                messages::note("note.fragment-checksum-overridden", &[&job.url, &found]);
+            }
+        }
+        (Some(FragmentDigest::Checked(found)), false) => {
+            if verbose {
+                messages::note("note.fragment-checksum", &[&job.url, &found]);
+            }
+            job.checksum = Some(found);
+        }
+        (Some(FragmentDigest::Unchecked(name)), false) => {
+            messages::warn("warning.fragment-checksum-unchecked", &[&job.url, &name]);
+        }
+        (Some(FragmentDigest::Unchecked(_)), true) | (None, _) => {}
+    }
+    Ok(())
+}
+
+/// Fetch the `--check` list from `url`.
+fn fetch_sums(client: &reqwest::blocking::Client, url: &str, opts: &Options) -> Result<String, DownloadError> {
+    let client = opts.clients.for_url(url).unwrap_or(client);
+    http_download(client, url, &header::HeaderMap::new(), opts)
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.text())
+        .map_err(|e| DownloadError::from_reqwest(e, url))
+}
+
+/// Take a job's checksum from the `--check` list, by the name of the file
+/// it is saved as or else its remote name. A checksum the input entry
+/// gives wins.
+fn listed_checksum(job: &mut Job, sums: &checksum::Sums, source: &str, verbose: bool) -> Result<(), DownloadError> {
+    if job.checksum.is_some() {
+        if verbose {
+            messages::note("note.check-overridden", &[&job.url, &source]);
+        }
+        return Ok(());
+    }
+    let saved = job
+        .output
+        .as_ref()
+        .filter(|path| !fsutil::is_stream(path))
+        .and_then(|path| path.file_name())
+        .map(|name| name.to_string_lossy().into_owned());
+    let remote = remote_name_path(&job.url, None, true)
+        .ok()
+        .map(|name| name.to_string_lossy().into_owned());
+    let (name, found) = match saved.iter().chain(remote.iter()).find_map(|name| Some((name, sums.find(name)?))) {
+        Some(listed) => listed,
+        None => {
+            let name = saved.or(remote).unwrap_or_default();
+            return Err(DownloadError::input("error.not-listed", &[&job.url, &name, &source]));
+        }
+    };
+    if verbose {
+        messages::note("note.check-checksum", &[&job.url, found, &name, &source]);
+    }
+    job.checksum = Some(found.clone());
+    Ok(())
+}
+
+/// `msg` about the batch entry numbered `line`, named by the catalog
+/// message `unit`, such as "line 3: ...".
+fn entry_error(unit: &'static str, line: usize, msg: Message) -> DownloadError {
+    DownloadError::Input(Message::Catalog("error.entry", vec![Message::new(unit, &[&line]), msg]))
+}
+
+/// Turn batch entries into jobs, resolving destinations and rejecting
+/// entries that would write to the same path. Errors name an entry by
+/// the catalog message `unit` and its number, such as "line 3".
+fn batch_jobs(
+    entries: Vec<input::Entry>,
+    unit: &'static str,
+    output_dir: Option<&Path>,
+    timestamp: Option<&template::Timestamp>,
+    output_template: Option<&template::OutputTemplate>,
+    decode_names: bool,
+    reject_credentials: bool,
+) -> Result<Vec<Job>, DownloadError> {
+    let mut seen: HashMap<PathBuf, usize> = HashMap::new();
+    let mut jobs = Vec::with_capacity(entries.len());
+    for mut entry in entries {
+        let line = entry.line;
+        let auth = url_credentials(&mut entry.url, reject_credentials).map_err(|e| match e {
+            DownloadError::Input(msg) => entry_error(unit, line, msg),
+            e => entry_error(unit, line, e.to_string().into()),
+        })?;
+        let remote_named = entry.output.is_none();
+        let dir = match (output_dir, entry.dir) {
+            (Some(base), Some(dir)) => Some(base.join(dir)),
+            (base, dir) => dir.or_else(|| base.map(Path::to_path_buf)),
+        };
+        let mut named = None;
+        let output = match entry.output {
+            Some(path) => {
+                let path = expand_timestamp(timestamp, path).map_err(|e| match e {
+                    DownloadError::Input(msg) => entry_error(unit, line, msg),
+                    e => entry_error(unit, line, e.to_string().into()),
+                })?;
+                match dir {
+                    Some(dir) => dir.join(path),
+                    None => path,
+                }
+            }
+            None => match output_template {
+                Some(template) => {
+                    let expanded = template
+                        .expand(&entry.url, dir.as_deref(), decode_names)
+                        .map_err(|e| entry_error(unit, line, e.into()))?;
+                    let path = expanded.path(None);
+                    named = Some(expanded);
+                    path
+                }
+                None => remote_name_path(&entry.url, dir.as_deref(), decode_names)?,
+            },
+        };
+        let mut headers = header::HeaderMap::new();
+        for (name, value) in &entry.headers {
+            let name = header::HeaderName::from_bytes(name.as_bytes());
+            let value = header::HeaderValue::from_str(value);
+            match (name, value) {
+                (Ok(name), Ok(value)) => {
+                    headers.append(name, value);
+                }
+                _ => {
+                    let entry = Message::new(unit, &[&line]);
+                    return Err(DownloadError::Input(Message::Catalog("error.entry-header", vec![entry])));
+                }
+            }
+        }
+        if let Some(auth) = auth {
+            headers.entry(header::AUTHORIZATION).or_insert(auth);
+        }
+        if let Some(first) = seen.insert(output.clone(), entry.line) {
+            let args = vec![
+                Message::new(unit, &[&entry.line]),
+                Message::Text(output.display().to_string()),
+                Message::new(unit, &[&first]),
+            ];
+            return Err(DownloadError::Input(Message::Catalog("error.same-destination", args)));
+        }
+        jobs.push(Job {
+            url: entry.url,
+            output: Some(output),
+            remote_named,
+            checksum: entry.checksum,
+            headers,
+            overrides: entry.overrides,
+            named,
+        });
+    }
+    Ok(jobs)
+}
+
+fn expand_timestamp(
+    timestamp: Option<&template::Timestamp>,
+    path: PathBuf,
+) -> Result<PathBuf, DownloadError> {
+    match timestamp {
+        Some(timestamp) => timestamp.expand(&path).map_err(|e| DownloadError::Input(e.into())),
+        None => Ok(path),
+    }
+}
+
+fn parse_query_pair(value: &str) -> Result<(String, String), DownloadError> {
+    match value.find('=') {
+        Some(idx) if idx > 0 => Ok((value[..idx].to_string(), value[idx + 1..].to_string())),
+        _ => Err(DownloadError::input("error.query-pair", &[&value])),
+    }
+}
+
+/// Parse the seconds of a timeout option, which must be more than none.
+fn parse_timeout_arg(flag: &str, value: &str) -> Result<Duration, DownloadError> {
+    match ioutil::parse_secs(value) {
+        Some(secs) if secs > Duration::from_secs(0) => Ok(secs),
+        _ => Err(DownloadError::input("error.expected-timeout", &[&flag, &value])),
+    }
+}
+
+/// Parse a `-H` header.
+fn parse_header_arg(value: &str) -> Result<(header::HeaderName, header::HeaderValue), DownloadError> {
+    let (name, text) = input::parse_header(value).map_err(|e| DownloadError::Input(format!("-H {}", e).into()))?;
+    match (header::HeaderName::from_bytes(name.as_bytes()), header::HeaderValue::from_str(&text)) {
+        (Ok(name), Ok(mut text)) => {
+            text.set_sensitive(SENSITIVE_HEADERS.contains(&name.as_str()));
+            Ok((name, text))
+        }
+        _ => Err(DownloadError::input("error.invalid-header", &[&value])),
+    }
+}
+
+fn read_input_file(path: &str, format: input::Format) -> Result<Vec<input::Entry>, DownloadError> {
+    let entries = if path == "-" {
+        let stdin = io::stdin();
+        let lock = stdin.lock();
+        input::read_entries(lock, format)
+    } else {
+        let file = File::open(path).map_err(|e| DownloadError::Input(format!("{}: {}", path, e).into()))?;
+        input::read_entries(BufReader::new(file), format)
+    };
+    entries.map_err(|e| {
+        let lines: Vec<String> = e.lines().map(|line| format!("{}: {}", path, line)).collect();
+        DownloadError::Input(lines.join("\n").into())
+    })
+}
+
+/// Read the manifest named on the `manifest` subcommand and resolve its
+/// files, failing before any request is made when one cannot be.
+fn read_manifest(args: &clap::ArgMatches) -> Result<Vec<input::Entry>, DownloadError> {
+    let path = args.value_of("file").unwrap();
+    let mut vars = HashMap::new();
+    for value in args.values_of("var").into_iter().flatten() {
+        let (name, value) = manifest::parse_var(value).map_err(|e| DownloadError::Input(e.into()))?;
+        vars.insert(name, value);
+    }
+    let text = fs::read_to_string(path).map_err(|e| DownloadError::Input(format!("{}: {}", path, e).into()))?;
+    manifest::Manifest::parse(&text)
+        .and_then(|manifest| manifest.entries(&vars, |name| env::var(name).ok()))
+        .map_err(|e| DownloadError::Input(format!("{}: {}", path, e).into()))
+}
+
+fn convert_input(args: &clap::ArgMatches) -> ! {
+    let format = input::Format::from_name(args.value_of("format").unwrap()).unwrap();
+    let entries = match read_input_file(args.value_of("file").unwrap(), format) {
+        Ok(entries) => entries,
+        Err(e) => {
+            report_error(&e, false);
+            console::exit(e.exit_code());
+        }
+    };
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for entry in &entries {
+        if let Err(e) = writeln!(out, "{}", entry.to_json()) {
+            report_error(&DownloadError::Output(e), false);
+            console::exit(error::EXIT_OUTPUT_FAILURE);
+        }
+    }
+    console::exit(0);
+}
+
+fn verify_range(args: &clap::ArgMatches) -> ! {
+    match hash_range(args) {
+        Ok(()) => console::exit(0),
+        Err(e) => {
+            report_error(&e, false);
+            console::exit(e.exit_code());
+        }
+    }
+}
+
+/// Hash bytes `--range` of a local file and compare them with `--checksum`.
+/// The file is only read, and kept whatever the outcome.
+fn hash_range(args: &clap::ArgMatches) -> Result<(), DownloadError> {
+    let path = Path::new(args.value_of("file").unwrap());
+    let range = verify::ByteRange::parse(args.value_of("range").unwrap()).map_err(|e| DownloadError::Input(e.into()))?;
+    let checksum = Checksum::parse(args.value_of("checksum").unwrap()).map_err(|e| DownloadError::Input(e.into()))?;
+
+    let file = File::open(path)?;
+    let (offset, len) = range
+        .resolve(file.metadata()?.len())
+        .map_err(|msg| DownloadError::Input(format!("{}: {}", path.display(), msg).into()))?;
+    let mut reader = verify::read_span(file, offset, len)?;
+    let mut algorithms = hasher::DEFAULT.to_vec();
+    if !algorithms.contains(&checksum.algorithm) {
+        algorithms.push(checksum.algorithm);
+    }
+    hasher::select(algorithms);
+    let progress = if console::screen_is_terminal() {
+        Progress::new(Box::new(bar::Bar::stdout(len).phase(Phase::Verify)), progress::DEFAULT_INTERVAL)
+    } else {
+        Progress::hidden()
+    };
+    let result = hash_with_progress(&mut reader, progress)?;
+    if result.bytes_written != len {
+        return Err(DownloadError::input("error.file-shrank", &[&path.display(), &range]));
+    }
+
+    print_digests(&format!("{}[{}]", path.display(), range), &result.digests);
+    verify_checksum(&checksum, &result, None)
+}
+
+fn self_test(args: &clap::ArgMatches) -> ! {
+    let remote = if args.is_present("offline") {
+        None
+    } else {
+        Some(match args.value_of("url") {
+            Some(url) => selftest::Remote {
+                url,
+                sha256: args.value_of("sha256"),
+            },
+            None => selftest::Remote {
+                url: selftest::DEFAULT_URL,
+                sha256: Some(selftest::DEFAULT_SHA256),
+            },
+        })
+    };
+    let client_opts = ClientOptions {
+        max_redirects: 5,
+        reject_url_credentials: true,
+        insecure: false,
+        insecure_hostname: false,
+        tcp_keepalive: None,
+        tcp_nodelay: true,
+        verbose: false,
+        pin: None,
+        profiles: Arc::default(),
+        profile: None,
+        timeout: None,
+        connect_timeout: None,
+        read_timeout: None,
+        proxy: None,
+        manual_redirects: false,
+        tls: Arc::default(),
+        http_version: None,
+        derived: false,
+    };
+    let passed = match build_client(&client_opts) {
+        Ok(client) => {
+            let dir = Path::new(args.value_of("dir").unwrap_or("."));
+            selftest::run(&client, dir, remote)
+        }
+        Err(e) => {
+            console::out(&format!("FAIL client: {}", e));
+            false
+        }
+    };
+    console::exit(if passed { 0 } else { 1 });
+}
+
+fn main() {
+    let args = App::new("download")
+        .version(crate_version!())
+        .about("remote file downloader command-line interface")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("convert-input")
+                .about("rewrite an input file as the native JSON-lines format on stdout")
+                .arg(
+                    Arg::with_name("format")
+                        .required(true)
+                        .possible_values(&input::FORMATS),
+                )
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("manifest")
+                .about("download and verify every file listed in a TOML release manifest")
+                .arg(
+                    Arg::with_name("var")
+                        .long("var")
+                        .value_name("NAME=VALUE")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .help("set a {NAME} manifest variable (default: $DOWNLOAD_VAR_NAME, then [vars])"),
+                )
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("check the digest of a byte range of a local file")
+                .arg(
+                    Arg::with_name("range")
+                        .long("range")
+                        .value_name("START-END")
+                        .takes_value(true)
+                        .required(true)
+                        .help("inclusive byte range to hash; START- runs to the end of the file"),
+                )
+                .arg(
+                    Arg::with_name("checksum")
+                        .long("checksum")
+                        .value_name("ALGO:HEX")
+                        .takes_value(true)
+                        .required(true)
+                        .help("expected digest of the range, e.g. sha256:<hex>"),
+                )
+                .arg(Arg::with_name("file").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("self-test")
+                .about("check that hashing, TLS roots, temp files and progress work on this host")
+                .arg(
+                    Arg::with_name("url")
+                        .long("url")
+                        .value_name("URL")
+                        .takes_value(true)
+                        .help("fetch URL instead of the default known resource"),
+                )
+                .arg(
+                    Arg::with_name("sha256")
+                        .long("sha256")
+                        .value_name("HEX")
+                        .takes_value(true)
+                        .requires("url")
+                        .help("expected sha256 of the resource fetched with --url"),
+                )
+                .arg(
+                    Arg::with_name("offline")
+                        .long("offline")
+                        .conflicts_with("url")
+                        .help("skip the remote fetch"),
+                )
+                .arg(
+                    Arg::with_name("dir")
+                        .long("dir")
+                        .value_name("DIR")
+                        .takes_value(true)
+                        .help("directory to create the temp file in (default .)"),
+                ),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("OUTPUT")
+                .help("output filename")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote-name")
+                .short("O")
+                .long("remote-name")
+                .help("output to a file using the same name as the remote"),
+        )
+        .arg(
+            Arg::with_name("content-disposition")
+                .long("content-disposition")
+                .conflicts_with_all(&[
+                    "continue",
+                    "start-offset",
+                    "timestamping",
+                    "etag",
+                    "zip-member",
+                    "follow-link-next",
+                ])
+                .help("name outputs taken from the URL after the server's Content-Disposition filename instead"),
+        )
+        .arg(
+            Arg::with_name("no-decode-filename")
+                .long("no-decode-filename")
+                .help("keep percent-escapes in filenames taken from the URL"),
+        )
+        .arg(
+            Arg::with_name("output-dir")
+                .short("P")
+                .long("output-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("directory for outputs named from the remote, created if missing"),
+        )
+        .arg(
+            Arg::with_name("output-template")
+                .long("output-template")
+                .value_name("TEMPLATE")
+                .takes_value(true)
+                .conflicts_with_all(&["output", "content-disposition", "zip-member"])
+                .help("name outputs from {host}, {path}, {basename}, {ext}, {date} and {sha256[:N]} of the remote"),
+        )
+        .arg(
+            Arg::with_name("create-dirs")
+                .long("create-dirs")
+                .help("create missing parent directories of output files"),
+        )
+        .arg(
+            Arg::with_name("control-socket")
+                .long("control-socket")
+                .value_name("PATH")
+                .takes_value(true)
+                .help("answer status, cancel and set-rate queries on a Unix socket at PATH"),
+        )
+        .arg(
+            Arg::with_name("sandbox")
+                .long("sandbox")
+                .help("once outputs are resolved, forbid running programs, changing privileges \
+                       and changing files outside the output directories (Linux)"),
+        )
+        .arg(
+            Arg::with_name("no-follow-symlinks")
+                .long("no-follow-symlinks")
+                .help("refuse to write an output file through a symbolic link \
+                       (always the case for names derived with -O)"),
+        )
+        .arg(
+            Arg::with_name("follow-symlinks")
+                .long("follow-symlinks")
+                .conflicts_with("no-follow-symlinks")
+                .help("allow names derived with -O to be written through a symbolic link"),
+        )
+        .arg(
+            Arg::with_name("restrict-to")
+                .long("restrict-to")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("refuse to write output files that resolve to a path outside DIR"),
+        )
+        .arg(
+            Arg::with_name("input-file")
+                .short("i")
+                .long("input-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with_all(&["output", "checksum", "expect-sha256", "expect-sha1"])
+                .help("read URLs from FILE ('-' for stdin), each optionally followed by a tab-separated output path and checksum"),
+        )
+        .arg(
+            Arg::with_name("input-format")
+                .long("input-format")
+                .takes_value(true)
+                .possible_values(&input::FORMATS)
+                .default_value("text")
+                .help("format of the input file"),
+        )
+        .arg(
+            Arg::with_name("checksum")
+                .long("checksum")
+                .value_name("ALGO:HEX")
+                .takes_value(true)
+                .help("verify the download against an expected md5, sha1, sha256, sha512 or blake3 digest"),
+        )
+        .arg(
+            Arg::with_name("check")
+                .long("check")
+                .value_name("FILE|URL")
+                .takes_value(true)
+                .conflicts_with_all(&["checksum", "expect-sha256", "expect-sha1", "start-offset"])
+                .help("verify each download against the digest a sha256sum-style list gives for its file name"),
+        )
+        .arg(
+            Arg::with_name("expect-sha256")
+                .long("expect-sha256")
+                .value_name("HEX")
+                .takes_value(true)
+                .conflicts_with_all(&["checksum", "expect-sha1"])
+                .help("verify the download's sha256 digest, removing the output and exiting with 4 on a mismatch"),
+        )
+        .arg(
+            Arg::with_name("expect-sha1")
+                .long("expect-sha1")
+                .value_name("HEX")
+                .takes_value(true)
+                .conflicts_with("checksum")
+                .help("verify the download's sha1 digest, removing the output and exiting with 4 on a mismatch"),
+        )
+        .arg(
+            Arg::with_name("digest")
+                .long("digest")
+                .value_name("LIST")
+                .takes_value(true)
+                .help("comma-separated digests to compute and print: md5, sha1, sha256, sha512, blake3 [default: sha1,sha256]"),
+        )
+        .arg(
+            Arg::with_name("user-agent")
+                .short("A")
+                .long("user-agent")
+                .takes_value(true)
+                .help("use value as user-agent header"),
+        )
+        .arg(
+            Arg::with_name("header")
+                .short("H")
+                .long("header")
+                .value_name("NAME: VALUE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("send this header with every request (repeatable); it replaces a header of the same name, \
+                       such as User-Agent, and is replaced by an input entry's own"),
+        )
+        .arg(
+            Arg::with_name("user")
+                .short("u")
+                .long("user")
+                .value_name("USER:PASSWORD")
+                .takes_value(true)
+                .conflicts_with("bearer")
+                .help("authenticate with HTTP Basic auth; credentials in a URL win for that URL"),
+        )
+        .arg(
+            Arg::with_name("cookie")
+                .short("b")
+                .long("cookie")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("send the cookies in FILE, in the Netscape format curl and wget use, and those servers set \
+                       during the run, redirects included"),
+        )
+        .arg(
+            Arg::with_name("cookie-jar")
+                .short("c")
+                .long("cookie-jar")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("write every cookie, set by servers or read with --cookie, to FILE at the end of the run"),
+        )
+        .arg(
+            Arg::with_name("netrc")
+                .long("netrc")
+                .help("take Basic auth credentials for each host from $NETRC or ~/.netrc"),
+        )
+        .arg(
+            Arg::with_name("netrc-file")
+                .long("netrc-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("take Basic auth credentials for each host from FILE, in netrc format"),
+        )
+        .arg(
+            Arg::with_name("bearer")
+                .long("bearer")
+                .value_name("TOKEN")
+                .takes_value(true)
+                .help("send TOKEN as a bearer token (default: $DOWNLOAD_TOKEN unless --user is given)"),
+        )
+        .arg(
+            Arg::with_name("query")
+                .long("query")
+                .value_name("KEY=VALUE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("append a percent-encoded query parameter to the URL (repeatable)"),
+        )
+        .arg(
+            Arg::with_name("query-replace")
+                .long("query-replace")
+                .requires("query")
+                .help("replace existing query parameters with the same key instead of appending"),
+        )
+        .arg(
+            Arg::with_name("max-redirects")
+                .long("max-redirects")
+                .takes_value(true)
+                .default_value("0")
+                .help("maximum number of redirects to follow"),
+        )
+        .arg(
+            Arg::with_name("timestamping")
+                .short("N")
+                .long("timestamping")
+                .conflicts_with_all(&["continue", "start-offset", "zip-member", "follow-link-next"])
+                .help("download only if the server's copy is newer than the output, asking with If-Modified-Since"),
+        )
+        .arg(
+            Arg::with_name("fail")
+                .short("f")
+                .long("fail")
+                .help("fail on a 4xx or 5xx answer, with exit code 10 or 11, instead of saving its body"),
+        )
+        .arg(
+            Arg::with_name("retry")
+                .long("retry")
+                .value_name("N")
+                .takes_value(true)
+                .help("retry transient failures (connection errors, dropped bodies, 408, 429 and 5xx) \
+                       up to N times, waiting longer before each retry"),
+        )
+        .arg(
+            Arg::with_name("retry-delay")
+                .long("retry-delay")
+                .value_name("SECS")
+                .takes_value(true)
+                .requires("retry")
+                .help("wait SECS before the first retry, doubling for each one after it (default 1)"),
+        )
+        .arg(
+            Arg::with_name("retry-max-time")
+                .long("retry-max-time")
+                .value_name("SECS")
+                .takes_value(true)
+                .requires("retry")
+                .help("start no retry later than SECS after the first attempt"),
+        )
+        .arg(
+            Arg::with_name("no-alt-address-retry")
+                .long("no-alt-address-retry")
+                .help("do not fetch again from the host's other addresses when the content fails verification"),
+        )
+        .arg(
+            Arg::with_name("reject-url-credentials")
+                .long("reject-url-credentials")
+                .help("refuse URLs and redirect targets with a user:password@ part"),
+        )
+        .arg(
+            Arg::with_name("insecure")
+                .short("k")
+                .long("insecure")
+                .help("skip all server certificate verification"),
+        )
+        .arg(
+            Arg::with_name("insecure-hostname")
+                .long("insecure-hostname")
+                .conflicts_with("insecure")
+                .help("verify the server certificate chain but not the hostname it was issued for"),
+        )
+        .arg(
+            Arg::with_name("cacert")
+                .long("cacert")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("also trust the CA certificates in the PEM FILE, such as an internal CA"),
+        )
+        .arg(
+            Arg::with_name("capath")
+                .long("capath")
+                .value_name("DIR")
+                .takes_value(true)
+                .help("also trust the CA certificates in the PEM files in DIR"),
+        )
+        .arg(
+            Arg::with_name("cert")
+                .long("cert")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("present the PEM client certificate in FILE, with its key from --key or FILE itself"),
+        )
+        .arg(
+            Arg::with_name("key")
+                .long("key")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("cert")
+                .help("the PEM private key for --cert"),
+        )
+        .arg(
+            Arg::with_name("pin-sha256")
+                .long("pin-sha256")
+                .value_name("DIGEST")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("accept only servers whose certificate key has this base64 SHA-256 digest of its \
+                       SubjectPublicKeyInfo (repeatable, or ;-separated, with an optional sha256// prefix)"),
+        )
+        .arg(
+            Arg::with_name("cacert-for")
+                .long("cacert-for")
+                .value_name("HOST=FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("trust only the CA certificates in FILE for HOST (repeatable)"),
+        )
+        .arg(
+            Arg::with_name("cert-for")
+                .long("cert-for")
+                .value_name("HOST=FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("present the client certificate in FILE to HOST (repeatable)"),
+        )
+        .arg(
+            Arg::with_name("key-for")
+                .long("key-for")
+                .value_name("HOST=FILE")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("read the private key for HOST's client certificate from FILE (repeatable)"),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("give up connecting, TLS handshake included, after SECS"),
+        )
+        .arg(
+            Arg::with_name("read-timeout")
+                .long("read-timeout")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("give up when the response or more of its body takes longer than SECS to arrive (default 30)"),
+        )
+        .arg(
+            Arg::with_name("max-time")
+                .long("max-time")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("give up on an attempt, body included, after SECS; retries get as long again"),
+        )
+        .arg(
+            Arg::with_name("tcp-keepalive")
+                .long("tcp-keepalive")
+                .value_name("SECS")
+                .takes_value(true)
+                .help("send TCP keepalive probes after SECS of idle time (off by default)"),
+        )
+        .arg(
+            Arg::with_name("tcp-nodelay")
+                .long("tcp-nodelay")
+                .help("set TCP_NODELAY on connections (the default)"),
+        )
+        .arg(
+            Arg::with_name("no-tcp-nodelay")
+                .long("no-tcp-nodelay")
+                .conflicts_with("tcp-nodelay")
+                .help("leave Nagle's algorithm on for connections"),
+        )
+        .arg(
+            Arg::with_name("http1.1")
+                .long("http1.1")
+                .conflicts_with("http2-prior-knowledge")
+                .help("speak only HTTP/1.1, even to servers that offer HTTP/2"),
+        )
+        .arg(
+            Arg::with_name("http2-prior-knowledge")
+                .long("http2-prior-knowledge")
+                .help("speak HTTP/2 from the start, without negotiating it, also over plain HTTP"),
+        )
+        .arg(
+            Arg::with_name("proxy")
+                .short("x")
+                .long("proxy")
+                .value_name("URL")
+                .takes_value(true)
+                .help("send requests through the HTTP(S) proxy at URL instead of the one HTTPS_PROXY, HTTP_PROXY \
+                       or ALL_PROXY name; hosts in NO_PROXY are still reached directly"),
+        )
+        .arg(
+            Arg::with_name("socks5")
+                .long("socks5")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .conflicts_with_all(&["proxy", "socks5-hostname"])
+                .help("connect through the SOCKS5 proxy at HOST:PORT, resolving host names locally; \
+                       [user:password@] before HOST logs in"),
+        )
+        .arg(
+            Arg::with_name("socks5-hostname")
+                .long("socks5-hostname")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .conflicts_with("proxy")
+                .help("connect through the SOCKS5 proxy at HOST:PORT, which also resolves host names, as Tor needs"),
+        )
+        .arg(
+            Arg::with_name("limit-rate")
+                .long("limit-rate")
+                .value_name("RATE")
+                .takes_value(true)
+                .help("limit the transfer rate to RATE bytes per second (e.g. 500K or 2M), shared by all downloads"),
+        )
+        .arg(
+            Arg::with_name("speed-limit")
+                .long("speed-limit")
+                .value_name("RATE")
+                .takes_value(true)
+                .help("fail a transfer slower than RATE bytes per second (e.g. 10K) for --speed-time"),
+        )
+        .arg(
+            Arg::with_name("speed-time")
+                .long("speed-time")
+                .value_name("SECS")
+                .takes_value(true)
+                .requires("speed-limit")
+                .help("how long a transfer may stay below --speed-limit (default 30)"),
+        )
+        .arg(
+            Arg::with_name("buffer-size")
+                .long("buffer-size")
+                .value_name("SIZE")
+                .takes_value(true)
+                .conflicts_with("max-buffer-size")
+                .help("read the body SIZE bytes at a time instead of adapting to throughput"),
+        )
+        .arg(
+            Arg::with_name("max-buffer-size")
+                .long("max-buffer-size")
+                .value_name("SIZE")
+                .takes_value(true)
+                .help("let the adaptive read buffer grow up to SIZE bytes (default 4M)"),
+        )
+        .arg(
+            Arg::with_name("har")
+                .long("har")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("record every request and response, with timings, headers and sizes, as an HTTP Archive in FILE"),
+        )
+        .arg(
+            Arg::with_name("dump-header")
+                .short("D")
+                .long("dump-header")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("write the status line and headers of every response, redirects included, to FILE"),
+        )
+        .arg(
+            Arg::with_name("digest-fd")
+                .long("digest-fd")
+                .value_name("FD")
+                .takes_value(true)
+                .help("after each transfer, write its size and digests as a JSON line to descriptor FD"),
+        )
+        .arg(
+            Arg::with_name("ignore-ratelimit-headers")
+                .long("ignore-ratelimit-headers")
+                .help("do not pace requests by the RateLimit-* headers servers send"),
+        )
+        .arg(
+            Arg::with_name("follow-meta-refresh")
+                .long("follow-meta-refresh")
+                .help("follow HTML pages that redirect with <meta http-equiv=\"refresh\">, \
+                       counting each hop against --max-redirects"),
+        )
+        .arg(
+            Arg::with_name("meta-refresh-host")
+                .long("meta-refresh-host")
+                .value_name("HOST")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .requires("follow-meta-refresh")
+                .help("also follow meta refreshes leading to HOST (by default only the same origin)"),
+        )
+        .arg(
+            Arg::with_name("head")
+                .short("I")
+                .long("head")
+                .conflicts_with("stdin-body")
+                .help("print the status, headers, output name and length without downloading"),
+        )
+        .arg(
+            Arg::with_name("stdin-body")
+                .long("stdin-body")
+                .help("stream stdin to URL as the request body (PUT unless -X says otherwise); \
+                       the response goes to stdout unless --then-get is given"),
+        )
+        .arg(
+            Arg::with_name("request")
+                .short("X")
+                .long("request")
+                .value_name("METHOD")
+                .takes_value(true)
+                .requires("stdin-body")
+                .help("method for the --stdin-body upload"),
+        )
+        .arg(
+            Arg::with_name("content-length")
+                .long("content-length")
+                .value_name("BYTES")
+                .takes_value(true)
+                .requires("stdin-body")
+                .help("send exactly BYTES of stdin with a Content-Length instead of chunked"),
+        )
+        .arg(
+            Arg::with_name("then-get")
+                .long("then-get")
+                .value_name("URL")
+                .takes_value(true)
+                .requires("stdin-body")
+                .help("after the upload succeeds, download URL as usual with the same client"),
+        )
+        .arg(
+            Arg::with_name("detect-type")
+                .long("detect-type")
+                .help("recognise the body's type from its first bytes, report it and warn when \
+                       Content-Type or the file extension disagree"),
+        )
+        .arg(
+            Arg::with_name("fix-extension")
+                .long("fix-extension")
+                .help("with -O, rename the output when its extension claims another type than \
+                       --detect-type found"),
+        )
+        .arg(
+            Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .value_name("WHEN")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .possible_values(&["finish", "abort"])
+                .help("stop starting batch entries after the first failure; =abort also cancels \
+                       downloads under way"),
+        )
+        .arg(
+            Arg::with_name("max-concurrent")
+                .long("max-concurrent")
+                .value_name("N")
+                .takes_value(true)
+                .help("download up to N batch entries at once [default: 1]"),
+        )
+        .arg(
+            Arg::with_name("decompress-keep-both")
+                .long("decompress-keep-both")
+                .conflicts_with_all(&["zip-member", "follow-link-next"])
+                .help("keep a .gz output as downloaded and also unpack it beside it, without the .gz"),
+        )
+        .arg(
+            Arg::with_name("zip-member")
+                .long("zip-member")
+                .value_name("NAME")
+                .takes_value(true)
+                .conflicts_with_all(&["pipelined-ranges", "follow-link-next"])
+                .help("extract the member NAME from a remote zip archive, fetching only the \
+                       parts of the archive it needs"),
+        )
+        .arg(
+            Arg::with_name("pipelined-ranges")
+                .long("pipelined-ranges")
+                .value_name("CHUNK")
+                .takes_value(true)
+                .min_values(0)
+                .require_equals(true)
+                .conflicts_with("follow-link-next")
+                .help("fetch the body as sequential ranges of CHUNK bytes (default 64M), \
+                       requesting each range while the previous one is read"),
+        )
+        .arg(
+            Arg::with_name("start-offset")
+                .long("start-offset")
+                .value_name("BYTES")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "input-file",
+                    "pipelined-ranges",
+                    "zip-member",
+                    "follow-link-next",
+                    "decompress-keep-both",
+                    "checksum",
+                    "expect-sha256",
+                    "expect-sha1",
+                    "skip-existing",
+                    "skip-verified",
+                    "if-missing",
+                ])
+                .help("fetch only the bytes from BYTES on, into the output without truncating it \
+                       or onto stdout; the server must honour the range"),
+        )
+        .arg(
+            Arg::with_name("continue")
+                .short("C")
+                .long("continue")
+                .conflicts_with_all(&[
+                    "start-offset",
+                    "pipelined-ranges",
+                    "zip-member",
+                    "follow-link-next",
+                    "decompress-keep-both",
+                    "detect-type",
+                    "fix-extension",
+                    "skip-existing",
+                    "skip-verified",
+                    "if-missing",
+                ])
+                .help("finish a partial output, or the .part an interrupted download left, by fetching \
+                       only the bytes it is missing; the digests still cover the whole file"),
+        )
+        .arg(
+            Arg::with_name("keep-partial")
+                .long("keep-partial")
+                .help("keep what a download cancelled with Ctrl-C had written, for --continue to finish"),
+        )
+        .arg(
+            Arg::with_name("segments")
+                .long("segments")
+                .value_name("N")
+                .takes_value(true)
+                .conflicts_with_all(&[
+                    "pipelined-ranges",
+                    "start-offset",
+                    "continue",
+                    "zip-member",
+                    "follow-link-next",
+                    "decompress-keep-both",
+                    "detect-type",
+                    "fix-extension",
+                ])
+                .help("fetch a file output as up to N ranges at once, each over its own \
+                       connection, when the server supports ranges"),
+        )
+        .arg(
+            Arg::with_name("follow-link-next")
+                .long("follow-link-next")
+                .help("keep fetching parts linked by Link: rel=\"next\" headers"),
+        )
+        .arg(
+            Arg::with_name("max-parts")
+                .long("max-parts")
+                .value_name("N")
+                .takes_value(true)
+                .default_value("100")
+                .help("maximum number of parts to fetch with --follow-link-next"),
+        )
+        .arg(
+            Arg::with_name("progress-interval")
+                .long("progress-interval")
+                .value_name("MS")
+                .takes_value(true)
+                .default_value("100")
+                .help("minimum milliseconds between progress updates, 1000 by default with --progress json"),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .value_name("MODE")
+                .takes_value(true)
+                .possible_values(&["bar", "json"])
+                .default_value("bar")
+                .help("show progress as a bar, or as JSON lines on stderr"),
+        )
+        .arg(
+            Arg::with_name("no-progress")
+                .long("no-progress")
+                .help("show no progress; the bar is also left out when it would not go to a terminal"),
+        )
+        .arg(
+            Arg::with_name("if-missing")
+                .long("if-missing")
+                .conflicts_with_all(&["skip-existing", "skip-verified"])
+                .help("skip outputs that already exist and are not empty, without any request"),
+        )
+        .arg(
+            Arg::with_name("no-clobber")
+                .long("no-clobber")
+                .conflicts_with_all(&["auto-rename", "continue", "start-offset", "timestamping", "etag"])
+                .help("fail rather than overwrite an output that already exists"),
+        )
+        .arg(
+            Arg::with_name("auto-rename")
+                .long("auto-rename")
+                .conflicts_with_all(&["continue", "start-offset", "timestamping", "etag"])
+                .help("write to <output>.1, <output>.2, ... rather than overwrite an output that already exists"),
+        )
+        .arg(
+            Arg::with_name("skip-existing")
+                .long("skip-existing")
+                .conflicts_with("skip-verified")
+                .help("skip outputs whose size and mtime match the remote (uses a HEAD request)"),
+        )
+        .arg(
+            Arg::with_name("skip-verified")
+                .long("skip-verified")
+                .help("skip outputs that already match their expected checksum"),
+        )
+        .arg(
+            Arg::with_name("save-validators")
+                .long("save-validators")
+                .help("record ETag, Last-Modified, Content-Length and the final URL in <output>.meta"),
+        )
+        .arg(
+            Arg::with_name("etag")
+                .long("etag")
+                .conflicts_with_all(&["continue", "start-offset", "zip-member", "follow-link-next"])
+                .help("save the ETag with the output, as --save-validators does, and leave the output alone \
+                       while If-None-Match with it gets a 304"),
+        )
+        .arg(
+            Arg::with_name("xattr")
+                .long("xattr")
+                .help("store validators in extended attributes instead of a sidecar file"),
+        )
+        .arg(
+            Arg::with_name("record-request")
+                .long("record-request")
+                .help("record the request headers and digests behind each output in its \
+                       validators and in --result-file"),
+        )
+        .arg(
+            Arg::with_name("result-file")
+                .long("result-file")
+                .value_name("FILE")
+                .takes_value(true)
+                .help("write a JSON document describing the outcome to FILE on exit"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .long("json")
+                .help("print the JSON document --result-file writes on stdout on exit, with the digests, \
+                       progress and other output on stderr"),
+        )
+        .arg(
+            Arg::with_name("timestamp-name")
+                .long("timestamp-name")
+                .help("expand strftime tokens such as %Y%m%d in output paths from the start time"),
+        )
+        .arg(
+            Arg::with_name("remote-time")
+                .short("R")
+                .long("remote-time")
+                .help("date outputs from the Last-Modified of the remote, as curl -R does (the default)"),
+        )
+        .arg(
+            Arg::with_name("mark-of-the-web")
+                .long("mark-of-the-web")
+                .help("on Windows, mark outputs as downloaded from the internet, as browsers do, \
+                       so that SmartScreen checks them"),
+        )
+        .arg(
+            Arg::with_name("no-provenance")
+                .long("no-provenance")
+                .help("record no user.xdg.origin.url and other extended attributes saying where outputs came from"),
+        )
+        .arg(
+            Arg::with_name("no-remote-time")
+                .long("no-remote-time")
+                .conflicts_with("remote-time")
+                .help("leave outputs dated when they were written"),
+        )
+        .arg(
+            Arg::with_name("timestamp-utc")
+                .long("timestamp-utc")
+                .requires("timestamp-name")
+                .help("expand --timestamp-name tokens in UTC (the default)"),
+        )
+        .arg(
+            Arg::with_name("timestamp-local")
+                .long("timestamp-local")
+                .requires("timestamp-name")
+                .conflicts_with("timestamp-utc")
+                .help("expand --timestamp-name tokens in the local timezone"),
+        )
+        .arg(
+            Arg::with_name("lang")
+                .long("lang")
+                .takes_value(true)
+                .value_name("LANG")
+                .possible_values(&messages::LANGS)
+                .help("language of messages on the terminal (default: from LC_ALL, LC_MESSAGES or LANG)"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("enable verbose logging (useful for debugging)"),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("show no progress and no notes; warnings, errors and results are still printed"),
+        )
+        .arg(
+            Arg::with_name("capabilities")
+                .long("capabilities")
+                .help("print the features, URL schemes, hash algorithms and protocols this build supports as JSON"),
+        )
+        .arg(
+            Arg::with_name("url")
+                .multiple(true)
+                .required_unless_one(&["input-file", "capabilities"]),
+        )
+        .get_matches();
+
+    messages::set_lang(args.value_of("lang").map(Lang::parse).unwrap_or_else(Lang::from_env));
+    if args.is_present("quiet") {
+        messages::set_quiet();
+    }
 
-                pb.finish_print("Done.");
+    if args.is_present("capabilities") {
+        console::out(&serde_json::to_string_pretty(&capabilities::listing()).unwrap());
+        console::exit(0);
+    }
+    if let Some(args) = args.subcommand_matches("self-test") {
+        self_test(args);
+    }
+    if let Some(args) = args.subcommand_matches("convert-input") {
+        convert_input(args);
+    }
+    if let Some(args) = args.subcommand_matches("verify") {
+        verify_range(args);
+    }
 
-                Ok(())
+    let started_at = Utc::now();
+    let timestamp = if args.is_present("timestamp-name") {
+        Some(template::Timestamp {
+            instant: started_at,
+            local: args.is_present("timestamp-local"),
+        })
+    } else {
+        None
+    };
+    let mut shutdown = Shutdown {
+        result_file: args.value_of("result-file").map(PathBuf::from),
+        control_socket: None,
+        cookie_jar: None,
+        har: None,
+        started_at,
+        timestamp,
+        verbose: args.is_present("verbose"),
+        json: args.is_present("json"),
+    };
+    if shutdown.json {
+        console::screen_on_stderr();
+    }
+    let user_agent = args.value_of("user-agent").unwrap_or(DEFAULT_USER_AGENT);
+    let max_redirects = args.value_of("max-redirects")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap();
+    let verbose = args.is_present("verbose");
+    let output_dir = args
+        .value_of("output-dir")
+        .map(|dir| expand_timestamp(timestamp.as_ref(), PathBuf::from(dir)))
+        .map(|dir| dir.unwrap_or_else(|e| shutdown.fail(&e)));
+    let output_dir = output_dir.as_deref();
+    let output_template = args.value_of("output-template").map(|value| {
+        let date = timestamp.unwrap_or(template::Timestamp { instant: started_at, local: false }).date();
+        template::OutputTemplate::parse(value, date)
+            .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(format!("--output-template {}", e).into())))
+    });
+    if output_template.as_ref().is_some_and(template::OutputTemplate::needs_sha256) {
+        // these need the name of the output before it is downloaded
+        let before = ["continue", "start-offset", "timestamping", "etag", "decompress-keep-both"];
+        if let Some(flag) = before.iter().find(|flag| args.is_present(flag)) {
+            shutdown.fail(&DownloadError::input("error.sha256-template", &[flag]));
+        }
+    }
+    let template = output_template.as_ref();
+    let progress_interval = args
+        .value_of("progress-interval")
+        .map(|ms| {
+            ms.parse::<u64>().map(Duration::from_millis).unwrap_or_else(|_| {
+                shutdown.fail(&DownloadError::input("error.expected-ms", &[&"--progress-interval", &ms]))
+            })
+        })
+        .unwrap_or(progress::DEFAULT_INTERVAL);
+    let progress = match args.value_of("progress") {
+        _ if args.is_present("no-progress") || args.is_present("quiet") => progress::Mode::Hidden,
+        Some("json") => progress::Mode::Json,
+        _ => progress::Mode::Bar,
+    };
+    // events are for programs, which want fewer of them than a bar redraws
+    let progress_interval = if progress == progress::Mode::Json && args.occurrences_of("progress-interval") == 0 {
+        progress::JSON_INTERVAL
+    } else {
+        progress_interval
+    };
+    let query = args
+        .values_of("query")
+        .map(|values| {
+            values
+                .map(|value| parse_query_pair(value).unwrap_or_else(|e| shutdown.fail(&e)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut extra_headers = header::HeaderMap::new();
+    for value in args.values_of("header").into_iter().flatten() {
+        let (name, value) = parse_header_arg(value).unwrap_or_else(|e| shutdown.fail(&e));
+        extra_headers.append(name, value);
+    }
+    // an Authorization given with -H wins over these
+    let token = env::var("DOWNLOAD_TOKEN").ok().filter(|token| !token.trim().is_empty());
+    let auth = match (args.value_of("user"), args.value_of("bearer"), token) {
+        (Some(pair), _, _) => Some(credentials::parse(pair).basic_auth()),
+        (_, Some(token), _) => Some(credentials::bearer(token).unwrap_or_else(|e| {
+            shutdown.fail(&DownloadError::Input(format!("--bearer: {}", e).into()))
+        })),
+        (_, _, Some(token)) => Some(credentials::bearer(&token).unwrap_or_else(|e| {
+            shutdown.fail(&DownloadError::Input(format!("DOWNLOAD_TOKEN: {}", e).into()))
+        })),
+        _ => None,
+    };
+    if let Some(auth) = auth {
+        extra_headers.entry(header::AUTHORIZATION).or_insert(auth);
+    }
+    let reject_credentials = args.is_present("reject-url-credentials");
+    let mut profile_args = Vec::new();
+    for option in &["cacert-for", "cert-for", "key-for"] {
+        profile_args.extend(args.values_of(option).into_iter().flatten().map(|value| (*option, value)));
+    }
+    let (cacert, capath) = (args.value_of("cacert").map(Path::new), args.value_of("capath").map(Path::new));
+    let mut tls_defaults = tls_config::Defaults::default();
+    tls_defaults.load_roots(cacert, capath).unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.into())));
+    if let Some(cert) = args.value_of("cert") {
+        let cert = tls_config::ClientCert::load(Path::new(cert), args.value_of("key").map(Path::new))
+            .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(format!("--cert {}", e).into())));
+        tls_defaults.client_cert = Some(cert);
+    }
+    for value in args.values_of("pin-sha256").into_iter().flatten() {
+        tls_defaults
+            .pins
+            .add(value)
+            .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(format!("--pin-sha256 {}", e).into())));
+    }
+    let profiles = tls_config::Profiles::load(&profile_args, &tls_defaults)
+        .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.into())));
+    // SOCKS5 goes through a relay on loopback, bound now and served once
+    // threads may start
+    let socks = match (args.value_of("socks5"), args.value_of("socks5-hostname")) {
+        (Some(value), _) => Some(("--socks5", value, false)),
+        (_, Some(value)) => Some(("--socks5-hostname", value, true)),
+        _ => None,
+    };
+    let mut relayed_proxy = String::new();
+    let relay = socks.map(|(flag, value, remote_dns)| {
+        let proxy = socks::Proxy::parse(value, remote_dns)
+            .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(format!("{} {}: {}", flag, value, e).into())));
+        relayed_proxy = proxy.to_string();
+        socks::Relay::bind(proxy).unwrap_or_else(|e| shutdown.fail(&DownloadError::Output(e)))
+    });
+    let cookie_jar = args.value_of("cookie-jar").map(PathBuf::from);
+    let cookies = match args.value_of("cookie") {
+        Some(path) => Some(cookies::Jar::load(Path::new(path)).unwrap_or_else(|e| {
+            // the jar a first run is about to create
+            if cookie_jar.as_deref() == Some(Path::new(path)) && !Path::new(path).exists() {
+                return cookies::Jar::default();
+            }
+            shutdown.fail(&DownloadError::Input(format!("--cookie {}: {}", path, e).into()))
+        })),
+        None => cookie_jar.as_ref().map(|_| cookies::Jar::default()),
+    };
+    let cookies = cookies.map(Arc::new);
+    shutdown.cookie_jar = cookie_jar.zip(cookies.clone());
+    shutdown.har = args.value_of("har").map(|path| (PathBuf::from(path), Arc::new(har::Archive::default())));
+    let max_time = args
+        .value_of("max-time")
+        .map(|value| parse_timeout_arg("--max-time", value).unwrap_or_else(|e| shutdown.fail(&e)));
+    let speed_limit = args.value_of("speed-limit").map(|value| {
+        let rate = match ioutil::parse_size(value) {
+            Some(rate) if rate > 0 => rate,
+            _ => {
+                shutdown.fail(&DownloadError::input("error.expected-rate", &[&"--speed-limit", &value, &"10K"]))
+            }
+        };
+        let time = args.value_of("speed-time").map_or(Duration::from_secs(30), |value| {
+            parse_timeout_arg("--speed-time", value).unwrap_or_else(|e| shutdown.fail(&e))
+        });
+        (rate, time)
+    });
+    let client_opts = ClientOptions {
+        max_redirects,
+        reject_url_credentials: reject_credentials,
+        insecure: args.is_present("insecure"),
+        insecure_hostname: args.is_present("insecure-hostname"),
+        tcp_keepalive: args.value_of("tcp-keepalive").map(|_| {
+            value_t!(args, "tcp-keepalive", u64)
+                .map(Duration::from_secs)
+                .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.message.into())))
+        }),
+        tcp_nodelay: !args.is_present("no-tcp-nodelay"),
+        verbose,
+        pin: None,
+        profiles: Arc::new(profiles),
+        profile: None,
+        timeout: max_time,
+        connect_timeout: args
+            .value_of("connect-timeout")
+            .map(|value| parse_timeout_arg("--connect-timeout", value).unwrap_or_else(|e| shutdown.fail(&e))),
+        read_timeout: args
+            .value_of("read-timeout")
+            .map(|value| parse_timeout_arg("--read-timeout", value).unwrap_or_else(|e| shutdown.fail(&e)))
+            .into_iter()
+            .chain(speed_limit.map(|(_, time)| time))
+            .min(),
+        proxy: match relay {
+            Some(ref relay) => {
+                let url = relay.url().unwrap_or_else(|e| shutdown.fail(&DownloadError::Output(e)));
+                Some((url, relayed_proxy.to_string()))
+            }
+            None => args.value_of("proxy").map(|url| {
+                if reqwest::Proxy::all(url).is_err() {
+                    shutdown.fail(&DownloadError::input("error.not-proxy", &[&credentials::redact(url)]));
+                }
+                (url.to_string(), credentials::redact(url))
+            }),
+        },
+        manual_redirects: cookies.is_some() || args.is_present("dump-header") || args.is_present("har") || verbose,
+        tls: Arc::new(tls_defaults),
+        http_version: if args.is_present("http1.1") {
+            Some(reqwest::Version::HTTP_11)
+        } else if args.is_present("http2-prior-knowledge") {
+            Some(reqwest::Version::HTTP_2)
+        } else {
+            None
+        },
+        derived: false,
+    };
+    if !proxies_in_use(&client_opts).is_empty() {
+        error::set_proxied();
+    }
+    let mut opts = Options {
+        user_agent,
+        headers: extra_headers,
+        cookies: cookies.clone(),
+        verbose,
+        create_dirs: args.is_present("create-dirs"),
+        output_dir,
+        follow_symlinks: if args.is_present("no-follow-symlinks") {
+            Some(false)
+        } else if args.is_present("follow-symlinks") {
+            Some(true)
+        } else {
+            None
+        },
+        restrict_to: args.value_of("restrict-to").map(|dir| {
+            fs::canonicalize(dir).unwrap_or_else(|e| {
+                shutdown.fail(&DownloadError::Input(format!("--restrict-to {}: {}", dir, e).into()))
             })
-            .map_err(|e| {
-                let _ = writeln!(&mut io::stderr(), "{}", e);
-                process::exit(EXIT_OUTPUT_FAILURE);
+        }),
+        query,
+        query_replace: args.is_present("query-replace"),
+        progress,
+        progress_interval,
+        follow_link_next: args.is_present("follow-link-next"),
+        max_parts: value_t!(args, "max-parts", usize)
+            .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.message.into()))),
+        skip: if args.is_present("if-missing") {
+            Some(SkipPolicy::Missing)
+        } else if args.is_present("skip-existing") {
+            Some(SkipPolicy::Existing)
+        } else if args.is_present("skip-verified") {
+            Some(SkipPolicy::Verified)
+        } else {
+            None
+        },
+        clobber: if args.is_present("no-clobber") {
+            Some(Clobber::Refuse)
+        } else if args.is_present("auto-rename") {
+            Some(Clobber::Rename)
+        } else {
+            None
+        },
+        max_redirects,
+        buffer: match args.value_of("buffer-size") {
+            Some(value) => BufferSize::Fixed(ioutil::parse_size(value).unwrap_or_else(|| {
+                shutdown.fail(&DownloadError::input("error.expected-size", &[&"--buffer-size", &value, &"256K"]))
+            }) as usize),
+            None => BufferSize::Adaptive {
+                max: args
+                    .value_of("max-buffer-size")
+                    .map(|value| {
+                        ioutil::parse_size(value).unwrap_or_else(|| {
+                            let args: [&dyn Display; 3] = [&"--max-buffer-size", &value, &"4M"];
+                            shutdown.fail(&DownloadError::input("error.expected-size", &args))
+                        }) as usize
+                    })
+                    .unwrap_or(ioutil::DEFAULT_MAX_BUFFER),
+            },
+        },
+        har: shutdown.har.as_ref().map(|(_, har)| har.clone()),
+        dump_header: args.value_of("dump-header").map(|path| {
+            let file = File::create(path).unwrap_or_else(|e| {
+                shutdown.fail(&DownloadError::Input(format!("--dump-header {}: {}", path, e).into()))
             });
+            Mutex::new(file)
+        }),
+        digest_fd: args.value_of("digest-fd").map(|_| {
+            let fd = value_t!(args, "digest-fd", i32)
+                .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.message.into())));
+            if fd == libc::STDOUT_FILENO {
+                shutdown.fail(&DownloadError::input("error.digest-fd-stdout", &[]));
+            }
+            let file = fsutil::writable_fd(fd).unwrap_or_else(|e| {
+                shutdown.fail(&DownloadError::Input(format!("--digest-fd {}: {}", fd, e).into()))
+            });
+            Mutex::new(file)
+        }),
+        rate_limits: if args.is_present("ignore-ratelimit-headers") {
+            None
+        } else {
+            Some(ratelimit::Limits::new(verbose))
+        },
+        follow_meta_refresh: args.is_present("follow-meta-refresh"),
+        meta_refresh_hosts: args
+            .values_of("meta-refresh-host")
+            .map(|hosts| hosts.map(String::from).collect())
+            .unwrap_or_default(),
+        pipelined_ranges: if args.is_present("pipelined-ranges") {
+            Some(match args.value_of("pipelined-ranges") {
+                Some(value) => ioutil::parse_size(value).unwrap_or_else(|| {
+                    let args: [&dyn Display; 3] = [&"--pipelined-ranges", &value, &"64M"];
+                    shutdown.fail(&DownloadError::input("error.expected-size", &args))
+                }),
+                None => ranges::DEFAULT_CHUNK,
+            })
+        } else {
+            None
+        },
+        start_offset: args.value_of("start-offset").map(|value| {
+            value.parse().unwrap_or_else(|_| {
+                shutdown.fail(&DownloadError::input("error.expected-bytes", &[&"--start-offset", &value]))
+            })
+        }),
+        continue_partial: args.is_present("continue"),
+        keep_partial: args.is_present("keep-partial"),
+        fail_on_status: args.is_present("fail"),
+        remote_time: !args.is_present("no-remote-time"),
+        provenance: !args.is_present("no-provenance"),
+        mark_of_the_web: args.is_present("mark-of-the-web") && motw::SUPPORTED,
+        timestamping: args.is_present("timestamping"),
+        etag: args.is_present("etag"),
+        content_disposition: args.is_present("content-disposition"),
+        max_time,
+        retry: Retry {
+            count: args.value_of("retry").map_or(0, |value| {
+                value.parse().unwrap_or_else(|_| {
+                    shutdown.fail(&DownloadError::input("error.expected-retries", &[&"--retry", &value]))
+                })
+            }),
+            delay: args.value_of("retry-delay").map_or(Duration::from_secs(1), |value| {
+                ioutil::parse_secs(value).unwrap_or_else(|| {
+                    shutdown.fail(&DownloadError::input("error.expected-secs", &[&"--retry-delay", &value]))
+                })
+            }),
+            max_time: args.value_of("retry-max-time").map(|value| {
+                ioutil::parse_secs(value).unwrap_or_else(|| {
+                    shutdown.fail(&DownloadError::input("error.expected-secs", &[&"--retry-max-time", &value]))
+                })
+            }),
+        },
+        segments: args.value_of("segments").map(|value| match value.parse() {
+            Ok(count) if count > 0 => count,
+            _ => {
+                shutdown.fail(&DownloadError::input("error.expected-segments", &[&"--segments", &value]))
+            }
+        }),
+        record_request: args.is_present("record-request"),
+        zip_member: args.value_of("zip-member").map(String::from),
+        detect_type: args.is_present("detect-type") || args.is_present("fix-extension"),
+        fix_extension: args.is_present("fix-extension"),
+        decompress_keep_both: args.is_present("decompress-keep-both"),
+        fail_fast: match args.value_of("fail-fast") {
+            Some("abort") => Some(FailFast::Abort),
+            Some(_) => Some(FailFast::Finish),
+            None if args.is_present("fail-fast") => Some(FailFast::Finish),
+            None => None,
+        },
+        connections: timing::Connections::new(),
+        clients: ClientPool::default(),
+        alt_address_retry: if args.is_present("no-alt-address-retry") {
+            None
+        } else {
+            Some(client_opts.clone())
+        },
+        save_validators: if !["save-validators", "record-request", "etag"].iter().any(|name| args.is_present(name)) {
+            None
+        } else if args.is_present("xattr") {
+            Some(meta::Storage::Xattr)
+        } else {
+            Some(meta::Storage::Sidecar)
+        },
+    };
+
+    if args.is_present("xattr") && opts.save_validators.is_none() {
+        shutdown.fail(&DownloadError::input("error.xattr-storage", &[]));
+    }
+    if args.is_present("mark-of-the-web") && !motw::SUPPORTED {
+        messages::warn("warning.motw-unsupported", &[]);
+    }
+
+    let decode_names = !args.is_present("no-decode-filename");
+    let manifest_args = args.subcommand_matches("manifest");
+    // several URLs on the command line run as a batch, saved under their
+    // remote names
+    let urls: Vec<&str> = args.values_of("url").map(Iterator::collect).unwrap_or_default();
+    if urls.len() > 1 && !args.is_present("input-file") && manifest_args.is_none() {
+        let single = ["output", "checksum", "expect-sha256", "expect-sha1", "start-offset", "stdin-body"];
+        if let Some(name) = single.iter().find(|name| args.is_present(name)) {
+            shutdown.fail(&DownloadError::input("error.single-url", &[name, &urls.len()]));
+        }
+    }
+    let batch = args.is_present("input-file") || manifest_args.is_some() || urls.len() > 1;
+    let max_concurrent = match args.value_of("max-concurrent") {
+        Some(_) => match value_t!(args, "max-concurrent", usize) {
+            Ok(n) if n > 0 => n,
+            _ => shutdown.fail(&DownloadError::input("error.max-concurrent", &[])),
+        },
+        None => 1,
+    };
+    let mut upload = if args.is_present("stdin-body") {
+        let fail = |msg: Message| shutdown.fail(&DownloadError::Input(msg));
+        if batch {
+            fail(Message::new("error.stdin-body-batch", &[]));
+        }
+        if !args.is_present("then-get") && (args.is_present("output") || args.is_present("remote-name")) {
+            fail(Message::new("error.stdin-body-output", &[]));
+        }
+        let mut url = args.value_of("url").unwrap().to_string();
+        let mut headers = header::HeaderMap::new();
+        if let Some(auth) = url_credentials(&mut url, reject_credentials).unwrap_or_else(|e| shutdown.fail(&e)) {
+            headers.insert(header::AUTHORIZATION, auth);
+        }
+        let method = args.value_of("request").unwrap_or("PUT");
+        Some(Upload {
+            url,
+            method: reqwest::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .unwrap_or_else(|_| fail(Message::new("error.stdin-body-method", &[&method]))),
+            headers,
+            content_length: args.value_of("content-length").map(|_| {
+                value_t!(args, "content-length", u64)
+                    .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.message.into())))
+            }),
+        })
     } else {
-        let stdout = io::stdout();
-        let lock = stdout.lock();
-        let mut writer = BufWriter::new(lock);
+        None
+    };
+    let mut jobs = if let Some(manifest_args) = manifest_args {
+        read_manifest(manifest_args)
+            .and_then(|entries| {
+                let timestamp = timestamp.as_ref();
+                batch_jobs(entries, "unit.line", output_dir, timestamp, template, decode_names, reject_credentials)
+            })
+            .unwrap_or_else(|e| shutdown.fail(&e))
+    } else if let Some(input_file) = args.value_of("input-file") {
+        let format = input::Format::from_name(args.value_of("input-format").unwrap()).unwrap();
+        read_input_file(input_file, format)
+            .and_then(|entries| {
+                let timestamp = timestamp.as_ref();
+                batch_jobs(entries, "unit.line", output_dir, timestamp, template, decode_names, reject_credentials)
+            })
+            .unwrap_or_else(|e| shutdown.fail(&e))
+    } else if urls.len() > 1 {
+        let entries = urls
+            .iter()
+            .enumerate()
+            .map(|(idx, url)| input::Entry {
+                line: idx + 1,
+                url: url.to_string(),
+                output: None,
+                dir: None,
+                checksum: None,
+                headers: Vec::new(),
+                overrides: input::Overrides::default(),
+            })
+            .collect();
+        batch_jobs(entries, "unit.url", output_dir, timestamp.as_ref(), template, decode_names, reject_credentials)
+            .unwrap_or_else(|e| shutdown.fail(&e))
+    } else {
+        let mut url = args.value_of("then-get").or(args.value_of("url")).unwrap().to_string();
+        let mut headers = header::HeaderMap::new();
+        if let Some(auth) = url_credentials(&mut url, reject_credentials).unwrap_or_else(|e| shutdown.fail(&e)) {
+            headers.insert(header::AUTHORIZATION, auth);
+        } else if let Some(auth) = upload.as_ref().and_then(|upload| same_origin_auth(upload, &url)) {
+            headers.insert(header::AUTHORIZATION, auth);
+        }
 
-        if verbose {
-            write_status(&mut writer, &resp);
-            write_headers(&mut writer, &resp);
+        // determine an output filename; if none are set then send to stdout
+        let mut named = None;
+        let output = if let (true, Some(member)) = (args.is_present("remote-name"), opts.zip_member.as_ref()) {
+            Some(member_name_path(member, output_dir).unwrap_or_else(|e| shutdown.fail(&e)))
+        } else if let (true, Some(template)) = (args.is_present("remote-name"), template) {
+            let expanded = template
+                .expand(&url, output_dir, decode_names)
+                .unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.into())));
+            named = Some(expanded);
+            named.as_ref().map(|named| named.path(None))
+        } else if args.is_present("remote-name") {
+            Some(remote_name_path(&url, output_dir, decode_names).unwrap_or_else(|e| shutdown.fail(&e)))
+        } else {
+            args.value_of("output")
+                .map(|path| expand_timestamp(timestamp.as_ref(), PathBuf::from(path)))
+                .map(|path| path.unwrap_or_else(|e| shutdown.fail(&e)))
+        };
+        let checksum = match (args.value_of("expect-sha256"), args.value_of("expect-sha1")) {
+            (Some(hex), _) => Some(Checksum::new(Algorithm::Sha256, hex.trim())),
+            (_, Some(hex)) => Some(Checksum::new(Algorithm::Sha1, hex.trim())),
+            _ => args.value_of("checksum").map(Checksum::parse),
+        };
+        let checksum =
+            checksum.map(|checksum| checksum.unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.into()))));
+        vec![Job {
+            url,
+            output,
+            remote_named: args.is_present("remote-name"),
+            checksum,
+            headers,
+            overrides: input::Overrides::default(),
+            named,
+        }]
+    };
+
+    // -I fetches no body, and prints the headers on stdout
+    let head = args.is_present("head");
+    if shutdown.json && !head && jobs.iter().any(|job| job.output.is_none()) {
+        shutdown.fail(&DownloadError::input("error.json-stdout", &[]));
+    }
+    // a body on stdout leaves the bar and the digests to stderr
+    if !head && jobs.iter().any(|job| job.output.is_none()) {
+        console::screen_on_stderr();
+    }
+
+    let netrc = match (args.value_of("netrc-file"), args.is_present("netrc")) {
+        (Some(path), _) => Some(netrc::Netrc::load(Path::new(path)).unwrap_or_else(|e| {
+            shutdown.fail(&DownloadError::Input(format!("--netrc-file {}: {}", path, e).into()))
+        })),
+        // a missing ~/.netrc just has no credentials in it
+        (None, true) => netrc::default_path().and_then(|path| match netrc::Netrc::load(&path) {
+            Ok(netrc) => Some(netrc),
+            Err(_) if !path.exists() => None,
+            Err(e) => shutdown.fail(&DownloadError::Input(format!("{}: {}", path.display(), e).into())),
+        }),
+        _ => None,
+    };
+    if let Some(netrc) = netrc.filter(|_| !opts.headers.contains_key(header::AUTHORIZATION)) {
+        let headers = jobs.iter_mut().map(|job| (&job.url, &mut job.headers));
+        for (url, headers) in headers.chain(upload.iter_mut().map(|upload| (&upload.url, &mut upload.headers))) {
+            let host = reqwest::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_string));
+            if let Some(found) = host.and_then(|host| netrc.lookup(&host)) {
+                headers.entry(header::AUTHORIZATION).or_insert_with(|| found.basic_auth());
+            }
+        }
+    }
+
+    // a local --check list is read before the sandbox could shut it out
+    let check = args.value_of("check").map(|source| {
+        let remote = reqwest::Url::parse(source)
+            .map(|url| url.scheme() == "http" || url.scheme() == "https")
+            .unwrap_or(false);
+        let local = if remote {
+            None
+        } else {
+            Some(fs::read_to_string(source).unwrap_or_else(|e| {
+                shutdown.fail(&DownloadError::Input(format!("--check {}: {}", source, e).into()))
+            }))
+        };
+        (source, local)
+    });
+
+    // the client and the control socket start threads, which must already
+    // be inside the sandbox
+    if args.is_present("sandbox") {
+        let mut allowed = sandbox::Allowed::default();
+        for path in jobs.iter().filter_map(|job| job.output.as_ref()) {
+            if fsutil::is_stream(path) {
+                allowed.file(path);
+            } else {
+                allowed.dir(path.parent().unwrap_or_else(|| Path::new("")));
+            }
+        }
+        let result_file = shutdown.result_file.as_deref();
+        let cookie_jar = shutdown.cookie_jar.as_ref().map(|(path, _)| path.as_path());
+        let har = shutdown.har.as_ref().map(|(path, _)| path.as_path());
+        let control_socket = args.value_of("control-socket").map(Path::new);
+        for path in result_file.into_iter().chain(control_socket).chain(cookie_jar).chain(har) {
+            allowed.dir(path.parent().unwrap_or_else(|| Path::new("")));
+        }
+        if opts.zip_member.is_some() {
+            allowed.dir(&env::temp_dir());
+        }
+        sandbox::enter(&allowed, verbose);
+    }
+    if let Some((rate, time)) = speed_limit {
+        control::set_speed_limit(rate, time);
+    }
+    if let Some(value) = args.value_of("limit-rate") {
+        match ioutil::parse_size(value) {
+            Some(rate) if rate > 0 => control::set_rate_limit(rate),
+            _ => {
+                shutdown.fail(&DownloadError::input("error.expected-rate", &[&"--limit-rate", &value, &"2M"]))
+            }
+        }
+    }
+    if let Some(relay) = relay {
+        relay.serve();
+    }
+    if let Some(path) = args.value_of("control-socket") {
+        control::listen(Path::new(path)).unwrap_or_else(|e| {
+            shutdown.fail(&DownloadError::Input(format!("--control-socket {}: {}", path, e).into()))
+        });
+        shutdown.control_socket = Some(PathBuf::from(path));
+    }
+    control::cancel_on_signals();
+
+    opts.clients = ClientPool::build(&client_opts)
+        .unwrap_or_else(|e| shutdown.fail(&DownloadError::from_reqwest(e, "")));
+    let client = opts.clients.for_url("").unwrap();
+
+    if let Some((source, local)) = check {
+        let text = match local {
+            Some(text) => text,
+            None => fetch_sums(client, source, &opts).unwrap_or_else(|e| shutdown.fail(&e)),
+        };
+        let sums = checksum::Sums::parse(&text);
+        if sums.is_empty() {
+            shutdown.fail(&DownloadError::input("error.no-digests", &[&source]));
+        }
+        for job in &mut jobs {
+            listed_checksum(job, &sums, source, verbose).unwrap_or_else(|e| shutdown.fail(&e));
+        }
+    }
+    for job in &mut jobs {
+        fragment_checksum(job, verbose).unwrap_or_else(|e| shutdown.fail(&e));
+    }
+    // an expected digest is always computed, even if not asked for
+    let mut algorithms = match args.value_of("digest") {
+        Some(list) => hasher::parse_list(list).unwrap_or_else(|e| shutdown.fail(&DownloadError::Input(e.into()))),
+        None => hasher::DEFAULT.to_vec(),
+    };
+    for checksum in jobs.iter().filter_map(|job| job.checksum.as_ref()) {
+        if !algorithms.contains(&checksum.algorithm) {
+            algorithms.push(checksum.algorithm);
+        }
+    }
+    hasher::select(algorithms);
+
+    if head {
+        let mut code = 0;
+        let entries: Vec<Value> = jobs
+            .iter()
+            .map(|job| {
+                let client = opts.clients.for_url(&job.url).unwrap_or(client);
+                run_head(client, job, &opts).unwrap_or_else(|e| {
+                    report_error(&e, verbose);
+                    code = e.exit_code();
+                    json!({ "url": job.url, "error": error_json(&e) })
+                })
+            })
+            .collect();
+        shutdown.exit(code, json!({ "head": entries }));
+    }
+
+    let uploaded = upload.as_ref().map(|upload| {
+        let then_get = args.is_present("then-get");
+        let client = opts.clients.for_url(&upload.url).unwrap();
+        let doc = run_upload(client, upload, &opts, !then_get).unwrap_or_else(|e| shutdown.fail(&e));
+        if !then_get {
+            shutdown.exit(0, json!({ "upload": doc }));
+        }
+        doc
+    });
+
+    if jobs.len() == 1 && !batch {
+        let outcome = process_job(client, &jobs[0], &opts);
+        let code = match outcome {
+            Ok(_) => 0,
+            Err(ref e) => {
+                report_error(e, verbose);
+                e.exit_code()
+            }
+        };
+        let mut doc = outcome_json(&jobs[0], &outcome);
+        if let Some(uploaded) = uploaded {
+            doc["upload"] = uploaded;
+        }
+        if let Some(limits) = opts.rate_limits.as_ref().filter(|limits| !limits.is_empty()) {
+            doc["rate_limits"] = limits.to_json();
+        }
+        shutdown.exit(code, doc);
+    }
+
+    let outcomes = run_queue(client, &jobs, &opts, max_concurrent);
+
+    // summarize where every entry ended up
+    for (job, outcome) in jobs.iter().zip(&outcomes) {
+        let renamed = match *outcome {
+            Ok(Transfer::Downloaded(ref result)) => result.renamed_to.as_ref(),
+            _ => None,
+        };
+        let path = renamed.or(job.output.as_ref()).map(|p| p.display().to_string()).unwrap_or_default();
+        match *outcome {
+            Ok(Transfer::Downloaded(ref result)) => console::out(&messages::render(
+                "summary.downloaded",
+                &[&path, &result.bytes_written, &job.url],
+            )),
+            Ok(Transfer::Skipped) => console::out(&messages::render("summary.skipped", &[&path, &job.url])),
+            Ok(Transfer::NotAttempted) => {
+                console::out(&messages::render("summary.not-attempted", &[&path, &job.url]))
+            }
+            Err(ref e) => console::out(&messages::render(
+                "summary.failed",
+                &[&path, &e.localized(), &job.url],
+            )),
+        }
+    }
+    let skipped = outcomes.iter().filter(|o| matches!(**o, Ok(Transfer::Skipped))).count();
+    let failed = outcomes.iter().filter(|o| o.is_err()).count();
+    let not_attempted = outcomes.iter().filter(|o| matches!(**o, Ok(Transfer::NotAttempted))).count();
+    let downloaded = outcomes.len() - skipped - failed - not_attempted;
+    console::out(&messages::render("summary.totals", &[&downloaded, &skipped, &failed]));
+    if not_attempted > 0 {
+        console::out(&messages::render("summary.not-attempted-total", &[&not_attempted]));
+    }
+    let exit_code = batch_exit_code(&outcomes, control::is_cancelled());
+
+    let entries: Vec<Value> = jobs
+        .iter()
+        .zip(&outcomes)
+        .map(|(job, outcome)| outcome_json(job, outcome))
+        .collect();
+    let first_error = outcomes.iter().filter_map(|o| o.as_ref().err()).next();
+    let mut summary = timing::summary(outcomes.iter().filter_map(|outcome| match *outcome {
+        Ok(Transfer::Downloaded(ref result)) => Some(&result.timing),
+        _ => None,
+    }));
+    summary["downloaded"] = json!(downloaded);
+    summary["skipped"] = json!(skipped);
+    summary["failed"] = json!(failed);
+    summary["not_attempted"] = json!(not_attempted);
+    let mut doc = json!({
+        "error": first_error.map(error_json),
+        "entries": entries,
+        "summary": summary,
+    });
+    if let Some(limits) = opts.rate_limits.as_ref().filter(|limits| !limits.is_empty()) {
+        doc["rate_limits"] = limits.to_json();
+    }
+    shutdown.exit(exit_code, doc);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{BufWriter, Cursor, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use data_encoding::HEXLOWER;
+    use digest::Digest;
+    use reqwest;
+    use reqwest::header;
+    use serde_json;
+    use sha2::Sha256;
+
+    use checksum::Algorithm;
+    use error;
+    use error::{DownloadError, Kind};
+    use ioutil::{BufferSize, RetryWriter, DEFAULT_MAX_BUFFER};
+    use progress;
+    use progress::{Progress, Renderer};
+
+    use super::{apply_query, batch_exit_code, download_with_progress, recorded_request, remote_name_path, Transfer};
+
+    /// Fails every other write with `error`, and takes at most 1000 bytes
+    /// of the others.
+    struct Stuttering<'a> {
+        error: io::ErrorKind,
+        written: &'a mut Vec<u8>,
+        calls: usize,
+    }
+
+    impl<'a> Write for Stuttering<'a> {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.calls += 1;
+            if self.calls % 2 == 1 {
+                return Err(io::Error::from(self.error));
+            }
+            let len = buf.len().min(1000);
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Copy a body of 300 KB through a writer failing with `error` and
+    /// check that all of it arrived with the right digest.
+    fn copy_through(error: io::ErrorKind) {
+        let body: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        let mut written = Vec::new();
+        let stuttering = Stuttering {
+            error,
+            written: &mut written,
+            calls: 0,
+        };
+        let mut writer = BufWriter::new(RetryWriter::new(stuttering));
+        let result = download_with_progress(
+            &mut Cursor::new(body.clone()),
+            &mut writer,
+            &mut Progress::hidden(),
+            BufferSize::Fixed(8192),
+        )
+        .unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+        assert_eq!(result.bytes_written, body.len() as u64);
+        let expected = HEXLOWER.encode(&Sha256::digest(&body));
+        assert_eq!(result.digests.get(Algorithm::Sha256), Some(expected.as_str()));
+        assert_eq!(written, body);
+    }
+
+    #[test]
+    fn download_survives_interrupted_writes() {
+        copy_through(io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn download_survives_would_block_writes() {
+        copy_through(io::ErrorKind::WouldBlock);
+    }
+
+    /// Yields `data`, then fails the way a malformed chunked body does.
+    struct CutShort(Cursor<Vec<u8>>);
+
+    impl Read for CutShort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.read(buf)? {
+                0 => Err(io::Error::new(io::ErrorKind::InvalidData, "malformed chunk")),
+                n => Ok(n),
+            }
         }
+    }
+
+    #[test]
+    fn body_read_error_says_how_far_the_body_got() {
+        let mut reader = CutShort(Cursor::new(vec![7; 10000]));
+        let mut out = Vec::new();
+        let err = download_with_progress(&mut reader, &mut out, &mut Progress::hidden(), BufferSize::Fixed(4096))
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = DownloadError::from(err);
+        assert_eq!(out.len(), 8192);
+        assert_eq!(err.received_bytes(), Some(8192));
+        assert_eq!(err.kind(), Kind::Io);
+        assert!(err.is_transient());
+        assert_eq!(
+            err.to_string(),
+            "body decode error after 8.00 KiB (last read 4096 bytes, 0s elapsed): malformed chunk"
+        );
+    }
+
+    #[test]
+    fn body_read_error_before_any_data_received_nothing() {
+        let mut reader = CutShort(Cursor::new(Vec::new()));
+        let err = download_with_progress(&mut reader, &mut io::sink(), &mut Progress::hidden(), BufferSize::Fixed(4096))
+            .map(|_| ())
+            .unwrap_err();
+        let err = DownloadError::from(err);
+        assert_eq!(err.received_bytes(), Some(0));
+        assert!(err.to_string().starts_with("body decode error after 0 B (last read 0 bytes"));
+    }
+
+    /// Takes `room` bytes, then fails as a filesystem that turned
+    /// read-only does.
+    struct TurnsReadOnly {
+        room: usize,
+    }
+
+    impl Write for TurnsReadOnly {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::Error::from_raw_os_error(libc::EROFS));
+            }
+            let n = buf.len().min(self.room);
+            self.room -= n;
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_only_output_says_how_much_was_written() {
+        let mut reader = Cursor::new(vec![7; 10000]);
+        let mut writer = TurnsReadOnly { room: 5000 };
+        let err = download_with_progress(&mut reader, &mut writer, &mut Progress::hidden(), BufferSize::Fixed(4096))
+            .map(|_| ())
+            .unwrap_err();
+        let err = DownloadError::from(err);
+        assert_eq!(err.kind(), Kind::ReadOnlyFs);
+        assert_eq!(err.kind().name(), "read_only_fs");
+        assert_eq!(err.errno_name(), Some("EROFS"));
+        assert_eq!(err.exit_code(), error::EXIT_OUTPUT_FAILURE);
+        assert_eq!(err.received_bytes(), Some(4096));
+        assert!(!err.is_transient());
+        assert_eq!(err.to_string(), "destination filesystem became read-only after 4.00 KiB");
+    }
+
+    /// Counts the progress updates it is given.
+    struct Updates(Arc<AtomicUsize>);
 
-        io::copy(&mut resp, &mut writer).unwrap_or_else(|e| {
-            let _ = writeln!(&mut io::stderr(), "{}", e);
-            process::exit(EXIT_OUTPUT_FAILURE);
+    impl Renderer for Updates {
+        fn inc(&mut self, _: u64) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn finish(&mut self) {}
+
+        fn abandon(&mut self) {}
+    }
+
+    /// Send `len` bytes to the first connection to the returned address, at
+    /// `rate` bytes per second when given.
+    fn stream_source(len: usize, rate: Option<f64>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let piece = [0; 16 * 1024];
+            let started = Instant::now();
+            let mut sent = 0;
+            while sent < len {
+                let n = piece.len().min(len - sent);
+                stream.write_all(&piece[..n]).unwrap();
+                sent += n;
+                if let Some(rate) = rate {
+                    let due = Duration::from_secs_f64(sent as f64 / rate);
+                    thread::sleep(due.saturating_sub(started.elapsed()));
+                }
+            }
         });
+        addr
+    }
+
+    /// `cargo test --release -- --ignored --nocapture read_buffer_sizes`
+    /// times the copy with a small fixed, the adaptive and a large fixed
+    /// buffer, from a local socket and from one throttled to 1 MB/s, and
+    /// counts the progress updates each gets.
+    #[test]
+    #[ignore]
+    fn read_buffer_sizes_on_fast_and_slow_links() {
+        let links = [("local, 100 MiB", 100 << 20, None), ("1 MB/s, 2 MiB", 2 << 20, Some(1e6))];
+        let sizes = [
+            ("8K fixed", BufferSize::Fixed(8 * 1024)),
+            ("adaptive", BufferSize::Adaptive { max: DEFAULT_MAX_BUFFER }),
+            ("4M fixed", BufferSize::Fixed(4 << 20)),
+        ];
+        for &(link, len, rate) in &links {
+            for &(name, size) in &sizes {
+                let mut stream = TcpStream::connect(stream_source(len, rate)).unwrap();
+                let updates = Arc::new(AtomicUsize::new(0));
+                let mut progress = Progress::new(Box::new(Updates(updates.clone())), progress::DEFAULT_INTERVAL);
+                let started = Instant::now();
+                let result = download_with_progress(&mut stream, &mut io::sink(), &mut progress, size).unwrap();
+                progress.finish();
+                assert_eq!(result.bytes_written, len as u64);
+                println!(
+                    "{}, {}: {:.2}s, {} progress updates",
+                    link,
+                    name,
+                    started.elapsed().as_secs_f64(),
+                    updates.load(Ordering::Relaxed)
+                );
+            }
+        }
+    }
+
+    fn remote_name(url: &str, decode: bool) -> Option<PathBuf> {
+        remote_name_path(url, None, decode).ok()
+    }
+
+    #[test]
+    fn remote_name_is_decoded_unless_asked_not_to() {
+        assert_eq!(remote_name("https://host/files/my%20file.pdf", true), Some(PathBuf::from("my file.pdf")));
+        assert_eq!(remote_name("https://host/files/my%20file.pdf", false), Some(PathBuf::from("my%20file.pdf")));
+        assert_eq!(remote_name("https://host/files/a%2Fb.txt", true), Some(PathBuf::from("a%2Fb.txt")));
+        assert_eq!(remote_name("https://host/files/a%2Fb.txt", false), Some(PathBuf::from("a%2Fb.txt")));
+    }
+
+    #[test]
+    fn remote_name_goes_under_the_output_dir() {
+        let path = remote_name_path("https://host/files/tool.tar.gz", Some(Path::new("out")), true).unwrap();
+        assert_eq!(path, Path::new("out").join("tool.tar.gz"));
+        assert_eq!(remote_name("https://host/files/", true), None);
+    }
+
+    fn header_names(record: &serde_json::Value) -> Vec<(&str, &str)> {
+        record["headers"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| {
+                let name = entry["name"].as_str().unwrap();
+                (name, entry["value"].as_str().or_else(|| entry["redacted"].as_str()).unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn recorded_request_keeps_the_method_and_headers_sent() {
+        let url = reqwest::Url::parse("http://host:8080/upload").unwrap();
+        let mut sent = header::HeaderMap::new();
+        sent.insert(header::ACCEPT, header::HeaderValue::from_static("application/json"));
+        sent.insert(header::RANGE, header::HeaderValue::from_static("bytes=10-"));
+        sent.insert(header::COOKIE, header::HeaderValue::from_static("id=1"));
+        let record = recorded_request(&reqwest::Method::PUT, &sent, &url, &url);
+        assert_eq!(record["method"], "PUT");
+        assert_eq!(record["url"], "http://host:8080/upload");
+        assert_eq!(
+            header_names(&record),
+            [("host", "host:8080"), ("accept", "application/json"), ("range", "bytes=10-"), ("cookie", "sensitive")]
+        );
+    }
+
+    #[test]
+    fn recorded_request_marks_what_a_redirect_to_another_origin_dropped() {
+        let requested = reqwest::Url::parse("https://one.example/file").unwrap();
+        let url = reqwest::Url::parse("https://two.example/file").unwrap();
+        let mut sent = header::HeaderMap::new();
+        sent.insert(header::AUTHORIZATION, header::HeaderValue::from_static("Basic dTpw"));
+        let record = recorded_request(&reqwest::Method::HEAD, &sent, &requested, &url);
+        assert_eq!(record["method"], "HEAD");
+        assert_eq!(
+            header_names(&record),
+            [("host", "two.example"), ("authorization", "stripped_on_redirect"), ("accept", "*/*")]
+        );
+    }
+
+    #[test]
+    fn remote_name_leaves_out_the_fragment() {
+        assert_eq!(remote_name("https://host/file.tar.gz#sha256=abc", true), Some(PathBuf::from("file.tar.gz")));
+        assert_eq!(remote_name("https://host/docs/page.html#section-2", true), Some(PathBuf::from("page.html")));
+    }
+
+    #[test]
+    fn remote_name_leaves_out_matrix_parameters() {
+        assert_eq!(remote_name("https://host/file.tar.gz;jsessionid=AB12", true), Some(PathBuf::from("file.tar.gz")));
+        assert_eq!(remote_name("https://host/dir;v=1/file.zip;a=1;b=2?q=3", true), Some(PathBuf::from("file.zip")));
+        assert_eq!(remote_name("https://host/files/;jsessionid=AB12", true), None);
+    }
+
+    #[test]
+    fn remote_name_leaves_out_matrix_parameters_and_fragment() {
+        assert_eq!(
+            remote_name("https://host/my%20file.tar.gz;jsessionid=AB12?x=1#md5=d41d8cd98f00b204e9800998ecf8427e", true),
+            Some(PathBuf::from("my file.tar.gz"))
+        );
+    }
+
+    fn downloaded() -> Result<Transfer, DownloadError> {
+        Ok(Transfer::Downloaded(Box::default()))
+    }
+
+    fn failed() -> Result<Transfer, DownloadError> {
+        Err(DownloadError::Input("missing".to_string().into()))
+    }
+
+    #[test]
+    fn batch_that_went_on_past_failures_says_how_many_failed() {
+        assert_eq!(batch_exit_code(&[downloaded(), Ok(Transfer::Skipped)], false), 0);
+        assert_eq!(batch_exit_code(&[downloaded(), failed(), downloaded()], false), error::EXIT_SOME_FAILED);
+        assert_eq!(batch_exit_code(&[failed(), failed()], false), error::EXIT_ALL_FAILED);
+    }
+
+    #[test]
+    fn batch_stopped_by_fail_fast_says_so() {
+        let finished = [downloaded(), failed(), Ok(Transfer::NotAttempted)];
+        assert_eq!(batch_exit_code(&finished, false), error::EXIT_STOPPED_EARLY);
+        let aborted = [Err(DownloadError::Cancelled), failed()];
+        assert_eq!(batch_exit_code(&aborted, false), error::EXIT_STOPPED_EARLY);
+        assert_eq!(batch_exit_code(&aborted, true), error::EXIT_CANCELLED);
+    }
+
+    #[test]
+    fn remote_name_from_a_hostile_url_stays_in_the_output_dir() {
+        let name = |url| remote_name(url, true);
+        assert_eq!(name("https://host/..%2F..%2Fetc%2Fpasswd"), Some(PathBuf::from("..%2F..%2Fetc%2Fpasswd")));
+        assert_eq!(name("https://host/..%5C..%5Cboot.ini"), Some(PathBuf::from("..%5C..%5Cboot.ini")));
+        assert_eq!(name("https://host/x%00.txt"), Some(PathBuf::from("x%00.txt")));
+        assert_eq!(name("https://host/evil%0D%0Aname.txt"), Some(PathBuf::from("evil__name.txt")));
+        assert_eq!(name("https://host/files/%2e%2e"), None);
+        assert_eq!(name("https://host/files/%2E"), None);
+        let path = remote_name_path("https://host/%2e%2e%2fescape", Some(Path::new("out")), true).unwrap();
+        assert_eq!(path, Path::new("out").join("..%2fescape"));
+    }
+
+    fn with_query(url: &str, query: &[(&str, &str)], replace: bool) -> String {
+        let mut url = reqwest::Url::parse(url).unwrap();
+        let query: Vec<(String, String)> = query.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+        apply_query(&mut url, &query, replace);
+        url.to_string()
+    }
+
+    #[test]
+    fn query_parameters_are_appended() {
+        assert_eq!(with_query("https://host/f", &[("a", "1 2")], false), "https://host/f?a=1+2");
+        assert_eq!(with_query("https://host/f?a=0&c=%20", &[("a", "1")], false), "https://host/f?a=0&c=%20&a=1");
+    }
+
+    #[test]
+    fn replaced_query_keeps_other_parameters_verbatim() {
+        let url = "https://host/f?c=%20&a=old&path=%2Fx%2Fy&sig=ab%3D%3D&a%20b=1";
+        assert_eq!(
+            with_query(url, &[("a", "new")], true),
+            "https://host/f?c=%20&path=%2Fx%2Fy&sig=ab%3D%3D&a%20b=1&a=new"
+        );
+        assert_eq!(
+            with_query(url, &[("a b", "2")], true),
+            "https://host/f?c=%20&a=old&path=%2Fx%2Fy&sig=ab%3D%3D&a+b=2"
+        );
+        assert_eq!(with_query("https://host/f?a=1&a=2", &[("a", "3")], true), "https://host/f?a=3");
     }
 }
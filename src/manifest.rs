@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use reqwest::Url;
+
+use checksum::{Algorithm, Checksum};
+use input::Entry;
+
+/// Environment variables named this plus the upper-cased variable name
+/// supply values not given with `--var`.
+pub const ENV_PREFIX: &str = "DOWNLOAD_VAR_";
+
+/// A release manifest: a base URL, variables and the files to fetch, in
+/// the small subset of TOML these files need:
+///
+/// ```toml
+/// base_url = "https://example.com/releases/{version}/"
+///
+/// [vars]
+/// version = "1.2.3"
+///
+/// [[file]]
+/// path = "tool-{version}.tar.gz"
+/// dest = "dist/tool.tar.gz"
+/// sha256 = "..."
+/// ```
+#[derive(Debug, Default)]
+pub struct Manifest {
+    base_url: Option<(usize, String)>,
+    /// Defaults from `[vars]`, overridden by `--var` and the environment.
+    vars: HashMap<String, String>,
+    files: Vec<Artifact>,
+}
+
+#[derive(Debug, Default)]
+struct Artifact {
+    /// Line of the `[[file]]` header, for messages.
+    line: usize,
+    path: Option<String>,
+    dest: Option<String>,
+    sha256: Option<String>,
+    sha1: Option<String>,
+}
+
+enum Section {
+    Root,
+    Vars,
+    File,
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest, String> {
+        let mut manifest = Manifest::default();
+        let mut section = Section::Root;
+        for (idx, raw) in text.lines().enumerate() {
+            let line_no = idx + 1;
+            let at = |msg: String| format!("line {}: {}", line_no, msg);
+            let line = strip_comment(raw).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with("[[") {
+                match line.strip_suffix("]]").map(|name| name[2..].trim()) {
+                    Some("file") | Some("files") => {
+                        section = Section::File;
+                        manifest.files.push(Artifact {
+                            line: line_no,
+                            ..Artifact::default()
+                        });
+                    }
+                    _ => return Err(at(format!("unknown array of tables {}", line))),
+                }
+                continue;
+            }
+            if line.starts_with('[') {
+                match line.strip_suffix(']').map(|name| name[1..].trim()) {
+                    Some("vars") => section = Section::Vars,
+                    _ => return Err(at(format!("unknown table {}", line))),
+                }
+                continue;
+            }
+
+            let eq = line.find('=').ok_or_else(|| at("expected key = value".to_string()))?;
+            let key = unquote_key(line[..eq].trim()).map_err(&at)?;
+            let value = parse_value(line[eq + 1..].trim()).map_err(&at)?;
+            match section {
+                Section::Root => match key.as_str() {
+                    "base_url" => manifest.base_url = Some((line_no, value)),
+                    _ => return Err(at(format!("unknown key {}", key))),
+                },
+                Section::Vars => {
+                    manifest.vars.insert(key, value);
+                }
+                Section::File => {
+                    let file = manifest.files.last_mut().unwrap();
+                    let slot = match key.as_str() {
+                        "path" | "url" => &mut file.path,
+                        "dest" | "output" => &mut file.dest,
+                        "sha256" => &mut file.sha256,
+                        "sha1" => &mut file.sha1,
+                        _ => return Err(at(format!("unknown key {} in [[file]]", key))),
+                    };
+                    if slot.replace(value).is_some() {
+                        return Err(at(format!("{} given twice", key)));
+                    }
+                }
+            }
+        }
+        Ok(manifest)
+    }
+
+    /// Resolve every file into an input entry. `vars` from `--var` win
+    /// over `env` lookups, which win over the manifest's `[vars]`. Nothing
+    /// is returned unless every entry has a URL, a known set of variables
+    /// and a digest.
+    pub fn entries<F>(&self, vars: &HashMap<String, String>, env: F) -> Result<Vec<Entry>, String>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let lookup = |name: &str| {
+            vars.get(name)
+                .cloned()
+                .or_else(|| env(&format!("{}{}", ENV_PREFIX, name.to_ascii_uppercase())))
+                .or_else(|| self.vars.get(name).cloned())
+        };
+        let base = match self.base_url {
+            Some((line, ref base)) => {
+                let mut base = interpolate(base, &lookup).map_err(|e| format!("line {}: {}", line, e))?;
+                if !base.ends_with('/') {
+                    base.push('/');
+                }
+                Some(Url::parse(&base).map_err(|e| format!("line {}: base_url {}: {}", line, base, e))?)
+            }
+            None => None,
+        };
+        if self.files.is_empty() {
+            return Err("no [[file]] entries".to_string());
+        }
+
+        let mut entries = Vec::with_capacity(self.files.len());
+        for file in &self.files {
+            let at = |msg: String| format!("line {}: {}", file.line, msg);
+            let path = file.path.as_ref().ok_or_else(|| at("[[file]] has no path".to_string()))?;
+            let path = interpolate(path, &lookup).map_err(&at)?;
+            let url = match base {
+                Some(ref base) => base.join(&path),
+                None => Url::parse(&path),
+            }
+            .map_err(|e| at(format!("{}: {}", path, e)))?;
+            let checksum = match (&file.sha256, &file.sha1) {
+                (Some(hex), _) => Checksum::new(Algorithm::Sha256, hex),
+                (None, Some(hex)) => Checksum::new(Algorithm::Sha1, hex),
+                (None, None) => return Err(at(format!("{} has no sha256 or sha1", path))),
+            }
+            .map_err(&at)?;
+            let mut entry = Entry::new(file.line, url.into());
+            entry.output = match file.dest {
+                Some(ref dest) => Some(PathBuf::from(interpolate(dest, &lookup).map_err(&at)?)),
+                None => None,
+            };
+            entry.checksum = Some(checksum);
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+}
+
+/// Parse a `--var name=value` argument.
+pub fn parse_var(value: &str) -> Result<(String, String), String> {
+    match value.find('=') {
+        Some(idx) if idx > 0 => Ok((value[..idx].to_string(), value[idx + 1..].to_string())),
+        _ => Err(format!("--var {}: expected name=value", value)),
+    }
+}
+
+/// Replace each `{name}` in `template`; `{{` and `}}` stand for braces.
+fn interpolate<F>(template: &str, lookup: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(idx) = rest.find(['{', '}']) {
+        out.push_str(&rest[..idx]);
+        let tail = &rest[idx..];
+        if tail.starts_with("{{") || tail.starts_with("}}") {
+            out.push_str(&tail[..1]);
+            rest = &tail[2..];
+            continue;
+        }
+        if tail.starts_with('}') {
+            return Err(format!("unmatched }} in {}", template));
+        }
+        let end = tail.find('}').ok_or_else(|| format!("unterminated {{ in {}", template))?;
+        let name = &tail[1..end];
+        let value = lookup(name).ok_or_else(|| {
+            format!(
+                "unknown variable {{{}}} (set it with --var {}=... or {}{})",
+                name,
+                name,
+                ENV_PREFIX,
+                name.to_ascii_uppercase()
+            )
+        })?;
+        out.push_str(&value);
+        rest = &tail[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The line without a trailing `#` comment, leaving `#` inside strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (idx, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => return &line[..idx],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn unquote_key(key: &str) -> Result<String, String> {
+    if key.starts_with('"') || key.starts_with('\'') {
+        return parse_value(key);
+    }
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+        return Err(format!("invalid key {}", key));
+    }
+    Ok(key.to_string())
+}
+
+/// A string, integer or boolean value, as text.
+fn parse_value(value: &str) -> Result<String, String> {
+    if let Some(body) = value.strip_prefix('\'') {
+        return body
+            .strip_suffix('\'')
+            .filter(|body| !body.contains('\''))
+            .map(String::from)
+            .ok_or_else(|| format!("unterminated string {}", value));
+    }
+    if let Some(body) = value.strip_prefix('"') {
+        let mut out = String::new();
+        let mut chars = body.chars();
+        loop {
+            match chars.next() {
+                None => return Err(format!("unterminated string {}", value)),
+                Some('"') => break,
+                Some('\\') => match chars.next() {
+                    Some('"') => out.push('"'),
+                    Some('\\') => out.push('\\'),
+                    Some('n') => out.push('\n'),
+                    Some('t') => out.push('\t'),
+                    Some('u') => {
+                        let hex: String = chars.by_ref().take(4).collect();
+                        let c = u32::from_str_radix(&hex, 16)
+                            .ok()
+                            .and_then(::std::char::from_u32)
+                            .ok_or_else(|| format!("invalid escape \\u{} in {}", hex, value))?;
+                        out.push(c);
+                    }
+                    _ => return Err(format!("invalid escape in {}", value)),
+                },
+                Some(c) => out.push(c),
+            }
+        }
+        if !chars.as_str().trim().is_empty() {
+            return Err(format!("unexpected text after string {}", value));
+        }
+        return Ok(out);
+    }
+    if value == "true" || value == "false" || value.replace('_', "").parse::<i64>().is_ok() {
+        return Ok(value.replace('_', ""));
+    }
+    Err(format!("unsupported value {} (expected a string, integer or boolean)", value))
+}
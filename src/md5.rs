@@ -0,0 +1,131 @@
+/// MD5 (RFC 1321), for checking against the digests older mirrors still
+/// publish. It is not fit to detect deliberate tampering.
+pub struct Md5 {
+    /// The integer parts of the sines, as the RFC derives its table.
+    k: [u32; 64],
+    state: [u32; 4],
+    buf: [u8; 64],
+    buffered: usize,
+    len: u64,
+}
+
+const SHIFTS: [u32; 64] = [
+    7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9, 14, 20, 5, 9,
+    14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15,
+    21, 6, 10, 15, 21,
+];
+
+impl Md5 {
+    pub fn new() -> Md5 {
+        let mut k = [0u32; 64];
+        for (i, entry) in k.iter_mut().enumerate() {
+            *entry = ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32;
+        }
+        Md5 {
+            k,
+            state: [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476],
+            buf: [0; 64],
+            buffered: 0,
+            len: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if self.buffered > 0 {
+            let take = (64 - self.buffered).min(data.len());
+            self.buf[self.buffered..self.buffered + take].copy_from_slice(&data[..take]);
+            self.buffered += take;
+            data = &data[take..];
+            if self.buffered < 64 {
+                return;
+            }
+            let block = self.buf;
+            self.compress(&block);
+            self.buffered = 0;
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        let rest = blocks.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    pub fn finalize(mut self) -> [u8; 16] {
+        let bits = self.len.wrapping_mul(8);
+        let mut pad = vec![0x80];
+        pad.resize(1 + (119 - self.buffered) % 64, 0);
+        pad.extend_from_slice(&bits.to_le_bytes());
+        // padding does not count towards the length already taken
+        let len = self.len;
+        self.update(&pad);
+        self.len = len;
+        let mut out = [0; 16];
+        for (word, bytes) in self.state.iter().zip(out.chunks_exact_mut(4)) {
+            bytes.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    fn compress(&mut self, block: &[u8]) {
+        let mut m = [0u32; 16];
+        for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let [mut a, mut b, mut c, mut d] = self.state;
+        for (i, (&k, &shift)) in self.k.iter().zip(SHIFTS.iter()).enumerate() {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a.wrapping_add(f).wrapping_add(k).wrapping_add(m[g]).rotate_left(shift);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+        self.state[0] = self.state[0].wrapping_add(a);
+        self.state[1] = self.state[1].wrapping_add(b);
+        self.state[2] = self.state[2].wrapping_add(c);
+        self.state[3] = self.state[3].wrapping_add(d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Md5;
+
+    fn md5(data: &[u8], piece: usize) -> String {
+        let mut md5 = Md5::new();
+        for part in data.chunks(piece) {
+            md5.update(part);
+        }
+        md5.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    #[test]
+    fn rfc_1321_test_suite() {
+        let digits = "1234567890".repeat(8);
+        let suite = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("a", "0cc175b9c0f1b6a831c399e269772661"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            ("abcdefghijklmnopqrstuvwxyz", "c3fcd3d76192e4007dfb496cca67e13b"),
+            (
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "d174ab98d277d9f5a5611c2c9f419d9f",
+            ),
+            (&digits, "57edf4a22be3c955ac49da2e2107b67a"),
+        ];
+        for &(input, digest) in &suite {
+            assert_eq!(md5(input.as_bytes(), 64), digest, "{:?}", input);
+            // fed across block boundaries
+            assert_eq!(md5(input.as_bytes(), 7), digest, "{:?} in pieces", input);
+        }
+    }
+}
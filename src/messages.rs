@@ -0,0 +1,599 @@
+use std::env;
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use console;
+
+/// A language the catalog has translations for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Lang {
+    En,
+    De,
+}
+
+pub const LANGS: [&str; 2] = ["en", "de"];
+
+impl Lang {
+    /// The language of a locale name such as `de`, `de_DE.UTF-8` or
+    /// `de-AT`; unknown languages fall back to English.
+    pub fn parse(locale: &str) -> Lang {
+        let code = locale.split(['_', '-', '.', '@']).next().unwrap_or("");
+        match code.to_ascii_lowercase().as_str() {
+            "de" => Lang::De,
+            _ => Lang::En,
+        }
+    }
+
+    /// The language the environment asks for, by the usual precedence of
+    /// `LC_ALL`, `LC_MESSAGES` and `LANG`.
+    pub fn from_env() -> Lang {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|name| env::var(name).ok())
+            .find(|value| !value.is_empty())
+            .map(|value| Lang::parse(&value))
+            .unwrap_or(Lang::En)
+    }
+}
+
+static LANG: AtomicUsize = AtomicUsize::new(0);
+
+pub fn set_lang(lang: Lang) {
+    LANG.store(lang as usize, Ordering::Relaxed);
+}
+
+/// Set by `--quiet`: notes are dropped, warnings still printed.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+pub fn lang() -> Lang {
+    match LANG.load(Ordering::Relaxed) {
+        1 => Lang::De,
+        _ => Lang::En,
+    }
+}
+
+/// Every user-facing message as `(id, English, German)`. Ids are stable;
+/// each `{}` is filled with the next argument in order. JSON field names,
+/// digests and exit codes are never looked up here.
+static CATALOG: &[(&str, &str, &str)] = &[
+    ("warning", "warning", "Warnung"),
+    ("caused-by", "caused by", "verursacht durch"),
+    ("error.dns", "could not resolve host {}", "Host {} konnte nicht aufgelöst werden"),
+    (
+        "error.network-unreachable",
+        "network unreachable ({}): do you need --proxy?",
+        "Netzwerk nicht erreichbar ({}): wird --proxy benötigt?",
+    ),
+    (
+        "error.host-unreachable",
+        "host unreachable: no route to {}",
+        "Host nicht erreichbar: keine Route zu {}",
+    ),
+    (
+        "error.connection-refused",
+        "connection refused by {}",
+        "Verbindung von {} abgelehnt",
+    ),
+    (
+        "error.connect-timeout",
+        "connection to {} timed out",
+        "Zeitüberschreitung bei der Verbindung zu {}",
+    ),
+    (
+        "error.checksum-mismatch",
+        "checksum mismatch: expected {} but got {}:{}",
+        "Prüfsumme stimmt nicht: erwartet {}, erhalten {}:{}",
+    ),
+    (
+        "error.pin-mismatch",
+        "the public key of {} is {}, which matches no --pin-sha256",
+        "der öffentliche Schlüssel von {} ist {} und passt zu keinem --pin-sha256",
+    ),
+    ("error.unsafe-output", "unsafe output: {}", "unsichere Ausgabe: {}"),
+    ("error.cancelled", "cancelled", "abgebrochen"),
+    (
+        "error.retry-after",
+        "{}; the server asks to retry in {}s",
+        "{}; der Server bittet um einen neuen Versuch in {} s",
+    ),
+    (
+        "error.body-read",
+        "body decode error after {} (last read {} bytes, {}s elapsed)",
+        "Fehler beim Lesen des Inhalts nach {} (zuletzt {} Bytes gelesen, {} s vergangen)",
+    ),
+    (
+        "error.read-only-after",
+        "destination filesystem became read-only after {}",
+        "das Zieldateisystem wurde nach {} schreibgeschützt",
+    ),
+    (
+        "error.read-only",
+        "destination filesystem is read-only",
+        "das Zieldateisystem ist schreibgeschützt",
+    ),
+    ("error.no-filename", "{}: cannot derive a filename", "{}: kein Dateiname ableitbar"),
+    (
+        "error.url-credentials-refused",
+        "{}: credentials in the URL are refused by --reject-url-credentials",
+        "{}: Zugangsdaten in der URL werden von --reject-url-credentials abgelehnt",
+    ),
+    ("error.symlink", "{} is a symbolic link ({})", "{} ist ein symbolischer Link ({})"),
+    ("error.symlink-refused", "refused by --no-follow-symlinks", "von --no-follow-symlinks abgelehnt"),
+    (
+        "error.symlink-follow",
+        "pass --follow-symlinks to write through it",
+        "mit --follow-symlinks wird durch ihn geschrieben",
+    ),
+    ("error.outside-root", "{} resolves to a path outside {}", "{} führt zu einem Pfad außerhalb von {}"),
+    (
+        "error.start-offset-short",
+        "--start-offset {}: {} holds only {} bytes",
+        "--start-offset {}: {} enthält nur {} Bytes",
+    ),
+    (
+        "error.start-offset-checksum",
+        "--start-offset {}: a checksum covers the whole file, but only its end is fetched",
+        "--start-offset {}: eine Prüfsumme deckt die ganze Datei ab, geladen wird aber nur ihr Ende",
+    ),
+    ("error.meta-refresh-loop", "{}: meta refresh loop", "{}: Meta-Refresh-Schleife"),
+    (
+        "error.meta-refresh-redirects",
+        "{}: more than {} redirects (raise --max-redirects to follow meta refreshes)",
+        "{}: mehr als {} Weiterleitungen (--max-redirects erhöhen, um Meta-Refreshes zu folgen)",
+    ),
+    (
+        "error.link-checksum",
+        "--checksum needs a single output when following Link headers",
+        "--checksum braucht eine einzige Ausgabe, wenn Link-Headern gefolgt wird",
+    ),
+    ("error.url-fragment", "{}: URL fragment: {}", "{}: URL-Fragment: {}"),
+    ("error.not-listed", "{}: {} is not listed in {}", "{}: {} ist in {} nicht aufgeführt"),
+    ("error.no-digests", "--check {}: no digests listed", "--check {}: keine Prüfsummen aufgeführt"),
+    (
+        "error.file-shrank",
+        "{}: file shrank while hashing range {}",
+        "{}: Datei wurde beim Hashen des Bereichs {} kürzer",
+    ),
+    ("unit.line", "line {}", "Zeile {}"),
+    ("unit.url", "URL {}", "URL {}"),
+    ("error.entry", "{}: {}", "{}: {}"),
+    ("error.entry-header", "{}: invalid header", "{}: ungültiger Header"),
+    ("error.same-destination", "{}: {} is also the destination of {}", "{}: {} ist auch das Ziel von {}"),
+    ("error.invalid-header", "-H {}: invalid header", "-H {}: ungültiger Header"),
+    ("error.query-pair", "--query {}: expected key=value", "--query {}: key=value erwartet"),
+    (
+        "error.expected-timeout",
+        "{} {}: expected a positive number of seconds",
+        "{} {}: positive Anzahl Sekunden erwartet",
+    ),
+    ("error.expected-secs", "{} {}: expected seconds", "{} {}: Sekunden erwartet"),
+    ("error.expected-ms", "{} {}: expected milliseconds", "{} {}: Millisekunden erwartet"),
+    ("error.expected-bytes", "{} {}: expected a number of bytes", "{} {}: Anzahl Bytes erwartet"),
+    ("error.expected-size", "{} {}: expected a size such as {}", "{} {}: Größe erwartet, etwa {}"),
+    (
+        "error.expected-rate",
+        "{} {}: expected bytes per second such as {}",
+        "{} {}: Bytes pro Sekunde erwartet, etwa {}",
+    ),
+    ("error.expected-retries", "{} {}: expected a number of retries", "{} {}: Anzahl Wiederholungen erwartet"),
+    ("error.expected-segments", "{} {}: expected a number of segments", "{} {}: Anzahl Segmente erwartet"),
+    ("error.max-concurrent", "--max-concurrent needs a number above 0", "--max-concurrent braucht eine Zahl über 0"),
+    ("error.not-proxy", "--proxy {}: not a proxy URL", "--proxy {}: keine Proxy-URL"),
+    (
+        "error.digest-fd-stdout",
+        "--digest-fd 1: stdout is reserved for the body",
+        "--digest-fd 1: stdout ist für den Inhalt reserviert",
+    ),
+    (
+        "error.xattr-storage",
+        "--xattr requires --save-validators, --record-request or --etag",
+        "--xattr braucht --save-validators, --record-request oder --etag",
+    ),
+    (
+        "error.single-url",
+        "--{} applies to a single URL but {} were given",
+        "--{} gilt für eine einzelne URL, angegeben wurden aber {}",
+    ),
+    (
+        "error.sha256-template",
+        "--{} cannot be used with an --output-template naming outputs by {sha256}",
+        "--{} ist nicht mit einem --output-template nutzbar, das Ausgaben nach {sha256} benennt",
+    ),
+    (
+        "error.stdin-body-batch",
+        "--stdin-body: uploads a single body and cannot be combined with -i or manifest",
+        "--stdin-body: lädt einen einzigen Inhalt hoch und ist nicht mit -i oder manifest kombinierbar",
+    ),
+    (
+        "error.stdin-body-output",
+        "--stdin-body: -o and -O name the --then-get download",
+        "--stdin-body: -o und -O benennen den Download von --then-get",
+    ),
+    ("error.stdin-body-method", "--stdin-body: -X {}: not a method", "--stdin-body: -X {}: keine Methode"),
+    (
+        "error.json-stdout",
+        "--json: stdout is for the document; save the body with -o or -O",
+        "--json: stdout ist für das Dokument; den Inhalt mit -o oder -O speichern",
+    ),
+    ("error.not-saved", "{} {}: not saved: {}", "{} {}: nicht gespeichert: {}"),
+    (
+        "hint.clock-skew",
+        "local clock differs from server by {} \u{2014} check system time",
+        "lokale Uhr weicht um {} von der des Servers ab \u{2014} Systemzeit prüfen",
+    ),
+    (
+        "warning.signed-url-clock",
+        "{} was refused; {}",
+        "{} wurde abgelehnt; {}",
+    ),
+    (
+        "warning.insecure",
+        "--insecure: server certificates are not verified at all",
+        "--insecure: Serverzertifikate werden überhaupt nicht geprüft",
+    ),
+    (
+        "warning.insecure-hostname",
+        "--insecure-hostname: certificate hostnames are not verified \
+         (chain and expiry are still checked)",
+        "--insecure-hostname: Hostnamen in Zertifikaten werden nicht geprüft \
+         (Kette und Ablaufdatum werden weiterhin geprüft)",
+    ),
+    (
+        "warning.url-credentials",
+        "{} carries credentials in the URL, which leaks them into shell history \
+         and logs; use --user or a netrc file instead",
+        "{} enthält Zugangsdaten in der URL, die so in Shell-Verlauf und Logs \
+         gelangen; verwenden Sie stattdessen --user oder eine netrc-Datei",
+    ),
+    (
+        "warning.meta-refresh",
+        "{} is an HTML page that refreshes to {}; use --follow-meta-refresh to follow it",
+        "{} ist eine HTML-Seite, die auf {} weiterleitet; mit --follow-meta-refresh \
+         wird ihr gefolgt",
+    ),
+    (
+        "warning.meta-refresh-host",
+        "not following meta refresh from {} to {}: pass --meta-refresh-host {} to allow it",
+        "Meta-Refresh von {} nach {} wird nicht gefolgt: --meta-refresh-host {} erlaubt es",
+    ),
+    ("note.meta-refresh", "meta refresh: {} -> {}", "Meta-Refresh: {} -> {}"),
+    (
+        "warning.link-loop",
+        "Link loop back to {}; stopping",
+        "Link-Schleife zurück zu {}; Abbruch",
+    ),
+    (
+        "warning.max-parts",
+        "stopping after {} parts; {} not fetched",
+        "Abbruch nach {} Teilen; {} nicht geladen",
+    ),
+    (
+        "warning.reader-closed",
+        "the reader closed {} after {} bytes",
+        "der Leser hat {} nach {} Bytes geschlossen",
+    ),
+    (
+        "warning.mark-of-the-web",
+        "could not mark {} as downloaded from the internet: {}",
+        "{} konnte nicht als aus dem Internet geladen markiert werden: {}",
+    ),
+    (
+        "warning.motw-unsupported",
+        "--mark-of-the-web only has an effect on Windows",
+        "--mark-of-the-web wirkt nur unter Windows",
+    ),
+    (
+        "warning.provenance",
+        "could not record where {} came from: {}",
+        "Herkunft von {} konnte nicht vermerkt werden: {}",
+    ),
+    (
+        "warning.remote-time",
+        "could not date {} from Last-Modified: {}",
+        "{} konnte nicht auf Last-Modified datiert werden: {}",
+    ),
+    (
+        "warning.save-validators",
+        "could not save validators: {}",
+        "Validatoren konnten nicht gespeichert werden: {}",
+    ),
+    (
+        "warning.zip-no-range",
+        "{} ignored the range request; downloading the whole archive to extract {}",
+        "{} hat die Range-Anfrage ignoriert; das ganze Archiv wird geladen, um {} \
+         zu entpacken",
+    ),
+    (
+        "warning.aria2-option",
+        "line {}: ignoring unsupported aria2 option {}",
+        "Zeile {}: nicht unterstützte aria2-Option {} wird ignoriert",
+    ),
+    (
+        "warning.alt-address",
+        "{} failed verification; fetching it again from {}",
+        "{} hat die Prüfung nicht bestanden; erneuter Abruf von {}",
+    ),
+    (
+        "note.alt-address-verified",
+        "{} served content that verified",
+        "{} lieferte Inhalte, die die Prüfung bestanden",
+    ),
+    (
+        "warning.sandbox-unavailable",
+        "--sandbox: {} is not available ({}); continuing without it",
+        "--sandbox: {} ist nicht verfügbar ({}); es wird ohne fortgefahren",
+    ),
+    (
+        "warning.sandbox-unsupported",
+        "--sandbox is not supported on this platform; continuing without it",
+        "--sandbox wird auf dieser Plattform nicht unterstützt; es wird ohne fortgefahren",
+    ),
+    (
+        "note.sandbox-landlock",
+        "sandbox: filesystem changes restricted (Landlock ABI {})",
+        "Sandbox: Dateisystemänderungen eingeschränkt (Landlock-ABI {})",
+    ),
+    (
+        "note.sandbox-seccomp",
+        "sandbox: {} system calls denied",
+        "Sandbox: {} Systemaufrufe gesperrt",
+    ),
+    (
+        "warning.type-mismatch",
+        "{}: the body looks like {}, but Content-Type is {} and the extension is {}",
+        "{}: der Inhalt sieht nach {} aus, aber Content-Type ist {} und die Endung {}",
+    ),
+    (
+        "warning.fix-extension-exists",
+        "not renaming {}: {} already exists",
+        "{} wird nicht umbenannt: {} existiert bereits",
+    ),
+    ("note.fix-extension", "renamed {} to {}", "{} in {} umbenannt"),
+    (
+        "note.auto-rename",
+        "{} already exists, saving as {}",
+        "{} existiert bereits, wird als {} gespeichert",
+    ),
+    (
+        "note.partial-kept",
+        "the partial output {} was kept; --continue can finish it",
+        "die unvollständige Ausgabe {} bleibt erhalten; --continue kann sie fertigstellen",
+    ),
+    (
+        "note.content-disposition",
+        "{}: saving as {}, the name the server gave",
+        "{}: wird unter dem vom Server genannten Namen {} gespeichert",
+    ),
+    ("note.upload", "{} {}: {}", "{} {}: {}"),
+    (
+        "warning.continue-other-url",
+        "{}: the partial output {} was started from {}; downloading it again",
+        "{}: die unvollständige Ausgabe {} stammt von {}; sie wird neu heruntergeladen",
+    ),
+    (
+        "warning.continue-changed",
+        "{}: the remote changed since the partial output was started; downloading it again",
+        "{}: die Gegenstelle hat sich geändert, seit die unvollständige Ausgabe begonnen wurde; \
+         sie wird neu heruntergeladen",
+    ),
+    (
+        "warning.resume-state",
+        "could not save the state of {} for --continue: {}",
+        "der Zustand von {} für --continue konnte nicht gespeichert werden: {}",
+    ),
+    (
+        "warning.read-only-partial",
+        "the partial output {} was left in place; it cannot be removed from a read-only filesystem",
+        "die unvollständige Ausgabe {} bleibt liegen; auf einem schreibgeschützten Dateisystem \
+         kann sie nicht entfernt werden",
+    ),
+    (
+        "warning.remove-failed",
+        "could not remove {}: {}",
+        "{} konnte nicht entfernt werden: {}",
+    ),
+    ("note.tls-profile", "{}: TLS profile {}", "{}: TLS-Profil {}"),
+    (
+        "note.fragment-checksum",
+        "{}: verifying against {} from the URL fragment",
+        "{}: Prüfung gegen {} aus dem URL-Fragment",
+    ),
+    (
+        "note.fragment-checksum-overridden",
+        "{}: the expected checksum overrides {} from the URL fragment",
+        "{}: die erwartete Prüfsumme ersetzt {} aus dem URL-Fragment",
+    ),
+    (
+        "warning.fragment-checksum-unchecked",
+        "{}: the {} digest in the URL fragment cannot be checked",
+        "{}: die {}-Prüfsumme im URL-Fragment kann nicht geprüft werden",
+    ),
+    (
+        "note.check-checksum",
+        "{}: verifying against {} listed for {} in {}",
+        "{}: Prüfung gegen {}, für {} aufgeführt in {}",
+    ),
+    (
+        "note.check-overridden",
+        "{}: the entry's checksum overrides the one in {}",
+        "{}: die Prüfsumme des Eintrags ersetzt die aus {}",
+    ),
+    ("note.entry-overrides", "{}: entry settings {}", "{}: Einstellungen des Eintrags {}"),
+    (
+        "warning.retrying",
+        "{} failed ({}); retrying in {}s (attempt {} of {})",
+        "{} fehlgeschlagen ({}); neuer Versuch in {} s (Versuch {} von {})",
+    ),
+    ("summary.head", "output: {}, length: {}", "Ausgabe: {}, Länge: {}"),
+    (
+        "warning.dump-header",
+        "could not write the --dump-header file: {}",
+        "die Datei für --dump-header konnte nicht geschrieben werden: {}",
+    ),
+    (
+        "note.stats",
+        "{} in {}s, {} on average, {} at peak; {} retries, {} redirects, {}",
+        "{} in {} s, {} im Mittel, {} in der Spitze; {} Wiederholungen, {} Weiterleitungen, {}",
+    ),
+    (
+        "note.tcp-info",
+        "{}: {} TCP retransmits, RTT {} ms, delivery rate {}/s",
+        "{}: {} TCP-Neuübertragungen, RTT {} ms, Zustellrate {}/s",
+    ),
+    (
+        "note.tcp-info-no-rate",
+        "{}: {} TCP retransmits, RTT {} ms",
+        "{}: {} TCP-Neuübertragungen, RTT {} ms",
+    ),
+    (
+        "warning.continue-restart",
+        "{} ignored the range request; downloading it again from the start",
+        "{} hat die Range-Anfrage ignoriert; es wird erneut von vorn geladen",
+    ),
+    (
+        "note.continue-complete",
+        "{} is already complete ({} bytes)",
+        "{} ist bereits vollständig ({} Bytes)",
+    ),
+    (
+        "note.segments",
+        "fetching in {} segments ({} bytes)",
+        "wird in {} Segmenten geladen ({} Bytes)",
+    ),
+    (
+        "warning.retry-max-time",
+        "{} is not retried again, as --retry-max-time {}s would be exceeded",
+        "{} wird nicht erneut versucht, da --retry-max-time {} s überschritten würde",
+    ),
+    (
+        "warning.socks-failed",
+        "SOCKS5 proxy {} could not reach {}: {}",
+        "SOCKS5-Proxy {} konnte {} nicht erreichen: {}",
+    ),
+    ("note.skipping", "skipping existing {}", "vorhandene Datei {} wird übersprungen"),
+    (
+        "note.not-modified",
+        "{} is up to date: {} is not modified",
+        "{} ist aktuell: {} ist unverändert",
+    ),
+    (
+        "note.waiting-reader",
+        "waiting for a reader on {}",
+        "warte auf einen Leser an {}",
+    ),
+    (
+        "note.rate-limit-wait",
+        "rate limit: waiting {}s before the next request to {}",
+        "Ratenbegrenzung: warte {} s vor der nächsten Anfrage an {}",
+    ),
+    (
+        "note.rate-limit",
+        "rate limit: {} has {}/{} requests left, resetting in {}s",
+        "Ratenbegrenzung: {} erlaubt noch {}/{} Anfragen, Zurücksetzen in {} s",
+    ),
+    ("progress.done", "Done.", "Fertig."),
+    ("progress.upload", "uploading", "lade hoch"),
+    ("progress.verify", "verifying", "prüfe"),
+    (
+        "summary.downloaded",
+        "{}: {} bytes from {}",
+        "{}: {} Bytes von {}",
+    ),
+    (
+        "summary.skipped",
+        "{}: skipped (already present) from {}",
+        "{}: übersprungen (bereits vorhanden) von {}",
+    ),
+    ("summary.failed", "{}: FAILED ({}) from {}", "{}: FEHLGESCHLAGEN ({}) von {}"),
+    (
+        "summary.not-attempted",
+        "{}: not attempted (--fail-fast) from {}",
+        "{}: nicht versucht (--fail-fast) von {}",
+    ),
+    (
+        "summary.not-attempted-total",
+        "{} not attempted after a failure (--fail-fast)",
+        "{} nach einem Fehler nicht versucht (--fail-fast)",
+    ),
+    (
+        "summary.totals",
+        "{} downloaded, {} skipped, {} failed",
+        "{} geladen, {} übersprungen, {} fehlgeschlagen",
+    ),
+];
+
+/// The message `id` in `lang` with its placeholders filled from `args`.
+pub fn render_in(lang: Lang, id: &str, args: &[&dyn Display]) -> String {
+    let template = match CATALOG.iter().find(|entry| entry.0 == id) {
+        Some(&(_, en, de)) => match lang {
+            Lang::En => en,
+            Lang::De => de,
+        },
+        None => id,
+    };
+    let mut out = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let mut pieces = template.split("{}");
+    out.push_str(pieces.next().unwrap_or(""));
+    for piece in pieces {
+        if let Some(arg) = args.next() {
+            out.push_str(&arg.to_string());
+        }
+        out.push_str(piece);
+    }
+    out
+}
+
+/// The message `id` in the selected language.
+pub fn render(id: &str, args: &[&dyn Display]) -> String {
+    render_in(lang(), id, args)
+}
+
+/// A message kept unrendered until the language it is shown in is known:
+/// a catalog id with its arguments, or text from outside the catalog.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Text(String),
+    Catalog(&'static str, Vec<Message>),
+}
+
+impl Message {
+    /// The message `id`, its arguments rendered as text.
+    pub fn new(id: &'static str, args: &[&dyn Display]) -> Message {
+        Message::Catalog(id, args.iter().map(|arg| Message::Text(arg.to_string())).collect())
+    }
+
+    pub fn render_in(&self, lang: Lang) -> String {
+        match *self {
+            Message::Text(ref text) => text.clone(),
+            Message::Catalog(id, ref args) => {
+                let args: Vec<String> = args.iter().map(|arg| arg.render_in(lang)).collect();
+                let args: Vec<&dyn Display> = args.iter().map(|arg| arg as &dyn Display).collect();
+                render_in(lang, id, &args)
+            }
+        }
+    }
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Message {
+        Message::Text(text)
+    }
+}
+
+/// Print the message `id` on stderr, unless `--quiet`.
+pub fn note(id: &str, args: &[&dyn Display]) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    console::err(&render(id, args));
+}
+
+/// Print the message `id` on stderr as a warning.
+pub fn warn(id: &str, args: &[&dyn Display]) {
+    console::err(&format!("{}: {}", render("warning", &[]), render(id, args)));
+}
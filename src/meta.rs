@@ -0,0 +1,187 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, ETAG, LAST_MODIFIED};
+use serde_json;
+use serde_json::Value;
+
+use fsutil;
+use xattr;
+
+const XATTR_PREFIX: &str = "user.download.";
+
+/// Where validators are kept alongside an output file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Storage {
+    /// A JSON document at `<output>.meta`.
+    Sidecar,
+    /// `user.download.*` extended attributes on the output itself.
+    Xattr,
+}
+
+/// Where an output came from: the URL asked for, kept as
+/// `user.xdg.origin.url` where other tools look for it, and the final URL,
+/// ETag and SHA-256 as the `user.download.*` attributes validators use.
+pub struct Provenance<'a> {
+    pub url: &'a str,
+    pub url_effective: &'a str,
+    pub etag: Option<&'a str>,
+    pub sha256: Option<&'a str>,
+}
+
+impl<'a> Provenance<'a> {
+    /// Record the provenance in extended attributes of `output`. A
+    /// filesystem without them is no error.
+    pub fn record(&self, output: &Path) -> io::Result<()> {
+        let fields = [
+            ("user.xdg.origin.url".to_string(), Some(self.url)),
+            (format!("{}url_effective", XATTR_PREFIX), Some(self.url_effective)),
+            (format!("{}etag", XATTR_PREFIX), self.etag),
+            (format!("{}sha256", XATTR_PREFIX), self.sha256),
+        ];
+        for (name, value) in &fields {
+            if let Some(value) = value {
+                match xattr::set(output, name, value.as_bytes()) {
+                    Err(ref e) if xattr::is_unsupported(e) => return Ok(()),
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The cache validators seen for an output, used for later conditional
+/// requests by this tool (`--continue`, ETag comparison, skipping existing
+/// files) or by other tools.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Validators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_length: Option<u64>,
+    pub url_effective: Option<String>,
+    /// Digests of the body, kept with `--record-request`.
+    pub sha1: Option<String>,
+    pub sha256: Option<String>,
+    /// The request that produced the response, kept with `--record-request`.
+    pub request: Option<Value>,
+}
+
+fn header_string(headers: &HeaderMap, name: ::reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+impl Validators {
+    pub fn from_response(resp: &Response) -> Validators {
+        let headers = resp.headers();
+        Validators {
+            etag: header_string(headers, ETAG),
+            last_modified: header_string(headers, LAST_MODIFIED),
+            content_length: header_string(headers, CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+            url_effective: Some(resp.url().to_string()),
+            ..Validators::default()
+        }
+    }
+
+    pub fn sidecar_path(output: &Path) -> PathBuf {
+        let mut name = output.as_os_str().to_owned();
+        name.push(".meta");
+        PathBuf::from(name)
+    }
+
+    pub fn save(&self, output: &Path, storage: Storage) -> io::Result<()> {
+        match storage {
+            Storage::Sidecar => {
+                let mut doc = json!({
+                    "etag": self.etag,
+                    "last_modified": self.last_modified,
+                    "content_length": self.content_length,
+                    "url_effective": self.url_effective,
+                });
+                if let Some(ref sha1) = self.sha1 {
+                    doc["sha1"] = json!(sha1);
+                }
+                if let Some(ref sha256) = self.sha256 {
+                    doc["sha256"] = json!(sha256);
+                }
+                if let Some(ref request) = self.request {
+                    doc["request"] = request.clone();
+                }
+                let contents = serde_json::to_vec_pretty(&doc).unwrap();
+                fsutil::write_atomic(&Validators::sidecar_path(output), &contents)
+            }
+            Storage::Xattr => {
+                let content_length = self.content_length.map(|len| len.to_string());
+                let request = self.request.as_ref().map(Value::to_string);
+                let fields = [
+                    ("etag", self.etag.as_ref()),
+                    ("last_modified", self.last_modified.as_ref()),
+                    ("content_length", content_length.as_ref()),
+                    ("url_effective", self.url_effective.as_ref()),
+                    ("sha1", self.sha1.as_ref()),
+                    ("sha256", self.sha256.as_ref()),
+                    ("request", request.as_ref()),
+                ];
+                for &(name, value) in &fields {
+                    if let Some(value) = value {
+                        xattr::set(output, &format!("{}{}", XATTR_PREFIX, name), value.as_bytes())?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Load validators for `output` from its sidecar, falling back to
+    /// extended attributes. Missing or corrupted metadata yields `None` so
+    /// that callers degrade to unconditional requests.
+    pub fn load(output: &Path) -> Option<Validators> {
+        Validators::load_sidecar(output).or_else(|| Validators::load_xattr(output))
+    }
+
+    fn load_sidecar(output: &Path) -> Option<Validators> {
+        let contents = fs::read(Validators::sidecar_path(output)).ok()?;
+        let doc: Value = serde_json::from_slice(&contents).ok()?;
+        if !doc.is_object() {
+            return None;
+        }
+        let string = |key: &str| doc.get(key).and_then(Value::as_str).map(String::from);
+        Some(Validators {
+            etag: string("etag"),
+            last_modified: string("last_modified"),
+            content_length: doc.get("content_length").and_then(Value::as_u64),
+            url_effective: string("url_effective"),
+            sha1: string("sha1"),
+            sha256: string("sha256"),
+            request: doc.get("request").cloned(),
+        })
+    }
+
+    fn load_xattr(output: &Path) -> Option<Validators> {
+        let string = |name: &str| {
+            xattr::get(output, &format!("{}{}", XATTR_PREFIX, name))
+                .ok()
+                .and_then(|value| value)
+                .and_then(|value| String::from_utf8(value).ok())
+        };
+        let validators = Validators {
+            etag: string("etag"),
+            last_modified: string("last_modified"),
+            content_length: string("content_length").and_then(|len| len.parse().ok()),
+            url_effective: string("url_effective"),
+            sha1: string("sha1"),
+            sha256: string("sha256"),
+            request: string("request").and_then(|request| serde_json::from_str(&request).ok()),
+        };
+        if validators == Validators::default() {
+            None
+        } else {
+            Some(validators)
+        }
+    }
+}
@@ -0,0 +1,23 @@
+use std::io;
+use std::path::Path;
+
+/// Whether outputs can carry the Mark of the Web on this platform.
+pub const SUPPORTED: bool = cfg!(windows);
+
+/// Mark `path` as downloaded from the internet from `url`, as browsers do,
+/// by writing the `Zone.Identifier` stream SmartScreen and Office read.
+#[cfg(windows)]
+pub fn mark(path: &Path, url: &str) -> io::Result<()> {
+    use std::fs;
+
+    // zone 3 is the Internet zone
+    let contents = format!("[ZoneTransfer]\r\nZoneId=3\r\nHostUrl={}\r\n", url);
+    let mut stream = path.as_os_str().to_owned();
+    stream.push(":Zone.Identifier");
+    fs::write(stream, contents)
+}
+
+#[cfg(not(windows))]
+pub fn mark(_path: &Path, _url: &str) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use credentials::Credentials;
+
+/// The machines of a netrc file, as curl and wget read it: `machine`,
+/// `login` and `password` tokens separated by white space, `default` for
+/// any other host, `#` comments and `macdef` macros, which are skipped.
+pub struct Netrc {
+    machines: Vec<Machine>,
+}
+
+struct Machine {
+    /// `None` for the `default` entry.
+    host: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+/// `$NETRC`, or `.netrc` in the home directory.
+pub fn default_path() -> Option<PathBuf> {
+    match env::var_os("NETRC") {
+        Some(path) => Some(PathBuf::from(path)),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".netrc")),
+    }
+}
+
+impl Netrc {
+    pub fn load(path: &Path) -> Result<Netrc, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        Netrc::parse(&text)
+    }
+
+    fn parse(text: &str) -> Result<Netrc, String> {
+        let mut machines: Vec<Machine> = Vec::new();
+        let mut tokens = Tokens { rest: text };
+        while let Some(token) = tokens.next() {
+            match token.as_str() {
+                "machine" => {
+                    let host = tokens.next().ok_or("\"machine\" without a host name")?;
+                    machines.push(Machine { host: Some(host), login: None, password: None });
+                }
+                "default" => machines.push(Machine { host: None, login: None, password: None }),
+                "login" | "password" | "account" => {
+                    let value = tokens.next().ok_or_else(|| format!("\"{}\" without a value", token))?;
+                    let machine = machines
+                        .last_mut()
+                        .ok_or_else(|| format!("\"{}\" before any \"machine\"", token))?;
+                    match token.as_str() {
+                        "login" => machine.login = Some(value),
+                        "password" => machine.password = Some(value),
+                        _ => {}
+                    }
+                }
+                "macdef" => tokens.skip_macro(),
+                other => return Err(format!("unexpected \"{}\"", other)),
+            }
+        }
+        Ok(Netrc { machines })
+    }
+
+    /// The credentials for `host`: its own machine's, or else the
+    /// default's. Entries without a login are of no use here.
+    pub fn lookup(&self, host: &str) -> Option<Credentials> {
+        let own = self.machines.iter().find(|machine| {
+            machine.host.as_ref().is_some_and(|name| name.eq_ignore_ascii_case(host))
+        });
+        let machine = own.or_else(|| self.machines.iter().find(|machine| machine.host.is_none()))?;
+        Some(Credentials {
+            user: machine.login.clone()?,
+            password: machine.password.clone(),
+        })
+    }
+}
+
+struct Tokens<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokens<'a> {
+    /// The next token, which may be double-quoted with backslash escapes.
+    fn next(&mut self) -> Option<String> {
+        loop {
+            self.rest = self.rest.trim_start();
+            if !self.rest.starts_with('#') {
+                break;
+            }
+            self.rest = self.rest.find('\n').map_or("", |end| &self.rest[end..]);
+        }
+        if self.rest.is_empty() {
+            return None;
+        }
+        if let Some(quoted) = self.rest.strip_prefix('"') {
+            let mut token = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((idx, c)) = chars.next() {
+                match c {
+                    '"' => {
+                        end = idx + 1;
+                        break;
+                    }
+                    '\\' => token.extend(chars.next().map(|(_, escaped)| escaped)),
+                    c => token.push(c),
+                }
+            }
+            self.rest = &quoted[end..];
+            return Some(token);
+        }
+        let end = self.rest.find(char::is_whitespace).unwrap_or(self.rest.len());
+        let token = self.rest[..end].to_string();
+        self.rest = &self.rest[end..];
+        Some(token)
+    }
+
+    /// Skip a macro's name and body, which runs to the next empty line.
+    fn skip_macro(&mut self) {
+        self.rest = match self.rest.find("\n\n") {
+            Some(end) => &self.rest[end..],
+            None => "",
+        };
+    }
+}
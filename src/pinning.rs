@@ -0,0 +1,159 @@
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use data_encoding::BASE64;
+use rustls;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, CertificateError, ServerName};
+use sha2::{Digest, Sha256};
+
+/// The SHA-256 digests of the public keys `--pin-sha256` accepts.
+#[derive(Clone, Debug, Default)]
+pub struct Pins(Vec<[u8; 32]>);
+
+impl Pins {
+    /// Add the pins in `value`: base64 SHA-256 digests of a DER-encoded
+    /// SubjectPublicKeyInfo, optionally prefixed with `sha256//` and
+    /// separated by `;`, as curl's `--pinnedpubkey` takes them.
+    pub fn add(&mut self, value: &str) -> Result<(), String> {
+        for pin in value.split(';').map(str::trim).filter(|pin| !pin.is_empty()) {
+            let encoded = pin.strip_prefix("sha256//").unwrap_or(pin);
+            let digest = BASE64.decode(encoded.as_bytes()).ok().filter(|digest| digest.len() == 32);
+            match digest {
+                Some(digest) => {
+                    let mut pin = [0; 32];
+                    pin.copy_from_slice(&digest);
+                    self.0.push(pin);
+                }
+                None => return Err(format!("{}: expected the base64 SHA-256 digest of a public key", pin)),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// `inner`, but also refusing certificates whose key is not pinned.
+    pub fn wrap(&self, inner: Arc<dyn ServerCertVerifier>) -> Arc<dyn ServerCertVerifier> {
+        if self.is_empty() {
+            return inner;
+        }
+        Arc::new(Pinned {
+            inner,
+            pins: self.clone(),
+        })
+    }
+}
+
+/// The server's key matched none of the pins.
+#[derive(Debug)]
+pub struct PinMismatch {
+    pub host: String,
+    /// The key's `sha256//` pin, for comparing with the expected ones.
+    pub found: String,
+}
+
+impl fmt::Display for PinMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the public key of {} is {}, which is not pinned", self.host, self.found)
+    }
+}
+
+impl Error for PinMismatch {}
+
+struct Pinned {
+    inner: Arc<dyn ServerCertVerifier>,
+    pins: Pins,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        let digest = spki(&end_entity.0)
+            .map(Sha256::digest)
+            .ok_or(rustls::Error::InvalidCertificate(CertificateError::BadEncoding))?;
+        if self.pins.0.iter().any(|pin| pin[..] == digest[..]) {
+            return Ok(verified);
+        }
+        let host = match *server_name {
+            ServerName::DnsName(ref name) => name.as_ref().to_string(),
+            ServerName::IpAddress(addr) => addr.to_string(),
+            _ => String::new(),
+        };
+        let mismatch = PinMismatch {
+            host,
+            found: format!("sha256//{}", BASE64.encode(&digest)),
+        };
+        Err(rustls::Error::InvalidCertificate(CertificateError::Other(Arc::new(mismatch))))
+    }
+}
+
+/// A DER element split off the front of some input.
+struct Element<'a> {
+    tag: u8,
+    /// The element itself, header included.
+    whole: &'a [u8],
+    contents: &'a [u8],
+    /// What follows the element.
+    rest: &'a [u8],
+}
+
+fn element(input: &[u8]) -> Option<Element<'_>> {
+    let (&tag, rest) = input.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let len = rest[..count].iter().fold(0usize, |len, &byte| len << 8 | byte as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let header = input.len() - rest.len();
+    Some(Element {
+        tag,
+        whole: &input[..header + len],
+        contents: &rest[..len],
+        rest: &rest[len..],
+    })
+}
+
+/// The DER SubjectPublicKeyInfo of an X.509 certificate: the seventh
+/// field of its TBSCertificate, counting the optional version.
+fn spki(cert: &[u8]) -> Option<&[u8]> {
+    let cert = element(cert)?.contents;
+    let mut fields = element(cert)?.contents;
+    // an explicit [0] version comes first in v2 and v3 certificates
+    if fields.first() == Some(&0xa0) {
+        fields = element(fields)?.rest;
+    }
+    // serial number, signature algorithm, issuer, validity and subject
+    for _ in 0..5 {
+        fields = element(fields)?.rest;
+    }
+    let spki = element(fields)?;
+    if spki.tag == 0x30 {
+        Some(spki.whole)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,205 @@
+use std::time::{Duration, Instant};
+
+/// Publish accumulated bytes at least this often regardless of the interval.
+const PUBLISH_BYTES: u64 = 1024 * 1024;
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long `--progress json` waits between events unless
+/// `--progress-interval` says otherwise.
+pub const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The ways progress can be shown: a bar on the terminal, JSON events on
+/// stderr, or not at all when the body goes to stdout.
+pub const MODES: [&str; 3] = ["bar", "json", "hidden"];
+
+/// What `--progress` asks for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Bar,
+    Json,
+    /// `--no-progress` or `--quiet`.
+    Hidden,
+}
+
+/// What the bytes being counted are for, so a display can label them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    /// Bytes received from the network.
+    Download,
+    /// Bytes of a request body sent with `--upload`.
+    Upload,
+    /// Bytes of an existing local file read back to check its digest.
+    Verify,
+}
+
+impl Phase {
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Download => "download",
+            Phase::Upload => "upload",
+            Phase::Verify => "verify",
+        }
+    }
+}
+
+/// Something that can display transfer progress. Upload bars are driven
+/// from the client's thread, hence `Send`.
+pub trait Renderer: Send {
+    fn inc(&mut self, bytes: u64);
+    fn finish(&mut self);
+    /// Clear the display of a transfer that stopped short, without
+    /// saying it is done.
+    fn abandon(&mut self);
+}
+
+/// Batches byte counts from the read loop and only hands them to the
+/// renderer once `interval` has elapsed or enough bytes have accumulated.
+pub struct Progress {
+    renderer: Option<Box<dyn Renderer>>,
+    interval: Duration,
+    pending: u64,
+    last_publish: Instant,
+}
+
+impl Progress {
+    pub fn new(renderer: Box<dyn Renderer>, interval: Duration) -> Progress {
+        Progress {
+            renderer: Some(renderer),
+            interval,
+            pending: 0,
+            last_publish: Instant::now(),
+        }
+    }
+
+    /// Progress that is tracked nowhere, e.g. when the body goes to stdout.
+    pub fn hidden() -> Progress {
+        Progress {
+            renderer: None,
+            interval: DEFAULT_INTERVAL,
+            pending: 0,
+            last_publish: Instant::now(),
+        }
+    }
+
+    pub fn add(&mut self, bytes: u64) {
+        if self.renderer.is_none() {
+            return;
+        }
+        self.pending += bytes;
+        if self.pending >= PUBLISH_BYTES || self.last_publish.elapsed() >= self.interval {
+            self.flush();
+        }
+    }
+
+    /// Publish any accumulated bytes immediately.
+    pub fn flush(&mut self) {
+        if let Some(ref mut renderer) = self.renderer {
+            if self.pending > 0 {
+                renderer.inc(self.pending);
+            }
+        }
+        self.pending = 0;
+        self.last_publish = Instant::now();
+    }
+
+    /// Publish what is left and close the display; finishing again does
+    /// nothing.
+    pub fn finish(&mut self) {
+        self.flush();
+        if let Some(mut renderer) = self.renderer.take() {
+            renderer.finish();
+        }
+    }
+
+    /// Close the display of a transfer that failed or was cancelled.
+    pub fn abandon(&mut self) {
+        if let Some(mut renderer) = self.renderer.take() {
+            renderer.abandon();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::{Progress, Renderer};
+
+    /// What a renderer was told, shared with the test that drives it.
+    #[derive(Default)]
+    struct Calls {
+        incs: Vec<u64>,
+        finished: bool,
+        abandoned: bool,
+    }
+
+    struct Recorder(Arc<Mutex<Calls>>);
+
+    impl Renderer for Recorder {
+        fn inc(&mut self, bytes: u64) {
+            self.0.lock().unwrap().incs.push(bytes);
+        }
+
+        fn finish(&mut self) {
+            self.0.lock().unwrap().finished = true;
+        }
+
+        fn abandon(&mut self) {
+            self.0.lock().unwrap().abandoned = true;
+        }
+    }
+
+    /// Progress that only publishes by size, as when chunks arrive faster
+    /// than any interval.
+    fn recorded() -> (Progress, Arc<Mutex<Calls>>) {
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        (Progress::new(Box::new(Recorder(calls.clone())), Duration::from_secs(3600)), calls)
+    }
+
+    #[test]
+    fn publishes_once_per_mib_of_small_chunks() {
+        let (mut progress, calls) = recorded();
+        for _ in 0..131_072 {
+            progress.add(8192);
+        }
+        progress.finish();
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.incs.len(), 1024);
+        assert!(calls.incs.iter().all(|&bytes| bytes == 1024 * 1024));
+        assert!(calls.finished);
+    }
+
+    #[test]
+    fn finish_publishes_what_is_left() {
+        let (mut progress, calls) = recorded();
+        progress.add(100);
+        assert!(calls.lock().unwrap().incs.is_empty());
+        progress.finish();
+        progress.finish();
+        let calls = calls.lock().unwrap();
+        assert_eq!(calls.incs, vec![100]);
+        assert!(calls.finished);
+    }
+
+    #[test]
+    fn publishes_after_the_interval() {
+        let calls = Arc::new(Mutex::new(Calls::default()));
+        let mut progress = Progress::new(Box::new(Recorder(calls.clone())), Duration::from_millis(0));
+        progress.add(10);
+        progress.add(20);
+        assert_eq!(calls.lock().unwrap().incs, vec![10, 20]);
+    }
+
+    #[test]
+    fn abandon_does_not_finish() {
+        let (mut progress, calls) = recorded();
+        progress.add(100);
+        progress.abandon();
+        progress.finish();
+        let calls = calls.lock().unwrap();
+        assert!(calls.abandoned);
+        assert!(!calls.finished);
+    }
+}
@@ -0,0 +1,436 @@
+use std::io;
+use std::io::Read;
+use std::thread;
+use std::thread::JoinHandle;
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE,
+                      LAST_MODIFIED, RANGE};
+use reqwest::{StatusCode, Url};
+
+pub const DEFAULT_CHUNK: u64 = 64 * 1024 * 1024;
+
+/// `Range` header value for `len` bytes starting at `start`.
+pub fn range_header(start: u64, len: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("bytes={}-{}", start, start + len - 1)).unwrap()
+}
+
+/// `Range` header value for everything from `start` on.
+pub fn from_header(start: u64) -> HeaderValue {
+    HeaderValue::from_str(&format!("bytes={}-", start)).unwrap()
+}
+
+/// Check that `resp` answers a request for the bytes from `start` on with
+/// exactly those bytes. Anything else would be taken for the remainder by
+/// a reader that cannot go back.
+pub fn check_from(resp: &Response, start: u64) -> Result<(), String> {
+    match (resp.status(), content_range(resp)) {
+        (StatusCode::PARTIAL_CONTENT, Some((got, _, _))) if got == start => Ok(()),
+        (StatusCode::PARTIAL_CONTENT, Some((got, end, total))) => Err(format!(
+            "asked for bytes {}- but the server sent {}-{}/{}",
+            start, got, end, total
+        )),
+        (StatusCode::RANGE_NOT_SATISFIABLE, _) => {
+            Err(format!("offset {} is at or past the end of the resource", start))
+        }
+        (StatusCode::OK, _) => Err(format!(
+            "the server ignored the request for bytes {}- and sent the whole body",
+            start
+        )),
+        (status, _) => Err(format!("expected a partial response from byte {} but got {}", start, status)),
+    }
+}
+
+/// Whether `resp` refuses a range from `len` on because the resource is
+/// exactly `len` bytes long, i.e. a partial copy of that length is done.
+pub fn ends_at(resp: &Response, len: u64) -> bool {
+    resp.status() == StatusCode::RANGE_NOT_SATISFIABLE
+        && resp
+            .headers()
+            .get(CONTENT_RANGE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().strip_prefix("bytes */"))
+            .and_then(|total| total.trim().parse::<u64>().ok())
+            == Some(len)
+}
+
+/// The validator to send as `If-Range` so that later ranges come from the
+/// same version as `resp`. Weak ETags cannot be used for that.
+pub fn validator(resp: &Response) -> Option<HeaderValue> {
+    resp.headers()
+        .get(ETAG)
+        .filter(|etag| !etag.as_bytes().starts_with(b"W/"))
+        .or_else(|| resp.headers().get(LAST_MODIFIED))
+        .cloned()
+}
+
+/// The `If-Range` value for carrying on a copy saved with these
+/// validators: the ETag unless it is weak, else the date.
+pub fn if_range(etag: Option<&str>, last_modified: Option<&str>) -> Option<HeaderValue> {
+    etag.filter(|etag| !etag.starts_with("W/"))
+        .or(last_modified)
+        .and_then(|value| HeaderValue::from_str(value).ok())
+}
+
+/// Parse a `Content-Range: bytes START-END/TOTAL` header.
+pub fn content_range(resp: &Response) -> Option<(u64, u64, u64)> {
+    let value = resp.headers().get(CONTENT_RANGE)?.to_str().ok()?;
+    let value = value.trim().strip_prefix("bytes ")?;
+    let (range, total) = value.split_at(value.find('/')?);
+    let (start, end) = range.split_at(range.find('-')?);
+    Some((
+        start.trim().parse().ok()?,
+        end[1..].trim().parse().ok()?,
+        total[1..].trim().parse().ok()?,
+    ))
+}
+
+fn content_length(resp: &Response) -> Option<u64> {
+    resp.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse().ok())
+}
+
+/// A response body, either read in one piece or stitched together from
+/// consecutive ranges.
+pub enum Body {
+    Plain(Response),
+    Ranged(Box<Ranges>),
+}
+
+impl Body {
+    /// Continue from the response to a first ranged request, sending
+    /// `headers` with every later range. Servers that answer with anything
+    /// but a partial response are read as a plain GET.
+    pub fn open(client: &Client, first: Response, chunk: u64, headers: HeaderMap) -> Body {
+        match content_range(&first) {
+            Some((0, end, total)) if first.status() == StatusCode::PARTIAL_CONTENT => {
+                Body::Ranged(Box::new(Ranges::new(
+                    client.clone(),
+                    first,
+                    chunk,
+                    headers,
+                    end + 1,
+                    total,
+                )))
+            }
+            _ => Body::Plain(first),
+        }
+    }
+
+    /// The response currently being read; before the first read this is the
+    /// response to the initial request.
+    pub fn response(&self) -> &Response {
+        match *self {
+            Body::Plain(ref resp) => resp,
+            Body::Ranged(ref ranges) => &ranges.current,
+        }
+    }
+
+    /// Size of the whole resource, when known.
+    pub fn total(&self) -> Option<u64> {
+        match *self {
+            Body::Plain(ref resp) => content_length(resp),
+            Body::Ranged(ref ranges) => Some(ranges.total),
+        }
+    }
+}
+
+impl Read for Body {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Body::Plain(ref mut resp) => resp.read(buf),
+            Body::Ranged(ref mut ranges) => ranges.read(buf),
+        }
+    }
+}
+
+/// Reads consecutive ranges strictly in order, always keeping the request
+/// for the following range in flight so that its latency overlaps with
+/// reading the current one.
+pub struct Ranges {
+    client: Client,
+    url: Url,
+    headers: HeaderMap,
+    chunk: u64,
+    total: u64,
+    /// First byte not yet requested.
+    next: u64,
+    /// Bytes of the whole resource read so far.
+    received: u64,
+    current: Response,
+    pending: Option<(u64, JoinHandle<reqwest::Result<Response>>)>,
+}
+
+impl Ranges {
+    fn new(
+        client: Client,
+        first: Response,
+        chunk: u64,
+        mut headers: HeaderMap,
+        next: u64,
+        total: u64,
+    ) -> Ranges {
+        // refuse to stitch together ranges of two different versions
+        if let Some(validator) = validator(&first) {
+            headers.insert(IF_RANGE, validator);
+        }
+        let mut ranges = Ranges {
+            client,
+            url: first.url().clone(),
+            headers,
+            chunk,
+            total,
+            next,
+            received: 0,
+            current: first,
+            pending: None,
+        };
+        ranges.request_next();
+        ranges
+    }
+
+    /// Send the request for the next range on a separate connection.
+    fn request_next(&mut self) {
+        if self.next >= self.total {
+            return;
+        }
+        let start = self.next;
+        let len = self.chunk.min(self.total - start);
+        let req = self
+            .client
+            .get(self.url.clone())
+            .headers(self.headers.clone())
+            .header(RANGE, range_header(start, len));
+        self.next += len;
+        self.pending = Some((start, thread::spawn(move || req.send())));
+    }
+
+    /// Swap in the pending response once the current one is exhausted.
+    /// Returns false when there is nothing left to read.
+    fn advance(&mut self) -> io::Result<bool> {
+        let (start, handle) = match self.pending.take() {
+            Some(pending) => pending,
+            None if self.received == self.total => return Ok(false),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("range ended after {} of {} bytes", self.received, self.total),
+                ))
+            }
+        };
+        let resp = handle
+            .join()
+            .map_err(|_| io::Error::other("range request panicked"))?
+            .map_err(io::Error::other)?;
+        match content_range(&resp) {
+            Some((got, _, total))
+                if resp.status() == StatusCode::PARTIAL_CONTENT
+                    && got == start
+                    && total == self.total => {}
+            _ if resp.status() == StatusCode::OK => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "resource changed during a ranged download",
+                ))
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected {} for range starting at {}", resp.status(), start),
+                ))
+            }
+        }
+        if start != self.received {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("range ended at {} but the next one starts at {}", self.received, start),
+            ));
+        }
+        self.current = resp;
+        self.request_next();
+        Ok(true)
+    }
+}
+
+impl Read for Ranges {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.current.read(buf)?;
+            if len > 0 {
+                self.received += len as u64;
+                return Ok(len);
+            }
+            if !self.advance()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use reqwest::blocking::Client;
+    use reqwest::header::{HeaderMap, RANGE};
+
+    use super::{range_header, Body};
+
+    /// A link to the test server: how late each answer starts, and the
+    /// bytes per second all of its connections share, if limited.
+    #[derive(Clone)]
+    struct Link {
+        latency: Duration,
+        rate: Option<f64>,
+        /// When the link is next free to send.
+        free_at: Arc<Mutex<Instant>>,
+    }
+
+    impl Link {
+        fn new(latency: Duration, rate: Option<f64>) -> Link {
+            Link {
+                latency,
+                rate,
+                free_at: Arc::new(Mutex::new(Instant::now())),
+            }
+        }
+
+        fn local() -> Link {
+            Link::new(Duration::from_millis(0), None)
+        }
+
+        /// Write `data` once the link has room for it.
+        fn send(&self, stream: &mut TcpStream, data: &[u8]) {
+            for piece in data.chunks(64 * 1024) {
+                if let Some(rate) = self.rate {
+                    let slot = {
+                        let mut free_at = self.free_at.lock().unwrap();
+                        let slot = (*free_at).max(Instant::now());
+                        *free_at = slot + Duration::from_secs_f64(piece.len() as f64 / rate);
+                        slot
+                    };
+                    thread::sleep(slot.saturating_duration_since(Instant::now()));
+                }
+                if stream.write_all(piece).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Serve `body` on a local port over `link`. `bytes=START-END` ranges
+    /// get a 206 when `ranges`, anything else the whole body.
+    fn serve(body: Vec<u8>, link: Link, ranges: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        let body = Arc::new(body);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let (body, link) = (body.clone(), link.clone());
+                thread::spawn(move || answer(stream.unwrap(), &body, &link, ranges));
+            }
+        });
+        url
+    }
+
+    fn answer(mut stream: TcpStream, body: &[u8], link: &Link, ranges: bool) {
+        let mut range = None;
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            let lower = line.trim().to_ascii_lowercase();
+            if let Some(spec) = lower.strip_prefix("range: bytes=") {
+                let (start, end) = spec.split_at(spec.find('-').unwrap());
+                range = Some((start.parse::<usize>().unwrap(), end[1..].parse::<usize>().unwrap()));
+            }
+            line.clear();
+        }
+        thread::sleep(link.latency);
+        let (status, extra, slice) = match range {
+            Some((start, end)) if ranges => (
+                "206 Partial Content",
+                format!("Content-Range: bytes {}-{}/{}\r\n", start, end, body.len()),
+                &body[start..end + 1],
+            ),
+            _ => ("200 OK", String::new(), body),
+        };
+        let head = format!(
+            "HTTP/1.1 {}\r\nContent-Length: {}\r\nETag: \"v1\"\r\n{}Connection: close\r\n\r\n",
+            status,
+            slice.len(),
+            extra
+        );
+        link.send(&mut stream, head.as_bytes());
+        link.send(&mut stream, slice);
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Read `url` as `--pipelined-ranges chunk` does.
+    fn read_pipelined(client: &Client, url: &str, chunk: u64) -> (Body, Vec<u8>) {
+        let first = client.get(url).header(RANGE, range_header(0, chunk)).send().unwrap();
+        let mut body = Body::open(client, first, chunk, HeaderMap::new());
+        let mut read = Vec::new();
+        body.read_to_end(&mut read).unwrap();
+        (body, read)
+    }
+
+    #[test]
+    fn ranges_are_stitched_together_in_order() {
+        let data = pattern(100_000);
+        let url = serve(data.clone(), Link::local(), true);
+        let (body, read) = read_pipelined(&Client::new(), &url, 30_000);
+        assert!(matches!(body, Body::Ranged(_)));
+        assert_eq!(body.total(), Some(100_000));
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn server_without_ranges_is_read_as_plain_get() {
+        let data = pattern(100_000);
+        let url = serve(data.clone(), Link::local(), false);
+        let (body, read) = read_pipelined(&Client::new(), &url, 30_000);
+        assert!(matches!(body, Body::Plain(_)));
+        assert_eq!(read, data);
+    }
+
+    /// `cargo test --release -- --ignored --nocapture pipelining_hides`
+    /// compares pipelined ranges with the same ranges fetched one after
+    /// another, over a link of 10 MB/s that answers every request 200 ms
+    /// late.
+    #[test]
+    #[ignore]
+    fn pipelining_hides_request_latency() {
+        const LEN: usize = 20 << 20;
+        let url = serve(pattern(LEN), Link::new(Duration::from_millis(200), Some(10e6)), true);
+        let client = Client::new();
+        for &chunk in &[1u64 << 20, 4 << 20, 8 << 20] {
+            let started = Instant::now();
+            let mut sequential = Vec::new();
+            for start in (0..LEN as u64).step_by(chunk as usize) {
+                let len = chunk.min(LEN as u64 - start);
+                let mut resp = client.get(&url).header(RANGE, range_header(start, len)).send().unwrap();
+                resp.read_to_end(&mut sequential).unwrap();
+            }
+            let sequential_time = started.elapsed();
+            let started = Instant::now();
+            let (_, pipelined) = read_pipelined(&client, &url, chunk);
+            let pipelined_time = started.elapsed();
+            assert_eq!(pipelined, sequential);
+            println!(
+                "{} MiB chunks: one after another {:.2}s, pipelined {:.2}s",
+                chunk >> 20,
+                sequential_time.as_secs_f64(),
+                pipelined_time.as_secs_f64()
+            );
+        }
+    }
+}
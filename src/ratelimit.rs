@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, Utc};
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use serde_json::Value;
+
+use messages;
+
+/// Start spacing requests out once less than this fraction of the limit is
+/// left.
+const PACE_BELOW: f64 = 0.1;
+/// Without a known limit, start pacing at this many remaining requests.
+const PACE_BELOW_REQUESTS: u64 = 10;
+/// Reset values above this are absolute epoch seconds rather than deltas.
+const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+/// The most recent rate limit a host reported.
+#[derive(Clone, Debug)]
+struct Limit {
+    limit: Option<u64>,
+    remaining: u64,
+    reset: Instant,
+    /// When the next request may be sent to pace out what remains.
+    next_allowed: Instant,
+}
+
+/// Rate limits observed per host from `RateLimit-*` and `X-RateLimit-*`
+/// response headers, used to delay requests so a host's quota is spread
+/// over its reset window rather than exhausted into a wall of 429s.
+pub struct Limits {
+    hosts: Mutex<HashMap<String, Limit>>,
+    verbose: bool,
+}
+
+fn header_u64(headers: &HeaderMap, names: &[&str]) -> Option<u64> {
+    names.iter().find_map(|name| {
+        headers
+            .get(*name)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+    })
+}
+
+/// Parse the combined `RateLimit: limit=100, remaining=50, reset=30` form.
+fn combined(headers: &HeaderMap) -> Option<(Option<u64>, u64, Option<u64>)> {
+    let value = headers.get("ratelimit")?.to_str().ok()?;
+    let mut limit = None;
+    let mut remaining = None;
+    let mut reset = None;
+    for param in value.split([',', ';']) {
+        let mut kv = param.splitn(2, '=');
+        let key = kv.next()?.trim().to_ascii_lowercase();
+        let value = kv.next().and_then(|value| value.trim().parse().ok());
+        match key.as_str() {
+            "limit" => limit = value,
+            "remaining" | "r" => remaining = value,
+            "reset" | "t" => reset = value,
+            _ => {}
+        }
+    }
+    Some((limit, remaining?, reset))
+}
+
+/// How long a `Retry-After` header asks to wait: a number of seconds or
+/// an HTTP date, which is no wait at all once it has passed.
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let secs = date.timestamp().saturating_sub(Utc::now().timestamp());
+    Some(Duration::from_secs(secs.max(0) as u64))
+}
+
+/// `(limit, remaining, seconds until reset)` from a response's headers.
+fn parse(headers: &HeaderMap) -> Option<(Option<u64>, u64, u64)> {
+    let remaining = header_u64(headers, &["ratelimit-remaining", "x-ratelimit-remaining"]);
+    let (limit, remaining, reset) = match remaining {
+        Some(remaining) => (
+            header_u64(headers, &["ratelimit-limit", "x-ratelimit-limit"]),
+            remaining,
+            header_u64(headers, &["ratelimit-reset", "x-ratelimit-reset"]),
+        ),
+        None => combined(headers)?,
+    };
+    let reset = match reset {
+        Some(reset) if reset > EPOCH_THRESHOLD => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|now| now.as_secs())
+                .unwrap_or(0);
+            reset.saturating_sub(now)
+        }
+        Some(reset) => reset,
+        None => 0,
+    };
+    Some((limit, remaining, reset))
+}
+
+impl Limits {
+    pub fn new(verbose: bool) -> Limits {
+        Limits {
+            hosts: Mutex::new(HashMap::new()),
+            verbose,
+        }
+    }
+
+    /// Block until a request to `host` is allowed under its last reported
+    /// limit.
+    pub fn wait(&self, host: &str) {
+        let until = match self.hosts.lock().unwrap().get(host) {
+            Some(limit) if limit.remaining == 0 => limit.reset,
+            Some(limit) => limit.next_allowed,
+            None => return,
+        };
+        let now = Instant::now();
+        if until <= now {
+            return;
+        }
+        let delay = until - now;
+        if delay >= Duration::from_secs(1) || self.verbose {
+            let secs = format!("{:.1}", delay.as_secs_f64());
+            messages::note("note.rate-limit-wait", &[&secs, &host]);
+        }
+        thread::sleep(delay);
+    }
+
+    /// Record the limit reported by `resp`, if any.
+    pub fn observe(&self, resp: &Response) {
+        let host = match resp.url().host_str() {
+            Some(host) => host.to_string(),
+            None => return,
+        };
+        let (limit, remaining, reset_secs) = match parse(resp.headers()) {
+            Some(parsed) => parsed,
+            None => return,
+        };
+        let now = Instant::now();
+        let reset = now + Duration::from_secs(reset_secs);
+        let threshold = limit
+            .map(|limit| (limit as f64 * PACE_BELOW) as u64)
+            .unwrap_or(PACE_BELOW_REQUESTS);
+        let next_allowed = if remaining > 0 && remaining <= threshold {
+            now + Duration::from_secs(reset_secs) / remaining as u32
+        } else {
+            now
+        };
+        if self.verbose {
+            let limit = limit.map(|limit| limit.to_string()).unwrap_or_else(|| "?".to_string());
+            messages::note("note.rate-limit", &[&host, &remaining, &limit, &reset_secs]);
+        }
+        self.hosts.lock().unwrap().insert(
+            host,
+            Limit {
+                limit,
+                remaining,
+                reset,
+                next_allowed,
+            },
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hosts.lock().unwrap().is_empty()
+    }
+
+    /// The last limit seen for every host, for the result document.
+    pub fn to_json(&self) -> Value {
+        let now = Instant::now();
+        let hosts = self.hosts.lock().unwrap();
+        let mut doc = json!({});
+        for (host, limit) in hosts.iter() {
+            doc[host] = json!({
+                "limit": limit.limit,
+                "remaining": limit.remaining,
+                "reset_secs": limit.reset.saturating_duration_since(now).as_secs(),
+            });
+        }
+        doc
+    }
+}
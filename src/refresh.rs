@@ -0,0 +1,96 @@
+use reqwest::blocking::Response;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::Url;
+
+/// Pages larger than this are never treated as a redirect.
+pub const SNIFF_LIMIT: u64 = 64 * 1024;
+
+/// Whether the response claims to be HTML and is small enough to sniff.
+pub fn is_candidate(resp: &Response) -> bool {
+    let html = resp
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim_start().to_ascii_lowercase().starts_with("text/html"))
+        .unwrap_or(false);
+    html && resp.content_length().map(|len| len <= SNIFF_LIMIT).unwrap_or(true)
+}
+
+/// The target of a `<meta http-equiv="refresh" content="0; url=...">` tag,
+/// resolved against `base`.
+pub fn target(html: &[u8], base: &Url) -> Option<Url> {
+    let html = String::from_utf8_lossy(html);
+    let lower = html.to_ascii_lowercase();
+    let mut offset = 0;
+    while let Some(start) = lower[offset..].find("<meta") {
+        let start = offset + start;
+        let end = start + lower[start..].find('>').unwrap_or(lower.len() - start);
+        let attrs = attributes(&html[start + 5..end]);
+        let is_refresh = attrs
+            .iter()
+            .any(|(name, value)| name == "http-equiv" && value.eq_ignore_ascii_case("refresh"));
+        if is_refresh {
+            let content = attrs.iter().find(|(name, _)| name == "content");
+            if let Some(url) = content.and_then(|(_, value)| refresh_url(value)) {
+                return base.join(&url).ok();
+            }
+        }
+        offset = end;
+    }
+    None
+}
+
+/// Split `name="value" name='value' name=value` into lowercased names and
+/// raw values.
+fn attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attrs = Vec::new();
+    let mut rest = tag.trim_start_matches('/');
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == '/');
+        let name_end = rest
+            .find(|c: char| c == '=' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        if name_end == 0 {
+            break;
+        }
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        if !rest.starts_with('=') {
+            attrs.push((name, String::new()));
+            continue;
+        }
+        rest = rest[1..].trim_start();
+        let value = match rest.chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => {
+                let len = rest[1..].find(quote).unwrap_or(rest.len() - 1);
+                let value = &rest[1..1 + len];
+                rest = &rest[(2 + len).min(rest.len())..];
+                value
+            }
+            _ => {
+                let len = rest.find(char::is_whitespace).unwrap_or(rest.len());
+                let value = &rest[..len];
+                rest = &rest[len..];
+                value
+            }
+        };
+        attrs.push((name, value.to_string()));
+    }
+    attrs
+}
+
+/// The URL part of a refresh `content` value such as `0; url=/next`.
+fn refresh_url(content: &str) -> Option<String> {
+    let (_, rest) = content.split_at(content.find([';', ','])?);
+    let rest = rest[1..].trim_start();
+    match rest.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("url=") => {}
+        _ => return None,
+    }
+    let url = rest[4..].trim().trim_matches(['"', '\'']);
+    if url.is_empty() {
+        None
+    } else {
+        Some(url.to_string())
+    }
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use reqwest::blocking::Response;
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, ETAG, LAST_MODIFIED};
+use serde_json;
+use serde_json::Value;
+
+use fsutil;
+use ranges;
+use segments::Segment;
+
+/// What `--continue` needs to know about a `.part`: the URL and version of
+/// the resource it holds a copy of, and for a segmented download which
+/// bytes have arrived. The bytes of a single stream are the ones the
+/// `.part` holds, in order from the start.
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// The size of the whole resource, where the server gave it.
+    pub total: Option<u64>,
+    /// Each connection's share; empty for a single stream.
+    pub segments: Vec<Segment>,
+}
+
+fn header_string(headers: &HeaderMap, name: ::reqwest::header::HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+}
+
+/// The size of the whole resource `resp` is all or part of.
+fn whole_length(resp: &Response) -> Option<u64> {
+    match ranges::content_range(resp) {
+        Some((_, _, total)) => Some(total),
+        None => header_string(resp.headers(), CONTENT_LENGTH).and_then(|len| len.parse().ok()),
+    }
+}
+
+impl State {
+    /// The state of a download of `url` that `resp` answered.
+    pub fn of(url: &str, resp: &Response) -> State {
+        State {
+            url: url.to_string(),
+            etag: header_string(resp.headers(), ETAG),
+            last_modified: header_string(resp.headers(), LAST_MODIFIED),
+            total: whole_length(resp),
+            segments: Vec::new(),
+        }
+    }
+
+    /// Where the state of `part` is kept: `<output>.part.state`.
+    pub fn path(part: &Path) -> PathBuf {
+        let mut name = part.as_os_str().to_owned();
+        name.push(".state");
+        PathBuf::from(name)
+    }
+
+    /// The state saved for `part`. A missing or unreadable file yields
+    /// `None`, and the `.part` is continued as its length says.
+    pub fn load(part: &Path) -> Option<State> {
+        let contents = fs::read(State::path(part)).ok()?;
+        let doc: Value = serde_json::from_slice(&contents).ok()?;
+        let string = |key: &str| doc.get(key).and_then(Value::as_str).map(String::from);
+        let mut segments = Vec::new();
+        for segment in doc.get("segments").and_then(Value::as_array).map_or(&[][..], Vec::as_slice) {
+            let field = |idx: usize| segment.get(idx).and_then(Value::as_u64);
+            let segment = Segment {
+                start: field(0)?,
+                end: field(1)?,
+                next: field(2)?,
+            };
+            if segment.start > segment.next || segment.next > segment.end {
+                return None;
+            }
+            segments.push(segment);
+        }
+        Some(State {
+            url: string("url")?,
+            etag: string("etag"),
+            last_modified: string("last_modified"),
+            total: doc.get("total").and_then(Value::as_u64),
+            segments,
+        })
+    }
+
+    pub fn save(&self, part: &Path) -> io::Result<()> {
+        let segments: Vec<Value> = self
+            .segments
+            .iter()
+            .map(|segment| json!([segment.start, segment.end, segment.next]))
+            .collect();
+        let doc = json!({
+            "url": self.url,
+            "etag": self.etag,
+            "last_modified": self.last_modified,
+            "total": self.total,
+            "segments": segments,
+        });
+        fsutil::write_atomic(&State::path(part), &serde_json::to_vec_pretty(&doc).unwrap())
+    }
+
+    /// Remove the state of `part`, which is done with or gone.
+    pub fn remove(part: &Path) {
+        let _ = fs::remove_file(State::path(part));
+    }
+
+    /// The first byte still missing from a segmented download, or its
+    /// total once every segment is complete.
+    pub fn first_missing(&self) -> Option<u64> {
+        let last = self.segments.last()?;
+        let pending = self.segments.iter().find(|segment| segment.next < segment.end);
+        Some(pending.map_or(last.end, |segment| segment.next))
+    }
+
+    /// Whether `resp` sends the same version of the resource as the one
+    /// the `.part` was started from, as far as the validators both carry
+    /// can tell.
+    pub fn describes(&self, resp: &Response) -> bool {
+        let theirs = State::of(&self.url, resp);
+        let same = |ours: &Option<String>, theirs: &Option<String>| match (ours, theirs) {
+            (Some(ours), Some(theirs)) => ours == theirs,
+            _ => true,
+        };
+        let etags = self.etag.is_some() && theirs.etag.is_some();
+        let length = match (self.total, theirs.total) {
+            (Some(ours), Some(theirs)) => ours == theirs,
+            _ => true,
+        };
+        length && same(&self.etag, &theirs.etag) && (etags || same(&self.last_modified, &theirs.last_modified))
+    }
+}
@@ -0,0 +1,320 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What the process may still change once `enter` has run: directories
+/// where files are created, replaced or removed, and existing files, such
+/// as FIFOs and devices, that are only written to.
+#[derive(Debug, Default)]
+pub struct Allowed {
+    dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+impl Allowed {
+    /// Allow creating entries under `dir`. A directory that does not exist
+    /// yet, as with `--create-dirs`, is allowed at its nearest existing
+    /// ancestor so that it can still be created.
+    pub fn dir(&mut self, dir: &Path) {
+        let mut dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        while fs::metadata(dir).is_err() {
+            match dir.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => dir = parent,
+                _ => {
+                    dir = Path::new(".");
+                    break;
+                }
+            }
+        }
+        if !self.dirs.iter().any(|known| known == dir) {
+            self.dirs.push(dir.to_path_buf());
+        }
+    }
+
+    /// Allow writing to the existing file at `path`.
+    pub fn file(&mut self, path: &Path) {
+        self.files.push(path.to_path_buf());
+    }
+}
+
+/// Restrict this process, and every thread it starts afterwards, to what
+/// a download needs: no new programs, no privilege changes, no mounts or
+/// namespaces, and no filesystem changes outside `allowed`. Threads that
+/// are already running are not covered by the filesystem rules, so this
+/// must run before the client or the control socket start any. Whatever
+/// the platform or kernel cannot enforce is reported and skipped.
+pub fn enter(allowed: &Allowed, verbose: bool) {
+    imp::enter(allowed, verbose)
+}
+
+/// Whether `enter` can restrict anything on this platform.
+pub const SUPPORTED: bool = cfg!(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")));
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+mod imp {
+    use std::ffi::CString;
+    use std::io;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use libc;
+
+    use super::Allowed;
+    use messages;
+
+    const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+    const LANDLOCK_RULE_PATH_BENEATH: u32 = 1;
+
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_CHAR: u64 = 1 << 6;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    const ACCESS_FS_MAKE_SOCK: u64 = 1 << 9;
+    const ACCESS_FS_MAKE_FIFO: u64 = 1 << 10;
+    const ACCESS_FS_MAKE_BLOCK: u64 = 1 << 11;
+    const ACCESS_FS_MAKE_SYM: u64 = 1 << 12;
+    /// Renames and links across directories; Landlock ABI 2.
+    const ACCESS_FS_REFER: u64 = 1 << 13;
+    /// `truncate` and `O_TRUNC`; Landlock ABI 3.
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// x32 system calls reuse the x86_64 arch with this bit set.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// Offsets into `struct seccomp_data`.
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+
+    /// System calls a download never needs and an attacker would want:
+    /// running programs, debugging other processes, changing credentials,
+    /// ownership or mounts, loading kernel code, and entering namespaces.
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setfsuid,
+        libc::SYS_setfsgid,
+        libc::SYS_setgroups,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_fchown,
+        libc::SYS_fchownat,
+        libc::SYS_mknodat,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_chown,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_lchown,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_mknod,
+    ];
+
+    pub fn enter(allowed: &Allowed, verbose: bool) {
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            let err = io::Error::last_os_error();
+            messages::warn("warning.sandbox-unavailable", &[&"no_new_privs", &err]);
+            return;
+        }
+        match restrict_filesystem(allowed) {
+            Ok(abi) if verbose => messages::note("note.sandbox-landlock", &[&abi]),
+            Ok(_) => {}
+            Err(err) => messages::warn("warning.sandbox-unavailable", &[&"Landlock", &err]),
+        }
+        match filter_syscalls() {
+            Ok(()) if verbose => messages::note("note.sandbox-seccomp", &[&DENIED.len()]),
+            Ok(()) => {}
+            Err(err) => messages::warn("warning.sandbox-unavailable", &[&"seccomp", &err]),
+        }
+    }
+
+    fn check(ret: libc::c_long) -> io::Result<libc::c_long> {
+        if ret < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(ret)
+        }
+    }
+
+    /// Apply a Landlock ruleset, returning the ABI version it was built for.
+    fn restrict_filesystem(allowed: &Allowed) -> io::Result<libc::c_long> {
+        let abi = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                0usize,
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        })?;
+        let mut write_dir = ACCESS_FS_WRITE_FILE
+            | ACCESS_FS_REMOVE_DIR
+            | ACCESS_FS_REMOVE_FILE
+            | ACCESS_FS_MAKE_CHAR
+            | ACCESS_FS_MAKE_DIR
+            | ACCESS_FS_MAKE_REG
+            | ACCESS_FS_MAKE_SOCK
+            | ACCESS_FS_MAKE_FIFO
+            | ACCESS_FS_MAKE_BLOCK
+            | ACCESS_FS_MAKE_SYM;
+        let mut write_file = ACCESS_FS_WRITE_FILE;
+        if abi >= 2 {
+            write_dir |= ACCESS_FS_REFER;
+        }
+        if abi >= 3 {
+            write_dir |= ACCESS_FS_TRUNCATE;
+            write_file |= ACCESS_FS_TRUNCATE;
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_EXECUTE | write_dir,
+        };
+        let ruleset = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        })? as libc::c_int;
+        let result = add_rules(ruleset, &allowed.dirs, write_dir)
+            .and_then(|_| add_rules(ruleset, &allowed.files, write_file))
+            .and_then(|_| check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset, 0u32) }));
+        unsafe { libc::close(ruleset) };
+        result.map(|_| abi)
+    }
+
+    fn add_rules<P: AsRef<Path>>(ruleset: libc::c_int, paths: &[P], access: u64) -> io::Result<()> {
+        for path in paths {
+            let path = path.as_ref();
+            let c_path = CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if fd < 0 {
+                let err = io::Error::last_os_error();
+                return Err(io::Error::new(err.kind(), format!("{}: {}", path.display(), err)));
+            }
+            let rule = PathBeneathAttr {
+                allowed_access: access,
+                parent_fd: fd,
+            };
+            let ret = check(unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    ruleset,
+                    LANDLOCK_RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0u32,
+                )
+            });
+            unsafe { libc::close(fd) };
+            ret.map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        }
+        Ok(())
+    }
+
+    fn stmt(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// Install a seccomp filter, on every thread, that fails the `DENIED`
+    /// system calls and any call made through a foreign ABI with `EPERM`.
+    fn filter_syscalls() -> io::Result<()> {
+        let deny = libc::SECCOMP_RET_ERRNO | (libc::EPERM as u32 & libc::SECCOMP_RET_DATA);
+        let mut program = vec![
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH),
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(libc::BPF_RET | libc::BPF_K, deny),
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        {
+            program.push(jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1));
+            program.push(stmt(libc::BPF_RET | libc::BPF_K, deny));
+        }
+        for &nr in DENIED {
+            // on a match fall through to the deny, otherwise skip it
+            program.push(jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr as u32, 0, 1));
+            program.push(stmt(libc::BPF_RET | libc::BPF_K, deny));
+        }
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW));
+
+        let prog = libc::sock_fprog {
+            len: program.len() as libc::c_ushort,
+            filter: program.as_mut_ptr(),
+        };
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const libc::sock_fprog,
+            )
+        })
+        .map(|_| ())
+    }
+}
+
+#[cfg(not(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))))]
+mod imp {
+    use super::Allowed;
+    use messages;
+
+    pub fn enter(_allowed: &Allowed, _verbose: bool) {
+        messages::warn("warning.sandbox-unsupported", &[]);
+    }
+}
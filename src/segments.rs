@@ -0,0 +1,247 @@
+use std::cmp;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{HeaderMap, RANGE};
+use reqwest::{StatusCode, Url};
+
+use control;
+use progress::Progress;
+use ranges;
+
+/// Segments are never smaller than this, so that a small file is not
+/// spread over many connections for nothing.
+pub const MIN_SEGMENT: u64 = 1024 * 1024;
+
+const COPY_CHUNK: usize = 64 * 1024;
+
+/// How often the bytes counted by all segments are handed to the bar.
+const POLL: Duration = Duration::from_millis(50);
+
+/// How often `fetch` hands the segments' progress to its checkpoint.
+const CHECKPOINT: Duration = Duration::from_secs(2);
+
+/// The size of the resource when `resp`, the answer to a request for its
+/// bytes from `from` on, is a partial response and so can be split up.
+pub fn splittable(resp: &Response, from: u64) -> Option<u64> {
+    if resp.status() != StatusCode::PARTIAL_CONTENT {
+        return None;
+    }
+    match ranges::content_range(resp) {
+        Some((start, end, total)) if start == from && end + 1 == total => Some(total),
+        _ => None,
+    }
+}
+
+/// The bytes `start`..`end` of the resource one connection fetches, of
+/// which those before `next` have arrived.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Segment {
+    pub start: u64,
+    pub end: u64,
+    pub next: u64,
+}
+
+/// How `total` bytes at a URL are split up and fetched.
+pub struct Plan {
+    client: Client,
+    url: Url,
+    headers: HeaderMap,
+    total: u64,
+    segments: Vec<Segment>,
+    /// How far each segment has got while `fetch` runs.
+    next: Vec<AtomicU64>,
+}
+
+impl Plan {
+    /// Split `total` bytes into up to `wanted` segments of at least
+    /// `MIN_SEGMENT` bytes. Ranges are requested with `headers`, which
+    /// should pin them to one version with `If-Range`.
+    pub fn new(client: &Client, url: Url, headers: HeaderMap, total: u64, wanted: u64) -> Plan {
+        let count = cmp::max(1, cmp::min(wanted, total / MIN_SEGMENT));
+        let size = total / count;
+        let segments = (0..count)
+            .map(|idx| {
+                let end = if idx + 1 == count { total } else { (idx + 1) * size };
+                Segment { start: idx * size, end, next: idx * size }
+            })
+            .collect();
+        Plan::resume(client, url, headers, total, segments)
+    }
+
+    /// Fetch what `segments`, saved by an earlier run, are still missing.
+    pub fn resume(client: &Client, url: Url, headers: HeaderMap, total: u64, segments: Vec<Segment>) -> Plan {
+        Plan {
+            client: client.clone(),
+            url,
+            headers,
+            total,
+            next: segments.iter().map(|segment| AtomicU64::new(segment.next)).collect(),
+            segments,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// The segments with bytes still to fetch, each over a connection.
+    pub fn count(&self) -> u64 {
+        self.segments.iter().filter(|segment| segment.next < segment.end).count() as u64
+    }
+
+    /// The bytes still to fetch.
+    pub fn remaining(&self) -> u64 {
+        self.segments.iter().map(|segment| segment.end - segment.next).sum()
+    }
+
+    /// Every segment, with how far it has got.
+    pub fn progress(&self) -> Vec<Segment> {
+        self.segments
+            .iter()
+            .zip(&self.next)
+            .map(|(segment, next)| Segment { next: next.load(Ordering::SeqCst), ..*segment })
+            .collect()
+    }
+
+    /// Fetch every segment still missing bytes into `file` at once, each
+    /// over its own connection. `body` answers the request for everything
+    /// from the first missing byte on and supplies the first of them. The
+    /// first failure stops every segment. `checkpoint` is handed the plan
+    /// every few seconds, so that its progress can be saved.
+    pub fn fetch<R: Read + Send>(
+        &self,
+        body: R,
+        file: &File,
+        progress: &mut Progress,
+        checkpoint: &mut dyn FnMut(&Plan),
+    ) -> io::Result<()> {
+        let received = AtomicU64::new(0);
+        let stop = AtomicBool::new(false);
+        // the segments count towards this job's transfer
+        let slot = control::handle();
+        let mut pending = self.segments.iter().zip(&self.next).filter(|(segment, _)| segment.next < segment.end);
+        thread::scope(|scope| {
+            let (done, results) = mpsc::channel();
+            let (received, stop) = (&received, &stop);
+            if let Some((first, next)) = pending.next() {
+                let first_done = done.clone();
+                scope.spawn(move || {
+                    control::adopt(slot);
+                    let _ = first_done.send(copy(body, file, first, next, received, stop));
+                });
+            }
+            for (segment, next) in pending {
+                let (start, end) = (segment.next, segment.end);
+                let req = self
+                    .client
+                    .get(self.url.clone())
+                    .headers(self.headers.clone())
+                    .header(RANGE, ranges::range_header(start, end - start));
+                let total = self.total;
+                let done = done.clone();
+                scope.spawn(move || {
+                    control::adopt(slot);
+                    let outcome = req
+                        .send()
+                        .map_err(io::Error::other)
+                        .and_then(|resp| check(&resp, start, end, total).map(|_| resp))
+                        .and_then(|resp| copy(resp, file, segment, next, received, stop));
+                    let _ = done.send(outcome);
+                });
+            }
+            drop(done);
+
+            let mut outcome = Ok(());
+            let mut published = 0;
+            let mut checkpointed = Instant::now();
+            loop {
+                match results.recv_timeout(POLL) {
+                    Ok(Err(e)) => {
+                        if outcome.is_ok() {
+                            stop.store(true, Ordering::SeqCst);
+                            outcome = Err(e);
+                        }
+                    }
+                    Ok(Ok(())) | Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                let now = received.load(Ordering::SeqCst);
+                progress.add(now - published);
+                published = now;
+                if checkpointed.elapsed() >= CHECKPOINT {
+                    checkpoint(self);
+                    checkpointed = Instant::now();
+                }
+            }
+            outcome
+        })
+    }
+}
+
+/// Check that `resp` answers the request for segment `start`..`end` with
+/// exactly those bytes of the same `total`.
+fn check(resp: &Response, start: u64, end: u64, total: u64) -> io::Result<()> {
+    match ranges::content_range(resp) {
+        Some((got, last, size))
+            if resp.status() == StatusCode::PARTIAL_CONTENT && got == start && last + 1 == end && size == total =>
+        {
+            Ok(())
+        }
+        _ if resp.status() == StatusCode::OK => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "resource changed during a segmented download",
+        )),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unexpected {} for the segment starting at {}", resp.status(), start),
+        )),
+    }
+}
+
+/// Write the rest of `segment` from `reader` into `file` at its place,
+/// keeping `next` at the first byte not yet written.
+fn copy<R: Read>(
+    reader: R,
+    file: &File,
+    segment: &Segment,
+    next: &AtomicU64,
+    received: &AtomicU64,
+    stop: &AtomicBool,
+) -> io::Result<()> {
+    let (start, end) = (segment.start, segment.end);
+    let mut at = segment.next;
+    let mut reader = control::SpeedCheck::new(reader.take(end - at));
+    let mut buf = vec![0; COPY_CHUNK];
+    while at < end {
+        if stop.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        control::check()?;
+        control::check_deadline()?;
+        let len = match reader.read(&mut buf) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("segment {}-{} ended after {} bytes", start, end - 1, at - start),
+                ))
+            }
+            Ok(len) => len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        file.write_all_at(&buf[..len], at)?;
+        at += len as u64;
+        next.store(at, Ordering::SeqCst);
+        received.fetch_add(len as u64, Ordering::SeqCst);
+        control::advance(len as u64);
+    }
+    Ok(())
+}
@@ -0,0 +1,131 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use reqwest::blocking::Client;
+
+use bar::Bar;
+use checksum::Algorithm;
+use console;
+use fsutil;
+use hasher::Hasher;
+use progress::Renderer;
+use tls_config;
+
+/// A small resource with a digest that does not change, fetched by default.
+pub const DEFAULT_URL: &str = "https://raw.githubusercontent.com/xstevens/download/master/LICENSE";
+pub const DEFAULT_SHA256: &str = "ce05c8fe55381fb9fb361261786e146a693c4d51bce4b60ef5b4f928cb9377b0";
+
+/// The remote part of the self-test.
+pub struct Remote<'a> {
+    pub url: &'a str,
+    /// Expected sha256; without one the fetch only has to succeed.
+    pub sha256: Option<&'a str>,
+}
+
+/// Digests of "abc" from each algorithm's specification.
+const ABC: [(Algorithm, &str); 5] = [
+    (Algorithm::Md5, "900150983cd24fb0d6963f7d28e17f72"),
+    (Algorithm::Sha1, "a9993e364706816aba3e25717850c26c9cd0d89d"),
+    (Algorithm::Sha256, "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"),
+    (
+        Algorithm::Sha512,
+        "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+         2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+    ),
+    (Algorithm::Blake3, "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"),
+];
+
+fn check_hashing() -> Result<(), String> {
+    let mut hasher = Hasher::with(&Algorithm::ALL);
+    hasher.update(b"abc");
+    let digests = hasher.finish();
+    for &(algorithm, expected) in ABC.iter() {
+        let actual = digests.get(algorithm).unwrap_or_default();
+        if actual != expected {
+            return Err(format!("{}(\"abc\") = {}", algorithm, actual));
+        }
+    }
+    Ok(())
+}
+
+fn check_tls_roots() -> Result<(), String> {
+    if tls_config::webpki_root_store().is_empty() {
+        return Err("no trust anchors loaded".to_string());
+    }
+    Ok(())
+}
+
+fn check_temp_file(dir: &Path) -> Result<(), String> {
+    let path = dir.join(".download-self-test");
+    let contents = b"download self-test\n";
+    fsutil::write_atomic(&path, contents).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let read = fs::read(&path);
+    let _ = fs::remove_file(&path);
+    match read {
+        Ok(ref read) if read[..] == contents[..] => Ok(()),
+        Ok(_) => Err(format!("{}: contents differ after rename", path.display())),
+        Err(e) => Err(format!("{}: {}", path.display(), e)),
+    }
+}
+
+fn check_progress() -> Result<(), String> {
+    let mut out = Vec::new();
+    {
+        let mut bar = Bar::new(&mut out, 100);
+        bar.inc(50);
+        bar.finish();
+    }
+    let frame = String::from_utf8_lossy(&out);
+    if frame.contains("50") && frame.ends_with("Done.\n") {
+        Ok(())
+    } else {
+        Err(format!("unexpected frame {:?}", frame))
+    }
+}
+
+fn check_remote(client: &Client, remote: &Remote) -> Result<(), String> {
+    let mut resp = client.get(remote.url).send().map_err(|e| e.to_string())?;
+    if !resp.status().is_success() {
+        return Err(format!("{} answered {}", remote.url, resp.status()));
+    }
+    let mut body = Vec::new();
+    resp.read_to_end(&mut body).map_err(|e| e.to_string())?;
+    let mut hasher = Hasher::with(&[Algorithm::Sha256]);
+    hasher.update(&body);
+    let digests = hasher.finish();
+    let sha256 = digests.get(Algorithm::Sha256).unwrap_or_default();
+    match remote.sha256 {
+        Some(expected) if !expected.eq_ignore_ascii_case(sha256) => Err(format!(
+            "{}: expected sha256 {} but got {}",
+            remote.url, expected, sha256
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Run every check, printing PASS or FAIL for each. Returns whether all of
+/// them passed.
+pub fn run(client: &Client, dir: &Path, remote: Option<Remote>) -> bool {
+    let mut checks: Vec<(&str, Result<(), String>)> = vec![
+        ("hashing", check_hashing()),
+        ("tls roots", check_tls_roots()),
+        ("temp file", check_temp_file(dir)),
+        ("progress", check_progress()),
+    ];
+    if let Some(ref remote) = remote {
+        checks.push(("remote fetch", check_remote(client, remote)));
+    }
+
+    let mut passed = true;
+    for (name, result) in checks {
+        match result {
+            Ok(()) => console::out(&format!("PASS {}", name)),
+            Err(e) => {
+                passed = false;
+                console::out(&format!("FAIL {}: {}", name, e));
+            }
+        }
+    }
+    passed
+}
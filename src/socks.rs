@@ -0,0 +1,371 @@
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::BuildHasher;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::thread;
+
+use data_encoding::BASE64;
+use reqwest::Url;
+
+use messages;
+use percent_encoding;
+
+/// The longest request or response head the relay reads before giving up.
+const MAX_HEAD: usize = 64 * 1024;
+
+/// A SOCKS5 proxy (RFC 1928), as given to `--socks5` or `--socks5-hostname`
+/// in the form `[user:password@]host[:port]`.
+#[derive(Clone, Debug)]
+pub struct Proxy {
+    host: String,
+    port: u16,
+    /// Whether host names are passed to the proxy to resolve, as Tor needs,
+    /// rather than resolved here first.
+    remote_dns: bool,
+    /// Username and password for RFC 1929 authentication.
+    login: Option<(String, String)>,
+}
+
+impl Proxy {
+    pub fn parse(value: &str, remote_dns: bool) -> Result<Proxy, String> {
+        let scheme = if remote_dns { "socks5h" } else { "socks5" };
+        let url = Url::parse(&format!("{}://{}", scheme, value)).map_err(|e| e.to_string())?;
+        let host = match url.host_str() {
+            Some(host) if url.path().is_empty() || url.path() == "/" => host.trim_matches(|c| c == '[' || c == ']'),
+            _ => return Err("expected [user:password@]host[:port]".to_string()),
+        };
+        let login = if url.username().is_empty() {
+            None
+        } else {
+            let decode = |part: &str| percent_encoding::percent_decode_str(part).decode_utf8_lossy().into_owned();
+            Some((decode(url.username()), decode(url.password().unwrap_or(""))))
+        };
+        Ok(Proxy {
+            host: host.to_string(),
+            port: url.port().unwrap_or(1080),
+            remote_dns,
+            login,
+        })
+    }
+
+    /// Open a connection to `host:port` through the proxy.
+    pub fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))?;
+        stream.set_nodelay(true)?;
+        self.greet(&mut stream)?;
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(ip) => push_ip(&mut request, ip),
+            Err(_) if self.remote_dns => {
+                if host.len() > 255 {
+                    return Err(invalid(format!("host name too long for SOCKS5: {}", host)));
+                }
+                request.push(3);
+                request.push(host.len() as u8);
+                request.extend_from_slice(host.as_bytes());
+            }
+            Err(_) => {
+                let addr = (host, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| invalid(format!("{} has no address", host)))?;
+                push_ip(&mut request, addr.ip());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != 5 {
+            return Err(invalid("not a SOCKS5 proxy".to_string()));
+        }
+        if reply[1] != 0 {
+            return Err(io::Error::other(format!("SOCKS5 proxy: {}", reply_message(reply[1]))));
+        }
+        // the address the proxy connected from is of no use here
+        let skip = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(invalid("SOCKS5 proxy sent an unknown address type".to_string())),
+        };
+        let mut bound = vec![0; skip + 2];
+        stream.read_exact(&mut bound)?;
+        Ok(stream)
+    }
+
+    /// Agree on an authentication method and authenticate.
+    fn greet(&self, stream: &mut TcpStream) -> io::Result<()> {
+        let methods: &[u8] = if self.login.is_some() { &[0, 2] } else { &[0] };
+        let mut hello = vec![5, methods.len() as u8];
+        hello.extend_from_slice(methods);
+        stream.write_all(&hello)?;
+        let mut choice = [0; 2];
+        stream.read_exact(&mut choice)?;
+        match (choice, self.login.as_ref()) {
+            ([5, 0], _) => Ok(()),
+            ([5, 2], Some((user, password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    return Err(invalid("SOCKS5 username and password are limited to 255 bytes".to_string()));
+                }
+                let mut login = vec![1, user.len() as u8];
+                login.extend_from_slice(user.as_bytes());
+                login.push(password.len() as u8);
+                login.extend_from_slice(password.as_bytes());
+                stream.write_all(&login)?;
+                let mut status = [0; 2];
+                stream.read_exact(&mut status)?;
+                if status[1] == 0 {
+                    Ok(())
+                } else {
+                    Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        "SOCKS5 proxy rejected the username or password",
+                    ))
+                }
+            }
+            ([5, _], _) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy accepts none of our authentication methods",
+            )),
+            _ => Err(invalid("not a SOCKS5 proxy".to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Proxy {
+    /// The proxy without its login.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = if self.remote_dns { "socks5h" } else { "socks5" };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}", scheme, self.host, self.port)
+        } else {
+            write!(f, "{}://{}:{}", scheme, self.host, self.port)
+        }
+    }
+}
+
+fn push_ip(request: &mut Vec<u8>, ip: IpAddr) {
+    match ip {
+        IpAddr::V4(ip) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// An HTTP proxy on the loopback interface that opens every connection
+/// through a SOCKS5 proxy. reqwest is built without SOCKS support, so it
+/// is pointed at this relay instead: `CONNECT` tunnels carry HTTPS, and
+/// plain HTTP requests are forwarded one per connection. Other local
+/// users could reach the port too, so requests have to carry a password
+/// made up for this run.
+pub struct Relay {
+    listener: TcpListener,
+    proxy: Proxy,
+    password: String,
+}
+
+impl Relay {
+    /// Take a port for the relay; nothing is served until `serve`.
+    pub fn bind(proxy: Proxy) -> io::Result<Relay> {
+        let listener = TcpListener::bind(("127.0.0.1", 0))?;
+        let random = RandomState::new();
+        let password = format!("{:016x}{:016x}", random.hash_one(1), random.hash_one(2));
+        Ok(Relay {
+            listener,
+            proxy,
+            password,
+        })
+    }
+
+    /// The proxy URL to build clients with.
+    pub fn url(&self) -> io::Result<String> {
+        Ok(format!("http://relay:{}@{}", self.password, self.listener.local_addr()?))
+    }
+
+    /// Answer connections from the client on helper threads.
+    pub fn serve(self) {
+        let expected = format!("Basic {}", BASE64.encode(format!("relay:{}", self.password).as_bytes()));
+        thread::spawn(move || {
+            for stream in self.listener.incoming().flatten() {
+                let proxy = self.proxy.clone();
+                let expected = expected.clone();
+                thread::spawn(move || {
+                    if let Err((target, e)) = relay(stream, &proxy, &expected) {
+                        messages::warn("warning.socks-failed", &[&proxy, &target, &e]);
+                    }
+                });
+            }
+        });
+    }
+}
+
+/// Read an HTTP head, up to and including its blank line. A connection
+/// closed before its first byte gives no lines.
+fn read_head<R: BufRead>(reader: &mut R) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    let mut total = 0;
+    loop {
+        let mut line = String::new();
+        let len = reader.read_line(&mut line)?;
+        if len == 0 && total == 0 {
+            return Ok(lines);
+        }
+        total += len;
+        if len == 0 || total > MAX_HEAD {
+            return Err(invalid("incomplete HTTP head".to_string()));
+        }
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty() {
+            return Ok(lines);
+        }
+        lines.push(line);
+    }
+}
+
+/// The head with every `Connection`-like header replaced by
+/// `Connection: close`, and with `skip` headers left out.
+fn closing_head(first: &str, headers: &[String], skip: &[&str]) -> Vec<u8> {
+    let mut head = format!("{}\r\n", first);
+    for header in headers {
+        let name = header.split(':').next().unwrap_or("").trim().to_ascii_lowercase();
+        if name == "connection" || name == "keep-alive" || skip.contains(&name.as_str()) {
+            continue;
+        }
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    head.into_bytes()
+}
+
+/// Serve one connection from the client. Failures carry the target they
+/// were for.
+fn relay(client: TcpStream, proxy: &Proxy, expected: &str) -> Result<(), (String, io::Error)> {
+    let unknown = |e| ("?".to_string(), e);
+    let mut reader = BufReader::new(client.try_clone().map_err(unknown)?);
+    let head = read_head(&mut reader).map_err(unknown)?;
+    let mut client = client;
+    if head.is_empty() {
+        return Ok(());
+    }
+    let authorized = head[1..].iter().any(|header| match header.find(':') {
+        Some(idx) => header[..idx].eq_ignore_ascii_case("proxy-authorization") && header[idx + 1..].trim() == expected,
+        None => false,
+    });
+    if !authorized {
+        // not our client, so there is nothing to warn it about
+        let _ = client.write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\nConnection: close\r\n\r\n");
+        return Ok(());
+    }
+    let mut words = head[0].split(' ');
+    let (method, target) = (words.next().unwrap_or(""), words.next().unwrap_or("").to_string());
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let authority = Url::parse(&format!("http://{}", target)).ok();
+        let host = authority.as_ref().and_then(Url::host_str).map(|host| host.trim_matches(|c| c == '[' || c == ']'));
+        let (host, port) = match (host, authority.as_ref().and_then(Url::port)) {
+            (Some(host), Some(port)) => (host.to_string(), port),
+            _ => {
+                let _ = client.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+                return Err((target, invalid("not a host:port".to_string())));
+            }
+        };
+        let upstream = match proxy.connect(&host, port) {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n");
+                return Err((target, e));
+            }
+        };
+        client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").map_err(|e| (target.clone(), e))?;
+        splice(reader, client, upstream);
+        return Ok(());
+    }
+
+    // a plain HTTP request in absolute form, sent on in origin form
+    let url = match Url::parse(&target) {
+        Ok(url) if url.scheme() == "http" && url.host_str().is_some() => url,
+        _ => {
+            let _ = client.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+            return Err((target, invalid("not an absolute http URL".to_string())));
+        }
+    };
+    let host = url.host_str().unwrap().trim_matches(|c| c == '[' || c == ']').to_string();
+    let port = url.port_or_known_default().unwrap_or(80);
+    let mut path = url.path().to_string();
+    if let Some(query) = url.query() {
+        path.push('?');
+        path.push_str(query);
+    }
+    let version = head[0].rsplit(' ').next().unwrap_or("HTTP/1.1");
+    let first = format!("{} {} {}", method, path, version);
+    // a failure closes the connection unanswered, so that the request
+    // fails rather than seeming to return an error page from the server
+    let mut upstream = proxy.connect(&host, port).map_err(|e| (target.clone(), e))?;
+    let request = closing_head(&first, &head[1..], &["proxy-authorization", "proxy-connection"]);
+    upstream.write_all(&request).map_err(|e| (target.clone(), e))?;
+
+    // the response is marked as the last one on this connection too, so
+    // the client never sends another request for some other host down it
+    let response_stream = upstream.try_clone().map_err(|e| (target.clone(), e))?;
+    let request_body = thread::spawn(move || {
+        let _ = io::copy(&mut reader, &mut upstream);
+        let _ = upstream.shutdown(Shutdown::Write);
+    });
+    let mut response = BufReader::new(response_stream);
+    let response_head = read_head(&mut response).map_err(|e| (target.clone(), e))?;
+    let head = closing_head(&response_head[0], &response_head[1..], &[]);
+    client.write_all(&head).map_err(|e| (target.clone(), e))?;
+    let _ = io::copy(&mut response, &mut client);
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = request_body.join();
+    Ok(())
+}
+
+/// Copy both ways until either side is done, then close both.
+fn splice(mut from_client: BufReader<TcpStream>, mut client: TcpStream, upstream: TcpStream) {
+    let mut to_upstream = match upstream.try_clone() {
+        Ok(stream) => stream,
+        Err(_) => return,
+    };
+    let mut from_upstream = upstream;
+    let outbound = thread::spawn(move || {
+        let _ = io::copy(&mut from_client, &mut to_upstream);
+        let _ = to_upstream.shutdown(Shutdown::Write);
+    });
+    let _ = io::copy(&mut from_upstream, &mut client);
+    let _ = client.shutdown(Shutdown::Both);
+    let _ = outbound.join();
+}
@@ -0,0 +1,179 @@
+use std::cell::Cell;
+use std::io;
+use std::io::Read;
+use std::os::unix::io::RawFd;
+
+use hyper::client::connect::HttpInfo;
+use reqwest::blocking::Response;
+use serde_json::Value;
+
+/// Whether the kernel's TCP statistics can be read on this platform.
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+/// What the kernel knows about a connection's health. Retransmits count
+/// over the connection's whole life, so on a reused connection they
+/// include earlier requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub retransmits: u32,
+    /// Smoothed round-trip time.
+    pub rtt_ms: f64,
+    /// Bytes per second, on kernels that report it.
+    pub delivery_rate: Option<u64>,
+}
+
+impl Stats {
+    /// The `tcp_*` fields of a result, all null without statistics.
+    pub fn add_to(stats: Option<Stats>, doc: &mut Value) {
+        doc["tcp_retransmits"] = json!(stats.map(|stats| stats.retransmits));
+        doc["tcp_rtt_ms"] = json!(stats.map(|stats| stats.rtt_ms));
+        doc["tcp_delivery_rate"] = json!(stats.and_then(|stats| stats.delivery_rate));
+    }
+}
+
+/// The connection a response arrived on, read again as its body streams
+/// in, because the client can close it as soon as the body ends.
+pub struct Socket {
+    fd: RawFd,
+    last: Cell<Option<Stats>>,
+}
+
+impl Socket {
+    /// Find the connection behind `resp` among this process's sockets by
+    /// its local and remote addresses.
+    pub fn of(resp: &Response) -> Option<Socket> {
+        let info = resp.extensions().get::<HttpInfo>()?;
+        let fd = imp::find(info.local_addr(), info.remote_addr())?;
+        let socket = Socket {
+            fd,
+            last: Cell::new(None),
+        };
+        socket.sample();
+        Some(socket)
+    }
+
+    fn sample(&self) {
+        if let Some(stats) = imp::stats(self.fd) {
+            self.last.set(Some(stats));
+        }
+    }
+
+    /// The latest statistics read while the connection was open.
+    pub fn last(&self) -> Option<Stats> {
+        self.last.get()
+    }
+}
+
+/// A body reader that samples its connection after every read.
+pub struct Watch<'a, R> {
+    inner: R,
+    socket: Option<&'a Socket>,
+}
+
+impl<'a, R: Read> Watch<'a, R> {
+    pub fn new(inner: R, socket: Option<&'a Socket>) -> Watch<'a, R> {
+        Watch { inner, socket }
+    }
+}
+
+impl<'a, R: Read> Read for Watch<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if let (true, Some(socket)) = (len > 0, self.socket) {
+            socket.sample();
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::fs;
+    use std::mem;
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::RawFd;
+
+    use libc;
+
+    use super::Stats;
+
+    type AddrFn = unsafe extern "C" fn(libc::c_int, *mut libc::sockaddr, *mut libc::socklen_t) -> libc::c_int;
+
+    fn address(fd: RawFd, get: AddrFn) -> Option<SocketAddr> {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        let ret = unsafe { get(fd, &mut storage as *mut _ as *mut libc::sockaddr, &mut len) };
+        if ret != 0 {
+            return None;
+        }
+        match libc::c_int::from(storage.ss_family) {
+            libc::AF_INET => {
+                let addr = unsafe { &*(&storage as *const _ as *const libc::sockaddr_in) };
+                Some(SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(&storage as *const _ as *const libc::sockaddr_in6) };
+                Some(SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            _ => None,
+        }
+    }
+
+    /// The descriptor of the connection from `local` to `peer`.
+    pub fn find(local: SocketAddr, peer: SocketAddr) -> Option<RawFd> {
+        fs::read_dir("/proc/self/fd")
+            .ok()?
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .find(|&fd| {
+                address(fd, libc::getsockname) == Some(local) && address(fd, libc::getpeername) == Some(peer)
+            })
+    }
+
+    pub fn stats(fd: RawFd) -> Option<Stats> {
+        let mut info: libc::tcp_info = unsafe { mem::zeroed() };
+        let mut len = mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return None;
+        }
+        // older kernels fill in less of the structure
+        let rate_end = mem::offset_of!(libc::tcp_info, tcpi_delivery_rate) + mem::size_of::<u64>();
+        Some(Stats {
+            retransmits: info.tcpi_total_retrans,
+            rtt_ms: f64::from(info.tcpi_rtt) / 1000.0,
+            delivery_rate: Some(info.tcpi_delivery_rate).filter(|_| len as usize >= rate_end),
+        })
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::net::SocketAddr;
+    use std::os::unix::io::RawFd;
+
+    use super::Stats;
+
+    pub fn find(_local: SocketAddr, _peer: SocketAddr) -> Option<RawFd> {
+        None
+    }
+
+    pub fn stats(_fd: RawFd) -> Option<Stats> {
+        None
+    }
+}
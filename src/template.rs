@@ -0,0 +1,237 @@
+use std::path::{Component, Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local, Utc};
+use reqwest::Url;
+
+use filename;
+
+/// The instant and timezone used to expand strftime tokens in output paths.
+#[derive(Clone, Copy, Debug)]
+pub struct Timestamp {
+    pub instant: DateTime<Utc>,
+    pub local: bool,
+}
+
+impl Timestamp {
+    /// Expand `%Y`, `%m`, `%d`, `%H%M%S` and the other strftime tokens in
+    /// `path`. Paths that are not valid UTF-8 are returned unchanged.
+    pub fn expand(&self, path: &Path) -> Result<PathBuf, String> {
+        let pattern = match path.to_str() {
+            Some(pattern) => pattern,
+            None => return Ok(path.to_path_buf()),
+        };
+        let items: Vec<Item> = StrftimeItems::new(pattern).collect();
+        if items.contains(&Item::Error) {
+            return Err(format!("{}: invalid timestamp token", pattern));
+        }
+        let expanded = if self.local {
+            self.instant
+                .with_timezone(&Local)
+                .format_with_items(items.into_iter())
+                .to_string()
+        } else {
+            self.instant.format_with_items(items.into_iter()).to_string()
+        };
+        Ok(PathBuf::from(expanded))
+    }
+
+    /// The day `{date}` stands for in an `--output-template`.
+    pub fn date(&self) -> String {
+        if self.local {
+            self.instant.with_timezone(&Local).format("%F").to_string()
+        } else {
+            self.instant.format("%F").to_string()
+        }
+    }
+}
+
+/// An `--output-template` such as `{host}/{basename}`, naming outputs
+/// after their URL, the day of the run and, with `{sha256}`, their body.
+#[derive(Clone, Debug)]
+pub struct OutputTemplate {
+    parts: Vec<Part>,
+    date: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Text(String),
+    Host,
+    /// The URL path up to the file name, without its slashes at either end.
+    Path,
+    Basename,
+    /// The file name's extension, without the dot.
+    Ext,
+    Date,
+    /// The first so many hex digits of the body's SHA-256.
+    Sha256(usize),
+}
+
+/// Tells apart the names outputs wait under for their SHA-256.
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+impl OutputTemplate {
+    /// Parse `{host}`, `{path}`, `{basename}`, `{ext}`, `{date}`,
+    /// `{sha256}` and `{sha256:N}` among literal text; `{{` and `}}` stand
+    /// for braces. `{date}` stands for `date`.
+    pub fn parse(template: &str, date: String) -> Result<OutputTemplate, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut rest = template;
+        while let Some(c) = rest.chars().next() {
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                text.push(c);
+                rest = &rest[2..];
+                continue;
+            }
+            if c == '}' {
+                return Err(format!("{}: unmatched }}", template));
+            }
+            if c != '{' {
+                text.push(c);
+                rest = &rest[c.len_utf8()..];
+                continue;
+            }
+            let end = rest.find('}').ok_or_else(|| format!("{}: unclosed {{", template))?;
+            let part = match &rest[1..end] {
+                "host" => Part::Host,
+                "path" => Part::Path,
+                "basename" => Part::Basename,
+                "ext" => Part::Ext,
+                "date" => Part::Date,
+                "sha256" => Part::Sha256(64),
+                name => match name.strip_prefix("sha256:").map(str::parse::<usize>) {
+                    Some(Ok(len)) if len > 0 && len <= 64 => Part::Sha256(len),
+                    Some(_) => return Err(format!("{{{}}}: expected 1 to 64 digits", name)),
+                    None => return Err(format!("{{{}}}: unknown variable", name)),
+                },
+            };
+            if !text.is_empty() {
+                parts.push(Part::Text(text.split_off(0)));
+            }
+            parts.push(part);
+            rest = &rest[end + 1..];
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(OutputTemplate { parts, date })
+    }
+
+    pub fn needs_sha256(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Sha256(_)))
+    }
+
+    /// Expand every variable but `{sha256}` for `url`, under `dir` when
+    /// given, percent-decoding the path unless `decode` is false.
+    /// Expansions that would climb out of the directory, such as a `..`
+    /// file name, are refused.
+    pub fn expand(&self, url: &str, dir: Option<&Path>, decode: bool) -> Result<Expanded, String> {
+        let url = Url::parse(url).map_err(|e| format!("{}: {}", url, e))?;
+        let decode = |segment: &str| if decode { filename::decode(segment) } else { segment.to_string() };
+        let mut segments: Vec<&str> = url.path().split('/').filter(|segment| !segment.is_empty()).collect();
+        if url.path().ends_with('/') {
+            segments.push("");
+        }
+        let basename = segments.pop().map(filename::strip_matrix).map(decode).unwrap_or_default();
+        let basename = match basename.as_str() {
+            "" => basename,
+            name => filename::sanitize(name).ok_or_else(|| format!("{}: cannot derive a filename", url))?,
+        };
+        let ext = match basename.rfind('.') {
+            Some(dot) if dot > 0 => basename[dot + 1..].to_string(),
+            _ => String::new(),
+        };
+        let path = segments
+            .iter()
+            .map(|segment| filename::sanitize(&decode(segment)))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| format!("{}: the path holds a . or .. segment", url))?
+            .join("/");
+        let mut parts = Vec::with_capacity(self.parts.len());
+        // an empty variable takes the slash after it along, so that
+        // `{path}/{basename}` does not become an absolute path
+        let mut empty = false;
+        for part in &self.parts {
+            let text = match *part {
+                Part::Text(ref text) if empty => text.strip_prefix('/').unwrap_or(text).to_string(),
+                Part::Text(ref text) => text.clone(),
+                Part::Host => match url.host_str() {
+                    Some(host) => host.to_string(),
+                    None => return Err(format!("{}: no host to name the output after", url)),
+                },
+                Part::Path => path.clone(),
+                Part::Basename if basename.is_empty() => return Err(format!("{}: cannot derive a filename", url)),
+                Part::Basename => basename.clone(),
+                Part::Ext => ext.clone(),
+                Part::Date => self.date.clone(),
+                Part::Sha256(len) => {
+                    parts.push(Part::Sha256(len));
+                    empty = false;
+                    continue;
+                }
+            };
+            let before = match parts.last() {
+                Some(Part::Text(before)) => before.as_str(),
+                Some(_) => "-",
+                None => "",
+            };
+            empty = !matches!(*part, Part::Text(_)) && text.is_empty() && (before.is_empty() || before.ends_with('/'));
+            match parts.last_mut() {
+                Some(Part::Text(ref mut before)) => before.push_str(&text),
+                _ => parts.push(Part::Text(text)),
+            }
+        }
+        let mut expanded = Expanded {
+            dir: PathBuf::new(),
+            parts,
+            pending: format!(".download-{}-{}", process::id(), PENDING.fetch_add(1, Ordering::Relaxed)),
+        };
+        let probe = expanded.path(Some(&"0".repeat(64)));
+        let climbs = probe.components().any(|part| matches!(part, Component::ParentDir | Component::CurDir));
+        if climbs || probe.file_name().is_none() {
+            return Err(format!("{}: the output template expands to {}", url, probe.display()));
+        }
+        expanded.dir = dir.map(Path::to_path_buf).unwrap_or_default();
+        Ok(expanded)
+    }
+}
+
+/// An `--output-template` expanded for one URL.
+#[derive(Clone, Debug)]
+pub struct Expanded {
+    /// `-P`, which the expansion goes under.
+    dir: PathBuf,
+    /// Text, and the digits of the SHA-256 still to fill in.
+    parts: Vec<Part>,
+    /// The file name the output is written under before its SHA-256 is known.
+    pending: String,
+}
+
+impl Expanded {
+    pub fn needs_sha256(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, Part::Sha256(_)))
+    }
+
+    /// The output's path, named after the hex digest `sha256`. Without it,
+    /// a name that depends on the digest is a hidden one in the deepest
+    /// directory that does not.
+    pub fn path(&self, sha256: Option<&str>) -> PathBuf {
+        let mut path = String::new();
+        for part in &self.parts {
+            match (part, sha256) {
+                (Part::Text(text), _) => path.push_str(text),
+                (&Part::Sha256(len), Some(sha256)) => path.push_str(&sha256[..len.min(sha256.len())]),
+                (Part::Sha256(_), None) => {
+                    let dir = path.rfind('/').map_or("", |end| &path[..end + 1]);
+                    return self.dir.join(format!("{}{}", dir, self.pending));
+                }
+                _ => {}
+            }
+        }
+        self.dir.join(path)
+    }
+}
@@ -0,0 +1,222 @@
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use hyper::client::connect::HttpInfo;
+use reqwest::blocking::Response;
+use reqwest::Url;
+use serde_json::Value;
+
+pub fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
+
+/// How one request went, as recorded by `Connections::exchange` and the
+/// body copy. DNS, connect and TLS times are not exposed by the HTTP
+/// client; on a new connection they are part of `ttfb_ms`, and they stay
+/// null in the output, as does the TLS version.
+#[derive(Clone, Debug, Default)]
+pub struct Phases {
+    pub connection_reused: Option<bool>,
+    pub dns_ms: Option<u64>,
+    pub connect_ms: Option<u64>,
+    pub tls_ms: Option<u64>,
+    /// From sending the request to receiving the response headers.
+    pub ttfb_ms: Option<u64>,
+    /// Reading the body.
+    pub transfer_ms: Option<u64>,
+    /// Redirects followed on the way to the response.
+    pub redirects: Option<u64>,
+    /// The HTTP version the response came in, e.g. `HTTP/2.0`.
+    pub http_version: Option<String>,
+    pub tls_version: Option<String>,
+    /// The entry `--har` recorded the exchange under.
+    pub har_entry: Option<usize>,
+}
+
+impl Phases {
+    /// Take the exchange timings recorded on `resp`, keeping `transfer_ms`.
+    pub fn record_response(&mut self, resp: &Response) {
+        if let Some(exchange) = resp.extensions().get::<Phases>() {
+            *self = Phases {
+                transfer_ms: self.transfer_ms,
+                ..exchange.clone()
+            };
+        }
+    }
+
+    pub fn add_to(&self, doc: &mut Value) {
+        doc["connection_reused"] = json!(self.connection_reused);
+        doc["dns_ms"] = json!(self.dns_ms);
+        doc["connect_ms"] = json!(self.connect_ms);
+        doc["tls_ms"] = json!(self.tls_ms);
+        doc["ttfb_ms"] = json!(self.ttfb_ms);
+        doc["transfer_ms"] = json!(self.transfer_ms);
+        doc["redirects"] = json!(self.redirects);
+        doc["http_version"] = json!(self.http_version);
+        doc["tls_version"] = json!(self.tls_version);
+    }
+}
+
+/// The connections responses have arrived on, told apart by their local
+/// address: a response on an address seen before came over a pooled
+/// connection.
+#[derive(Default)]
+pub struct Connections {
+    seen: Mutex<HashSet<SocketAddr>>,
+}
+
+thread_local! {
+    /// The server address of the latest response on this thread; jobs run
+    /// one to a thread, so concurrent jobs do not see each other's.
+    static LAST_PEER: Cell<Option<SocketAddr>> = const { Cell::new(None) };
+}
+
+/// The server address of the latest response the current thread received.
+pub fn last_peer() -> Option<SocketAddr> {
+    LAST_PEER.with(Cell::get)
+}
+
+impl Connections {
+    pub fn new() -> Connections {
+        Connections::default()
+    }
+
+    /// Attach the timings of the exchange that produced `resp`, sent at
+    /// `sent`, to the response.
+    pub fn exchange(&self, resp: &mut Response, sent: Instant) {
+        let ttfb = millis(sent.elapsed());
+        let info = resp.extensions().get::<HttpInfo>();
+        let reused = info.map(|info| !self.seen.lock().unwrap().insert(info.local_addr()));
+        if let Some(info) = info {
+            LAST_PEER.with(|peer| peer.set(Some(info.remote_addr())));
+        }
+        let http_version = format!("{:?}", resp.version());
+        resp.extensions_mut().insert(Phases {
+            connection_reused: reused,
+            ttfb_ms: Some(ttfb),
+            http_version: Some(http_version),
+            ..Phases::default()
+        });
+    }
+}
+
+/// The redirect chains the client's policy is following, as the URL each
+/// started from, the URL it has got to and the hops so far. The client
+/// follows them on a thread of its own, so they are matched up with the
+/// request afterwards by their ends.
+static FOLLOWED: Mutex<Vec<(Url, Url, u64)>> = Mutex::new(Vec::new());
+
+/// The redirect policy is following a redirect to `target`, after the
+/// URLs of `previous`.
+pub fn follow(previous: &[Url], target: &Url) {
+    let first = match previous.first() {
+        Some(first) => first,
+        None => return,
+    };
+    let mut chains = FOLLOWED.lock().unwrap();
+    chains.retain(|chain| chain.0 != *first);
+    chains.push((first.clone(), target.clone(), previous.len() as u64));
+}
+
+/// How many redirects the client followed from `first` to `last`.
+pub fn followed(first: &Url, last: &Url) -> u64 {
+    let mut chains = FOLLOWED.lock().unwrap();
+    let hops = chains
+        .iter()
+        .find(|chain| chain.0 == *first && chain.1 == *last)
+        .map_or(0, |chain| chain.2);
+    chains.retain(|chain| chain.0 != *first);
+    hops
+}
+
+fn percentile(sorted: &[u64], p: f64) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * (sorted.len() - 1) as f64).round() as usize;
+    Some(sorted[rank])
+}
+
+/// Connection reuse rate and time-to-first-byte percentiles over a batch.
+pub fn summary<'a, I>(phases: I) -> Value
+where
+    I: IntoIterator<Item = &'a Phases>,
+{
+    let mut requests = 0;
+    let mut known = 0;
+    let mut reused = 0;
+    let mut ttfb = Vec::new();
+    for phase in phases {
+        requests += 1;
+        if let Some(was_reused) = phase.connection_reused {
+            known += 1;
+            if was_reused {
+                reused += 1;
+            }
+        }
+        ttfb.extend(phase.ttfb_ms);
+    }
+    ttfb.sort_unstable();
+    json!({
+        "requests": requests,
+        "connections_reused": reused,
+        "connection_reuse_rate": if known > 0 { Some(reused as f64 / known as f64) } else { None },
+        "ttfb_ms_p50": percentile(&ttfb, 0.5),
+        "ttfb_ms_p95": percentile(&ttfb, 0.95),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+    use std::time::Instant;
+
+    use reqwest;
+
+    use super::{last_peer, Connections};
+
+    /// Answer one request on a local port, giving its address.
+    fn serve() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 4096];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n");
+        });
+        addr
+    }
+
+    #[test]
+    fn concurrent_jobs_keep_their_own_peer() {
+        let connections = Arc::new(Connections::new());
+        let barrier = Arc::new(Barrier::new(2));
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let (connections, barrier) = (connections.clone(), barrier.clone());
+                thread::spawn(move || {
+                    let addr = serve();
+                    let client = reqwest::blocking::Client::new();
+                    let sent = Instant::now();
+                    let mut resp = client.get(format!("http://{}/", addr)).send().unwrap();
+                    connections.exchange(&mut resp, sent);
+                    // both have answered before either looks
+                    barrier.wait();
+                    (addr, last_peer())
+                })
+            })
+            .collect();
+        for worker in workers {
+            let (addr, peer) = worker.join().unwrap();
+            assert_eq!(peer, Some(addr));
+        }
+        assert_eq!(last_peer(), None);
+    }
+}
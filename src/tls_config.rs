@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use reqwest::Url;
+use rustls;
+use rustls::client::{
+    verify_server_cert_signed_by_trust_anchor, ServerCertVerified, ServerCertVerifier, WebPkiVerifier,
+};
+use rustls::server::ParsedCertificate;
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use rustls_pemfile;
+use webpki_roots;
+
+use pinning::Pins;
+
+/// Validates the certificate chain against the trust roots, expiry
+/// included, but does not check which hostname it was issued for.
+struct ChainOnlyVerifier {
+    roots: RootCertStore,
+}
+
+impl ServerCertVerifier for ChainOnlyVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        verify_server_cert_signed_by_trust_anchor(&cert, &self.roots, intermediates, now)?;
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The bundled Mozilla roots, the same set reqwest trusts by default.
+pub fn webpki_root_store() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    roots
+}
+
+/// Accepts any certificate, for `--insecure` with `--pin-sha256`, where
+/// only the pin is checked.
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// TLS settings for every host without a profile saying otherwise:
+/// `--cacert` and `--capath` roots, and the `--cert` client certificate.
+/// `--pin-sha256` applies to every host, profiles included.
+#[derive(Default)]
+pub struct Defaults {
+    /// Trusted besides the bundled roots.
+    pub roots: Vec<Certificate>,
+    pub client_cert: Option<ClientCert>,
+    pub pins: Pins,
+}
+
+/// A client certificate chain and its key.
+pub struct ClientCert {
+    pub chain: Vec<Certificate>,
+    key: PrivateKey,
+    /// The PEM files as given, which is how reqwest takes an identity.
+    pub pem: Vec<u8>,
+}
+
+impl ClientCert {
+    /// Read the chain from `cert` and the key from `key`, or else from
+    /// `cert` itself.
+    pub fn load(cert: &Path, key: Option<&Path>) -> Result<ClientCert, String> {
+        let chain: Vec<Certificate> = read_certs(cert)?.into_iter().map(Certificate).collect();
+        let key_path = key.unwrap_or(cert);
+        let key = read_key(key_path)?;
+        let mut pem = fs::read(cert).map_err(|e| format!("{}: {}", cert.display(), e))?;
+        if key_path != cert {
+            pem.push(b'\n');
+            pem.extend(fs::read(key_path).map_err(|e| format!("{}: {}", key_path.display(), e))?);
+        }
+        // a key of a type rustls cannot sign with is refused here
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(RootCertStore::empty())
+            .with_client_auth_cert(chain.clone(), key.clone())
+            .map_err(|e| format!("{}: {}", cert.display(), e))?;
+        Ok(ClientCert { chain, key, pem })
+    }
+}
+
+impl Defaults {
+    /// Read the certificates `--cacert` and `--capath` add to the trusted
+    /// roots. Every file in the `--capath` directory is read, as OpenSSL's
+    /// hashed links or plain `.pem` files; files without certificates are
+    /// skipped.
+    pub fn load_roots(&mut self, cacert: Option<&Path>, capath: Option<&Path>) -> Result<(), String> {
+        let mut certs = Vec::new();
+        if let Some(path) = cacert {
+            certs.extend(read_certs(path)?);
+        }
+        if let Some(dir) = capath {
+            let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+                .map_err(|e| format!("{}: {}", dir.display(), e))?
+                .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+                .filter(|path| path.is_file())
+                .collect();
+            paths.sort();
+            let before = certs.len();
+            for path in &paths {
+                certs.extend(read_certs(path).unwrap_or_default());
+            }
+            if certs.len() == before {
+                return Err(format!("{}: no PEM certificates", dir.display()));
+            }
+        }
+        let mut roots = RootCertStore::empty();
+        let (_, unusable) = roots.add_parsable_certificates(&certs);
+        if unusable > 0 {
+            return Err(format!("{} of the given CA certificates cannot be used as trust roots", unusable));
+        }
+        self.roots = certs.into_iter().map(Certificate).collect();
+        Ok(())
+    }
+
+    /// The bundled roots and the extra ones.
+    fn root_store(&self) -> RootCertStore {
+        let mut roots = webpki_root_store();
+        roots.add_parsable_certificates(&self.roots.iter().map(|cert| cert.0.clone()).collect::<Vec<_>>());
+        roots
+    }
+}
+
+/// A client config that trusts the bundled web roots and the extra ones,
+/// but skips hostname verification.
+pub fn insecure_hostname_config(defaults: &Defaults) -> ClientConfig {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty());
+    let mut config = match defaults.client_cert {
+        Some(ref cert) => builder
+            .with_client_auth_cert(cert.chain.clone(), cert.key.clone())
+            .expect("checked when loaded"),
+        None => builder.with_no_client_auth(),
+    };
+    config.dangerous().set_certificate_verifier(defaults.pins.wrap(Arc::new(ChainOnlyVerifier {
+        roots: defaults.root_store(),
+    })));
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    config
+}
+
+/// The config reqwest would build, checking the `--pin-sha256` pins as
+/// well, or only them when `insecure`. It offers HTTP/2 like reqwest's.
+pub fn pinned_config(defaults: &Defaults, insecure: bool) -> ClientConfig {
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(RootCertStore::empty());
+    let mut config = match defaults.client_cert {
+        Some(ref cert) => builder
+            .with_client_auth_cert(cert.chain.clone(), cert.key.clone())
+            .expect("checked when loaded"),
+        None => builder.with_no_client_auth(),
+    };
+    let inner: Arc<dyn ServerCertVerifier> = if insecure {
+        Arc::new(AnyCertificate)
+    } else {
+        Arc::new(WebPkiVerifier::new(defaults.root_store(), None))
+    };
+    config.dangerous().set_certificate_verifier(defaults.pins.wrap(inner));
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    config
+}
+
+/// The files behind one TLS profile.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Files {
+    cacert: Option<PathBuf>,
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+/// A TLS configuration used for some hosts instead of the global one.
+pub struct Profile {
+    /// The files it was built from, for verbose output.
+    pub label: String,
+    pub config: ClientConfig,
+}
+
+/// TLS profiles given per host with `--cacert-for`, `--cert-for` and
+/// `--key-for`. Hosts with the same files share a profile, and so a client.
+#[derive(Default)]
+pub struct Profiles {
+    profiles: Vec<Profile>,
+    hosts: BTreeMap<String, usize>,
+}
+
+impl Profiles {
+    /// Build the profiles from `(option, "host=path")` arguments, where
+    /// option is `cacert-for`, `cert-for` or `key-for`. What a profile
+    /// does not set comes from `defaults`.
+    pub fn load(args: &[(&str, &str)], defaults: &Defaults) -> Result<Profiles, String> {
+        let mut by_host: BTreeMap<String, Files> = BTreeMap::new();
+        for &(option, arg) in args {
+            let (host, path) = match arg.find('=') {
+                Some(idx) if idx > 0 && idx + 1 < arg.len() => (&arg[..idx], PathBuf::from(&arg[idx + 1..])),
+                _ => return Err(format!("--{} {}: expected HOST=FILE", option, arg)),
+            };
+            let files = by_host.entry(host.to_ascii_lowercase()).or_default();
+            let slot = match option {
+                "cacert-for" => &mut files.cacert,
+                "cert-for" => &mut files.cert,
+                _ => &mut files.key,
+            };
+            if slot.replace(path).is_some() {
+                return Err(format!("--{} given twice for {}", option, host));
+            }
+        }
+
+        let mut loaded: Vec<Files> = Vec::new();
+        let mut profiles = Profiles::default();
+        for (host, files) in by_host {
+            if files.key.is_some() && files.cert.is_none() {
+                return Err(format!("--key-for {}: needs --cert-for {}", host, host));
+            }
+            let idx = match loaded.iter().position(|known| *known == files) {
+                Some(idx) => idx,
+                None => {
+                    let config = files.config(defaults).map_err(|e| format!("TLS profile for {}: {}", host, e))?;
+                    profiles.profiles.push(Profile {
+                        label: files.label(),
+                        config,
+                    });
+                    loaded.push(files);
+                    loaded.len() - 1
+                }
+            };
+            profiles.hosts.insert(host, idx);
+        }
+        Ok(profiles)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.profiles.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Profile> {
+        self.profiles.iter()
+    }
+
+    pub fn get(&self, idx: usize) -> &Profile {
+        &self.profiles[idx]
+    }
+
+    /// The profile for `host`, if it has one.
+    pub fn for_host(&self, host: &str) -> Option<usize> {
+        self.hosts.get(&host.to_ascii_lowercase()).cloned()
+    }
+
+    pub fn for_url(&self, url: &Url) -> Option<usize> {
+        url.host_str().and_then(|host| self.for_host(host))
+    }
+}
+
+impl Files {
+    fn label(&self) -> String {
+        let parts = [("cacert", &self.cacert), ("cert", &self.cert), ("key", &self.key)];
+        parts
+            .iter()
+            .filter_map(|(name, path)| path.as_ref().map(|path| format!("{}={}", name, path.display())))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Trust only the CA file when one is given, and present the client
+    /// certificate when one is given; otherwise do as `defaults` says.
+    fn config(&self, defaults: &Defaults) -> Result<ClientConfig, String> {
+        let roots = match self.cacert {
+            Some(ref path) => {
+                let mut roots = RootCertStore::empty();
+                let (added, _) = roots.add_parsable_certificates(&read_certs(path)?);
+                if added == 0 {
+                    return Err(format!("{}: no certificates", path.display()));
+                }
+                roots
+            }
+            None => defaults.root_store(),
+        };
+        let verifier = defaults.pins.wrap(Arc::new(WebPkiVerifier::new(roots.clone(), None)));
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let mut config = match self.cert {
+            Some(ref cert) => {
+                let chain = read_certs(cert)?.into_iter().map(Certificate).collect();
+                let key = read_key(self.key.as_deref().unwrap_or(cert))?;
+                builder
+                    .with_client_auth_cert(chain, key)
+                    .map_err(|e| format!("{}: {}", cert.display(), e))?
+            }
+            None => match defaults.client_cert {
+                Some(ref cert) => builder
+                    .with_client_auth_cert(cert.chain.clone(), cert.key.clone())
+                    .expect("checked when loaded"),
+                None => builder.with_no_client_auth(),
+            },
+        };
+        if !defaults.pins.is_empty() {
+            config.dangerous().set_certificate_verifier(verifier);
+        }
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+fn open_pem(path: &Path) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Vec<u8>>, String> {
+    let certs = rustls_pemfile::certs(&mut open_pem(path)?).map_err(|e| format!("{}: {}", path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no PEM certificates", path.display()));
+    }
+    Ok(certs)
+}
+
+/// The first private key in a PEM file, in any of the encodings rustls
+/// accepts.
+fn read_key(path: &Path) -> Result<PrivateKey, String> {
+    let mut reader = open_pem(path)?;
+    loop {
+        match rustls_pemfile::read_one(&mut reader) {
+            Ok(Some(rustls_pemfile::Item::RSAKey(der)))
+            | Ok(Some(rustls_pemfile::Item::PKCS8Key(der)))
+            | Ok(Some(rustls_pemfile::Item::ECKey(der))) => return Ok(PrivateKey(der)),
+            Ok(Some(_)) => continue,
+            Ok(None) => return Err(format!("{}: no PEM private key", path.display())),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::sync::Arc;
+
+    use rustls;
+    use rustls::{Certificate, CertificateError, ClientConfig, ClientConnection, PrivateKey};
+    use rustls::{ServerConfig, ServerConnection, ServerName};
+    use rustls_pemfile;
+
+    use super::{insecure_hostname_config, pinned_config, Defaults};
+
+    const CA: &[u8] = include_bytes!("../tests/data/ca.pem");
+    /// Issued by `CA` for `other.test`.
+    const OTHER: (&[u8], &[u8]) = (
+        include_bytes!("../tests/data/other.pem"),
+        include_bytes!("../tests/data/other.key"),
+    );
+    /// Self-signed for `localhost`.
+    const UNTRUSTED: (&[u8], &[u8]) = (
+        include_bytes!("../tests/data/untrusted.pem"),
+        include_bytes!("../tests/data/untrusted.key"),
+    );
+
+    fn certs(pem: &[u8]) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut &pem[..]).unwrap().into_iter().map(Certificate).collect()
+    }
+
+    fn trusting_ca() -> Defaults {
+        Defaults {
+            roots: certs(CA),
+            ..Defaults::default()
+        }
+    }
+
+    /// Run a handshake in memory between `client`, connecting to `name`,
+    /// and a server presenting `server`.
+    fn handshake(client: ClientConfig, name: &str, server: (&[u8], &[u8])) -> Result<(), rustls::Error> {
+        let key = PrivateKey(rustls_pemfile::pkcs8_private_keys(&mut &server.1[..]).unwrap().remove(0));
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(certs(server.0), key)
+            .unwrap();
+        let mut server = ServerConnection::new(Arc::new(config)).unwrap();
+        let mut client = ClientConnection::new(Arc::new(client), ServerName::try_from(name).unwrap()).unwrap();
+        for _ in 0..10 {
+            let mut wire = Vec::new();
+            while client.wants_write() {
+                client.write_tls(&mut wire).unwrap();
+            }
+            server.read_tls(&mut &wire[..]).unwrap();
+            server.process_new_packets()?;
+            wire.clear();
+            while server.wants_write() {
+                server.write_tls(&mut wire).unwrap();
+            }
+            client.read_tls(&mut &wire[..]).unwrap();
+            client.process_new_packets()?;
+            if !client.is_handshaking() && !server.is_handshaking() {
+                return Ok(());
+            }
+        }
+        panic!("handshake did not finish");
+    }
+
+    fn certificate_error(result: Result<(), rustls::Error>) -> CertificateError {
+        match result {
+            Err(rustls::Error::InvalidCertificate(e)) => e,
+            other => panic!("expected a certificate error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn default_verification_trusts_the_extra_roots() {
+        handshake(pinned_config(&trusting_ca(), false), "other.test", OTHER).unwrap();
+    }
+
+    #[test]
+    fn default_verification_refuses_another_hostname() {
+        let result = handshake(pinned_config(&trusting_ca(), false), "localhost", OTHER);
+        assert_eq!(certificate_error(result), CertificateError::NotValidForName);
+    }
+
+    #[test]
+    fn insecure_hostname_accepts_another_hostname() {
+        handshake(insecure_hostname_config(&trusting_ca()), "localhost", OTHER).unwrap();
+    }
+
+    #[test]
+    fn insecure_hostname_still_refuses_an_untrusted_issuer() {
+        let result = handshake(insecure_hostname_config(&trusting_ca()), "localhost", UNTRUSTED);
+        assert_eq!(certificate_error(result), CertificateError::UnknownIssuer);
+    }
+}
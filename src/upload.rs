@@ -0,0 +1,51 @@
+use std::io;
+use std::io::Read;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use reqwest::blocking::Body;
+
+use control;
+use progress::Progress;
+
+/// A reader that reports every byte read from it to a progress bar and
+/// to a shared counter, finishing the bar at end of input.
+struct Counted<R> {
+    inner: R,
+    progress: Progress,
+    sent: Arc<AtomicU64>,
+    done: bool,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        control::check()?;
+        let len = self.inner.read(buf)?;
+        if len == 0 && !self.done {
+            self.done = true;
+            self.progress.finish();
+        }
+        self.progress.add(len as u64);
+        self.sent.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+/// A request body streamed from stdin as it is read, never held in memory
+/// as a whole. With a known `len` it is sent with that `Content-Length`
+/// and cut off after `len` bytes; otherwise it is sent chunked. The
+/// counter returned holds the bytes sent so far.
+pub fn stdin_body(len: Option<u64>, progress: Progress) -> (Body, Arc<AtomicU64>) {
+    let sent = Arc::new(AtomicU64::new(0));
+    let reader = Counted {
+        inner: io::stdin().take(len.unwrap_or(u64::MAX)),
+        progress,
+        sent: sent.clone(),
+        done: false,
+    };
+    let body = match len {
+        Some(len) => Body::sized(reader, len),
+        None => Body::new(reader),
+    };
+    (body, sent)
+}
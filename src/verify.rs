@@ -0,0 +1,118 @@
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Seek, SeekFrom};
+
+/// An inclusive byte range `START-END` of a file, as in an HTTP `Range`
+/// header; `START-` runs to the end of the file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+impl ByteRange {
+    pub fn parse(value: &str) -> Result<ByteRange, String> {
+        let invalid = || format!("invalid range {} (expected START-END or START-)", value);
+        let dash = value.find('-').ok_or_else(invalid)?;
+        let start = value[..dash].trim().parse::<u64>().map_err(|_| invalid())?;
+        let end = match value[dash + 1..].trim() {
+            "" => None,
+            end => Some(end.parse::<u64>().map_err(|_| invalid())?),
+        };
+        if end.is_some_and(|end| end < start) {
+            return Err(format!("invalid range {}: END is before START", value));
+        }
+        Ok(ByteRange { start, end })
+    }
+
+    /// The `(offset, length)` this range covers in a file of `len` bytes.
+    /// `START-` may start at the very end of the file, covering nothing.
+    pub fn resolve(&self, len: u64) -> Result<(u64, u64), String> {
+        let end = match self.end {
+            Some(end) if end >= len => {
+                return Err(format!("range {} ends beyond the end of the file ({} bytes)", self, len))
+            }
+            Some(end) => end + 1,
+            None if self.start > len => {
+                return Err(format!("range {} starts beyond the end of the file ({} bytes)", self, len))
+            }
+            None => len,
+        };
+        Ok((self.start, end - self.start))
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.end {
+            Some(end) => write!(f, "{}-{}", self.start, end),
+            None => write!(f, "{}-", self.start),
+        }
+    }
+}
+
+/// A reader over the `len` bytes of `file` starting at `offset`.
+pub fn read_span(mut file: File, offset: u64, len: u64) -> io::Result<io::Take<File>> {
+    file.seek(SeekFrom::Start(offset))?;
+    Ok(file.take(len))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::fs::File;
+    use std::io::Read;
+    use std::process;
+
+    use super::{read_span, ByteRange};
+
+    fn resolve(range: &str, len: u64) -> Result<(u64, u64), String> {
+        ByteRange::parse(range).unwrap().resolve(len)
+    }
+
+    #[test]
+    fn ranges_parse_with_and_without_an_end() {
+        assert_eq!(ByteRange::parse("10-19"), Ok(ByteRange { start: 10, end: Some(19) }));
+        assert_eq!(ByteRange::parse("10-"), Ok(ByteRange { start: 10, end: None }));
+        assert_eq!(ByteRange::parse("5-5").unwrap().to_string(), "5-5");
+        assert!(ByteRange::parse("19-10").unwrap_err().contains("END is before START"));
+        for invalid in &["", "10", "-10", "a-b", "10-b"] {
+            assert!(ByteRange::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn ranges_resolve_inside_the_file() {
+        assert_eq!(resolve("0-9", 10), Ok((0, 10)));
+        assert_eq!(resolve("4-4", 10), Ok((4, 1)));
+        assert_eq!(resolve("4-", 10), Ok((4, 6)));
+    }
+
+    #[test]
+    fn open_range_at_the_end_of_the_file_is_empty() {
+        assert_eq!(resolve("0-", 0), Ok((0, 0)));
+        assert_eq!(resolve("10-", 10), Ok((10, 0)));
+    }
+
+    #[test]
+    fn ranges_beyond_the_end_are_refused() {
+        assert_eq!(resolve("0-10", 10), Err("range 0-10 ends beyond the end of the file (10 bytes)".to_string()));
+        assert_eq!(resolve("11-", 10), Err("range 11- starts beyond the end of the file (10 bytes)".to_string()));
+        assert!(resolve("0-0", 0).is_err());
+    }
+
+    #[test]
+    fn span_reads_only_the_range() {
+        let path = env::temp_dir().join(format!("download-{}-span", process::id()));
+        fs::write(&path, b"0123456789").unwrap();
+        let mut read = String::new();
+        read_span(File::open(&path).unwrap(), 3, 4).unwrap().read_to_string(&mut read).unwrap();
+        assert_eq!(read, "3456");
+        read.clear();
+        read_span(File::open(&path).unwrap(), 10, 0).unwrap().read_to_string(&mut read).unwrap();
+        assert_eq!(read, "");
+        fs::remove_file(path).unwrap();
+    }
+}
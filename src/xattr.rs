@@ -0,0 +1,108 @@
+use std::io;
+use std::path::Path;
+
+/// Whether extended attributes can be written on this platform.
+pub const SUPPORTED: bool = cfg!(target_os = "linux");
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use libc;
+
+    pub const ENOTSUP: i32 = libc::ENOTSUP;
+
+    fn c_path(path: &Path) -> io::Result<CString> {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    fn c_name(name: &str) -> io::Result<CString> {
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+        let path = c_path(path)?;
+        let name = c_name(name)?;
+        let ret = unsafe {
+            libc::setxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr() as *const libc::c_void,
+                value.len(),
+                0,
+            )
+        };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+        let path = c_path(path)?;
+        let name = c_name(name)?;
+        let size = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), ::std::ptr::null_mut(), 0) };
+        if size < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                Some(libc::ENODATA) => Ok(None),
+                _ => Err(err),
+            };
+        }
+        let mut buf = vec![0u8; size as usize];
+        let read = unsafe {
+            libc::getxattr(
+                path.as_ptr(),
+                name.as_ptr(),
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+            )
+        };
+        if read < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        buf.truncate(read as usize);
+        Ok(Some(buf))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::io;
+    use std::path::Path;
+
+    pub const ENOTSUP: i32 = 0;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(io::ErrorKind::Other, "extended attributes are not supported on this platform")
+    }
+
+    pub fn set(_path: &Path, _name: &str, _value: &[u8]) -> io::Result<()> {
+        Err(unsupported())
+    }
+
+    pub fn get(_path: &Path, _name: &str) -> io::Result<Option<Vec<u8>>> {
+        Err(unsupported())
+    }
+}
+
+/// Whether `err` says the filesystem, or the platform, keeps no extended
+/// attributes at all.
+pub fn is_unsupported(err: &io::Error) -> bool {
+    !SUPPORTED || err.raw_os_error() == Some(imp::ENOTSUP)
+}
+
+/// Set the extended attribute `name` on `path`.
+pub fn set(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    imp::set(path, name, value)
+}
+
+/// Read the extended attribute `name` from `path`, `None` when unset.
+pub fn get(path: &Path, name: &str) -> io::Result<Option<Vec<u8>>> {
+    imp::get(path, name)
+}
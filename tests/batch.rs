@@ -0,0 +1,124 @@
+extern crate serde_json;
+
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+
+/// Answer `/ok*` at once, `/slow` a hundred bytes at a time over about
+/// half a second, and everything else with a 404.
+fn serve() -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            thread::spawn(move || answer(stream));
+        }
+    });
+    port
+}
+
+fn answer(mut stream: TcpStream) {
+    let mut request = String::new();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    reader.read_line(&mut request).unwrap();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+            break;
+        }
+    }
+    let path = request.split(' ').nth(1).unwrap_or("");
+    if path.starts_with("/ok") {
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok");
+    } else if path == "/slow" {
+        let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2000\r\nConnection: close\r\n\r\n");
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(25));
+            if stream.write_all(&[b's'; 100]).is_err() {
+                return;
+            }
+        }
+    } else {
+        let _ = stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+    }
+}
+
+/// Run a batch of `paths` with `args`, giving the exit code and the
+/// status of each entry in the result file.
+fn batch(name: &str, paths: &[&str], args: &[&str]) -> (i32, Vec<String>) {
+    let port = serve();
+    let dir = env::temp_dir().join(format!("download-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let list: String = paths.iter().map(|path| format!("http://127.0.0.1:{}{}\n", port, path)).collect();
+    fs::write(dir.join("list"), list).unwrap();
+
+    let result: PathBuf = dir.join("result.json");
+    let status = Command::new(env!("CARGO_BIN_EXE_download"))
+        .arg("--input-file")
+        .arg(dir.join("list"))
+        .arg("--output-dir")
+        .arg(dir.join("out"))
+        .arg("--result-file")
+        .arg(&result)
+        .arg("--fail")
+        .args(args)
+        .output()
+        .unwrap()
+        .status;
+    let doc: Value = serde_json::from_slice(&fs::read(&result).unwrap()).unwrap();
+    let statuses = doc["entries"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|entry| entry["status"].as_str().unwrap().to_string())
+        .collect();
+    fs::remove_dir_all(dir).unwrap();
+    (status.code().unwrap(), statuses)
+}
+
+#[test]
+fn batch_continues_past_failures() {
+    let (code, statuses) = batch("continue", &["/ok1", "/missing", "/ok2"], &[]);
+    assert_eq!(code, 6);
+    assert_eq!(statuses, ["downloaded", "failed", "downloaded"]);
+}
+
+#[test]
+fn batch_of_only_failures_says_all_failed() {
+    let (code, statuses) = batch("all-failed", &["/missing1", "/missing2"], &[]);
+    assert_eq!(code, 7);
+    assert_eq!(statuses, ["failed", "failed"]);
+}
+
+#[test]
+fn fail_fast_lets_downloads_under_way_finish() {
+    let paths = ["/slow", "/missing", "/ok1", "/ok2"];
+    let (code, statuses) = batch("fail-fast", &paths, &["--fail-fast", "--max-concurrent", "2"]);
+    assert_eq!(code, 8);
+    assert_eq!(statuses, ["downloaded", "failed", "not_attempted", "not_attempted"]);
+}
+
+#[test]
+fn fail_fast_abort_cancels_downloads_under_way() {
+    let paths = ["/slow", "/missing", "/ok1", "/ok2"];
+    let (code, statuses) = batch("fail-fast-abort", &paths, &["--fail-fast=abort", "--max-concurrent", "2"]);
+    assert_eq!(code, 8);
+    assert_eq!(statuses, ["failed", "failed", "not_attempted", "not_attempted"]);
+}
+
+#[test]
+fn fail_fast_without_failures_downloads_everything() {
+    let (code, statuses) = batch("fail-fast-ok", &["/ok1", "/ok2"], &["--fail-fast"]);
+    assert_eq!(code, 0);
+    assert_eq!(statuses, ["downloaded", "downloaded"]);
+}
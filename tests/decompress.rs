@@ -0,0 +1,69 @@
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::process;
+use std::process::Command;
+use std::thread;
+
+/// A gzip header followed by a deflate block of the reserved type.
+const CORRUPT: &[u8] = &[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3, 0xff, 0xff, 0xff, 0xff];
+/// The first 24 bytes of a gzip stream of 360 bytes of JSON lines.
+const TRUNCATED: &[u8] = &[
+    0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xab, 0x56, 0x4a, 0x54, 0xb2, 0x52, 0x30, 0xac, 0xe5,
+    0xaa, 0x1e, 0x65, 0xd0, 0x92,
+];
+
+/// Answer every request with `body`.
+fn serve(body: &'static [u8]) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap() == 0 || line == "\r\n" {
+                    break;
+                }
+            }
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len());
+            let _ = stream.write_all(head.as_bytes()).and_then(|_| stream.write_all(body));
+        }
+    });
+    port
+}
+
+/// Fetch `body` as `bad.json.gz` with `-O --decompress-keep-both` and
+/// check that it fails, leaving only the compressed output.
+fn keep_both_fails(name: &str, body: &'static [u8]) {
+    let port = serve(body);
+    let dir = env::temp_dir().join(format!("download-{}-{}", process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_download"))
+        .current_dir(&dir)
+        .arg("-O")
+        .arg("--decompress-keep-both")
+        .arg(format!("http://127.0.0.1:{}/bad.json.gz", port))
+        .output()
+        .unwrap()
+        .status;
+    assert!(!status.success());
+    assert_eq!(fs::read(dir.join("bad.json.gz")).unwrap(), body);
+    assert!(!dir.join("bad.json").exists());
+    assert!(!dir.join("bad.json.gz.part").exists());
+    assert!(!dir.join("bad.json.gz.part.state").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn corrupt_stream_keeps_the_compressed_output() {
+    keep_both_fails("keep-both-corrupt", CORRUPT);
+}
+
+#[test]
+fn truncated_stream_keeps_the_compressed_output() {
+    keep_both_fails("keep-both-truncated", TRUNCATED);
+}